@@ -0,0 +1,199 @@
+//! Allocation latency with the `FileStore` on pools of different sizes and
+//! fill levels.
+//!
+//! Pools are pre-filled from the start of the range and the last reserved IP
+//! is forgotten before every allocation, so each measured `get` has to walk
+//! past every occupied address before it finds a free one.
+//!
+//! The contention group runs concurrent ADDs on two range sets of the same
+//! network, holding either the network wide lock or only the lock of the
+//! range set allocated from. With a lock timeout the locks are polled
+//! instead of waited for.
+//!
+//! The lookup group finds the reservations of a container among 10k others,
+//! with and without the index of `FileStoreOptions::index`.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ipnetwork::IpNetwork;
+
+use host_local::allocator::range::Range;
+use host_local::allocator::rangeset::RangeSet;
+use host_local::allocator::Allocator;
+use host_local::store::filestore::{FileStore, FileStoreOptions};
+use host_local::store::Store;
+
+const SUBNETS: &[&str] = &["10.10.0.0/24", "10.20.0.0/20", "10.30.0.0/16"];
+const FILL_PERCENTS: &[usize] = &[0, 50, 99];
+const THREADS: u32 = 4;
+const ADDS_PER_THREAD: usize = 10;
+const LOOKUP_RESERVATIONS: usize = 10_000;
+
+/// Writes reservation files directly instead of going through
+/// `Store::reserve`, filling a /16 with fsyncs would take minutes.
+fn fill(data_dir: &Path, range: &Range, percent: usize) {
+    let free: Vec<IpNetwork> = range.iter_free().collect();
+    let count = free.len() * percent / 100;
+    fill_count(data_dir, free, count);
+}
+
+fn fill_count(data_dir: &Path, free: Vec<IpNetwork>, count: usize) {
+    for (index, ip_net) in free.into_iter().take(count).enumerate() {
+        fs::write(
+            data_dir.join(ip_net.ip().to_string()),
+            format!("filler-{}\r\neth0", index),
+        )
+        .unwrap();
+    }
+}
+
+fn allocation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("allocation");
+    group.sample_size(10);
+
+    for subnet in SUBNETS {
+        for percent in FILL_PERCENTS {
+            let network = format!("bench-{}-{}", subnet.replace('/', "_"), percent);
+            let root = std::env::temp_dir().join("host-local-bench");
+            let store = FileStore::new(&network, root.to_str().unwrap()).unwrap();
+            let data_dir = store.data_dir().to_path_buf();
+
+            let range = Range::new(subnet.parse().unwrap(), None, None, None).unwrap();
+            let mut range_set = RangeSet::new();
+            range_set.add(range).unwrap();
+            fill(&data_dir, &range, *percent);
+
+            let store: Arc<dyn Store> = Arc::new(store);
+            let allocator = Allocator::new(range_set, store.clone());
+
+            group.bench_with_input(
+                BenchmarkId::new(*subnet, format!("{}%", percent)),
+                percent,
+                |b, _| {
+                    b.iter_batched(
+                        || {
+                            let _ = fs::remove_file(data_dir.join("last_reserved_ip.json"));
+                        },
+                        |_| {
+                            allocator.get("bench", "eth0", None).unwrap();
+                            store.release_by_id("bench", "eth0").unwrap();
+                        },
+                        BatchSize::PerIteration,
+                    )
+                },
+            );
+
+            let _ = fs::remove_dir_all(&data_dir);
+        }
+    }
+
+    group.finish();
+}
+
+/// One plugin process: opens the store on its own and allocates and releases
+/// an IP of range set `range_id` a few times.
+fn adds(root: &Path, range_id: u32, per_range: bool, lock_timeout: Option<Duration>) {
+    let options = FileStoreOptions {
+        lock_timeout: lock_timeout,
+        ..FileStoreOptions::default()
+    };
+    let store =
+        FileStore::with_options("bench-contention", root.to_str().unwrap(), options).unwrap();
+    let store: Arc<dyn Store> = Arc::new(store);
+
+    let range = Range::new(
+        format!("10.{}.0.0/24", range_id + 1).parse().unwrap(),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut range_set = RangeSet::new();
+    range_set.add(range).unwrap();
+    let allocator = Allocator::new(range_set, store.clone()).with_range_id(range_id.to_string());
+
+    for n in 0..ADDS_PER_THREAD {
+        let id = format!("bench-{}-{}", range_id, n);
+        if per_range {
+            store.lock_range(allocator.range_id()).unwrap();
+        } else {
+            store.lock().unwrap();
+        }
+
+        allocator.get(&id, "eth0", None).unwrap();
+        store.release_by_id(&id, "eth0").unwrap();
+
+        if per_range {
+            store.unlock_range(allocator.range_id()).unwrap();
+        } else {
+            store.unlock().unwrap();
+        }
+    }
+}
+
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contention");
+    group.sample_size(10);
+
+    let root = std::env::temp_dir().join("host-local-bench");
+    let polling = Some(Duration::from_secs(10));
+    let cases = [
+        ("network lock", false, None),
+        ("range locks", true, None),
+        ("network lock, polling", false, polling),
+        ("range locks, polling", true, polling),
+    ];
+    for (name, per_range, lock_timeout) in &cases {
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let threads: Vec<_> = (0..THREADS)
+                    .map(|thread| {
+                        let root = root.clone();
+                        let (per_range, lock_timeout) = (*per_range, *lock_timeout);
+                        thread::spawn(move || adds(&root, thread % 2, per_range, lock_timeout))
+                    })
+                    .collect();
+
+                for thread in threads {
+                    thread.join().unwrap();
+                }
+            })
+        });
+    }
+
+    let _ = fs::remove_dir_all(root.join("bench-contention"));
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    group.sample_size(10);
+
+    let root = std::env::temp_dir().join("host-local-bench");
+    let range = Range::new("10.40.0.0/16".parse().unwrap(), None, None, None).unwrap();
+    for index in &[false, true] {
+        let options = FileStoreOptions {
+            index: *index,
+            ..FileStoreOptions::default()
+        };
+        let store =
+            FileStore::with_options("bench-lookup", root.to_str().unwrap(), options).unwrap();
+        let data_dir = store.data_dir().to_path_buf();
+        fill_count(&data_dir, range.iter_free().collect(), LOOKUP_RESERVATIONS);
+
+        let name = if *index { "index" } else { "files" };
+        group.bench_function(name, |b| b.iter(|| store.get_by_id("filler-0", "eth0")));
+
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, allocation, contention, lookup);
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use host_local::config::NetConf;
+
+// Malformed runtime input must surface as an error, never as a panic: a
+// panicking plugin fails the ADD without a CNI error result.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(conf) = NetConf::parse(data) {
+        let _ = conf.ipam.range_sets();
+    }
+});
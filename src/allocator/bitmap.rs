@@ -0,0 +1,215 @@
+//! In-memory index of the reserved IPs of a range set.
+//!
+//! The allocator builds one from `Store::list` before searching for a free IP,
+//! so occupied addresses are skipped without a round trip to the store, or
+//! keeps one warm across allocations, see `Allocator::warm_cache`.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use super::range::Range;
+use super::rangeset::RangeSet;
+use crate::core::to_u128;
+
+/// Ranges with more addresses than this fall back to a hash set, a bitmap of
+/// an IPv6 /64 wouldn't fit in memory.
+const MAX_BITMAP_BITS: u128 = 1 << 24;
+
+#[derive(Clone)]
+enum Slots {
+    Bits(Vec<u64>),
+    Sparse(HashSet<u128>),
+}
+
+#[derive(Clone)]
+struct Slice {
+    start: u128,
+    end: u128,
+    slots: Slots,
+}
+
+#[derive(Clone)]
+pub struct ReservedBitmap {
+    slices: Vec<Slice>,
+    len: usize,
+}
+
+impl ReservedBitmap {
+    pub fn new(range_set: &RangeSet) -> ReservedBitmap {
+        let slices = range_set
+            .iter()
+            .map(|range| {
+                let start = to_u128(range.start);
+                let end = to_u128(range.end);
+                let size = end.saturating_sub(start) + 1;
+
+                let slots = if size <= MAX_BITMAP_BITS {
+                    Slots::Bits(vec![0; ((size + 63) / 64) as usize])
+                } else {
+                    Slots::Sparse(HashSet::new())
+                };
+
+                Slice {
+                    start: start,
+                    end: end,
+                    slots: slots,
+                }
+            })
+            .collect();
+
+        ReservedBitmap {
+            slices: slices,
+            len: 0,
+        }
+    }
+
+    /// Builds the index of `range_set` from the reserved IPs of a store,
+    /// IPs outside of the range set are ignored.
+    pub fn from_reserved<I>(range_set: &RangeSet, reserved: I) -> ReservedBitmap
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let mut bitmap = Self::new(range_set);
+        for ip in reserved {
+            bitmap.insert(ip);
+        }
+
+        bitmap
+    }
+
+    /// Marks `ip` as reserved, returns false if it's outside of every range.
+    pub fn insert(&mut self, ip: IpAddr) -> bool {
+        let value = to_u128(ip);
+
+        match self.slice_mut(value) {
+            Some(slice) => {
+                let offset = value - slice.start;
+                let added = match &mut slice.slots {
+                    Slots::Bits(bits) => {
+                        let word = &mut bits[(offset / 64) as usize];
+                        let added = *word & (1 << (offset % 64)) == 0;
+                        *word |= 1 << (offset % 64);
+                        added
+                    }
+                    Slots::Sparse(set) => set.insert(offset),
+                };
+                if added {
+                    self.len += 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks `ip` as free again.
+    pub fn remove(&mut self, ip: IpAddr) {
+        let value = to_u128(ip);
+
+        if let Some(slice) = self.slice_mut(value) {
+            let offset = value - slice.start;
+            let removed = match &mut slice.slots {
+                Slots::Bits(bits) => {
+                    let word = &mut bits[(offset / 64) as usize];
+                    let removed = *word & (1 << (offset % 64)) != 0;
+                    *word &= !(1 << (offset % 64));
+                    removed
+                }
+                Slots::Sparse(set) => set.remove(&offset),
+            };
+            if removed {
+                self.len -= 1;
+            }
+        }
+    }
+
+    /// Number of reserved IPs.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of reserved IPs of `range`, one of the ranges the index was
+    /// built for.
+    pub fn reserved_in(&self, range: &Range) -> usize {
+        let (start, end) = (to_u128(range.start), to_u128(range.end));
+
+        self.slices
+            .iter()
+            .find(|slice| slice.start == start && slice.end == end)
+            .map_or(0, |slice| match &slice.slots {
+                Slots::Bits(bits) => bits.iter().map(|word| word.count_ones() as usize).sum(),
+                Slots::Sparse(set) => set.len(),
+            })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let value = to_u128(ip);
+
+        self.slices
+            .iter()
+            .find(|slice| slice.start <= value && value <= slice.end)
+            .is_some_and(|slice| {
+                let offset = value - slice.start;
+                match &slice.slots {
+                    Slots::Bits(bits) => bits[(offset / 64) as usize] & (1 << (offset % 64)) != 0,
+                    Slots::Sparse(set) => set.contains(&offset),
+                }
+            })
+    }
+
+    fn slice_mut(&mut self, value: u128) -> Option<&mut Slice> {
+        self.slices
+            .iter_mut()
+            .find(|slice| slice.start <= value && value <= slice.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::range::Range;
+
+    #[test]
+    fn insert_and_contains() {
+        let mut range_set = RangeSet::new();
+        range_set
+            .add(Range::new("10.1.0.0/16".parse().unwrap(), None, None, None).unwrap())
+            .unwrap();
+
+        let mut bitmap = ReservedBitmap::from_reserved(
+            &range_set,
+            vec!["10.1.0.2".parse().unwrap(), "10.2.0.2".parse().unwrap()],
+        );
+
+        assert!(bitmap.contains("10.1.0.2".parse().unwrap()));
+        assert!(!bitmap.contains("10.1.0.3".parse().unwrap()));
+        assert!(!bitmap.contains("10.2.0.2".parse().unwrap()));
+
+        assert!(bitmap.insert("10.1.255.254".parse().unwrap()));
+        assert!(bitmap.contains("10.1.255.254".parse().unwrap()));
+        assert!(!bitmap.insert("10.2.0.3".parse().unwrap()));
+        assert_eq!(bitmap.len(), 2);
+
+        bitmap.remove("10.1.0.2".parse().unwrap());
+        bitmap.remove("10.1.0.2".parse().unwrap());
+        assert!(!bitmap.contains("10.1.0.2".parse().unwrap()));
+        assert_eq!(bitmap.len(), 1);
+    }
+
+    #[test]
+    fn sparse_ipv6() {
+        let mut range_set = RangeSet::new();
+        range_set
+            .add(Range::new("2001:db8::/64".parse().unwrap(), None, None, None).unwrap())
+            .unwrap();
+
+        let mut bitmap = ReservedBitmap::new(&range_set);
+        assert!(bitmap.insert("2001:db8::ffff:ffff".parse().unwrap()));
+        assert!(bitmap.contains("2001:db8::ffff:ffff".parse().unwrap()));
+        assert!(!bitmap.contains("2001:db8::2".parse().unwrap()));
+    }
+}
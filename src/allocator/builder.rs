@@ -0,0 +1,220 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use super::range::Range;
+use super::rangeset::{RangeSet, RangeSetError};
+use super::retry::RetryPolicy;
+use super::{AllocationObserver, AllocationStrategy, Allocator};
+use crate::store::{Labels, Pod, Store};
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("no ranges given")]
+    NoRanges,
+
+    #[error("no store given")]
+    NoStore,
+
+    #[error(transparent)]
+    RangeSetError(#[from] RangeSetError),
+}
+
+/// Assembles an `Allocator`, see `Allocator::builder`. Only the ranges and
+/// the store are required, everything else defaults like `Allocator::new`.
+#[derive(Default)]
+pub struct AllocatorBuilder {
+    ranges: Vec<Range>,
+    store: Option<Arc<dyn Store>>,
+    range_id: Option<String>,
+    retry_policy: Option<RetryPolicy>,
+    reserved_ips: Vec<IpAddr>,
+    quota: Option<usize>,
+    strategy: AllocationStrategy,
+    netns: Option<String>,
+    pod: Option<Pod>,
+    labels: Labels,
+    observers: Vec<Box<dyn AllocationObserver>>,
+}
+
+impl AllocatorBuilder {
+    /// Adds `ranges` to the range set, they are checked by `build`.
+    pub fn ranges<I: IntoIterator<Item = Range>>(mut self, ranges: I) -> AllocatorBuilder {
+        self.ranges.extend(ranges);
+        self
+    }
+
+    /// Adds the ranges of `range_set`.
+    pub fn range_set(self, range_set: &RangeSet) -> AllocatorBuilder {
+        self.ranges(range_set.iter().copied())
+    }
+
+    /// The store shared with the allocators of the other range sets.
+    pub fn store(mut self, store: Arc<dyn Store>) -> AllocatorBuilder {
+        self.store = Some(store);
+        self
+    }
+
+    /// See `Allocator::with_range_id`, `RangeSet::id` by default.
+    pub fn range_id<S: Into<String>>(mut self, range_id: S) -> AllocatorBuilder {
+        self.range_id = Some(range_id.into());
+        self
+    }
+
+    /// See `Allocator::with_retry_policy`.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> AllocatorBuilder {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// See `Allocator::with_reserved_ips`.
+    pub fn reserved_ips<I: IntoIterator<Item = IpAddr>>(
+        mut self,
+        reserved_ips: I,
+    ) -> AllocatorBuilder {
+        self.reserved_ips.extend(reserved_ips);
+        self
+    }
+
+    /// See `Allocator::with_quota`.
+    pub fn quota(mut self, quota: usize) -> AllocatorBuilder {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// See `Allocator::with_strategy`.
+    pub fn strategy(mut self, strategy: AllocationStrategy) -> AllocatorBuilder {
+        self.strategy = strategy;
+        self
+    }
+
+    /// See `Allocator::with_netns`.
+    pub fn netns(mut self, netns: &str) -> AllocatorBuilder {
+        self.netns = Some(netns.to_owned());
+        self
+    }
+
+    /// See `Allocator::with_pod`.
+    pub fn pod(mut self, pod: Pod) -> AllocatorBuilder {
+        self.pod = Some(pod);
+        self
+    }
+
+    /// See `Allocator::with_labels`.
+    pub fn labels(mut self, labels: Labels) -> AllocatorBuilder {
+        self.labels = labels;
+        self
+    }
+
+    /// See `Allocator::subscribe`.
+    pub fn observer(mut self, observer: Box<dyn AllocationObserver>) -> AllocatorBuilder {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn build(self) -> Result<Allocator, BuildError> {
+        if self.ranges.is_empty() {
+            return Err(BuildError::NoRanges);
+        }
+
+        let mut range_set = RangeSet::new();
+        for range in self.ranges {
+            range_set.add(range)?;
+        }
+
+        let store = self.store.ok_or(BuildError::NoStore)?;
+        let mut allocator = Allocator::new(range_set, store)
+            .with_reserved_ips(self.reserved_ips)
+            .with_strategy(self.strategy);
+        if let Some(range_id) = self.range_id {
+            allocator = allocator.with_range_id(range_id);
+        }
+        if let Some(retry_policy) = self.retry_policy {
+            allocator = allocator.with_retry_policy(retry_policy);
+        }
+        if let Some(quota) = self.quota {
+            allocator = allocator.with_quota(quota);
+        }
+        if let Some(netns) = &self.netns {
+            allocator = allocator.with_netns(netns);
+        }
+        if let Some(pod) = self.pod {
+            allocator = allocator.with_pod(pod);
+        }
+        if !self.labels.is_empty() {
+            allocator = allocator.with_labels(self.labels);
+        }
+        for observer in self.observers {
+            allocator.subscribe(observer);
+        }
+
+        Ok(allocator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::filestore::FileStore;
+    use std::fs::remove_dir_all;
+
+    fn range(subnet: &str) -> Range {
+        Range::new(subnet.parse().unwrap(), None, None, None).unwrap()
+    }
+
+    #[test]
+    fn build() {
+        let data_dir = "/tmp/cni-builder";
+        let _ = remove_dir_all(data_dir);
+
+        let store = FileStore::builder("n")
+            .data_dir(data_dir)
+            .index(true)
+            .build()
+            .unwrap();
+        let store: Arc<dyn Store> = Arc::new(store);
+
+        let allocator = Allocator::builder()
+            .ranges(vec![range("10.1.2.0/24")])
+            .store(store.clone())
+            .range_id("0")
+            .reserved_ips(vec!["10.1.2.2".parse().unwrap()])
+            .strategy(AllocationStrategy::Sequential)
+            .build()
+            .unwrap();
+        assert_eq!(allocator.range_id(), "0");
+
+        let ip_config = allocator.get("c1", "eth0", None).unwrap();
+        assert_eq!(
+            ip_config.address().ip(),
+            "10.1.2.3".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            store.get_by_id("c1", "eth0"),
+            vec!["10.1.2.3".parse::<IpAddr>().unwrap()]
+        );
+
+        assert!(matches!(
+            Allocator::builder().store(store.clone()).build(),
+            Err(BuildError::NoRanges)
+        ));
+        assert!(matches!(
+            Allocator::builder()
+                .ranges(vec![range("10.1.2.0/24")])
+                .build(),
+            Err(BuildError::NoStore)
+        ));
+        assert!(matches!(
+            Allocator::builder()
+                .ranges(vec![range("10.1.2.0/24"), range("2001:db8::/64")])
+                .store(store)
+                .build(),
+            Err(BuildError::RangeSetError(
+                RangeSetError::DifferentAddressType
+            ))
+        ));
+
+        let _ = remove_dir_all(data_dir);
+    }
+}
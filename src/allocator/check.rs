@@ -0,0 +1,115 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+/// Why an IP fails CHECK.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckFailure {
+    /// The container holds the IP, but no configured range covers it any
+    /// more, `host-local reconcile` releases such reservations.
+    OutOfRange,
+    /// The previous result lists the IP, but the store holds no
+    /// reservation of it.
+    NotReserved,
+    /// The previous result lists the IP, but it is reserved for another
+    /// container or interface.
+    ReservedForOther,
+}
+
+/// An IP which failed CHECK.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FailedIp {
+    pub ip: IpAddr,
+    pub reason: CheckFailure,
+}
+
+/// The IPs of a container which failed CHECK, see `Allocator::check`.
+/// Serialized into the `details` of the CNI error result, so orchestrators
+/// can act on the specific addresses:
+///
+/// ```text
+/// {"failed": [{"ip": "10.1.2.2", "reason": "out-of-range"}]}
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CheckReport {
+    pub failed: Vec<FailedIp>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Adds the failures of `other`, e.g. of another range set.
+    pub fn merge(&mut self, other: CheckReport) {
+        self.failed.extend(other.failed);
+    }
+
+    pub(crate) fn fail(&mut self, ip: IpAddr, reason: CheckFailure) {
+        self.failed.push(FailedIp {
+            ip: ip,
+            reason: reason,
+        });
+    }
+}
+
+impl fmt::Display for FailedIp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            CheckFailure::OutOfRange => {
+                write!(f, "ip {} is outside of the configured ranges", self.ip)
+            }
+            CheckFailure::NotReserved => write!(f, "ip {} is not reserved", self.ip),
+            CheckFailure::ReservedForOther => {
+                write!(f, "ip {} is reserved for another container", self.ip)
+            }
+        }
+    }
+}
+
+/// Lists every failed IP, e.g. for the `msg` of the CNI error result.
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.failed.is_empty() {
+            return write!(f, "check passed");
+        }
+
+        for (index, failed) in self.failed.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", failed)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_and_details() {
+        let mut report = CheckReport::default();
+        assert!(report.is_ok());
+
+        report.fail("10.1.2.2".parse().unwrap(), CheckFailure::OutOfRange);
+        report.fail("10.1.2.3".parse().unwrap(), CheckFailure::ReservedForOther);
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.to_string(),
+            "ip 10.1.2.2 is outside of the configured ranges, \
+             ip 10.1.2.3 is reserved for another container"
+        );
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({"failed": [
+                {"ip": "10.1.2.2", "reason": "out-of-range"},
+                {"ip": "10.1.2.3", "reason": "reserved-for-other"}
+            ]})
+        );
+    }
+}
@@ -1,38 +1,162 @@
+pub mod bitmap;
+mod builder;
+mod check;
+mod observer;
+pub mod partition;
 pub mod range;
 pub mod rangeiter;
 pub mod rangeset;
+pub mod retry;
+mod rollback;
+mod utilization;
+mod warmpool;
 
 use ipnetwork::IpNetwork;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use super::store::{Store, StoreError};
+use super::store::{Labels, Owner, Pod, Store, StoreError, Transaction};
+use super::timing::{Phase, Timings};
+use bitmap::ReservedBitmap;
+use range::Range;
 use rangeiter::RangeIter;
 use rangeset::{RangeSet, RangeSetError};
+use retry::RetryPolicy;
+use warmpool::WarmPool;
+
+pub use builder::{AllocatorBuilder, BuildError};
+pub use check::{CheckFailure, CheckReport, FailedIp};
+pub use observer::AllocationObserver;
+pub use rollback::Rollback;
+pub use utilization::{FreeBlock, SliceUsage, Utilization};
+pub use warmpool::WARM_POOL_ID;
 
 pub struct Allocator {
     range_set: RangeSet,
-    store: Box<dyn Store>,
+    store: Arc<dyn Store>,
     range_id: String,
+    retry_policy: RetryPolicy,
+    observers: Vec<Box<dyn AllocationObserver>>,
+    reserved_ips: HashSet<IpAddr>,
+    quota: Option<usize>,
+    strategy: AllocationStrategy,
+    /// Reserved IPs of the range set, kept across allocations once
+    /// `warm_cache` was called.
+    cache: RefCell<Option<ReservedBitmap>>,
+    /// Ranges removed by `set_range_set`, their IPs are released but never
+    /// allocated again.
+    retired: Vec<Range>,
+    netns: Option<String>,
+    pod: Option<Pod>,
+    labels: Labels,
+    /// IPs reserved ahead of allocations, see `with_warm_pool`.
+    warm_pool: RefCell<Option<WarmPool>>,
+    timings: Option<Rc<Timings>>,
+}
+
+/// How the allocator picks a free IP when none is requested.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum AllocationStrategy {
+    /// The next free IP after the last reserved one.
+    #[default]
+    Sequential,
+    /// In IPv6 ranges, an IP derived from a hash of the container id and
+    /// interface, so a recreated container gets the same address again.
+    /// Falls back to sequential when that IP is taken, IPv4 ranges are
+    /// always sequential.
+    Hashed,
+    /// An IP derived from a hash of the container id and interface, in
+    /// every range. When it is taken the following IPs are tried in order.
+    Hash,
+    /// Like `Sequential` but from the top down, the next free IP below the
+    /// last reserved one. Leaves the bottom of the ranges to addresses
+    /// assigned by hand.
+    Descending,
+    /// Spreads allocations over the ranges in proportion to their free
+    /// IPs: a hash of the container id and interface picks the range, each
+    /// range weighted by its free IPs, and the IP is the next free one of
+    /// that range. Keeps one subnet from filling up while others sit idle.
+    Balance,
 }
 
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct IpConfig {
-    interface: Option<usize>,
-    address: IpNetwork,
-    gateway: IpAddr,
+    pub(crate) interface: Option<usize>,
+    pub(crate) address: IpNetwork,
+    pub(crate) gateway: Option<IpAddr>,
+    pub(crate) range_index: usize,
+    pub(crate) range: Range,
+}
+
+/// A reservation which a changed range set no longer covers, see
+/// `Allocator::reconcile`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Orphan {
+    pub ip: IpAddr,
+    pub id: String,
+    pub ifname: String,
+}
+
+impl IpConfig {
+    /// Index of the interface the IP belongs to in the `interfaces` list of
+    /// the result, if known.
+    pub fn interface(&self) -> Option<usize> {
+        self.interface
+    }
+
+    /// Allocated IP together with the prefix length of its subnet.
+    pub fn address(&self) -> IpNetwork {
+        self.address
+    }
+
+    /// Gateway of the range the IP was allocated from, unset for
+    /// point-to-point ranges.
+    pub fn gateway(&self) -> Option<IpAddr> {
+        self.gateway
+    }
+
+    /// Position of the originating range within its range set.
+    pub fn range_index(&self) -> usize {
+        self.range_index
+    }
+
+    /// Range the IP was allocated from.
+    pub fn range(&self) -> &Range {
+        &self.range
+    }
+
+    /// Sets the index of the interface the IP belongs to in the
+    /// `interfaces` list of the result.
+    pub fn with_interface(mut self, interface: usize) -> IpConfig {
+        self.interface = Some(interface);
+        self
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum AllocateError {
+    #[error("requested ip {0} is not of the address family of the range set")]
+    FamilyMismatch(IpAddr),
+
     #[error("requested ip {0} is gateway's ip")]
     GatewayIp(IpAddr),
 
-    #[error("{0}")]
-    RangeSetError(RangeSetError),
+    #[error("requested ip {0} is the broadcast address of its subnet")]
+    BroadcastIp(IpAddr),
 
-    #[error("{0}")]
-    StoreError(StoreError),
+    #[error(transparent)]
+    RangeSetError(#[from] RangeSetError),
+
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
 
     #[error("requested ip {0} is not available")]
     IpNotAvailable(IpAddr),
@@ -42,15 +166,278 @@ pub enum AllocateError {
 
     #[error("ip addresses are exhausted")]
     IpExhausted,
+
+    #[error("{id} holds {held} of at most {quota} ip addresses, {requested} more would exceed the quota")]
+    QuotaExceeded {
+        id: String,
+        quota: usize,
+        held: usize,
+        requested: usize,
+    },
+
+    #[error("{0} {1} holds no ip of the range set")]
+    NotAllocated(String, String),
 }
 
 impl Allocator {
-    pub fn new(range_set: RangeSet, store: Box<dyn Store>, range_id: u32) -> Allocator {
+    /// Creates an allocator for one range set. Allocators of the other range
+    /// sets of the same network share `store`, they are told apart by
+    /// `RangeSet::id` unless overridden with `with_range_id`.
+    pub fn new(range_set: RangeSet, store: Arc<dyn Store>) -> Allocator {
         Allocator {
+            range_id: range_set.id(),
             range_set: range_set,
             store: store,
-            range_id: format!("{}", range_id),
+            retry_policy: RetryPolicy::default(),
+            observers: Vec::new(),
+            reserved_ips: HashSet::new(),
+            quota: None,
+            strategy: AllocationStrategy::default(),
+            cache: RefCell::new(None),
+            retired: Vec::new(),
+            netns: None,
+            pod: None,
+            labels: Labels::new(),
+            warm_pool: RefCell::new(None),
+            timings: None,
+        }
+    }
+
+    /// Starts an allocator to be assembled step by step, e.g.
+    /// `Allocator::builder().ranges(ranges).store(store).build()?`.
+    pub fn builder() -> AllocatorBuilder {
+        AllocatorBuilder::default()
+    }
+
+    /// Sets how store operations failing with transient errors are retried.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Allocator {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Keeps `reserved_ips` out of dynamic allocation, they are only handed
+    /// out when requested explicitly.
+    pub fn with_reserved_ips<I: IntoIterator<Item = IpAddr>>(
+        mut self,
+        reserved_ips: I,
+    ) -> Allocator {
+        self.reserved_ips.extend(reserved_ips);
+        self
+    }
+
+    /// Limits how many IPs of the network, counting every range set and
+    /// interface, the same `id` may hold.
+    pub fn with_quota(mut self, quota: usize) -> Allocator {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Records `netns` as the network namespace of the containers the IPs
+    /// are reserved for.
+    pub fn with_netns(mut self, netns: &str) -> Allocator {
+        self.netns = Some(netns.to_owned());
+        self
+    }
+
+    /// Records `pod` as the Kubernetes pod the IPs are reserved for.
+    pub fn with_pod(mut self, pod: Pod) -> Allocator {
+        self.pod = Some(pod);
+        self
+    }
+
+    /// Attaches `labels` to the reservations, see `Labels`.
+    pub fn with_labels(mut self, labels: Labels) -> Allocator {
+        self.labels = labels;
+        self
+    }
+
+    /// Counts the time spent writing reservations to `Phase::Reserve` of
+    /// `timings`.
+    pub fn with_timings(mut self, timings: Rc<Timings>) -> Allocator {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Sets how free IPs are picked, see `AllocationStrategy`.
+    pub fn with_strategy(mut self, strategy: AllocationStrategy) -> Allocator {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Keeps up to `size` free IPs reserved ahead of allocations, so
+    /// allocating one skips searching the store for a free IP. Meant for
+    /// long running processes on nodes with many short lived containers:
+    /// they call `refill_warm_pool` between requests and
+    /// `expire_warm_pool` regularly, which releases IPs left unclaimed for
+    /// `timeout`.
+    ///
+    /// The IPs are reserved for `WARM_POOL_ID` and the range set id, so
+    /// other processes skip them. Hash strategies derive the IP from the
+    /// container and don't take IPs of the pool.
+    pub fn with_warm_pool(self, size: usize, timeout: Duration) -> Allocator {
+        *self.warm_pool.borrow_mut() = Some(WarmPool::new(size, timeout));
+        self
+    }
+
+    /// Registers `observer` to be told about every reservation and release
+    /// made through this allocator.
+    pub fn subscribe(&mut self, observer: Box<dyn AllocationObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Replaces the range set in place, e.g. when a long running process
+    /// reloads its configuration. Added ranges are allocated from right
+    /// away. Removed ranges are no longer allocated from, but the IPs still
+    /// reserved in them stay reserved until `release` frees them.
+    ///
+    /// The range id is kept, so the store keys of the allocator don't change
+    /// while it lives.
+    pub fn set_range_set(&mut self, range_set: RangeSet) -> Result<(), AllocateError> {
+        let retired: Vec<Range> = self
+            .range_set
+            .iter()
+            .chain(self.retired.iter())
+            .filter(|range| !range_set.iter().any(|r| r == *range))
+            .copied()
+            .collect();
+
+        self.range_set = range_set;
+        self.retired = retired;
+
+        // the cache indexes the old ranges, without a new one allocation
+        // falls back to listing the store
+        if self.cache.replace(None).is_some() {
+            self.warm_cache()?;
         }
+
+        Ok(())
+    }
+
+    /// Overrides the id this range set is known by in the store, e.g. with
+    /// its position in the configuration like upstream host-local.
+    pub fn with_range_id<S: Into<String>>(mut self, range_id: S) -> Allocator {
+        self.range_id = range_id.into();
+        self
+    }
+
+    /// The id this range set is known by in the store, e.g. for
+    /// `Store::lock_range`.
+    pub fn range_id(&self) -> &str {
+        &self.range_id
+    }
+
+    /// Extends the lease of the IP of this range set held by `id` and
+    /// `ifname` without allocating again.
+    ///
+    /// Fails with `NotAllocated` if the IP was released in the meantime.
+    pub fn renew(&self, id: &str, ifname: &str) -> Result<IpConfig, AllocateError> {
+        let ip_config = self
+            .find_allocated(id, ifname)
+            .ok_or_else(|| AllocateError::NotAllocated(id.to_owned(), ifname.to_owned()))?;
+
+        self.retry_policy
+            .run(|| self.store.touch(ip_config.address().ip(), id, ifname))
+            .map_err(AllocateError::StoreError)?;
+
+        Ok(ip_config)
+    }
+
+    /// Releases the IPs of this range set held by `id` and `ifname`, returns
+    /// the released IPs.
+    pub fn release(&self, id: &str, ifname: &str) -> Result<Vec<IpAddr>, AllocateError> {
+        let ips: Vec<IpAddr> = self
+            .store
+            .get_by_id(id, ifname)
+            .into_iter()
+            .filter(|ip| {
+                self.range_set.contains(*ip) || self.retired.iter().any(|r| r.contains(*ip))
+            })
+            .collect();
+
+        for ip in &ips {
+            self.retry_policy
+                .run(|| self.store.release_checked(*ip, id, ifname))
+                .map_err(AllocateError::StoreError)?;
+
+            self.notify_released(id, ifname, *ip);
+        }
+
+        Ok(ips)
+    }
+
+    fn notify_allocated(&self, id: &str, ifname: &str, ip_config: &IpConfig) {
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.insert(ip_config.address.ip());
+        }
+
+        for observer in &self.observers {
+            observer.allocated(id, ifname, ip_config);
+        }
+    }
+
+    fn notify_released(&self, id: &str, ifname: &str, ip: IpAddr) {
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.remove(ip);
+        }
+
+        for observer in &self.observers {
+            observer.released(id, ifname, ip);
+        }
+    }
+
+    /// Loads the reservations of the range set once and keeps them in
+    /// memory, so later allocations don't list the store again. Meant for
+    /// long running processes owning the data dir, the cache follows the
+    /// reservations and releases made through this allocator only.
+    ///
+    /// Calling it again rebuilds the cache.
+    pub fn warm_cache(&self) -> Result<(), AllocateError> {
+        let cache = self.load_cache().map_err(AllocateError::StoreError)?;
+        *self.cache.borrow_mut() = Some(cache);
+        Ok(())
+    }
+
+    /// Compares the number of cached reservations with the store and
+    /// rebuilds the cache if they differ, e.g. after another process
+    /// changed the data dir. Returns whether the cache was consistent, it
+    /// always is without a warm cache.
+    pub fn verify_cache(&self) -> Result<bool, AllocateError> {
+        let cached = match self.cache.borrow().as_ref() {
+            Some(cache) => cache.len(),
+            None => return Ok(true),
+        };
+
+        let reserved = self
+            .retry_policy
+            .run(|| self.store.list())
+            .map_err(AllocateError::StoreError)?
+            .into_iter()
+            .filter(|ip| self.range_set.contains(*ip))
+            .count();
+
+        if reserved == cached {
+            return Ok(true);
+        }
+
+        self.warm_cache()?;
+        Ok(false)
+    }
+
+    fn load_cache(&self) -> Result<ReservedBitmap, StoreError> {
+        let reserved = self.retry_policy.run(|| self.store.list())?;
+        Ok(ReservedBitmap::from_reserved(&self.range_set, reserved))
+    }
+
+    /// Rebuilds the cache after the store refused an IP the cache had as
+    /// free. Returns false if there is no cache.
+    fn rebuild_stale_cache(&self) -> Result<bool, StoreError> {
+        if self.cache.borrow().is_none() {
+            return Ok(false);
+        }
+
+        let cache = self.load_cache()?;
+        *self.cache.borrow_mut() = Some(cache);
+        Ok(true)
     }
 
     pub fn get(
@@ -59,89 +446,1639 @@ impl Allocator {
         ifname: &str,
         requested_ip: Option<IpAddr>,
     ) -> Result<IpConfig, AllocateError> {
-        // todo: store lock
-
-        let mut reserved_ip: IpNetwork;
-        let mut gateway: IpAddr;
-
         match requested_ip {
             Some(ip) => {
-                let range = self
-                    .range_set
-                    .get_range_for_ip(ip)
-                    .map_err(AllocateError::RangeSetError)?;
+                let ip_config = self.check_ip(ip)?;
+                self.check_holder(id, ifname, ip)?;
 
+                // runtimes retry ADD, the IP may already be ours
+                let owner = self.owner(id, ifname);
                 let reserved = self
-                    .store
-                    .reserve(id, ifname, ip, &self.range_id)
+                    .timed_reserve(|| {
+                        self.store
+                            .reserve_or_confirm(owner.clone(), ip, &self.range_id)
+                    })
                     .map_err(AllocateError::StoreError)?;
 
                 if !reserved {
                     return Err(AllocateError::IpNotAvailable(ip));
                 }
 
-                reserved_ip = IpNetwork::new(ip, range.subnet.prefix()).unwrap();
+                self.notify_allocated(id, ifname, &ip_config);
+                Ok(ip_config)
             }
             None => {
-                let allocated_ips = self.store.get_by_id(id, ifname);
-                for ip in allocated_ips.into_iter() {
-                    if self.range_set.get_range_for_ip(ip).is_err() {
-                        return Err(AllocateError::DuplicateAllocation(ip, id.to_owned()));
-                    }
+                // runtimes retry ADD after timeouts, hand out the same IP
+                // again instead of burning another one
+                if let Some(ip_config) = self.find_allocated(id, ifname) {
+                    return Ok(ip_config);
                 }
 
-                for (ip_net, gateway) in self.into_iter() {
-                    let reserved = self
-                        .store
-                        .reserve(id, ifname, ip_net.ip(), &self.range_id)
-                        .map_err(AllocateError::StoreError)?;
+                self.check_quota(id, 1)?;
 
-                    if reserved {
-                        reserved_ip = ip_net;
-                        break;
-                    }
-                }
+                let ip_config = match self.claim_warm(id, ifname)? {
+                    Some(ip_config) => ip_config,
+                    None => self.reserve_free(id, ifname)?,
+                };
+                self.notify_allocated(id, ifname, &ip_config);
+                Ok(ip_config)
+            }
+        }
+    }
+
+    /// Reserves the next free IP for `id` and `ifname`, as picked by the
+    /// strategy.
+    fn reserve_free(&self, id: &str, ifname: &str) -> Result<IpConfig, AllocateError> {
+        // skip IPs already known to be taken instead of paying a
+        // failed store reservation for each of them
+        let mut taken = self.taken().map_err(AllocateError::StoreError)?;
+
+        let tiers = self.range_set.tiers();
+        // the candidates of every tier are picked from the reservations now,
+        // `taken` is rebuilt while they are tried
+        let candidates: Vec<_> = tiers
+            .iter()
+            .map(|tier| self.candidates(tier, &taken, id, ifname))
+            .collect();
+
+        for (ip_net, _) in candidates.into_iter().flatten() {
+            if taken.contains(ip_net.ip()) {
+                continue;
+            }
+            let ip_config = match self.check_ip(ip_net.ip()) {
+                Ok(ip_config) => ip_config,
+                Err(_) => continue,
+            };
+
+            let ok = self
+                .reserve(id, ifname, &[ip_net.ip()])
+                .map_err(AllocateError::StoreError)?;
+
+            if ok {
+                return Ok(ip_config);
+            }
+
+            if self
+                .rebuild_stale_cache()
+                .map_err(AllocateError::StoreError)?
+            {
+                taken = self.taken().map_err(AllocateError::StoreError)?;
+            }
+        }
+
+        Err(AllocateError::IpExhausted)
+    }
+
+    /// The IPs of `range_set` in the order the strategy tries them.
+    fn candidates<'a>(
+        &'a self,
+        range_set: &'a RangeSet,
+        taken: &ReservedBitmap,
+        id: &str,
+        ifname: &str,
+    ) -> Box<dyn Iterator<Item = (IpNetwork, Option<IpAddr>)> + 'a> {
+        match self.strategy {
+            AllocationStrategy::Sequential => Box::new(self.iter_in(range_set)),
+            AllocationStrategy::Hashed => Box::new(
+                self.hashed_ips(range_set, id, ifname)
+                    .into_iter()
+                    .chain(self.iter_in(range_set)),
+            ),
+            AllocationStrategy::Hash => Box::new(self.iter_from_hash(range_set, id, ifname)),
+            AllocationStrategy::Descending => self.iter_descending(range_set),
+            AllocationStrategy::Balance => {
+                Box::new(self.iter_balanced(range_set, taken, id, ifname))
+            }
+        }
+    }
+
+    /// Takes an IP of the warm pool for `id` and `ifname`, none if the pool
+    /// is empty or the strategy doesn't use it. IPs another process took
+    /// in the meantime are skipped.
+    fn claim_warm(&self, id: &str, ifname: &str) -> Result<Option<IpConfig>, AllocateError> {
+        if !self.uses_warm_pool() {
+            return Ok(None);
+        }
+
+        loop {
+            let now = Instant::now();
+            let ip = match self.warm_pool.borrow_mut().as_mut() {
+                Some(pool) => match pool.pop(now) {
+                    Some(ip) => ip,
+                    None => return Ok(None),
+                },
+                None => return Ok(None),
+            };
+
+            // the reservation can't change hands within one transaction, a
+            // process taking the IP in between wins it
+            match self
+                .retry_policy
+                .run(|| self.store.release_checked(ip, WARM_POOL_ID, &self.range_id))
+            {
+                Ok(()) => {}
+                Err(StoreError::NotOwner(..)) | Err(StoreError::NotFound(_)) => continue,
+                Err(err) => return Err(AllocateError::StoreError(err)),
+            }
+
+            let mut txn = Transaction::new();
+            txn.reserve_for(self.owner(id, ifname), ip);
+            if self
+                .retry_policy
+                .run(|| self.store.commit(&txn))
+                .map_err(AllocateError::StoreError)?
+            {
+                return self
+                    .ip_config(ip)
+                    .map(Some)
+                    .map_err(AllocateError::RangeSetError);
+            }
+        }
+    }
+
+    /// Reserves free IPs until the warm pool is full again, after
+    /// releasing expired IPs and IPs a previous process left reserved for
+    /// the pool. Returns the number of IPs reserved, fewer than missing
+    /// once the range set is exhausted.
+    pub fn refill_warm_pool(&self) -> Result<usize, AllocateError> {
+        if !self.uses_warm_pool() {
+            return Ok(0);
+        }
+
+        self.expire_warm_pool()?;
+        let leftovers: Vec<IpAddr> = self
+            .store
+            .get_by_id(WARM_POOL_ID, &self.range_id)
+            .into_iter()
+            .filter(|ip| {
+                !self
+                    .warm_pool
+                    .borrow()
+                    .as_ref()
+                    .is_some_and(|pool| pool.contains(*ip))
+            })
+            .collect();
+        for ip in leftovers {
+            self.release_warm(ip)?;
+        }
+
+        let missing = self
+            .warm_pool
+            .borrow()
+            .as_ref()
+            .map_or(0, WarmPool::missing);
+        let mut reserved = 0;
+        while reserved < missing {
+            let ip = match self.reserve_free(WARM_POOL_ID, &self.range_id) {
+                Ok(ip_config) => ip_config.address().ip(),
+                Err(AllocateError::IpExhausted) => break,
+                Err(err) => return Err(err),
+            };
+            if let Some(cache) = self.cache.borrow_mut().as_mut() {
+                cache.insert(ip);
+            }
+            if let Some(pool) = self.warm_pool.borrow_mut().as_mut() {
+                pool.push(ip, Instant::now());
+            }
+            reserved += 1;
+        }
+
+        Ok(reserved)
+    }
+
+    /// Releases the IPs of the warm pool left unclaimed for longer than its
+    /// timeout, returns the released IPs.
+    pub fn expire_warm_pool(&self) -> Result<Vec<IpAddr>, AllocateError> {
+        let expired = match self.warm_pool.borrow_mut().as_mut() {
+            Some(pool) => pool.pop_expired(Instant::now()),
+            None => return Ok(Vec::new()),
+        };
+
+        for ip in &expired {
+            self.release_warm(*ip)?;
+        }
+
+        Ok(expired)
+    }
+
+    /// Number of IPs the warm pool holds.
+    pub fn warm_pool_len(&self) -> usize {
+        self.warm_pool.borrow().as_ref().map_or(0, WarmPool::len)
+    }
+
+    fn uses_warm_pool(&self) -> bool {
+        let sequential = match self.strategy {
+            AllocationStrategy::Sequential | AllocationStrategy::Descending => true,
+            AllocationStrategy::Hashed | AllocationStrategy::Hash | AllocationStrategy::Balance => {
+                false
+            }
+        };
+
+        sequential && self.warm_pool.borrow().is_some()
+    }
+
+    /// Releases `ip` if the warm pool still holds it in the store.
+    fn release_warm(&self, ip: IpAddr) -> Result<(), AllocateError> {
+        match self
+            .retry_policy
+            .run(|| self.store.release_checked(ip, WARM_POOL_ID, &self.range_id))
+        {
+            Ok(()) | Err(StoreError::NotOwner(..)) | Err(StoreError::NotFound(_)) => {}
+            Err(err) => return Err(AllocateError::StoreError(err)),
+        }
+
+        if let Some(cache) = self.cache.borrow_mut().as_mut() {
+            cache.remove(ip);
+        }
+        Ok(())
+    }
+
+    /// Allocates `count` IPs to the same `id` and `ifname` at once.
+    ///
+    /// The lock of this range set is held for the whole batch and either
+    /// every IP is reserved or, if the range set can't satisfy the request,
+    /// none is.
+    pub fn get_many(
+        &self,
+        id: &str,
+        ifname: &str,
+        count: usize,
+    ) -> Result<Vec<IpConfig>, AllocateError> {
+        self.retry_policy
+            .run(|| self.store.lock_range(&self.range_id))
+            .map_err(AllocateError::StoreError)?;
+        let result = self.reserve_batch(id, ifname, count);
+        self.retry_policy
+            .run(|| self.store.unlock_range(&self.range_id))
+            .map_err(AllocateError::StoreError)?;
+
+        result
+    }
 
+    fn reserve_batch(
+        &self,
+        id: &str,
+        ifname: &str,
+        count: usize,
+    ) -> Result<Vec<IpConfig>, AllocateError> {
+        self.check_duplicate(id, ifname)?;
+        self.check_quota(id, count)?;
+
+        loop {
+            let taken = self.taken().map_err(AllocateError::StoreError)?;
+
+            let tiers = self.range_set.tiers();
+            let ips = tiers.iter().flat_map(|tier| match self.strategy {
+                AllocationStrategy::Descending => self.iter_descending(tier),
+                _ => Box::new(self.iter_in(tier)),
+            });
+            let candidates: Vec<(IpNetwork, Option<IpAddr>)> = ips
+                .filter(|(ip_net, _)| !taken.contains(ip_net.ip()))
+                .filter(|(ip_net, _)| self.check_ip(ip_net.ip()).is_ok())
+                .take(count)
+                .collect();
+
+            if candidates.len() < count {
                 return Err(AllocateError::IpExhausted);
             }
+
+            let ips: Vec<IpAddr> = candidates.iter().map(|(ip_net, _)| ip_net.ip()).collect();
+            let reserved = self
+                .reserve(id, ifname, &ips)
+                .map_err(AllocateError::StoreError)?;
+
+            // somebody bypassing the lock took one of the candidates, look
+            // again with a fresh view of the store
+            if reserved {
+                let ip_configs = ips
+                    .into_iter()
+                    .map(|ip| self.ip_config(ip).map_err(AllocateError::RangeSetError))
+                    .collect::<Result<Vec<IpConfig>, AllocateError>>()?;
+
+                for ip_config in &ip_configs {
+                    self.notify_allocated(id, ifname, ip_config);
+                }
+                return Ok(ip_configs);
+            }
+
+            self.rebuild_stale_cache()
+                .map_err(AllocateError::StoreError)?;
         }
+    }
+
+    /// Reserves every IP of `ips` or none of them with
+    /// `Store::reserve_many`, recording the network namespace, pod and
+    /// labels.
+    fn reserve(&self, id: &str, ifname: &str, ips: &[IpAddr]) -> Result<bool, StoreError> {
+        let owner = self.owner(id, ifname);
+        self.timed_reserve(|| self.store.reserve_many(owner.clone(), ips, &self.range_id))
+    }
+
+    /// Runs `reserve` with the retry policy, timed as `Phase::Reserve`.
+    fn timed_reserve<F>(&self, reserve: F) -> Result<bool, StoreError>
+    where
+        F: FnMut() -> Result<bool, StoreError>,
+    {
+        let reserve = || self.retry_policy.run(reserve);
+        match &self.timings {
+            Some(timings) => timings.time(Phase::Reserve, reserve),
+            None => reserve(),
+        }
+    }
+
+    /// The owner of reservations for `id` and `ifname`.
+    fn owner(&self, id: &str, ifname: &str) -> Owner {
+        Owner {
+            id: id.to_owned(),
+            ifname: ifname.to_owned(),
+            netns: self.netns.clone(),
+            pod: self.pod.clone(),
+            labels: self.labels.clone(),
+        }
+    }
+
+    /// IPs dynamic allocation has to skip, the ones reserved in the store and
+    /// the ones kept for explicit requests.
+    fn taken(&self) -> Result<ReservedBitmap, StoreError> {
+        if let Some(cache) = self.cache.borrow().as_ref() {
+            let mut taken = cache.clone();
+            for ip in &self.reserved_ips {
+                taken.insert(*ip);
+            }
+            return Ok(taken);
+        }
+
+        let reserved = self.retry_policy.run(|| self.store.list())?;
+
+        Ok(ReservedBitmap::from_reserved(
+            &self.range_set,
+            reserved
+                .into_iter()
+                .chain(self.reserved_ips.iter().copied()),
+        ))
+    }
+
+    /// Returns the IP of this range set already allocated to `id` and
+    /// `ifname`, if any.
+    fn find_allocated(&self, id: &str, ifname: &str) -> Option<IpConfig> {
+        self.store
+            .get_by_id(id, ifname)
+            .into_iter()
+            .find_map(|ip| self.ip_config(ip).ok())
+    }
+
+    /// Describes `ip` by the range of the range set it belongs to.
+    fn ip_config(&self, ip: IpAddr) -> Result<IpConfig, RangeSetError> {
+        let range_index = self
+            .range_set
+            .iter()
+            .position(|range| range.contains(ip))
+            .ok_or_else(|| RangeSetError::NoRangeForIP(ip, self.range_set.to_string()))?;
+        let range = *self.range_set.get(range_index).unwrap();
 
         Ok(IpConfig {
             interface: None,
-            address: reserved_ip,
-            gateway: gateway,
+            address: IpNetwork::new(ip, range.subnet.prefix()).unwrap(),
+            gateway: range.gateway,
+            range_index: range_index,
+            range: range,
         })
     }
 
-    pub fn into_iter(&self) -> RangeIter {
+    /// Checks that `ip` may be handed out at all, in order: it is of the
+    /// family of the range set, inside one of its ranges, neither the gateway
+    /// nor a broadcast address. Requested IPs and the ones picked by the
+    /// strategy go through the same steps, the latter also skip the reserved
+    /// IPs, which are kept for explicit requests.
+    fn check_ip(&self, ip: IpAddr) -> Result<IpConfig, AllocateError> {
+        let ipv4 = self
+            .range_set
+            .iter()
+            .next()
+            .map(|range| range.start.is_ipv4());
+        if ipv4.is_some_and(|ipv4| ipv4 != ip.is_ipv4()) {
+            return Err(AllocateError::FamilyMismatch(ip));
+        }
+
+        let ip_config = self.ip_config(ip).map_err(AllocateError::RangeSetError)?;
+        if ip_config.gateway == Some(ip) {
+            return Err(AllocateError::GatewayIp(ip));
+        }
+        match ip_config.range.subnet {
+            IpNetwork::V4(subnet) if subnet.prefix() < 31 && subnet.broadcast() == ip => {
+                return Err(AllocateError::BroadcastIp(ip))
+            }
+            _ => {}
+        }
+
+        Ok(ip_config)
+    }
+
+    /// Checks that `id` and `ifname` may be given `ip`: they hold no other
+    /// IP of the range set and stay within the quota. Holding `ip` already
+    /// is fine, runtimes retry ADD.
+    fn check_holder(&self, id: &str, ifname: &str, ip: IpAddr) -> Result<(), AllocateError> {
+        let held = self.store.get_by_id(id, ifname);
+        if held.contains(&ip) {
+            return Ok(());
+        }
+
+        if let Some(other) = held.iter().find(|held| self.range_set.contains(**held)) {
+            return Err(AllocateError::DuplicateAllocation(*other, id.to_owned()));
+        }
+
+        self.check_quota(id, 1)
+    }
+
+    /// Batch allocation hands out IPs of a range set to the same `id` and
+    /// `ifname` only once.
+    fn check_duplicate(&self, id: &str, ifname: &str) -> Result<(), AllocateError> {
+        let allocated_ips = self.store.get_by_id(id, ifname);
+        for ip in allocated_ips.into_iter() {
+            if self.range_set.contains(ip) {
+                return Err(AllocateError::DuplicateAllocation(ip, id.to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the reservations of this allocator's range set which
+    /// `range_set`, typically the same range set after an operator shrunk
+    /// it, no longer covers. Reservations outside of this allocator's range
+    /// set, e.g. of other range sets of the network, are never reported.
+    ///
+    /// With `release` set the orphans are released as well, while holding
+    /// the store lock, and observers are told about it.
+    pub fn reconcile(
+        &self,
+        range_set: &RangeSet,
+        release: bool,
+    ) -> Result<Vec<Orphan>, AllocateError> {
+        if release {
+            self.retry_policy
+                .run(|| self.store.lock())
+                .map_err(AllocateError::StoreError)?;
+        }
+
+        let result = self.find_orphans(range_set, release);
+
+        if release {
+            self.retry_policy
+                .run(|| self.store.unlock())
+                .map_err(AllocateError::StoreError)?;
+        }
+
+        result
+    }
+
+    fn find_orphans(
+        &self,
+        range_set: &RangeSet,
+        release: bool,
+    ) -> Result<Vec<Orphan>, AllocateError> {
+        let mut orphans = Vec::new();
+        let mut ips = self.store.list().map_err(AllocateError::StoreError)?;
+        ips.sort();
+
+        for ip in ips {
+            if !self.range_set.contains(ip) || range_set.contains(ip) {
+                continue;
+            }
+
+            let owner = self.store.owner(ip).map_err(AllocateError::StoreError)?;
+            if release {
+                self.retry_policy
+                    .run(|| self.store.release_checked(ip, &owner.id, &owner.ifname))
+                    .map_err(AllocateError::StoreError)?;
+
+                self.notify_released(&owner.id, &owner.ifname, ip);
+            }
+
+            orphans.push(Orphan {
+                ip: ip,
+                id: owner.id,
+                ifname: owner.ifname,
+            });
+        }
+
+        Ok(orphans)
+    }
+
+    /// Checks that the IPs of this range set in `expected`, typically the
+    /// previous result of the chain, are still reserved for `id` and
+    /// `ifname`. IPs of other range sets are left to their allocators.
+    pub fn check(
+        &self,
+        id: &str,
+        ifname: &str,
+        expected: &[IpAddr],
+    ) -> Result<CheckReport, AllocateError> {
+        let held = self.store.get_by_id(id, ifname);
+
+        let mut report = CheckReport::default();
+        for ip in expected {
+            if !self.range_set.contains(*ip) || held.contains(ip) {
+                continue;
+            }
+
+            match self.store.owner(*ip) {
+                Ok(_) => report.fail(*ip, CheckFailure::ReservedForOther),
+                Err(StoreError::NotFound(_)) => report.fail(*ip, CheckFailure::NotReserved),
+                Err(err) => return Err(AllocateError::StoreError(err)),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// The IP every IPv6 range of the set derives for `id` and `ifname`,
+    /// in the shape `into_iter` yields them. Gateways are left out.
+    fn hashed_ips(
+        &self,
+        range_set: &RangeSet,
+        id: &str,
+        ifname: &str,
+    ) -> Vec<(IpNetwork, Option<IpAddr>)> {
+        let hash = fnv1a_128(format!("{}/{}", id, ifname).as_bytes());
+
+        range_set
+            .iter()
+            .filter(|range| range.subnet.is_ipv6())
+            .filter_map(|range| {
+                let ip = range.nth_ip(hash).filter(|ip| Some(*ip) != range.gateway)?;
+                // UNWRAP: the prefix comes from another IpNetwork
+                let ip_net = IpNetwork::new(ip, range.subnet.prefix()).unwrap();
+                Some((ip_net, range.gateway))
+            })
+            .collect()
+    }
+
+    /// Iterates the whole range set like `into_iter`, but starting at
+    /// `start + hash % size` of the set instead of after the last reserved
+    /// IP.
+    fn iter_from_hash<'a>(
+        &'a self,
+        range_set: &'a RangeSet,
+        id: &str,
+        ifname: &str,
+    ) -> RangeIter<'a> {
+        let size = range_set
+            .iter()
+            .fold(0u128, |size, range| size.saturating_add(range.size()));
+        if size == 0 {
+            return self.iter_in(range_set);
+        }
+
+        let mut n = fnv1a_128(format!("{}/{}", id, ifname).as_bytes()) % size;
+        for (index, range) in range_set.iter().enumerate() {
+            if n < range.size() {
+                // the iterator yields the IP after `current_ip`, or the
+                // start of the range if there is none
+                return RangeIter {
+                    range_set: range_set,
+                    range_index: index,
+                    current_ip: match n {
+                        0 => None,
+                        _ => range.nth_ip(n - 1),
+                    },
+                    start_ip: None,
+                };
+            }
+            n -= range.size();
+        }
+
+        self.iter_in(range_set)
+    }
+
+    /// Iterates the whole range set like `into_iter`, but starting in the
+    /// range picked by a hash of `id` and `ifname` out of the free IPs of
+    /// every range, see `AllocationStrategy::Balance`. Resumes after the
+    /// last reserved IP if that range holds it, else at its start.
+    fn iter_balanced<'a>(
+        &'a self,
+        range_set: &'a RangeSet,
+        taken: &ReservedBitmap,
+        id: &str,
+        ifname: &str,
+    ) -> RangeIter<'a> {
+        let free: Vec<u128> = range_set
+            .iter()
+            .map(|range| {
+                let reserved = taken.reserved_in(range) as u128;
+                range.capacity().saturating_sub(reserved)
+            })
+            .collect();
+        let total = free
+            .iter()
+            .fold(0u128, |total, free| total.saturating_add(*free));
+
+        let mut range_iter = self.iter_in(range_set);
+        if total == 0 {
+            return range_iter;
+        }
+
+        let mut n = fnv1a_128(format!("{}/{}", id, ifname).as_bytes()) % total;
+        for (index, free) in free.into_iter().enumerate() {
+            if n < free {
+                if range_iter.range_index != index {
+                    range_iter.range_index = index;
+                    range_iter.current_ip = None;
+                }
+                break;
+            }
+            n -= free;
+        }
+
+        range_iter
+    }
+
+    /// Iterates the whole range set like `into_iter`, but downwards from
+    /// right below the last reserved IP, wrapping around to the top. The last
+    /// reserved IP itself comes last, as with `into_iter`.
+    fn iter_descending<'a>(
+        &'a self,
+        range_set: &'a RangeSet,
+    ) -> Box<dyn Iterator<Item = (IpNetwork, Option<IpAddr>)> + 'a> {
+        let mut range_iter = self.iter_in(range_set);
+        let last_reserved_ip = match range_iter.current_ip {
+            Some(ip) => ip,
+            None => return Box::new(range_iter.rev()),
+        };
+
+        // nothing below the last reserved IP was yielded yet, `next_back`
+        // starts right before it
+        range_iter.start_ip = Some(last_reserved_ip);
+        let last = range_set.get(range_iter.range_index).map(|range| {
+            // UNWRAP: the prefix comes from another IpNetwork
+            let ip_net = IpNetwork::new(last_reserved_ip, range.subnet.prefix()).unwrap();
+            (ip_net, range.gateway)
+        });
+
+        Box::new(range_iter.rev().chain(last))
+    }
+
+    /// Returns the IPs of the range set which are currently free, in range
+    /// order, without reserving any of them.
+    ///
+    /// The store is only listed once the first IP is requested. If that
+    /// fails, the error is the only item.
+    pub fn iter_available(&self) -> Available<'_> {
+        Available {
+            allocator: self,
+            taken: None,
+            range_iter: RangeIter {
+                range_set: &self.range_set,
+                range_index: 0,
+                current_ip: None,
+                start_ip: None,
+            },
+        }
+    }
+
+    /// Fails if `count` more IPs would take `id` over the quota.
+    fn check_quota(&self, id: &str, count: usize) -> Result<(), AllocateError> {
+        let quota = match self.quota {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        let held = self.held_by(id).map_err(AllocateError::StoreError)?;
+        if held + count > quota {
+            return Err(AllocateError::QuotaExceeded {
+                id: id.to_owned(),
+                quota: quota,
+                held: held,
+                requested: count,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Number of IPs of the network reserved for `id`, on any interface.
+    fn held_by(&self, id: &str) -> Result<usize, StoreError> {
+        let mut held = 0;
+        for ip in self.retry_policy.run(|| self.store.list())? {
+            match self.store.owner(ip) {
+                Ok(owner) if owner.id == id => held += 1,
+                // released since the listing
+                Ok(_) | Err(StoreError::NotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(held)
+    }
+
+    /// Returns an iterator over the range set which resumes right after the
+    /// last IP reserved for this range set, wrapping around to the first
+    /// range once the last one is exhausted. If no range holds that IP any
+    /// more, e.g. after its range was removed, it resumes at the first range
+    /// following it.
+    pub fn into_iter(&self) -> RangeIter<'_> {
+        self.iter_in(&self.range_set)
+    }
+
+    /// Like `into_iter`, but over `range_set`, e.g. a tier of the ranges
+    /// of this set, see `RangeSet::tiers`.
+    fn iter_in<'a>(&'a self, range_set: &'a RangeSet) -> RangeIter<'a> {
         let mut range_iter = RangeIter {
-            range_set: self.range_set,
+            range_set: range_set,
             range_index: 0,
             current_ip: None,
             start_ip: None,
         };
 
-        let mut start_from_last_reserved_ip = false;
-        let mut last_reserved_ip: IpAddr;
-
-        if let Ok(ip) = self.store.last_reserved_ip(&self.range_id) {
-            last_reserved_ip = ip;
-            start_from_last_reserved_ip = self.range_set.contains(last_reserved_ip);
-        };
-
-        if start_from_last_reserved_ip {
-            for (index, range) in self.range_set.iter().enumerate() {
+        // without a last reserved IP, e.g. on the first allocation, or if it
+        // can't be read the iteration simply starts at the first range
+        if let Ok(last_reserved_ip) = self.store.last_reserved_ip(&self.range_id) {
+            for (index, range) in range_set.iter().enumerate() {
                 if range.contains(last_reserved_ip) {
                     range_iter.range_index = index;
                     range_iter.current_ip = Some(last_reserved_ip);
                     break;
                 }
+
+                // ranges are sorted, the IP fell into a gap before this one
+                if range.start.is_ipv4() == last_reserved_ip.is_ipv4()
+                    && range.start > last_reserved_ip
+                {
+                    range_iter.range_index = index;
+                    break;
+                }
             }
-        } else {
-            range_iter.range_index = 0;
-            range_iter.start_ip = Some(self.range_set.get(0).unwrap().start);
-        };
+        }
 
         return range_iter;
     }
 }
+
+/// Iterator returned by `Allocator::iter_available`.
+pub struct Available<'a> {
+    allocator: &'a Allocator,
+    taken: Option<ReservedBitmap>,
+    range_iter: RangeIter<'a>,
+}
+
+impl<'a> Iterator for Available<'a> {
+    type Item = Result<IpNetwork, AllocateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.taken.is_none() {
+            let allocator = self.allocator;
+            match allocator.taken() {
+                Ok(taken) => self.taken = Some(taken),
+                Err(err) => {
+                    // end the iteration after reporting the error
+                    self.taken = Some(ReservedBitmap::new(&allocator.range_set));
+                    self.range_iter.range_index = allocator.range_set.len();
+                    return Some(Err(AllocateError::StoreError(err)));
+                }
+            }
+        }
+
+        let taken = self.taken.as_ref().unwrap();
+        self.range_iter
+            .by_ref()
+            .map(|(ip_net, _)| ip_net)
+            .find(|ip_net| !taken.contains(ip_net.ip()))
+            .map(Ok)
+    }
+
+    /// At most the IPs left in the ranges, taken ones are only known while
+    /// iterating. Before the first item an error listing them may come.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let error = self.taken.is_none() as usize;
+        let upper = self.range_iter.size_hint().1;
+        (0, upper.and_then(|upper| upper.checked_add(error)))
+    }
+}
+
+/// 128 bit FNV-1a, unlike `DefaultHasher` it is stable across Rust
+/// releases, so hashed IPs survive plugin upgrades.
+pub(crate) fn fnv1a_128(bytes: &[u8]) -> u128 {
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u128::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::filestore::FileStore;
+    use std::cell::RefCell;
+    use std::fs::remove_dir_all;
+
+    fn allocator(network: &str, subnet: &str) -> Allocator {
+        let mut range_set = RangeSet::new();
+        range_set
+            .add(Range::new(subnet.parse().unwrap(), None, None, None).unwrap())
+            .unwrap();
+
+        let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+        Allocator::new(range_set, Arc::new(store))
+    }
+
+    fn clean_data_dir(network: &str) {
+        let _ = remove_dir_all(format!("/tmp/cni-allocator/{}", network));
+    }
+
+    #[test]
+    fn get_is_idempotent() {
+        let network = "get-idempotent";
+        clean_data_dir(network);
+
+        let allocator = allocator(network, "10.1.0.0/29");
+
+        let first = allocator.get("c1", "eth0", None).unwrap();
+        let second = allocator.get("c1", "eth0", None).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(allocator.store.list().unwrap().len(), 1);
+
+        let other = allocator.get("c1", "eth1", None).unwrap();
+        assert_ne!(first.address, other.address);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn warm_pool() {
+        let network = "warm-pool";
+        clean_data_dir(network);
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let allocator =
+            allocator(network, "10.1.0.0/29").with_warm_pool(2, Duration::from_secs(60));
+        let range_id = allocator.range_id().to_owned();
+        assert_eq!(allocator.refill_warm_pool().unwrap(), 2);
+        let mut pooled = allocator.store.get_by_id(WARM_POOL_ID, &range_id);
+        pooled.sort();
+        assert_eq!(pooled, vec![ip("10.1.0.2"), ip("10.1.0.3")]);
+
+        let first = allocator.get("c1", "eth0", None).unwrap();
+        assert_eq!(first.address().ip(), ip("10.1.0.2"));
+        assert_eq!(allocator.store.owner(ip("10.1.0.2")).unwrap().id, "c1");
+        assert_eq!(allocator.warm_pool_len(), 1);
+
+        // another process took the other pooled IP in the meantime
+        allocator.store.release(ip("10.1.0.3")).unwrap();
+        allocator
+            .store
+            .reserve("c2", "eth0", ip("10.1.0.3"), "other")
+            .unwrap();
+        let second = allocator.get("c3", "eth0", None).unwrap();
+        assert_eq!(second.address().ip(), ip("10.1.0.4"));
+        assert_eq!(allocator.warm_pool_len(), 0);
+
+        // a restarted process releases what the previous one left pooled
+        assert_eq!(allocator.refill_warm_pool().unwrap(), 2);
+        let restarted =
+            self::allocator(network, "10.1.0.0/29").with_warm_pool(1, Duration::from_secs(0));
+        assert_eq!(restarted.refill_warm_pool().unwrap(), 1);
+        assert_eq!(
+            restarted.store.get_by_id(WARM_POOL_ID, &range_id),
+            vec![ip("10.1.0.5")]
+        );
+        assert_eq!(restarted.expire_warm_pool().unwrap(), vec![ip("10.1.0.5")]);
+        assert_eq!(restarted.store.list().unwrap().len(), 3);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn priority_ranges() {
+        let network = "priority-ranges";
+        clean_data_dir(network);
+
+        let range = |start: &str, end: &str| {
+            Range::new(
+                "10.1.0.0/24".parse().unwrap(),
+                Some(start.parse().unwrap()),
+                Some(end.parse().unwrap()),
+                None,
+            )
+            .unwrap()
+        };
+        let mut range_set = RangeSet::new();
+        range_set
+            .add_with_priority(range("10.1.0.2", "10.1.0.3"), -1)
+            .unwrap();
+        range_set.add(range("10.1.0.10", "10.1.0.11")).unwrap();
+        let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+        let allocator = Allocator::new(range_set, Arc::new(store));
+
+        let ips: Vec<String> = (0..3)
+            .map(|n| {
+                let ip_config = allocator.get(&format!("c{}", n), "eth0", None).unwrap();
+                ip_config.address().ip().to_string()
+            })
+            .collect();
+        assert_eq!(ips, vec!["10.1.0.10", "10.1.0.11", "10.1.0.2"]);
+
+        // the burst range is only used until the preferred one has room again
+        allocator.release("c0", "eth0").unwrap();
+        let ip_config = allocator.get("c3", "eth0", None).unwrap();
+        assert_eq!(ip_config.address().ip().to_string(), "10.1.0.10");
+
+        let batch = allocator.get_many("c4", "eth0", 1).unwrap();
+        assert_eq!(batch[0].address().ip().to_string(), "10.1.0.3");
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn balance_strategy() {
+        let network = "balance-strategy";
+        clean_data_dir(network);
+
+        let mut range_set = RangeSet::new();
+        for subnet in &["10.1.0.0/24", "10.1.1.0/25"] {
+            range_set
+                .add(Range::new(subnet.parse().unwrap(), None, None, None).unwrap())
+                .unwrap();
+        }
+        let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+        let allocator =
+            Allocator::new(range_set, Arc::new(store)).with_strategy(AllocationStrategy::Balance);
+
+        let mut per_range = [0, 0];
+        for n in 0..90 {
+            let ip_config = allocator.get(&format!("c{}", n), "eth0", None).unwrap();
+            per_range[ip_config.range_index()] += 1;
+        }
+        // twice the free IPs take about twice the allocations
+        assert_eq!(per_range[0] + per_range[1], 90);
+        assert!(per_range[0] > per_range[1], "{:?}", per_range);
+        assert!(per_range[1] >= 15, "{:?}", per_range);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn get_reports_originating_range() {
+        let network = "get-range";
+        clean_data_dir(network);
+
+        let mut range_set = RangeSet::new();
+        for (start, end, gateway) in &[
+            ("10.1.0.2", "10.1.0.3", "10.1.0.1"),
+            ("10.1.0.10", "10.1.0.11", "10.1.0.1"),
+        ] {
+            range_set
+                .add(
+                    Range::new(
+                        "10.1.0.0/24".parse().unwrap(),
+                        Some(start.parse().unwrap()),
+                        Some(end.parse().unwrap()),
+                        Some(gateway.parse().unwrap()),
+                    )
+                    .unwrap(),
+                )
+                .unwrap();
+        }
+
+        let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+        let allocator = Allocator::new(range_set, Arc::new(store));
+
+        let ip_configs = allocator.get_many("c1", "eth0", 3).unwrap();
+        let ranges: Vec<(String, usize, String)> = ip_configs
+            .iter()
+            .map(|ip_config| {
+                (
+                    ip_config.address().to_string(),
+                    ip_config.range_index(),
+                    ip_config.range().start.to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                ("10.1.0.2/24".to_owned(), 0, "10.1.0.2".to_owned()),
+                ("10.1.0.3/24".to_owned(), 0, "10.1.0.2".to_owned()),
+                ("10.1.0.10/24".to_owned(), 1, "10.1.0.10".to_owned()),
+            ]
+        );
+
+        let ip_config = allocator.get("c2", "eth0", None).unwrap();
+        assert_eq!(ip_config.range_index(), 1);
+        assert_eq!(ip_config.gateway(), ip_config.range().gateway);
+
+        assert!(matches!(
+            allocator.get("c3", "eth0", Some("10.1.0.3".parse().unwrap())),
+            Err(AllocateError::IpNotAvailable(_))
+        ));
+
+        // asking again for an IP already held is a retry, not a conflict
+        let requested_ip = "10.1.0.3".parse().unwrap();
+        let ip_config = allocator.get("c1", "eth0", Some(requested_ip)).unwrap();
+        assert_eq!(ip_config.address().ip(), requested_ip);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn continues_by_position() {
+        let network = "continues-by-position";
+        clean_data_dir(network);
+
+        let range_set = |ranges: &[(&str, &str)]| {
+            let mut range_set = RangeSet::new();
+            for (start, end) in ranges {
+                let range = Range::new(
+                    "10.1.0.0/24".parse().unwrap(),
+                    Some(start.parse().unwrap()),
+                    Some(end.parse().unwrap()),
+                    None,
+                );
+                range_set.add(range.unwrap()).unwrap();
+            }
+            range_set
+        };
+        let allocator = |ranges: &[(&str, &str)]| {
+            let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+            Allocator::new(range_set(ranges), Arc::new(store)).with_range_id("0")
+        };
+        let get = |allocator: &Allocator, id: &str| {
+            allocator
+                .get(id, "eth0", None)
+                .unwrap()
+                .address()
+                .ip()
+                .to_string()
+        };
+
+        let first = allocator(&[("10.1.0.10", "10.1.0.19"), ("10.1.0.20", "10.1.0.29")]);
+        assert_eq!(get(&first, "c1"), "10.1.0.10");
+
+        // reordering the ranges doesn't start over
+        let reordered = allocator(&[("10.1.0.20", "10.1.0.29"), ("10.1.0.10", "10.1.0.19")]);
+        assert_eq!(get(&reordered, "c2"), "10.1.0.11");
+
+        // with the range of the last reserved IP gone, the next one follows
+        let removed = allocator(&[("10.1.0.2", "10.1.0.5"), ("10.1.0.20", "10.1.0.29")]);
+        assert_eq!(get(&removed, "c3"), "10.1.0.20");
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn descending_strategy() {
+        let network = "descending-strategy";
+        clean_data_dir(network);
+
+        let descending =
+            allocator(network, "10.1.0.0/29").with_strategy(AllocationStrategy::Descending);
+        let get = |id: &str| {
+            descending
+                .get(id, "eth0", None)
+                .unwrap()
+                .address()
+                .ip()
+                .to_string()
+        };
+        assert_eq!(get("c1"), "10.1.0.6");
+        assert_eq!(get("c2"), "10.1.0.5");
+
+        let ip_configs = descending.get_many("c3", "eth0", 2).unwrap();
+        let ips: Vec<String> = ip_configs
+            .iter()
+            .map(|ip_config| ip_config.address().ip().to_string())
+            .collect();
+        assert_eq!(ips, vec!["10.1.0.4", "10.1.0.3"]);
+
+        // released IPs below are taken before wrapping around to the top
+        descending.release("c1", "eth0").unwrap();
+        assert_eq!(get("c4"), "10.1.0.2");
+        assert_eq!(get("c5"), "10.1.0.6");
+        assert!(matches!(
+            descending.get("c6", "eth0", None),
+            Err(AllocateError::IpExhausted)
+        ));
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn iter_available() {
+        let network = "iter-available";
+        clean_data_dir(network);
+
+        let allocator = allocator(network, "10.1.0.0/29");
+        allocator
+            .get("c1", "eth0", Some("10.1.0.3".parse().unwrap()))
+            .unwrap();
+
+        let available: Vec<String> = allocator
+            .iter_available()
+            .map(|ip_net| ip_net.unwrap().to_string())
+            .collect();
+        assert_eq!(
+            available,
+            vec!["10.1.0.2/29", "10.1.0.4/29", "10.1.0.5/29", "10.1.0.6/29"]
+        );
+        assert_eq!(allocator.store.list().unwrap().len(), 1);
+
+        clean_data_dir(network);
+    }
+
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl AllocationObserver for Recorder {
+        fn allocated(&self, id: &str, ifname: &str, ip_config: &IpConfig) {
+            self.0
+                .borrow_mut()
+                .push(format!("+{} {} {}", id, ifname, ip_config.address()));
+        }
+
+        fn released(&self, id: &str, ifname: &str, ip: IpAddr) {
+            self.0
+                .borrow_mut()
+                .push(format!("-{} {} {}", id, ifname, ip));
+        }
+    }
+
+    #[test]
+    fn observers() {
+        let network = "observers";
+        clean_data_dir(network);
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut allocator = allocator(network, "10.1.0.0/29");
+        allocator.subscribe(Box::new(Recorder(events.clone())));
+
+        allocator.get("c1", "eth0", None).unwrap();
+        // handing out the same IP again is no new allocation
+        allocator.get("c1", "eth0", None).unwrap();
+        allocator.get_many("c2", "eth0", 2).unwrap();
+        assert!(allocator.get_many("c3", "eth0", 5).is_err());
+        assert_eq!(allocator.release("c2", "eth0").unwrap().len(), 2);
+
+        // releases follow the unordered store listing
+        let mut events = events.borrow().clone();
+        events[3..].sort();
+        assert_eq!(
+            events,
+            vec![
+                "+c1 eth0 10.1.0.2/29",
+                "+c2 eth0 10.1.0.3/29",
+                "+c2 eth0 10.1.0.4/29",
+                "-c2 eth0 10.1.0.3",
+                "-c2 eth0 10.1.0.4",
+            ]
+        );
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn reserved_ips() {
+        let network = "reserved-ips";
+        clean_data_dir(network);
+
+        // 10.1.0.2 - 10.1.0.6 are free, 10.1.0.1 is the gateway
+        let allocator = allocator(network, "10.1.0.0/29").with_reserved_ips(vec![
+            "10.1.0.2".parse().unwrap(),
+            "10.1.0.3".parse().unwrap(),
+            "10.1.0.6".parse().unwrap(),
+        ]);
+
+        let available: Vec<String> = allocator
+            .iter_available()
+            .map(|ip_net| ip_net.unwrap().to_string())
+            .collect();
+        assert_eq!(available, vec!["10.1.0.4/29", "10.1.0.5/29"]);
+
+        let ip_config = allocator.get("c1", "eth0", None).unwrap();
+        assert_eq!(ip_config.address().to_string(), "10.1.0.4/29");
+        assert_eq!(allocator.get_many("c2", "eth0", 1).unwrap().len(), 1);
+        assert!(matches!(
+            allocator.get("c3", "eth0", None),
+            Err(AllocateError::IpExhausted)
+        ));
+
+        let requested_ip = "10.1.0.2".parse().unwrap();
+        let ip_config = allocator.get("c3", "eth0", Some(requested_ip)).unwrap();
+        assert_eq!(ip_config.address().ip(), requested_ip);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn requested_ip_validation() {
+        let network = "requested-ip-validation";
+        clean_data_dir(network);
+
+        let mut range_set = RangeSet::new();
+        let subnet = "10.1.0.0/29".parse().unwrap();
+        let broadcast = "10.1.0.7".parse().unwrap();
+        range_set
+            .add(Range::new(subnet, None, Some(broadcast), None).unwrap())
+            .unwrap();
+        let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+        let allocator = Allocator::new(range_set, Arc::new(store));
+        let get = |ip: &str| allocator.get("c1", "eth0", Some(ip.parse().unwrap()));
+
+        assert!(matches!(
+            get("2001:db8::2"),
+            Err(AllocateError::FamilyMismatch(_))
+        ));
+        assert!(matches!(
+            get("10.1.1.2"),
+            Err(AllocateError::RangeSetError(_))
+        ));
+        assert!(matches!(get("10.1.0.1"), Err(AllocateError::GatewayIp(_))));
+        assert!(matches!(
+            get("10.1.0.7"),
+            Err(AllocateError::BroadcastIp(_))
+        ));
+        get("10.1.0.5").unwrap();
+        get("10.1.0.5").unwrap();
+        assert!(matches!(
+            get("10.1.0.6"),
+            Err(AllocateError::DuplicateAllocation(_, _))
+        ));
+
+        // dynamic allocation skips the broadcast address as well
+        assert_eq!(allocator.get_many("c2", "eth0", 4).unwrap().len(), 4);
+        assert!(matches!(
+            allocator.get("c3", "eth0", None),
+            Err(AllocateError::IpExhausted)
+        ));
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn quota() {
+        let network = "quota";
+        clean_data_dir(network);
+
+        let allocator = allocator(network, "10.1.0.0/29").with_quota(2);
+
+        let err = allocator.get_many("c1", "eth0", 3).unwrap_err();
+        assert!(matches!(
+            err,
+            AllocateError::QuotaExceeded {
+                quota: 2,
+                held: 0,
+                requested: 3,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "c1 holds 0 of at most 2 ip addresses, 3 more would exceed the quota"
+        );
+        assert_eq!(allocator.get_many("c1", "eth0", 2).unwrap().len(), 2);
+
+        // handing out an IP already held doesn't count
+        allocator.get("c1", "eth0", None).unwrap();
+        assert!(matches!(
+            allocator.get("c1", "eth0", Some("10.1.0.6".parse().unwrap())),
+            Err(AllocateError::DuplicateAllocation(_, _))
+        ));
+        let held = allocator.store.get_by_id("c1", "eth0")[0];
+        assert!(allocator.get("c1", "eth0", Some(held)).is_ok());
+
+        // the quota counts every interface of the container
+        assert!(matches!(
+            allocator.get("c1", "eth1", None),
+            Err(AllocateError::QuotaExceeded { held: 2, .. })
+        ));
+        allocator.get("c2", "eth1", None).unwrap();
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn renew() {
+        let network = "renew";
+        clean_data_dir(network);
+
+        let allocator = allocator(network, "10.1.0.0/29");
+
+        assert!(matches!(
+            allocator.renew("c1", "eth0"),
+            Err(AllocateError::NotAllocated(_, _))
+        ));
+
+        let ip_config = allocator.get("c1", "eth0", None).unwrap();
+        assert_eq!(allocator.renew("c1", "eth0").unwrap(), ip_config);
+
+        allocator.release("c1", "eth0").unwrap();
+        assert!(matches!(
+            allocator.renew("c1", "eth0"),
+            Err(AllocateError::NotAllocated(_, _))
+        ));
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn get_many() {
+        let network = "get-many";
+        clean_data_dir(network);
+
+        // 10.1.0.2 - 10.1.0.6 are free, 10.1.0.1 is the gateway
+        let allocator = allocator(network, "10.1.0.0/29");
+
+        let ip_configs = allocator.get_many("c1", "eth0", 3).unwrap();
+        let ips: Vec<String> = ip_configs
+            .iter()
+            .map(|ip_config| ip_config.address.to_string())
+            .collect();
+        assert_eq!(ips, vec!["10.1.0.2/29", "10.1.0.3/29", "10.1.0.4/29"]);
+
+        assert!(matches!(
+            allocator.get_many("c2", "eth0", 3),
+            Err(AllocateError::IpExhausted)
+        ));
+        assert_eq!(allocator.store.list().unwrap().len(), 3);
+
+        assert!(matches!(
+            allocator.get_many("c1", "eth0", 1),
+            Err(AllocateError::DuplicateAllocation(_, _))
+        ));
+
+        assert_eq!(allocator.get_many("c2", "eth0", 2).unwrap().len(), 2);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn point_to_point() {
+        let network = "point-to-point";
+        clean_data_dir(network);
+
+        let mut range_set = RangeSet::new();
+        range_set
+            .add(Range::point_to_point("10.1.0.0/31".parse().unwrap(), None, None).unwrap())
+            .unwrap();
+        let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+        let allocator = Allocator::new(range_set, Arc::new(store));
+
+        let first = allocator.get("c1", "eth0", None).unwrap();
+        let second = allocator.get("c2", "eth0", None).unwrap();
+        assert_eq!(first.address.to_string(), "10.1.0.0/31");
+        assert_eq!(second.address.to_string(), "10.1.0.1/31");
+        assert_eq!(first.gateway(), None);
+        assert!(matches!(
+            allocator.get("c3", "eth0", None),
+            Err(AllocateError::IpExhausted)
+        ));
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn hashed_strategy() {
+        let network = "hashed";
+        clean_data_dir(network);
+
+        let hashed = allocator(network, "2001:db8::/64").with_strategy(AllocationStrategy::Hashed);
+
+        // the same container gets the same IP after it was deleted
+        let first = hashed.get("c1", "eth0", None).unwrap();
+        assert_ne!(first.address.ip(), "2001:db8::2".parse::<IpAddr>().unwrap());
+        hashed.release("c1", "eth0").unwrap();
+        assert_eq!(hashed.get("c1", "eth0", None).unwrap(), first);
+
+        let other = hashed.get("c1", "eth1", None).unwrap();
+        assert_ne!(other.address, first.address);
+
+        // somebody else holds it, fall back to the next sequential IP
+        hashed.release("c1", "eth0").unwrap();
+        hashed
+            .store
+            .reserve("c2", "eth0", first.address.ip(), "1")
+            .unwrap();
+        let fallback = hashed.get("c1", "eth0", None).unwrap();
+        assert_ne!(fallback.address, first.address);
+        assert_ne!(fallback.address, other.address);
+
+        // IPv4 ranges stay sequential
+        clean_data_dir(network);
+        let sequential =
+            allocator(network, "10.1.0.0/24").with_strategy(AllocationStrategy::Hashed);
+        let ip_config = sequential.get("c1", "eth0", None).unwrap();
+        assert_eq!(ip_config.address.to_string(), "10.1.0.2/24");
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn hash_strategy() {
+        let network = "hash";
+        clean_data_dir(network);
+
+        let hash = allocator(network, "10.1.0.0/24").with_strategy(AllocationStrategy::Hash);
+
+        // the same container gets the same IP after it was deleted
+        let first = hash.get("c1", "eth0", None).unwrap();
+        assert_ne!(first.address.to_string(), "10.1.0.2/24");
+        hash.release("c1", "eth0").unwrap();
+        assert_eq!(hash.get("c1", "eth0", None).unwrap(), first);
+
+        // somebody else holds it, probe the following IPs
+        hash.release("c1", "eth0").unwrap();
+        hash.store
+            .reserve("c2", "eth0", first.address.ip(), "1")
+            .unwrap();
+        let next = hash
+            .range_set
+            .iter()
+            .next()
+            .unwrap()
+            .iter_free()
+            .skip_while(|ip_net| ip_net.ip() != first.address.ip())
+            .nth(1)
+            .unwrap_or_else(|| "10.1.0.2/24".parse().unwrap());
+        assert_eq!(hash.get("c1", "eth0", None).unwrap().address, next);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn warm_cache() {
+        let network = "warm-cache";
+        clean_data_dir(network);
+
+        let allocator = allocator(network, "10.1.0.0/29");
+        allocator.get("c1", "eth0", None).unwrap();
+        allocator.warm_cache().unwrap();
+
+        let ip_config = allocator.get("c2", "eth0", None).unwrap();
+        assert_eq!(ip_config.address.to_string(), "10.1.0.3/29");
+        allocator.release("c1", "eth0").unwrap();
+        assert!(allocator.verify_cache().unwrap());
+
+        // reservations made behind the allocator's back are noticed once
+        // the store refuses an IP
+        allocator
+            .store
+            .reserve("c3", "eth0", "10.1.0.4".parse().unwrap(), "1")
+            .unwrap();
+        let ip_config = allocator.get("c4", "eth0", None).unwrap();
+        assert_eq!(ip_config.address.to_string(), "10.1.0.5/29");
+        assert!(allocator.verify_cache().unwrap());
+
+        // or by verifying
+        allocator.store.release_by_id("c3", "eth0").unwrap();
+        assert!(!allocator.verify_cache().unwrap());
+        assert!(allocator.verify_cache().unwrap());
+        let available: Vec<String> = allocator
+            .iter_available()
+            .map(|ip_net| ip_net.unwrap().ip().to_string())
+            .collect();
+        assert_eq!(available, vec!["10.1.0.2", "10.1.0.4", "10.1.0.6"]);
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn reconcile() {
+        let network = "reconcile";
+        clean_data_dir(network);
+
+        let allocator = allocator(network, "10.1.0.0/28");
+        for id in &["c1", "c2", "c3"] {
+            allocator.get(id, "eth0", None).unwrap();
+        }
+        let other = "10.9.0.5".parse().unwrap();
+        allocator.store.reserve("c4", "eth0", other, "1").unwrap();
+
+        // the range now starts at 10.1.0.4
+        let mut shrunk = RangeSet::new();
+        shrunk
+            .add(
+                Range::new(
+                    "10.1.0.0/28".parse().unwrap(),
+                    Some("10.1.0.4".parse().unwrap()),
+                    None,
+                    None,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let orphans = allocator.reconcile(&shrunk, false).unwrap();
+        let expected = vec![
+            Orphan {
+                ip: "10.1.0.2".parse().unwrap(),
+                id: "c1".to_owned(),
+                ifname: "eth0".to_owned(),
+            },
+            Orphan {
+                ip: "10.1.0.3".parse().unwrap(),
+                id: "c2".to_owned(),
+                ifname: "eth0".to_owned(),
+            },
+        ];
+        assert_eq!(orphans, expected);
+        assert_eq!(allocator.store.list().unwrap().len(), 4);
+
+        assert_eq!(allocator.reconcile(&shrunk, true).unwrap(), expected);
+        let mut left = allocator.store.list().unwrap();
+        left.sort();
+        assert_eq!(left, vec!["10.1.0.4".parse::<IpAddr>().unwrap(), other]);
+        assert!(allocator.reconcile(&shrunk, false).unwrap().is_empty());
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn read_only() {
+        use crate::store::filestore::FileStoreOptions;
+
+        let network = "read-only";
+        clean_data_dir(network);
+
+        let writer = allocator(network, "10.1.0.0/29");
+        let held = writer.get("c1", "eth0", None).unwrap();
+
+        let options = FileStoreOptions {
+            read_only: true,
+            ..FileStoreOptions::default()
+        };
+        let store = FileStore::with_options(network, "/tmp/cni-allocator", options).unwrap();
+        let reader = Allocator::new(writer.range_set.clone(), Arc::new(store));
+
+        // lookups work, anything that would write fails
+        assert_eq!(reader.get("c1", "eth0", None).unwrap(), held);
+        assert_eq!(reader.iter_available().count(), 4);
+        assert!(matches!(
+            reader.get("c2", "eth0", None),
+            Err(AllocateError::StoreError(StoreError::ReadOnly))
+        ));
+        assert!(matches!(
+            reader.release("c1", "eth0"),
+            Err(AllocateError::StoreError(StoreError::ReadOnly))
+        ));
+        assert!(matches!(
+            reader.renew("c1", "eth0"),
+            Err(AllocateError::StoreError(StoreError::ReadOnly))
+        ));
+        assert_eq!(writer.store.list().unwrap(), vec![held.address.ip()]);
+
+        let missing = FileStore::with_options("read-only-missing", "/tmp/cni-allocator", options);
+        assert!(missing.is_err());
+        assert!(!std::path::Path::new("/tmp/cni-allocator/read-only-missing").exists());
+
+        clean_data_dir(network);
+    }
+
+    #[test]
+    fn concurrent_get_many_across_ranges() {
+        let network = "concurrent-ranges";
+        clean_data_dir(network);
+
+        // every thread opens the store on its own, like concurrent plugin
+        // processes, and allocates from one of two range sets
+        let threads: Vec<_> = (0..8u32)
+            .map(|thread| {
+                std::thread::spawn(move || {
+                    let range_id = thread % 2;
+                    let subnet = format!("10.{}.0.0/24", range_id + 1).parse().unwrap();
+                    let mut range_set = RangeSet::new();
+                    range_set
+                        .add(Range::new(subnet, None, None, None).unwrap())
+                        .unwrap();
+
+                    let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+                    let allocator = Allocator::new(range_set, Arc::new(store))
+                        .with_range_id(range_id.to_string());
+
+                    (0..10)
+                        .map(|n| {
+                            allocator
+                                .get_many(&format!("c{}-{}", thread, n), "eth0", 1)
+                                .unwrap()[0]
+                                .address
+                                .ip()
+                        })
+                        .collect::<Vec<IpAddr>>()
+                })
+            })
+            .collect();
+
+        let ips: Vec<IpAddr> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+        let unique: HashSet<IpAddr> = ips.iter().copied().collect();
+        assert_eq!(ips.len(), 80);
+        assert_eq!(unique.len(), 80);
+
+        let store = FileStore::new(network, "/tmp/cni-allocator").unwrap();
+        assert_eq!(store.list().unwrap().len(), 80);
+        assert!(store.last_reserved_ip("0").is_ok());
+        assert!(store.last_reserved_ip("1").is_ok());
+
+        clean_data_dir(network);
+    }
+}
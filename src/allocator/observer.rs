@@ -0,0 +1,27 @@
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use super::IpConfig;
+
+/// Gets notified by an `Allocator` after IPs were reserved or released, e.g.
+/// to keep a hosts file or firewall set in sync with the store.
+///
+/// Observers are called after the store was updated and can't fail the
+/// operation. A retried request for an IP already held by the same
+/// container may be reported again.
+pub trait AllocationObserver {
+    fn allocated(&self, id: &str, ifname: &str, ip_config: &IpConfig);
+
+    fn released(&self, id: &str, ifname: &str, ip: IpAddr);
+}
+
+/// Lets one observer be shared by the allocators of every range set.
+impl<T: AllocationObserver + ?Sized> AllocationObserver for Rc<T> {
+    fn allocated(&self, id: &str, ifname: &str, ip_config: &IpConfig) {
+        (**self).allocated(id, ifname, ip_config)
+    }
+
+    fn released(&self, id: &str, ifname: &str, ip: IpAddr) {
+        (**self).released(id, ifname, ip)
+    }
+}
@@ -0,0 +1,114 @@
+//! Splitting a cluster CIDR into equally sized per-node subnets, so nodes
+//! can allocate from their own slice without a central IPAM. The slices
+//! only depend on the CIDR and the number of nodes, every node computes
+//! the same ones.
+
+use ipnetwork::IpNetwork;
+use thiserror::Error;
+
+use crate::core;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PartitionError {
+    #[error("a network can't be split into {0} slices")]
+    NoSlices(u32),
+
+    #[error("network {0} is too small for {1} slices")]
+    TooManySlices(IpNetwork, u32),
+
+    #[error("slice {0} is out of the {1} slices")]
+    OutOfRangeIndex(u32, u32),
+}
+
+/// Prefix length of the slices when `subnet` is split into at least
+/// `count` of them, e.g. 24 for a /16 and 256 nodes. Slices are a power of
+/// two, with fewer than `count` slices the last ones stay unused.
+pub fn slice_prefix(subnet: IpNetwork, count: u32) -> Result<u8, PartitionError> {
+    if count == 0 {
+        return Err(PartitionError::NoSlices(count));
+    }
+
+    let bits = 32 - (count - 1).leading_zeros();
+    let max_prefix = if subnet.is_ipv4() { 32 } else { 128 };
+    let prefix = u32::from(subnet.prefix()) + bits;
+    if prefix > max_prefix {
+        return Err(PartitionError::TooManySlices(subnet, count));
+    }
+
+    Ok(prefix as u8)
+}
+
+/// The `index`th of the `count` slices of `subnet`, counting from its
+/// network address.
+pub fn node_slice(subnet: IpNetwork, index: u32, count: u32) -> Result<IpNetwork, PartitionError> {
+    let prefix = slice_prefix(subnet, count)?;
+    if index >= count {
+        return Err(PartitionError::OutOfRangeIndex(index, count));
+    }
+
+    let max_prefix = if subnet.is_ipv4() { 32 } else { 128 };
+    let offset = u128::from(index)
+        .checked_shl(u32::from(max_prefix - prefix))
+        .unwrap_or(0);
+    let network = core::from_u128(core::to_u128(subnet.network()) + offset, subnet.is_ipv4());
+
+    // UNWRAP: the prefix fits the family, checked by `slice_prefix`
+    Ok(IpNetwork::new(network, prefix).unwrap())
+}
+
+/// All `count` slices of `subnet` in order, see `node_slice`.
+pub fn slices(subnet: IpNetwork, count: u32) -> Result<Vec<IpNetwork>, PartitionError> {
+    (0..count)
+        .map(|index| node_slice(subnet, index, count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(subnet: &str) -> IpNetwork {
+        subnet.parse().unwrap()
+    }
+
+    #[test]
+    fn partition() {
+        assert_eq!(slice_prefix(net("10.0.0.0/16"), 256), Ok(24));
+        assert_eq!(slice_prefix(net("10.0.0.0/16"), 200), Ok(24));
+        assert_eq!(slice_prefix(net("10.0.0.0/16"), 1), Ok(16));
+
+        assert_eq!(
+            node_slice(net("10.0.0.0/16"), 0, 256),
+            Ok(net("10.0.0.0/24"))
+        );
+        assert_eq!(
+            node_slice(net("10.0.0.0/16"), 255, 256),
+            Ok(net("10.0.255.0/24"))
+        );
+        assert_eq!(
+            node_slice(net("2001:db8::/48"), 3, 4),
+            Ok(net("2001:db8:0:c000::/50"))
+        );
+        assert_eq!(
+            slices(net("10.0.0.0/24"), 3),
+            Ok(vec![
+                net("10.0.0.0/26"),
+                net("10.0.0.64/26"),
+                net("10.0.0.128/26")
+            ])
+        );
+
+        assert_eq!(
+            node_slice(net("10.0.0.0/16"), 4, 4),
+            Err(PartitionError::OutOfRangeIndex(4, 4))
+        );
+        assert_eq!(
+            node_slice(net("10.0.0.0/30"), 0, 8),
+            Err(PartitionError::TooManySlices(net("10.0.0.0/30"), 8))
+        );
+        assert_eq!(
+            slice_prefix(net("10.0.0.0/16"), 0),
+            Err(PartitionError::NoSlices(0))
+        );
+    }
+}
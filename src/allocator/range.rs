@@ -1,16 +1,21 @@
 use std::cmp::PartialEq;
 use std::fmt;
 use std::net::IpAddr;
+use std::str::FromStr;
 
 use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+use crate::core;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Range {
     pub subnet: IpNetwork,
     pub start: IpAddr,
     pub end: IpAddr,
-    pub gateway: IpAddr,
+    /// Unset for point-to-point ranges, see `Range::point_to_point`.
+    pub gateway: Option<IpAddr>,
 }
 
 #[derive(Debug, Error, PartialEq)]
@@ -23,160 +28,363 @@ pub enum RangeError {
 
     #[error("IP {1} is out of network {0}")]
     OutOfRangeIp(IpNetwork, IpAddr),
+
+    #[error("Gateway {1} is out of network {0}")]
+    OutOfRangeGateway(IpNetwork, IpAddr),
+
+    #[error("Network {0} is too large for a point-to-point range")]
+    NotPointToPoint(IpNetwork),
+
+    #[error("Point-to-point network {0} has no gateway")]
+    PointToPointGateway(IpNetwork),
+}
+
+/// Error of parsing a range or range set from a string, with the column,
+/// counted from 1, where the offending part starts.
+#[derive(Debug, Error, PartialEq)]
+#[error("invalid range {input:?} at column {column}: {reason}")]
+pub struct ParseRangeError {
+    pub input: String,
+    pub column: usize,
+    pub reason: String,
 }
 
 impl Range {
     pub fn new(
         subnet: IpNetwork,
-        mut start: Option<IpAddr>,
-        mut end: Option<IpAddr>,
-        mut gateway: Option<IpAddr>,
+        start: Option<IpAddr>,
+        end: Option<IpAddr>,
+        gateway: Option<IpAddr>,
     ) -> Result<Self, RangeError> {
-        use RangeError::*;
-
-        // todo: ipv6 check
-        if subnet.is_ipv4() && subnet.prefix() > 30 {
-            return Err(TooSmallNetwork(subnet));
-        }
+        core::Range::new(subnet.into(), start, end, gateway)
+            .map(Range::from)
+            .map_err(RangeError::from)
+    }
 
-        if subnet.ip() != subnet.network() {
-            return Err(WrongNetworkAddr(subnet, subnet.network()));
-        }
+    /// Creates a range of an RFC 3021 /31 point-to-point subnet, or a /32
+    /// for a single host route (/127 and /128 for IPv6).
+    ///
+    /// Such subnets have neither a network nor a broadcast address, every IP
+    /// is allocatable and there is no gateway.
+    pub fn point_to_point(
+        subnet: IpNetwork,
+        start: Option<IpAddr>,
+        end: Option<IpAddr>,
+    ) -> Result<Self, RangeError> {
+        core::Range::point_to_point(subnet.into(), start, end)
+            .map(Range::from)
+            .map_err(RangeError::from)
+    }
 
-        // todo: out of range check
-        if gateway == None {
-            let mut iter = subnet.iter();
-            let _ = iter.next();
-            gateway = iter.next();
+    /// The range without its `IpNetwork`, for the math of `core::Range`.
+    pub fn to_core(&self) -> core::Range {
+        core::Range {
+            subnet: self.subnet.into(),
+            start: self.start,
+            end: self.end,
+            gateway: self.gateway,
         }
-
-        match start {
-            Some(ip) => {
-                if !subnet.contains(ip) {
-                    return Err(RangeError::OutOfRangeIp(subnet, ip));
-                }
-            }
-            None => {
-                let mut iter = subnet.iter();
-                let _ = iter.next();
-                start = iter.next();
-            }
-        };
-
-        match end {
-            Some(ip) => {
-                if !subnet.contains(ip) {
-                    return Err(RangeError::OutOfRangeIp(subnet, ip));
-                }
-            }
-            None => end = Some(Self::last_ip(subnet)),
-        };
-
-        return Ok(Range {
-            subnet: subnet,
-            gateway: gateway.unwrap(),
-            start: start.unwrap(),
-            end: end.unwrap(),
-        });
     }
-    /// Naive implementation of iterating the IP range.
+
+    /// Iterates the IP range.
     ///
     /// This iterator will yield every IP available in the range, that is, every
     /// IP in the subnet, except those lower than `start`, higher than
-    /// `end`, or the one which is the `gateway`.
-    ///
-    /// The current implementation iterates through the entire range and filters
-    /// off the excluded IPs as per above. For IPv4 this will likely never be an
-    /// issue but IPv6 ranges are monstrous and could spend a long time spinning
-    /// before reaching `start`.
-    pub fn iter_free(&self) -> impl Iterator<Item = IpNetwork> {
+    /// `end`, or the one which is the `gateway`. It starts right at `start`,
+    /// so IPv6 ranges deep inside their subnet cost nothing extra.
+    pub fn iter_free(&self) -> impl DoubleEndedIterator<Item = IpNetwork> + ExactSizeIterator {
         let prefix = self.subnet.prefix();
-        let start = self.start;
-        let end = self.end;
-        let gateway = self.gateway;
 
-        self.subnet
-            .iter()
-            .filter(move |ip| {
-                if ip < &start {
-                    // TODO: figure out how to START from there instead
-                    return false;
-                }
-
-                if ip > &end {
-                    // TODO: figure out how to stop the iterator there instead
-                    return false;
-                }
-
-                if ip == &gateway {
-                    return false;
-                }
-
-                true
-            })
+        self.to_core()
+            .ips()
             .map(move |ip| (IpNetwork::new(ip, prefix).unwrap()))
         // UNWRAP: panics on invalid prefix, but we got it from another IpNetwork
     }
 
-    fn last_ip(subnet: IpNetwork) -> IpAddr {
-        match subnet {
-            IpNetwork::V4(subnet) => {
-                let mut octets = subnet.ip().octets();
-                let mask = subnet.mask().octets();
+    /// Number of allocatable IPs in the range, i.e. what `iter_free` would
+    /// yield, computed without iterating.
+    pub fn capacity(&self) -> u128 {
+        self.to_core().capacity()
+    }
+
+    /// The first `head` and the last `tail` allocatable IPs of the range,
+    /// skipping the gateway.
+    pub fn edge_ips(&self, head: usize, tail: usize) -> Vec<IpAddr> {
+        let (start, end) = (core::to_u128(self.start), core::to_u128(self.end));
+        let gateway = self.gateway.map(core::to_u128);
+        let ipv4 = self.start.is_ipv4();
 
-                for i in 0..octets.len() {
-                    octets[i] = octets[i] | (!mask[i])
-                }
-                octets[3] -= 1;
+        let mut ips = Vec::new();
+        if start > end {
+            return ips;
+        }
 
-                IpAddr::from(octets)
-            }
-            IpNetwork::V6(subnet) => {
-                let mut segments = subnet.ip().segments();
-                let mask = subnet.mask().segments();
-
-                for i in 0..segments.len() {
-                    segments[i] = segments[i] | (!mask[i])
-                }
-                IpAddr::from(segments)
+        let head_ips = (start..=end).filter(|ip| Some(*ip) != gateway).take(head);
+        let tail_ips = (start..=end)
+            .rev()
+            .filter(|ip| Some(*ip) != gateway)
+            .take(tail);
+        for ip in head_ips.chain(tail_ips) {
+            let ip = core::from_u128(ip, ipv4);
+            if !ips.contains(&ip) {
+                ips.push(ip);
             }
         }
+
+        ips
+    }
+
+    /// Number of IPs from `start` to `end`, including the gateway. Saturates
+    /// for a whole IPv6 address space.
+    pub(crate) fn size(&self) -> u128 {
+        self.to_core().size()
+    }
+
+    /// The IP `n` positions after `start`, wrapping around at `end`. The
+    /// gateway isn't skipped.
+    pub(crate) fn nth_ip(&self, n: u128) -> Option<IpAddr> {
+        self.to_core().nth_ip(n)
     }
 
     // contains checks if a given ip is a valid, allocatable address in a given Range
     pub fn contains(&self, ip: IpAddr) -> bool {
-        if !self.subnet.contains(ip) {
-            return false;
+        self.to_core().contains(ip)
+    }
+
+    pub fn is_same_familiy(&self, other: &Self) -> bool {
+        self.to_core().is_same_family(&other.to_core())
+    }
+
+    pub fn overlaps(&self, other_range: &Self) -> bool {
+        self.to_core().overlaps(&other_range.to_core())
+    }
+
+    /// Splits the range in front of `ip` into the IPs before it and the
+    /// IPs from it on, both in the subnet and with the gateway of this
+    /// range. None if `ip` is out of the range or its start, where one part
+    /// would be empty.
+    pub fn split_at(&self, ip: IpAddr) -> Option<(Range, Range)> {
+        if !self.contains(ip) || ip == self.start {
+            return None;
         }
 
-        if self.start > ip {
-            return false;
+        Some((
+            self.with_bounds(self.start, core::prev_ip(ip)),
+            self.with_bounds(ip, self.end),
+        ))
+    }
+
+    /// The IPs of this range which are in `other` too, None if they don't
+    /// overlap. The subnet and gateway are kept from this range.
+    pub fn intersect(&self, other: &Range) -> Option<Range> {
+        if !self.overlaps(other) {
+            return None;
         }
 
-        if self.end < ip {
-            return false;
+        let start = self.start.max(other.start);
+        let end = self.end.min(other.end);
+        Some(self.with_bounds(start, end))
+    }
+
+    /// The IPs of this range which aren't in `other`, as up to two ranges
+    /// in order, in the subnet and with the gateway of this range.
+    pub fn subtract(&self, other: &Range) -> Vec<Range> {
+        if !self.overlaps(other) {
+            return vec![*self];
+        }
+
+        let mut ranges = Vec::new();
+        if self.start < other.start {
+            ranges.push(self.with_bounds(self.start, core::prev_ip(other.start)));
+        }
+        if other.end < self.end {
+            ranges.push(self.with_bounds(core::next_ip(other.end), self.end));
         }
 
-        return true;
+        ranges
     }
 
-    pub fn is_same_familiy(&self, other: &Self) -> bool {
-        match (self.subnet.ip(), other.subnet.ip()) {
-            (IpAddr::V4(_), IpAddr::V4(_)) => true,
-            (IpAddr::V6(_), IpAddr::V6(_)) => true,
-            _ => false,
+    fn with_bounds(&self, start: IpAddr, end: IpAddr) -> Range {
+        Range {
+            subnet: self.subnet,
+            start: start,
+            end: end,
+            gateway: self.gateway,
         }
     }
+}
 
-    pub fn overlaps(&self, other_range: &Self) -> bool {
-        if !self.is_same_familiy(other_range) {
-            return false;
+impl Range {
+    /// Parses `text`, which starts `offset` bytes into the input, see
+    /// `Range::from_str`. Errors carry the offset of the offending field.
+    pub(crate) fn parse(text: &str, offset: usize) -> Result<Range, (usize, String)> {
+        let fields = split_trimmed(text, ',', offset);
+        let (column, first) = fields[0];
+
+        if fields.len() == 1 {
+            if first.is_empty() {
+                return Err((
+                    column,
+                    "expected CIDR, START-END or CIDR,START,END,GATEWAY".to_owned(),
+                ));
+            }
+            if let [(start_column, start), (end_column, end)] =
+                split_trimmed(first, '-', column)[..]
+            {
+                let start = parse_ip(start_column, start)?;
+                let end = parse_ip(end_column, end)?;
+                return Range::spanning(start, end).map_err(|reason| (end_column, reason));
+            }
+        }
+        if fields.len() > 4 {
+            return Err((
+                fields[4].0,
+                "expected at most CIDR,START,END,GATEWAY".to_owned(),
+            ));
         }
 
-        return self.contains(other_range.start)
-            || self.contains(other_range.end)
-            || other_range.contains(self.start)
-            || other_range.contains(self.end);
+        let subnet: IpNetwork = first
+            .parse()
+            .map_err(|err| (column, format!("{:?} is no CIDR: {}", first, err)))?;
+        let mut ips = [None; 3];
+        for (ip, (column, field)) in ips.iter_mut().zip(&fields[1..]) {
+            if !field.is_empty() {
+                *ip = Some(parse_ip(*column, field)?);
+            }
+        }
+        let [start, end, gateway] = ips;
+
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err((
+                    fields[2].0,
+                    format!("end {} is before start {}", end, start),
+                ));
+            }
+        }
+
+        Range::new(subnet, start, end, gateway).map_err(|err| {
+            let column = match err {
+                RangeError::OutOfRangeIp(_, ip) if start == Some(ip) => fields[1].0,
+                RangeError::OutOfRangeIp(_, _) => fields[2].0,
+                RangeError::OutOfRangeGateway(_, _) => fields[3].0,
+                _ => column,
+            };
+            (column, err.to_string())
+        })
+    }
+
+    /// The range from `start` to `end` in the smallest subnet holding both
+    /// whose network address, and broadcast address for IPv4, lie outside
+    /// of the range. The gateway is the default one of the subnet.
+    fn spanning(start: IpAddr, end: IpAddr) -> Result<Range, String> {
+        if start.is_ipv4() != end.is_ipv4() {
+            return Err(format!("{} and {} are of different families", start, end));
+        }
+        if start > end {
+            return Err(format!("end {} is before start {}", end, start));
+        }
+
+        let bits: u8 = if start.is_ipv4() { 32 } else { 128 };
+        let common = (core::to_u128(start) ^ core::to_u128(end)).leading_zeros() as u8;
+        let mut prefix = (common - (128 - bits)).min(bits - 2);
+        loop {
+            // UNWRAP: the prefix is no longer than the address
+            let network = IpNetwork::new(start, prefix).unwrap().network();
+            let subnet = IpNetwork::new(network, prefix).unwrap();
+            if network < start && end <= core::Subnet::from(subnet).last_host() {
+                return Range::new(subnet, Some(start), Some(end), None)
+                    .map_err(|err| err.to_string());
+            }
+            if prefix == 0 {
+                return Err(format!(
+                    "no subnet holds {}-{} besides its network address",
+                    start, end
+                ));
+            }
+            prefix -= 1;
+        }
+    }
+}
+
+impl FromStr for Range {
+    type Err = ParseRangeError;
+
+    /// Parses `CIDR`, `START-END` or `CIDR,START,END,GATEWAY`, where the
+    /// fields after the CIDR may be empty or left out to get their
+    /// defaults, e.g. `10.1.0.0/24,10.1.0.10,,10.1.0.254`.
+    ///
+    /// A bare `START-END` is put into the smallest subnet holding it, see
+    /// `Range::spanning`, e.g. `10.1.0.10-10.1.0.50` into `10.1.0.0/26`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Range::parse(text, 0).map_err(|(offset, reason)| ParseRangeError {
+            input: text.to_owned(),
+            column: offset + 1,
+            reason: reason,
+        })
+    }
+}
+
+/// Splits `text` at `separator` into its fields without surrounding
+/// whitespace, each with its offset in the input `text` starts `offset`
+/// bytes into.
+pub(crate) fn split_trimmed(text: &str, separator: char, offset: usize) -> Vec<(usize, &str)> {
+    let mut fields = Vec::new();
+    let mut start = offset;
+    for field in text.split(separator) {
+        let trimmed = field.trim_start();
+        fields.push((start + field.len() - trimmed.len(), trimmed.trim_end()));
+        start += field.len() + separator.len_utf8();
+    }
+
+    fields
+}
+
+fn parse_ip(offset: usize, text: &str) -> Result<IpAddr, (usize, String)> {
+    text.parse()
+        .map_err(|err| (offset, format!("{:?} is no IP: {}", text, err)))
+}
+
+impl From<core::Range> for Range {
+    fn from(range: core::Range) -> Range {
+        Range {
+            subnet: range.subnet.into(),
+            start: range.start,
+            end: range.end,
+            gateway: range.gateway,
+        }
+    }
+}
+
+impl From<IpNetwork> for core::Subnet {
+    fn from(subnet: IpNetwork) -> core::Subnet {
+        // UNWRAP: the prefix of an IpNetwork fits its address
+        core::Subnet::new(subnet.ip(), subnet.prefix()).unwrap()
+    }
+}
+
+impl From<core::Subnet> for IpNetwork {
+    fn from(subnet: core::Subnet) -> IpNetwork {
+        // UNWRAP: the prefix of a Subnet fits its address
+        IpNetwork::new(subnet.ip(), subnet.prefix()).unwrap()
+    }
+}
+
+impl From<core::RangeError> for RangeError {
+    fn from(err: core::RangeError) -> RangeError {
+        match err {
+            core::RangeError::TooSmallNetwork(subnet) => RangeError::TooSmallNetwork(subnet.into()),
+            core::RangeError::WrongNetworkAddr(subnet, ip) => {
+                RangeError::WrongNetworkAddr(subnet.into(), ip)
+            }
+            core::RangeError::OutOfRangeIp(subnet, ip) => {
+                RangeError::OutOfRangeIp(subnet.into(), ip)
+            }
+            core::RangeError::OutOfRangeGateway(subnet, ip) => {
+                RangeError::OutOfRangeGateway(subnet.into(), ip)
+            }
+            core::RangeError::NotPointToPoint(subnet) => RangeError::NotPointToPoint(subnet.into()),
+        }
     }
 }
 
@@ -189,8 +397,37 @@ impl fmt::Display for Range {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use std::str::FromStr;
 
+    #[test]
+    fn edge_ips() {
+        let range = Range::new(
+            "10.1.0.0/24".parse().unwrap(),
+            Some("10.1.0.1".parse().unwrap()),
+            Some("10.1.0.20".parse().unwrap()),
+            Some("10.1.0.1".parse().unwrap()),
+        )
+        .unwrap();
+
+        let ips: Vec<String> = range
+            .edge_ips(3, 2)
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect();
+        assert_eq!(
+            ips,
+            vec!["10.1.0.2", "10.1.0.3", "10.1.0.4", "10.1.0.20", "10.1.0.19"]
+        );
+        assert_eq!(range.edge_ips(30, 30).len(), 19);
+
+        let range = Range::new("2001:db8::/64".parse().unwrap(), None, None, None).unwrap();
+        assert_eq!(
+            range.edge_ips(0, 1),
+            vec!["2001:db8::ffff:ffff:ffff:ffff".parse::<IpAddr>().unwrap()]
+        );
+    }
+
     #[test]
     fn range_iter() {
         let range = Range::new(
@@ -231,6 +468,49 @@ mod tests {
             Range::new(network, None, None, None),
             Err(RangeError::TooSmallNetwork(network))
         );
+
+        let network = "2001:db8::/127".parse().unwrap();
+        assert_eq!(
+            Range::new(network, None, None, None),
+            Err(RangeError::TooSmallNetwork(network))
+        );
+    }
+
+    #[test]
+    fn point_to_point() {
+        let range = Range::point_to_point("10.1.0.0/31".parse().unwrap(), None, None).unwrap();
+        assert_eq!(range.gateway, None);
+        assert_eq!(range.capacity(), 2);
+        assert_eq!(
+            range.iter_free().collect::<Vec<_>>(),
+            vec![
+                "10.1.0.0/31".parse().unwrap(),
+                "10.1.0.1/31".parse().unwrap()
+            ]
+        );
+
+        let range = Range::point_to_point("10.1.0.7/32".parse().unwrap(), None, None).unwrap();
+        assert_eq!(
+            range.edge_ips(1, 1),
+            vec!["10.1.0.7".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(range.capacity(), 1);
+
+        let range = Range::point_to_point("2001:db8::/127".parse().unwrap(), None, None).unwrap();
+        assert_eq!(range.end, "2001:db8::1".parse::<IpAddr>().unwrap());
+
+        let network = "10.1.0.0/30".parse().unwrap();
+        assert_eq!(
+            Range::point_to_point(network, None, None),
+            Err(RangeError::NotPointToPoint(network))
+        );
+
+        let network = "10.1.0.0/31".parse().unwrap();
+        let outside = "10.1.0.2".parse().unwrap();
+        assert_eq!(
+            Range::point_to_point(network, None, Some(outside)),
+            Err(RangeError::OutOfRangeIp(network, outside))
+        );
     }
 
     #[test]
@@ -246,7 +526,18 @@ mod tests {
     #[test]
     fn canonicalize_empty_gateway_ip() {
         let range = Range::new("2.2.0.0/16".parse().unwrap(), None, None, None).unwrap();
-        assert_eq!(range.gateway, "2.2.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(range.gateway, "2.2.0.1".parse().ok());
+    }
+
+    #[test]
+    fn canonicalize_out_of_range_gateway() {
+        let gateway = "2.3.0.1".parse().unwrap();
+        let subnet = "2.2.0.0/16".parse().unwrap();
+
+        assert_eq!(
+            Range::new(subnet, None, None, Some(gateway)),
+            Err(RangeError::OutOfRangeGateway(subnet, gateway))
+        );
     }
 
     #[test]
@@ -347,4 +638,148 @@ mod tests {
         let range2 = Range::new("2.3.0.0/16".parse().unwrap(), None, None, None).unwrap();
         assert!(!range.overlaps(&range2));
     }
+
+    #[test]
+    fn split_subtract_intersect() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let range = Range::new("10.1.2.0/24".parse().unwrap(), None, None, None).unwrap();
+        let bounds = |range: &Range| (range.start, range.end);
+
+        let (low, high) = range.split_at(ip("10.1.2.100")).unwrap();
+        assert_eq!(bounds(&low), (ip("10.1.2.1"), ip("10.1.2.99")));
+        assert_eq!(bounds(&high), (ip("10.1.2.100"), ip("10.1.2.254")));
+        assert_eq!(low.gateway, range.gateway);
+        assert_eq!(high.gateway, range.gateway);
+        assert_eq!(range.split_at(ip("10.1.2.1")), None);
+        assert_eq!(range.split_at(ip("10.1.3.1")), None);
+
+        let middle = Range::new(
+            "10.1.2.0/24".parse().unwrap(),
+            Some(ip("10.1.2.10")),
+            Some(ip("10.1.2.19")),
+            None,
+        )
+        .unwrap();
+        let parts: Vec<_> = range.subtract(&middle).iter().map(bounds).collect();
+        assert_eq!(
+            parts,
+            vec![
+                (ip("10.1.2.1"), ip("10.1.2.9")),
+                (ip("10.1.2.20"), ip("10.1.2.254"))
+            ]
+        );
+        assert!(middle.subtract(&range).is_empty());
+        assert_eq!(
+            bounds(&range.intersect(&middle).unwrap()),
+            (ip("10.1.2.10"), ip("10.1.2.19"))
+        );
+
+        let other = Range::new("10.1.3.0/24".parse().unwrap(), None, None, None).unwrap();
+        assert_eq!(range.subtract(&other), vec![range]);
+        assert_eq!(range.intersect(&other), None);
+    }
+
+    #[test]
+    fn parse_forms() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let range: Range = "10.1.0.0/24".parse().unwrap();
+        assert_eq!(
+            range,
+            Range::new("10.1.0.0/24".parse().unwrap(), None, None, None).unwrap()
+        );
+
+        let range: Range = " 10.1.0.10 - 10.1.0.50 ".parse().unwrap();
+        assert_eq!(range.subnet, "10.1.0.0/26".parse().unwrap());
+        assert_eq!((range.start, range.end), (ip("10.1.0.10"), ip("10.1.0.50")));
+        // the network address of the smallest subnet is in the range
+        let range: Range = "10.1.0.0-10.1.0.5".parse().unwrap();
+        assert_eq!(range.subnet, "10.0.0.0/15".parse().unwrap());
+        let range: Range = "2001:db8::10-2001:db8::1f".parse().unwrap();
+        assert_eq!(range.subnet, "2001:db8::/123".parse().unwrap());
+
+        let range: Range = "10.1.0.0/24, 10.1.0.10,,10.1.0.254".parse().unwrap();
+        assert_eq!(range.start, ip("10.1.0.10"));
+        assert_eq!(range.end, ip("10.1.0.254"));
+        assert_eq!(range.gateway, Some(ip("10.1.0.254")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| text.parse::<Range>().unwrap_err();
+
+        let err = error("10.1.0.0/24,10.1.0.10,10.1.1.50");
+        assert_eq!(err.column, 23);
+        assert_eq!(
+            err.to_string(),
+            "invalid range \"10.1.0.0/24,10.1.0.10,10.1.1.50\" at column 23: \
+             IP 10.1.1.50 is out of network 10.1.0.0/24"
+        );
+        assert_eq!(error("10.1.0.0/24,10.1.0.50,10.1.0.10").column, 23);
+        assert_eq!(error("10.1.0.0/24,,,10.2.0.1").column, 15);
+        assert_eq!(error("10.1.0.0/24,,,,").column, 16);
+        assert_eq!(error("10.1.0.1/24").column, 1);
+        assert_eq!(error("10.1.0.0/24, x").column, 14);
+        assert_eq!(error("10.1.0.0/33").column, 1);
+        assert_eq!(error("10.1.0.10 - 10.1.0.5").column, 13);
+        assert_eq!(error("10.1.0.10-2001:db8::1").column, 11);
+        assert_eq!(error("10.1.0.10-").column, 11);
+        assert_eq!(error("  ").column, 3);
+    }
+
+    /// Builds IPv4 ranges inside one of a few neighbouring /24s with arbitrary
+    /// bounds and gateway, so that generated pairs overlap often enough.
+    fn arb_range() -> impl Strategy<Value = Range> {
+        (0u8..3, 1u8..=254, 1u8..=254, 1u8..=254).prop_map(|(net, a, b, gateway)| {
+            let (start, end) = if a <= b { (a, b) } else { (b, a) };
+
+            Range::new(
+                format!("10.0.{}.0/24", net).parse().unwrap(),
+                Some(IpAddr::from([10, 0, net, start])),
+                Some(IpAddr::from([10, 0, net, end])),
+                Some(IpAddr::from([10, 0, net, gateway])),
+            )
+            .unwrap()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn iter_free_agrees_with_contains(range in arb_range()) {
+            let free: Vec<IpAddr> = range.iter_free().map(|ip_net| ip_net.ip()).collect();
+
+            for ip in &free {
+                prop_assert!(range.contains(*ip));
+                prop_assert_ne!(Some(*ip), range.gateway);
+            }
+
+            let expected = range
+                .subnet
+                .iter()
+                .filter(|ip| range.contains(*ip) && Some(*ip) != range.gateway)
+                .count();
+            prop_assert_eq!(free.len(), expected);
+            prop_assert_eq!(range.capacity(), expected as u128);
+            prop_assert_eq!(range.iter_free().len(), expected);
+        }
+
+        #[test]
+        fn overlaps_is_symmetric(a in arb_range(), b in arb_range()) {
+            prop_assert_eq!(a.overlaps(&b), b.overlaps(&a));
+        }
+
+        #[test]
+        fn subtract_and_intersect_partition(a in arb_range(), b in arb_range()) {
+            let mut parts = a.subtract(&b);
+            parts.extend(a.intersect(&b));
+
+            let mut ips: Vec<IpAddr> = parts
+                .iter()
+                .flat_map(|part| core::Ips::new(part.start, part.end, None))
+                .collect();
+            ips.sort();
+            let expected: Vec<IpAddr> = core::Ips::new(a.start, a.end, None).collect();
+            prop_assert_eq!(ips, expected);
+        }
+    }
 }
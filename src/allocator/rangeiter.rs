@@ -1,19 +1,18 @@
 use super::range::Range;
 use super::rangeset::RangeSet;
+use crate::core::{self, next_ip, prev_ip, to_u128};
 use ipnetwork::IpNetwork;
-use num_bigint::{BigInt, Sign};
-use std::convert::TryFrom;
 use std::net::IpAddr;
 
-pub struct RangeIter {
-  pub range_set: RangeSet,
+pub struct RangeIter<'a> {
+  pub range_set: &'a RangeSet,
   pub range_index: usize,
   pub current_ip: Option<IpAddr>,
   pub start_ip: Option<IpAddr>,
 }
 
-impl Iterator for RangeIter {
-  type Item = (IpNetwork, IpAddr);
+impl<'a> Iterator for RangeIter<'a> {
+  type Item = (IpNetwork, Option<IpAddr>);
 
   fn next(&mut self) -> Option<Self::Item> {
     let range = self.range_set.get(self.range_index);
@@ -27,7 +26,7 @@ impl Iterator for RangeIter {
       self.current_ip = Some(range.start);
       self.start_ip = self.current_ip;
 
-      if self.current_ip.unwrap() == range.gateway {
+      if self.current_ip == range.gateway {
         return self.next();
       }
 
@@ -50,39 +49,130 @@ impl Iterator for RangeIter {
       return None;
     }
 
-    if self.current_ip.unwrap() == range.gateway {
+    if self.current_ip == range.gateway {
       return self.next();
     }
 
     let ip_net = IpNetwork::new(self.current_ip.unwrap(), range.subnet.prefix());
     return Some((ip_net.unwrap(), range.gateway));
   }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    core::size_hint(self.remaining())
+  }
 }
 
-fn next_ip(ip: IpAddr) -> IpAddr {
-  match ip {
-    IpAddr::V4(ip) => {
-      let octets = ip.octets();
+/// Yields the IPs `next` has yet to reach from the other end, i.e.
+/// downwards from right before where `next` would stop.
+impl<'a> DoubleEndedIterator for RangeIter<'a> {
+  fn next_back(&mut self) -> Option<Self::Item> {
+    let len = self.range_set.len();
+    if self.range_set.get(self.range_index).is_none() {
+      return None;
+    }
 
-      let bi: BigInt = BigInt::from_bytes_be(Sign::Plus, &octets) + 1;
-      let (_, bytes) = bi.to_bytes_be();
-      let octets = <[u8; 4]>::try_from(bytes).unwrap();
-      IpAddr::from(octets)
+    // `next` would start at the start of the range, which is where it
+    // continues from the end of the previous one
+    if self.current_ip.is_none() {
+      self.range_index = (self.range_index + len - 1) % len;
+      self.current_ip = Some(self.range_set.get(self.range_index).unwrap().end);
     }
 
-    IpAddr::V6(ip) => {
-      let octets = ip.octets();
-      let bi: BigInt = BigInt::from_bytes_be(Sign::Plus, &octets) + 1;
-      let (_, bytes) = bi.to_bytes_be();
-      let octets = <[u8; 16]>::try_from(bytes).unwrap();
-      IpAddr::from(octets)
+    let ip = match self.start_ip {
+      // nothing was yielded yet, `next` would end with the current IP
+      None => self.current_ip.unwrap(),
+      Some(start_ip) => {
+        let ip = self.prev_in_ranges(start_ip);
+        if Some(ip) == self.current_ip {
+          return None;
+        }
+        ip
+      }
+    };
+    self.start_ip = Some(ip);
+
+    let range = self.range_set.iter().find(|range| range.contains(ip))?;
+    if Some(ip) == range.gateway {
+      return self.next_back();
     }
+
+    let ip_net = IpNetwork::new(ip, range.subnet.prefix());
+    Some((ip_net.unwrap(), range.gateway))
+  }
+}
+
+/// Exact unless more IPs are left than `usize` holds, see `core::size_hint`.
+impl<'a> ExactSizeIterator for RangeIter<'a> {}
+
+impl<'a> RangeIter<'a> {
+  /// Position of `ip` when counting the IPs of all ranges in order,
+  /// gateways included.
+  fn position(&self, ip: IpAddr) -> Option<u128> {
+    let mut offset = 0u128;
+    for range in self.range_set.iter() {
+      if range.contains(ip) {
+        return Some(offset + (to_u128(ip) - to_u128(range.start)));
+      }
+      offset = offset.saturating_add(range.size());
+    }
+
+    None
+  }
+
+  /// The IP before `ip`, the end of the previous range if `ip` starts one.
+  fn prev_in_ranges(&self, ip: IpAddr) -> IpAddr {
+    let len = self.range_set.len();
+    let index = self.range_set.iter().position(|range| range.start == ip);
+
+    match index {
+      Some(index) => self.range_set.get((index + len - 1) % len).unwrap().end,
+      None => prev_ip(ip),
+    }
+  }
+
+  /// Number of IPs `next` yields before the iteration wraps around to
+  /// where it started.
+  fn remaining(&self) -> u128 {
+    if self.range_set.get(self.range_index).is_none() {
+      return 0;
+    }
+
+    let total = self
+      .range_set
+      .iter()
+      .fold(0u128, |total, range| total.saturating_add(range.size()));
+    if total == 0 {
+      return 0;
+    }
+
+    // the positions still to come, as the first one and their number
+    let current = self.current_ip.and_then(|ip| self.position(ip));
+    let start = self.start_ip.and_then(|ip| self.position(ip));
+    let (first, len) = match (current, start) {
+      (None, _) => (0, total),
+      (Some(current), None) => ((current + 1) % total, total),
+      (Some(current), Some(start)) => {
+        ((current + 1) % total, (start + total - current - 1) % total)
+      }
+    };
+
+    let gateways = self
+      .range_set
+      .iter()
+      .filter_map(|range| range.gateway.filter(|gateway| range.contains(*gateway)))
+      .filter_map(|gateway| self.position(gateway))
+      .filter(|position| (position + total - first) % total < len)
+      .count();
+
+    len - gateways as u128
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use proptest::prelude::*;
+  use std::collections::HashSet;
   use std::str::FromStr;
 
   #[test]
@@ -100,7 +190,7 @@ mod tests {
     let _ = ranges.add(r1);
 
     let mut ri = RangeIter {
-      range_set: ranges,
+      range_set: &ranges,
       range_index: 0,
       current_ip: None,
       start_ip: None,
@@ -109,7 +199,7 @@ mod tests {
     let (ip_net, gateway) = ri.next().unwrap();
     assert_eq!(ip_net.ip(), IpAddr::from_str("10.1.0.1").unwrap());
     assert_eq!(ip_net.prefix(), 16u8);
-    assert_eq!(gateway, IpAddr::from_str("10.1.0.4").unwrap());
+    assert_eq!(gateway, IpAddr::from_str("10.1.0.4").ok());
 
     ri.next();
     ri.next();
@@ -119,4 +209,128 @@ mod tests {
 
     assert!(ri.next().is_none());
   }
+
+  /// Builds up to three ranges, each carved from its own /24 so they never
+  /// overlap, with arbitrary bounds and gateway.
+  fn arb_ranges() -> impl Strategy<Value = Vec<Range>> {
+    prop::collection::vec((1u8..=254, 1u8..=254, 1u8..=254), 1..4).prop_map(|bounds| {
+      bounds
+        .into_iter()
+        .enumerate()
+        .map(|(net, (a, b, gateway))| {
+          let net = net as u8;
+          let (start, end) = if a <= b { (a, b) } else { (b, a) };
+
+          Range::new(
+            format!("10.0.{}.0/24", net).parse().unwrap(),
+            Some(IpAddr::from([10, 0, net, start])),
+            Some(IpAddr::from([10, 0, net, end])),
+            Some(IpAddr::from([10, 0, net, gateway])),
+          )
+          .unwrap()
+        })
+        .collect()
+    })
+  }
+
+  fn iter_from(range_set: &RangeSet, last_reserved_ip: Option<IpAddr>) -> RangeIter<'_> {
+    let mut ri = RangeIter {
+      range_set: range_set,
+      range_index: 0,
+      current_ip: None,
+      start_ip: None,
+    };
+
+    if let Some(ip) = last_reserved_ip {
+      ri.range_index = range_set.iter().position(|r| r.contains(ip)).unwrap();
+      ri.current_ip = Some(ip);
+    }
+
+    ri
+  }
+
+  proptest! {
+    #[test]
+    fn rev_yields_the_same_ips_backwards(
+      ranges in arb_ranges(),
+      resume in any::<Option<prop::sample::Index>>(),
+      backwards in prop::collection::vec(any::<bool>(), 0..64),
+    ) {
+      let mut range_set = RangeSet::new();
+      for range in ranges {
+        range_set.add(range).unwrap();
+      }
+
+      let fresh: Vec<IpAddr> = iter_from(&range_set, None).map(|(ip_net, _)| ip_net.ip()).collect();
+      let last_reserved_ip = match resume {
+        Some(index) if !fresh.is_empty() => Some(fresh[index.index(fresh.len())]),
+        _ => None,
+      };
+
+      let forward: Vec<IpAddr> = iter_from(&range_set, last_reserved_ip)
+        .map(|(ip_net, _)| ip_net.ip())
+        .collect();
+      let mut reversed: Vec<IpAddr> = iter_from(&range_set, last_reserved_ip)
+        .rev()
+        .map(|(ip_net, _)| ip_net.ip())
+        .collect();
+      reversed.reverse();
+      prop_assert_eq!(&reversed, &forward);
+
+      // taking from both ends meets in the middle
+      let mut ri = iter_from(&range_set, last_reserved_ip);
+      let (mut front, mut back) = (Vec::new(), Vec::new());
+      for backwards in backwards.into_iter().chain(std::iter::repeat(false)) {
+        let remaining = ri.len();
+        let item = if backwards { ri.next_back() } else { ri.next() };
+        match item {
+          Some((ip_net, _)) if backwards => back.push(ip_net.ip()),
+          Some((ip_net, _)) => front.push(ip_net.ip()),
+          None => break,
+        }
+        prop_assert_eq!(ri.len(), remaining - 1);
+      }
+      back.reverse();
+      front.extend(back);
+      prop_assert_eq!(front, forward);
+    }
+
+    #[test]
+    fn iter_yields_free_ips_exactly_once(
+      ranges in arb_ranges(),
+      resume in any::<Option<prop::sample::Index>>(),
+    ) {
+      let mut range_set = RangeSet::new();
+      for range in ranges {
+        range_set.add(range).unwrap();
+      }
+
+      let capacity: usize = range_set.iter().map(|r| r.iter_free().count()).sum();
+
+      prop_assert_eq!(iter_from(&range_set, None).len(), capacity);
+      let fresh: Vec<IpAddr> = iter_from(&range_set, None).map(|(ip_net, _)| ip_net.ip()).collect();
+      prop_assert_eq!(fresh.len(), capacity);
+
+      let last_reserved_ip = match resume {
+        Some(index) if !fresh.is_empty() => Some(fresh[index.index(fresh.len())]),
+        _ => None,
+      };
+
+      let mut seen = HashSet::new();
+      let mut ri = iter_from(&range_set, last_reserved_ip);
+      while let Some((ip_net, gateway)) = ri.next() {
+        prop_assert_eq!(ri.len(), capacity - seen.len() - 1);
+
+        let ip = ip_net.ip();
+        let range = range_set.get_range_for_ip(ip);
+
+        prop_assert!(range.is_ok(), "{} is outside of every range", ip);
+        prop_assert_eq!(range.unwrap().gateway, gateway);
+        prop_assert_ne!(Some(ip), gateway);
+        prop_assert!(seen.insert(ip), "{} was yielded twice", ip);
+      }
+
+      prop_assert_eq!(seen.len(), capacity);
+    }
+  }
 }
@@ -1,12 +1,20 @@
 use std::cmp::PartialEq;
+use std::fmt;
 use std::net::IpAddr;
+use std::str::FromStr;
 
 use thiserror::Error;
 
-use super::range::Range;
+use super::fnv1a_128;
+use super::range::{split_trimmed, ParseRangeError, Range};
+use crate::core::{self, to_u128};
+use crate::validation::ValidationErrors;
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct RangeSet {
     ranges: Vec<Range>,
+    /// Priority of the range at the same index, see `add_with_priority`.
+    priorities: Vec<i32>,
 }
 
 #[derive(Debug, Error, PartialEq)]
@@ -17,13 +25,31 @@ pub enum RangeSetError {
     #[error("subnet {0} overlaps with subnet {1}")]
     Overlap(Range, Range),
 
-    #[error("no range found for ip {0}")]
-    NoRangeForIP(IpAddr),
+    #[error("no range found for ip {0}, ranges are {1}")]
+    NoRangeForIP(IpAddr, String),
+
+    #[error("ranges {0} and {1} of the same subnet have different gateways")]
+    ConflictingGateways(Range, Range),
+}
+
+impl From<core::RangeSetError> for RangeSetError {
+    fn from(err: core::RangeSetError) -> RangeSetError {
+        match err {
+            core::RangeSetError::DifferentAddressType => RangeSetError::DifferentAddressType,
+            core::RangeSetError::Overlap(a, b) => RangeSetError::Overlap(a.into(), b.into()),
+            core::RangeSetError::ConflictingGateways(a, b) => {
+                RangeSetError::ConflictingGateways(a.into(), b.into())
+            }
+        }
+    }
 }
 
 impl RangeSet {
     pub fn new() -> RangeSet {
-        RangeSet { ranges: Vec::new() }
+        RangeSet {
+            ranges: Vec::new(),
+            priorities: Vec::new(),
+        }
     }
 
     pub fn get_range_for_ip(&self, ip: IpAddr) -> Result<Range, RangeSetError> {
@@ -33,28 +59,84 @@ impl RangeSet {
             }
         }
 
-        return Err(RangeSetError::NoRangeForIP(ip));
+        return Err(RangeSetError::NoRangeForIP(ip, self.to_string()));
     }
 
-    pub fn get(&mut self, index: usize) -> Option<&Range> {
+    pub fn get(&self, index: usize) -> Option<&Range> {
         return self.ranges.get(index);
     }
 
     pub fn add(&mut self, range: Range) -> Result<(), RangeSetError> {
-        if self.ranges.len() > 0 {
-            if !self.ranges[0].is_same_familiy(&range) {
-                return Err(RangeSetError::DifferentAddressType);
-            }
+        self.add_with_priority(range, 0)
+    }
 
-            for r in &self.ranges {
-                if r.overlaps(&range) {
-                    return Err(RangeSetError::Overlap(*r, range));
-                }
+    /// Adds `range` with a priority other than the default 0. Allocation
+    /// exhausts the ranges of the highest priority before it takes IPs of
+    /// lower ones, e.g. of a range kept for bursts.
+    pub fn add_with_priority(&mut self, range: Range, priority: i32) -> Result<(), RangeSetError> {
+        let ranges = self.ranges.iter().map(Range::to_core);
+        core::RangeSet::check(ranges, &range.to_core()).map_err(RangeSetError::from)?;
+
+        // kept sorted, so reordering the ranges in the configuration changes
+        // neither iteration nor the index of a range
+        let index = self
+            .ranges
+            .partition_point(|r| sort_key(r) <= sort_key(&range));
+        self.ranges.insert(index, range);
+        self.priorities.insert(index, priority);
+        return Ok(());
+    }
+
+    /// Builds the set of `ranges`, each with the location it was configured
+    /// at and its priority. Ranges which failed to build or can't be added
+    /// are recorded in `errors` and left out, so the rest is still checked
+    /// against each other instead of stopping at the first problem.
+    pub fn collect<I, E>(ranges: I, errors: &mut ValidationErrors) -> RangeSet
+    where
+        I: IntoIterator<Item = (String, Result<Range, E>, i32)>,
+        E: fmt::Display,
+    {
+        let mut range_set = RangeSet::new();
+        for (location, range, priority) in ranges {
+            if let Some(range) = errors.check(&location, range) {
+                errors.check(&location, range_set.add_with_priority(range, priority));
             }
         }
 
-        self.ranges.push(range);
-        return Ok(());
+        range_set
+    }
+
+    /// Priority of the range at `index`, see `add_with_priority`.
+    pub fn priority(&self, index: usize) -> Option<i32> {
+        self.priorities.get(index).copied()
+    }
+
+    /// Splits the set into the ranges of equal priority, highest priority
+    /// first. A set without priorities is a single tier.
+    pub fn tiers(&self) -> Vec<RangeSet> {
+        let mut priorities = self.priorities.clone();
+        priorities.sort_unstable_by(|a, b| b.cmp(a));
+        priorities.dedup();
+        if priorities.len() < 2 {
+            return vec![self.clone()];
+        }
+
+        priorities
+            .into_iter()
+            .map(|priority| {
+                let (ranges, priorities) = self
+                    .ranges
+                    .iter()
+                    .zip(&self.priorities)
+                    .filter(|(_, range_priority)| **range_priority == priority)
+                    .map(|(range, priority)| (*range, *priority))
+                    .unzip();
+                RangeSet {
+                    ranges: ranges,
+                    priorities: priorities,
+                }
+            })
+            .collect()
     }
 
     pub fn contains(&self, ip: IpAddr) -> bool {
@@ -74,6 +156,105 @@ impl RangeSet {
     pub fn iter(&self) -> impl Iterator<Item = &Range> {
         self.ranges.iter()
     }
+
+    /// Identifies the range set by its ranges, e.g. to key what the store
+    /// remembers about it. Unlike the position of the set in the
+    /// configuration it stays the same when range sets, or the ranges
+    /// within the set, are reordered.
+    pub fn id(&self) -> String {
+        // priorities don't change which IPs the set holds
+        let mut canonical = RangeSet {
+            ranges: self.ranges.clone(),
+            priorities: vec![0; self.ranges.len()],
+        };
+        canonical.canonicalize();
+
+        let ranges: Vec<String> = canonical
+            .ranges
+            .iter()
+            .map(|range| {
+                format!(
+                    "{} {}-{} {:?}",
+                    range.subnet, range.start, range.end, range.gateway
+                )
+            })
+            .collect();
+
+        format!("{:016x}", fnv1a_128(ranges.join(",").as_bytes()) as u64)
+    }
+
+    /// Merges ranges of the same subnet and priority where one starts right
+    /// after the other ends. Together with the order `add` keeps, the set looks the
+    /// same no matter in which order or how split up the ranges were
+    /// configured.
+    pub fn canonicalize(&mut self) {
+        let mut sorted: Vec<(Range, i32)> = self
+            .ranges
+            .drain(..)
+            .zip(self.priorities.drain(..))
+            .collect();
+        sorted.sort_by_key(|(range, _)| sort_key(range));
+
+        for (range, priority) in sorted {
+            match self.ranges.last_mut() {
+                Some(last)
+                    if last.subnet == range.subnet
+                        && to_u128(last.end).checked_add(1) == Some(to_u128(range.start))
+                        && self.priorities.last() == Some(&priority) =>
+                {
+                    last.end = range.end;
+                }
+                _ => {
+                    self.ranges.push(range);
+                    self.priorities.push(priority);
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for RangeSet {
+    type Err = ParseRangeError;
+
+    /// Parses ranges separated by `;` in the forms of `Range::from_str`,
+    /// e.g. `10.1.0.0/24,10.1.0.10,10.1.0.50; 10.1.1.10-10.1.1.50`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let error = |offset: usize, reason: String| ParseRangeError {
+            input: text.to_owned(),
+            column: offset + 1,
+            reason: reason,
+        };
+
+        let mut range_set = RangeSet::new();
+        for (offset, range) in split_trimmed(text, ';', 0) {
+            let range =
+                Range::parse(range, offset).map_err(|(offset, reason)| error(offset, reason))?;
+            range_set
+                .add(range)
+                .map_err(|err| error(offset, err.to_string()))?;
+        }
+
+        Ok(range_set)
+    }
+}
+
+/// Ranges are ordered by family, then subnet, then first IP. `IpAddr` orders
+/// IPv4 before IPv6 already.
+fn sort_key(range: &Range) -> (IpAddr, IpAddr) {
+    (range.subnet.network(), range.start)
+}
+
+impl fmt::Display for RangeSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        for (index, range) in self.ranges.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}", range.subnet, range)?;
+        }
+        write!(f, "]")
+    }
 }
 
 #[cfg(test)]
@@ -99,7 +280,7 @@ mod tests {
             "10.1.0.0/16".parse().unwrap(),
             Some(IpAddr::from_str("10.1.0.6").unwrap()),
             Some(IpAddr::from_str("10.1.0.11").unwrap()),
-            Some(IpAddr::from_str("10.1.0.7").unwrap()),
+            Some(IpAddr::from_str("10.1.0.4").unwrap()),
         )
         .unwrap();
         assert!(ranges.add(r2).is_ok());
@@ -114,6 +295,18 @@ mod tests {
 
         assert_eq!(ranges.add(r3), Err(RangeSetError::Overlap(r2, r3)));
 
+        let r5 = Range::new(
+            "10.1.0.0/16".parse().unwrap(),
+            Some(IpAddr::from_str("10.1.0.20").unwrap()),
+            Some(IpAddr::from_str("10.1.0.30").unwrap()),
+            Some(IpAddr::from_str("10.1.0.20").unwrap()),
+        )
+        .unwrap();
+        assert_eq!(
+            ranges.add(r5),
+            Err(RangeSetError::ConflictingGateways(r1, r5))
+        );
+
         let r4 = Range::new(
             "2001:db8:abcd:0012::0/64".parse().unwrap(),
             None,
@@ -124,6 +317,119 @@ mod tests {
         assert_eq!(ranges.add(r4), Err(RangeSetError::DifferentAddressType));
     }
 
+    #[test]
+    fn sorted() {
+        let range_set = |subnets: &[&str]| {
+            let mut ranges = RangeSet::new();
+            for subnet in subnets {
+                ranges
+                    .add(Range::new(subnet.parse().unwrap(), None, None, None).unwrap())
+                    .unwrap();
+            }
+            ranges
+        };
+
+        let ranges = range_set(&["10.3.0.0/24", "10.1.0.0/24", "10.2.0.0/16"]);
+        let subnets: Vec<String> = ranges.iter().map(|r| r.subnet.to_string()).collect();
+        assert_eq!(subnets, vec!["10.1.0.0/24", "10.2.0.0/16", "10.3.0.0/24"]);
+        assert_eq!(
+            ranges,
+            range_set(&["10.2.0.0/16", "10.3.0.0/24", "10.1.0.0/24"])
+        );
+    }
+
+    #[test]
+    fn canonicalize() {
+        let range = |subnet: &str, start: &str, end: &str| {
+            Range::new(
+                subnet.parse().unwrap(),
+                Some(start.parse().unwrap()),
+                Some(end.parse().unwrap()),
+                Some("10.1.0.1".parse().unwrap()),
+            )
+            .unwrap()
+        };
+
+        let mut ranges = RangeSet::new();
+        for (subnet, start, end) in &[
+            ("10.1.0.0/24", "10.1.0.20", "10.1.0.29"),
+            ("10.1.0.0/24", "10.1.0.10", "10.1.0.19"),
+            ("10.1.0.0/24", "10.1.0.2", "10.1.0.5"),
+            ("10.1.0.0/23", "10.1.1.0", "10.1.1.9"),
+        ] {
+            ranges.add(range(subnet, start, end)).unwrap();
+        }
+
+        ranges.canonicalize();
+
+        let canonical: Vec<String> = ranges.iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            canonical,
+            vec![
+                "(10.1.0.2, 10.1.0.5)",
+                "(10.1.0.10, 10.1.0.29)",
+                "(10.1.1.0, 10.1.1.9)"
+            ]
+        );
+    }
+
+    #[test]
+    fn tiers() {
+        let range = |start: &str, end: &str| {
+            Range::new(
+                "10.1.0.0/24".parse().unwrap(),
+                Some(start.parse().unwrap()),
+                Some(end.parse().unwrap()),
+                None,
+            )
+            .unwrap()
+        };
+
+        let mut ranges = RangeSet::new();
+        ranges.add(range("10.1.0.10", "10.1.0.19")).unwrap();
+        ranges
+            .add_with_priority(range("10.1.0.20", "10.1.0.29"), -1)
+            .unwrap();
+        ranges
+            .add_with_priority(range("10.1.0.30", "10.1.0.39"), 5)
+            .unwrap();
+        assert_eq!(ranges.priority(1), Some(-1));
+        assert_eq!(ranges.tiers().len(), 3);
+        assert_eq!(
+            ranges.tiers()[0].get(0).unwrap().start.to_string(),
+            "10.1.0.30"
+        );
+
+        // priorities keep adjacent ranges apart, but don't change the id
+        let id = ranges.id();
+        ranges.canonicalize();
+        assert_eq!(ranges.len(), 3);
+        let mut merged = RangeSet::new();
+        merged.add(range("10.1.0.10", "10.1.0.39")).unwrap();
+        assert_eq!(id, merged.id());
+        assert_eq!(merged.tiers(), vec![merged.clone()]);
+    }
+
+    #[test]
+    fn id() {
+        let range_set = |subnets: &[&str]| {
+            let mut ranges = RangeSet::new();
+            for subnet in subnets {
+                ranges
+                    .add(Range::new(subnet.parse().unwrap(), None, None, None).unwrap())
+                    .unwrap();
+            }
+            ranges
+        };
+
+        let id = range_set(&["10.1.0.0/24", "10.2.0.0/24"]).id();
+        assert_eq!(id.len(), 16);
+        assert_eq!(range_set(&["10.1.0.0/24", "10.2.0.0/24"]).id(), id);
+        assert_ne!(range_set(&["10.1.0.0/24", "10.3.0.0/24"]).id(), id);
+        assert_ne!(range_set(&["10.1.0.0/24"]).id(), id);
+        assert_eq!(range_set(&["10.2.0.0/24", "10.1.0.0/24"]).id(), id);
+    }
+
     #[test]
     fn get_range_for_ip() {
         let mut ranges = RangeSet::new();
@@ -141,7 +447,7 @@ mod tests {
             "10.1.0.0/16".parse().unwrap(),
             Some(IpAddr::from_str("10.1.0.6").unwrap()),
             Some(IpAddr::from_str("10.1.0.11").unwrap()),
-            Some(IpAddr::from_str("10.1.0.7").unwrap()),
+            Some(IpAddr::from_str("10.1.0.4").unwrap()),
         )
         .unwrap();
         ranges.add(r2).unwrap();
@@ -155,7 +461,10 @@ mod tests {
         let ip = "10.1.0.12".parse().unwrap();
         assert_eq!(
             ranges.get_range_for_ip(ip),
-            Err(RangeSetError::NoRangeForIP(ip))
+            Err(RangeSetError::NoRangeForIP(
+                ip,
+                "[10.1.0.0/16 (10.1.0.1, 10.1.0.5), 10.1.0.0/16 (10.1.0.6, 10.1.0.11)]".to_owned()
+            ))
         );
     }
 
@@ -176,7 +485,7 @@ mod tests {
             "10.1.0.0/16".parse().unwrap(),
             Some(IpAddr::from_str("10.1.0.6").unwrap()),
             Some(IpAddr::from_str("10.1.0.11").unwrap()),
-            Some(IpAddr::from_str("10.1.0.7").unwrap()),
+            Some(IpAddr::from_str("10.1.0.4").unwrap()),
         )
         .unwrap();
         ranges.add(r2).unwrap();
@@ -185,4 +494,55 @@ mod tests {
         assert!(ranges.contains("10.1.0.10".parse().unwrap()));
         assert!(!ranges.contains("10.1.0.12".parse().unwrap()));
     }
+
+    #[test]
+    fn parse() {
+        let ranges: RangeSet = "10.1.1.10-10.1.1.50; 10.1.0.0/24,10.1.0.10,10.1.0.50"
+            .parse()
+            .unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(
+            ranges.get(0).unwrap().subnet,
+            "10.1.0.0/24".parse().unwrap()
+        );
+        assert!(ranges.contains("10.1.1.50".parse().unwrap()));
+
+        let err = "10.1.0.0/24; 10.1.0.10-10.1.0.50"
+            .parse::<RangeSet>()
+            .unwrap_err();
+        assert_eq!(err.column, 14);
+        assert!(err.reason.contains("overlaps"), "{}", err);
+        let err = "10.1.0.0/24; 10.1.0.0/24,x"
+            .parse::<RangeSet>()
+            .unwrap_err();
+        assert_eq!(err.column, 26);
+    }
+
+    #[test]
+    fn collect() {
+        let range = |subnet: &str| Range::new(subnet.parse().unwrap(), None, None, None);
+        let mut errors = ValidationErrors::new();
+
+        let ranges = RangeSet::collect(
+            vec![
+                ("r[0]".to_owned(), range("10.1.0.0/24"), 0),
+                ("r[1]".to_owned(), range("10.1.0.0/25"), 0),
+                ("r[2]".to_owned(), range("10.1.2.0/31"), 0),
+                ("r[3]".to_owned(), range("2001:db8::/64"), 0),
+                ("r[4]".to_owned(), range("10.1.1.0/24"), 5),
+            ],
+            &mut errors,
+        );
+
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges.priority(1), Some(5));
+        assert_eq!(
+            errors.lines(),
+            vec![
+                "r[1]: subnet (10.1.0.1, 10.1.0.254) overlaps with subnet (10.1.0.1, 10.1.0.126)",
+                "r[2]: Network 10.1.2.0/31 too small to allocate from",
+                "r[3]: range has different address type",
+            ]
+        );
+    }
 }
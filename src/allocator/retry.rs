@@ -0,0 +1,102 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::store::StoreError;
+
+/// How often and how patiently store operations are retried when they fail
+/// with a transient error, see `StoreError::is_retryable`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of tries, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled after every further failure.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Fails on the first error.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Duration::from_millis(0),
+        }
+    }
+
+    /// Runs `op` until it succeeds, fails with a permanent error or the
+    /// attempts are used up.
+    pub fn run<T, F>(&self, mut op: F) -> Result<T, StoreError>
+    where
+        F: FnMut() -> Result<T, StoreError>,
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+
+        loop {
+            match op() {
+                Err(err) if err.is_retryable() && attempt < self.attempts => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let mut calls = 0;
+        let result = policy().run(|| {
+            calls += 1;
+            if calls < 3 {
+                return Err(StoreError::IOError(Error::from(ErrorKind::WouldBlock)));
+            }
+            Ok(calls)
+        });
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn gives_up() {
+        let mut calls = 0;
+        let result: Result<(), StoreError> = policy().run(|| {
+            calls += 1;
+            Err(StoreError::IOError(Error::from(ErrorKind::WouldBlock)))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), StoreError> = policy().run(|| {
+            calls += 1;
+            Err(StoreError::IOError(Error::from(
+                ErrorKind::PermissionDenied,
+            )))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,142 @@
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use super::AllocationObserver;
+use crate::store::Store;
+
+/// Releases the IPs a sequence of allocations reserved for `id` and
+/// `ifname` when dropped before `commit`, e.g. the IPv4 address of a
+/// dual-stack ADD whose IPv6 allocation failed. IPs they held before the
+/// guard was created, e.g. for an ADD the runtime retries, are kept.
+pub struct Rollback<'a> {
+    store: &'a dyn Store,
+    id: &'a str,
+    ifname: &'a str,
+    held_before: Vec<IpAddr>,
+    reserved: Vec<IpAddr>,
+    observers: Vec<Rc<dyn AllocationObserver>>,
+    committed: bool,
+}
+
+impl<'a> Rollback<'a> {
+    pub fn new(store: &'a dyn Store, id: &'a str, ifname: &'a str) -> Rollback<'a> {
+        Rollback {
+            store: store,
+            id: id,
+            ifname: ifname,
+            held_before: store.get_by_id(id, ifname),
+            reserved: Vec::new(),
+            observers: Vec::new(),
+            committed: false,
+        }
+    }
+
+    /// Tells `observers` about the IPs released on rollback.
+    pub fn with_observers(mut self, observers: Vec<Rc<dyn AllocationObserver>>) -> Rollback<'a> {
+        self.observers = observers;
+        self
+    }
+
+    /// Records that the sequence reserved `ip`.
+    pub fn push(&mut self, ip: IpAddr) {
+        self.reserved.push(ip);
+    }
+
+    /// Keeps every recorded IP.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+
+    /// Releases the recorded IPs under the network lock. Nothing is released
+    /// if the lock can't be taken, fsck cleans up after crashes anyway.
+    fn rollback(&mut self) {
+        let held_before = &self.held_before;
+        let ips: Vec<IpAddr> = self
+            .reserved
+            .drain(..)
+            .filter(|ip| !held_before.contains(ip))
+            .collect();
+        if ips.is_empty() || self.store.lock().is_err() {
+            return;
+        }
+
+        for ip in ips {
+            if self.store.release_checked(ip, self.id, self.ifname).is_ok() {
+                for observer in &self.observers {
+                    observer.released(self.id, self.ifname, ip);
+                }
+            }
+        }
+        let _ = self.store.unlock();
+    }
+}
+
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.rollback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::range::Range;
+    use crate::allocator::rangeset::RangeSet;
+    use crate::allocator::{AllocateError, Allocator};
+    use crate::store::faultstore::{FaultStore, Op};
+    use crate::store::filestore::FileStore;
+    use crate::store::StoreError;
+    use std::fs::remove_dir_all;
+    use std::sync::Arc;
+
+    const DATA_DIR: &str = "/tmp/cni-rollback";
+
+    fn allocator(store: Arc<dyn Store>, subnet: &str) -> Allocator {
+        let mut range_set = RangeSet::new();
+        range_set
+            .add(Range::new(subnet.parse().unwrap(), None, None, None).unwrap())
+            .unwrap();
+
+        Allocator::new(range_set, store)
+    }
+
+    #[test]
+    fn store_failure_midway() {
+        let _ = remove_dir_all(DATA_DIR);
+
+        let store = Arc::new(FileStore::new("n", DATA_DIR).unwrap());
+        let ipv4 = allocator(store.clone(), "10.1.2.0/24");
+        // the same data dir, failing as soon as IPv6 addresses are reserved
+        let faulty = FaultStore::new(FileStore::new("n", DATA_DIR).unwrap()).fail_on(Op::Commit);
+        let ipv6 = allocator(Arc::new(faulty), "2001:db8::/64");
+
+        let held = ipv4.get("c1", "eth0", None).unwrap().address().ip();
+
+        {
+            let mut rollback = Rollback::new(&*store, "c1", "eth1");
+            rollback.push(ipv4.get("c1", "eth1", None).unwrap().address().ip());
+            assert!(matches!(
+                ipv6.get("c1", "eth1", None),
+                Err(AllocateError::StoreError(StoreError::IOError(_)))
+            ));
+        }
+        assert!(store.get_by_id("c1", "eth1").is_empty());
+
+        // a retried ADD keeps what was held before
+        {
+            let mut rollback = Rollback::new(&*store, "c1", "eth0");
+            rollback.push(ipv4.get("c1", "eth0", None).unwrap().address().ip());
+            assert!(ipv6.get("c1", "eth0", None).is_err());
+        }
+        assert_eq!(store.get_by_id("c1", "eth0"), vec![held]);
+
+        let mut rollback = Rollback::new(&*store, "c2", "eth0");
+        rollback.push(ipv4.get("c2", "eth0", None).unwrap().address().ip());
+        rollback.commit();
+        assert_eq!(store.get_by_id("c2", "eth0").len(), 1);
+
+        let _ = remove_dir_all(DATA_DIR);
+    }
+}
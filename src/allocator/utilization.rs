@@ -0,0 +1,226 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+
+use super::rangeset::RangeSet;
+use crate::core::{from_u128, to_u128};
+
+/// Slices hold 256 IPs, /24 subnets of IPv4 ranges and /120 subnets of
+/// IPv6 ranges.
+const SLICE_BITS: u32 = 8;
+/// Ranges spanning more slices only list the slices holding reservations,
+/// the others are empty anyway.
+const MAX_SLICES: u128 = 4096;
+
+/// A run of free IPs between reserved IPs, the gateway or the ends of a
+/// range.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FreeBlock {
+    pub start: IpAddr,
+    pub end: IpAddr,
+    pub size: u64,
+}
+
+/// How full a slice of the ranges is.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SliceUsage {
+    pub slice: IpNetwork,
+    /// Allocatable IPs of the ranges in the slice.
+    pub capacity: u64,
+    pub used: u64,
+    /// `used` in percent of `capacity`, rounded down.
+    pub percent: u8,
+}
+
+/// How full a range set is and how scattered its free IPs are, to decide
+/// when to extend it. Counts saturate at `u64::MAX` for huge IPv6 ranges.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Utilization {
+    pub capacity: u64,
+    pub used: u64,
+    /// Ordered by IP.
+    pub free_blocks: Vec<FreeBlock>,
+    /// A histogram of the ranges by slice, ordered by IP.
+    pub slices: Vec<SliceUsage>,
+}
+
+impl Utilization {
+    /// Analyzes `range_set` with the IPs of `reserved` taken. Reserved IPs
+    /// outside of the range set are ignored.
+    pub fn of(range_set: &RangeSet, reserved: &[IpAddr]) -> Utilization {
+        let mut utilization = Utilization::default();
+        let (mut capacity, mut used) = (0u128, 0u128);
+        let mut slices = BTreeMap::new();
+
+        for range in range_set.iter() {
+            let ipv4 = range.start.is_ipv4();
+            let (start, end) = (to_u128(range.start), to_u128(range.end));
+            let gateway = range
+                .gateway
+                .map(to_u128)
+                .filter(|gateway| start <= *gateway && *gateway <= end);
+
+            let mut taken: Vec<u128> = reserved
+                .iter()
+                .filter(|ip| range.contains(**ip))
+                .map(|ip| to_u128(*ip))
+                .filter(|ip| Some(*ip) != gateway)
+                .collect();
+            taken.sort_unstable();
+            taken.dedup();
+            capacity = capacity.saturating_add(range.capacity());
+            used += taken.len() as u128;
+
+            let mut occupied = taken.clone();
+            occupied.extend(gateway);
+            occupied.sort_unstable();
+            let mut next = Some(start);
+            for ip in occupied {
+                if let Some(from) = next.filter(|from| *from < ip) {
+                    utilization.free_blocks.push(free_block(from, ip - 1, ipv4));
+                }
+                next = ip.checked_add(1);
+            }
+            if let Some(from) = next.filter(|from| *from <= end) {
+                utilization.free_blocks.push(free_block(from, end, ipv4));
+            }
+
+            let bases: Vec<u128> = if (end >> SLICE_BITS) - (start >> SLICE_BITS) < MAX_SLICES {
+                ((start >> SLICE_BITS)..=(end >> SLICE_BITS)).collect()
+            } else {
+                let mut bases: Vec<u128> = taken.iter().map(|ip| ip >> SLICE_BITS).collect();
+                bases.dedup();
+                bases
+            };
+            for base in bases {
+                let low = cmp::max(start, base << SLICE_BITS);
+                let high = cmp::min(end, (base << SLICE_BITS) | ((1 << SLICE_BITS) - 1));
+                let in_slice = |ip: &u128| low <= *ip && *ip <= high;
+
+                let (slice_capacity, slice_used) = slices.entry((ipv4, base)).or_insert((0, 0));
+                *slice_capacity += high - low + 1 - gateway.filter(in_slice).map_or(0, |_| 1);
+                *slice_used += taken.iter().filter(|ip| in_slice(ip)).count() as u128;
+            }
+        }
+
+        utilization.capacity = saturate(capacity);
+        utilization.used = saturate(used);
+        utilization.slices = slices
+            .into_iter()
+            .map(|((ipv4, base), (capacity, used))| {
+                let prefix = if ipv4 { 32 } else { 128 } - SLICE_BITS as u8;
+                SliceUsage {
+                    // UNWRAP: the prefix is shorter than the address
+                    slice: IpNetwork::new(from_u128(base << SLICE_BITS, ipv4), prefix).unwrap(),
+                    capacity: saturate(capacity),
+                    used: saturate(used),
+                    percent: if capacity == 0 {
+                        0
+                    } else {
+                        (used * 100 / capacity) as u8
+                    },
+                }
+            })
+            .collect();
+
+        utilization
+    }
+
+    /// The first of the largest free blocks, none if every IP is taken.
+    pub fn largest_free_block(&self) -> Option<&FreeBlock> {
+        self.free_blocks.iter().rev().max_by_key(|block| block.size)
+    }
+}
+
+fn free_block(start: u128, end: u128, ipv4: bool) -> FreeBlock {
+    FreeBlock {
+        start: from_u128(start, ipv4),
+        end: from_u128(end, ipv4),
+        size: saturate((end - start).saturating_add(1)),
+    }
+}
+
+fn saturate(count: u128) -> u64 {
+    u64::try_from(count).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn blocks_and_slices() {
+        let range_set: RangeSet = "10.1.2.0/23".parse().unwrap();
+        let reserved = ips(&[
+            "10.1.2.2",
+            "10.1.2.3",
+            "10.1.2.10",
+            "10.1.3.200",
+            "10.2.0.1",
+        ]);
+
+        let utilization = Utilization::of(&range_set, &reserved);
+        assert_eq!((utilization.capacity, utilization.used), (509, 4));
+        let blocks: Vec<(String, String, u64)> = utilization
+            .free_blocks
+            .iter()
+            .map(|block| (block.start.to_string(), block.end.to_string(), block.size))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                ("10.1.2.4".to_owned(), "10.1.2.9".to_owned(), 6),
+                ("10.1.2.11".to_owned(), "10.1.3.199".to_owned(), 445),
+                ("10.1.3.201".to_owned(), "10.1.3.254".to_owned(), 54),
+            ]
+        );
+        assert_eq!(utilization.largest_free_block().unwrap().size, 445);
+
+        assert_eq!(
+            utilization.slices,
+            vec![
+                SliceUsage {
+                    slice: "10.1.2.0/24".parse().unwrap(),
+                    capacity: 254,
+                    used: 3,
+                    percent: 1,
+                },
+                SliceUsage {
+                    slice: "10.1.3.0/24".parse().unwrap(),
+                    capacity: 255,
+                    used: 1,
+                    percent: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn huge_ranges() {
+        let range_set: RangeSet = "fd00::/48".parse().unwrap();
+        let utilization = Utilization::of(&range_set, &ips(&["fd00::2", "fd00::1:5"]));
+
+        assert_eq!(utilization.capacity, u64::MAX);
+        assert_eq!(utilization.used, 2);
+        assert_eq!(utilization.free_blocks.len(), 2);
+        let slices: Vec<String> = utilization
+            .slices
+            .iter()
+            .map(|slice| slice.slice.to_string())
+            .collect();
+        assert_eq!(slices, vec!["fd00::/120", "fd00::1:0/120"]);
+
+        let full: RangeSet = "10.1.2.0/30".parse().unwrap();
+        let utilization = Utilization::of(&full, &ips(&["10.1.2.2"]));
+        assert_eq!(utilization.largest_free_block(), None);
+        assert_eq!(utilization.slices[0].percent, 100);
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Container id the IPs of a warm pool are reserved for in the store, with
+/// the range set id as interface name, see `Allocator::with_warm_pool`.
+pub const WARM_POOL_ID: &str = "host-local-warm-pool";
+
+/// IPs reserved ahead of the ADDs which will claim them, with the time
+/// they were reserved at. Unclaimed IPs expire after `timeout`.
+#[derive(Debug)]
+pub(crate) struct WarmPool {
+    size: usize,
+    timeout: Duration,
+    /// Oldest first.
+    ips: VecDeque<(IpAddr, Instant)>,
+}
+
+impl WarmPool {
+    pub(crate) fn new(size: usize, timeout: Duration) -> WarmPool {
+        WarmPool {
+            size: size,
+            timeout: timeout,
+            ips: VecDeque::with_capacity(size),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ips.len()
+    }
+
+    /// Number of IPs to reserve to fill the pool up.
+    pub(crate) fn missing(&self) -> usize {
+        self.size.saturating_sub(self.ips.len())
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        self.ips.iter().any(|(pooled, _)| *pooled == ip)
+    }
+
+    pub(crate) fn push(&mut self, ip: IpAddr, now: Instant) {
+        self.ips.push_back((ip, now));
+    }
+
+    /// Takes the oldest IP which hasn't expired at `now`.
+    pub(crate) fn pop(&mut self, now: Instant) -> Option<IpAddr> {
+        let timeout = self.timeout;
+        let position = self
+            .ips
+            .iter()
+            .position(|(_, since)| now.saturating_duration_since(*since) < timeout)?;
+
+        self.ips.remove(position).map(|(ip, _)| ip)
+    }
+
+    /// Takes every IP which has expired at `now`.
+    pub(crate) fn pop_expired(&mut self, now: Instant) -> Vec<IpAddr> {
+        let timeout = self.timeout;
+        let (expired, fresh): (VecDeque<_>, VecDeque<_>) = self
+            .ips
+            .drain(..)
+            .partition(|(_, since)| now.saturating_duration_since(*since) >= timeout);
+        self.ips = fresh;
+
+        expired.into_iter().map(|(ip, _)| ip).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(10);
+        let ips: Vec<IpAddr> = vec!["10.1.2.2".parse().unwrap(), "10.1.2.3".parse().unwrap()];
+
+        let mut pool = WarmPool::new(3, Duration::from_secs(30));
+        pool.push(ips[0], start);
+        pool.push(ips[1], later);
+        assert_eq!(pool.missing(), 1);
+        assert!(pool.contains(ips[1]));
+
+        let expired_first = start + Duration::from_secs(35);
+        assert_eq!(pool.pop(expired_first), Some(ips[1]));
+        assert_eq!(pool.pop(expired_first), None);
+        assert_eq!(pool.pop_expired(expired_first), vec![ips[0]]);
+        assert_eq!(pool.len(), 0);
+    }
+}
@@ -0,0 +1,1822 @@
+//! Operator subcommands of the `host-local` binary, everything besides the
+//! CNI commands which are selected through `CNI_COMMAND`.
+//!
+//! The arguments are parsed by `Cli`, which generates shell completions as
+//! well. Subcommands printing a report take the arguments of `OutputArgs`,
+//! to print it as a table, JSON or YAML.
+
+mod output;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::allocator::rangeset::RangeSet;
+use super::allocator::{
+    fnv1a_128, AllocationObserver, Allocator, FreeBlock, Orphan, SliceUsage, Utilization,
+};
+use super::cni;
+use super::config::{ConfigError, NetConf};
+use super::error::{report, HostLocalError};
+use super::store::filestore::{FileStore, FileStoreOptions, Problem};
+use super::store::{
+    ConflictPolicy, Manifest, Plan, Reservation, Restore, Selector, Snapshot, Store, StoreStats,
+};
+use super::validation::ValidationErrors;
+
+pub use output::{Format, Output, OutputArgs};
+
+/// How long `health` waits for the network lock by default.
+pub const DEFAULT_HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The arguments of `host-local` when it isn't run as a CNI plugin.
+/// Subcommands read the network configuration on stdin, like the plugin.
+#[derive(Debug, Parser)]
+#[command(name = "host-local", version)]
+pub struct Cli {
+    /// Keeps the data dir below the user's XDG data dir unless the
+    /// configuration sets one, like the `--rootless` of the plugin.
+    #[arg(long, global = true)]
+    pub rootless: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Reports every problem of the network configuration.
+    Validate(ValidateArgs),
+    /// Prints the number of allocatable IPs of every range.
+    Capacity,
+    /// Prints how full every range set is.
+    Status(StatusArgs),
+    /// Prints the reservations of the network.
+    List(ListArgs),
+    /// Prints who holds an IP.
+    Whois(WhoisArgs),
+    /// Checks the data dir for damage left by crashes.
+    Fsck(FsckArgs),
+    /// Finds the reservations a changed configuration no longer covers.
+    Reconcile(ReconcileArgs),
+    /// Checks that the store is usable, for liveness probes.
+    Health(HealthArgs),
+    /// Releases the reservations of containers which are gone.
+    Gc(GcArgs),
+    /// Prints a snapshot of the store.
+    Export(ExportArgs),
+    /// Restores a snapshot written by export.
+    Import(ImportArgs),
+    /// Compares the store with a manifest of static assignments.
+    Diff(DiffArgs),
+    /// Prints the completion script of a shell.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+impl Cli {
+    /// Runs the subcommand, returns the process exit code.
+    pub fn run<R: Read, W: Write>(&self, stdin: R, stdout: W) -> i32 {
+        let options = self.store_options();
+
+        match &self.command {
+            Command::Validate(args) => validate(args, stdin, stdout),
+            Command::Capacity => capacity(stdin, stdout),
+            Command::Status(args) => status(args, options, stdin, stdout),
+            Command::List(args) => list(args, options, stdin, stdout),
+            Command::Whois(args) => whois(args, options, stdin, stdout),
+            Command::Fsck(args) => fsck(args, options, stdin, stdout),
+            Command::Reconcile(args) => reconcile(args, options, stdin, stdout),
+            Command::Health(args) => health(args, options, stdin, stdout),
+            Command::Gc(args) => gc(args, options, stdin, stdout),
+            Command::Export(args) => export(args, options, stdin, stdout),
+            Command::Import(args) => import(args, options, stdin, stdout),
+            Command::Diff(args) => diff(args, options, stdin, stdout),
+            Command::Completions { shell } => completions(*shell, stdout),
+        }
+    }
+
+    /// The options of the stores subcommands open, before those of the
+    /// network configuration, see `cni::store_options`.
+    pub fn store_options(&self) -> FileStoreOptions {
+        FileStoreOptions {
+            rootless: if self.rootless { Some(true) } else { None },
+            ..FileStoreOptions::default()
+        }
+    }
+}
+
+/// Prints the completion script of `shell` for `host-local`.
+pub fn completions<W: Write>(shell: Shell, mut stdout: W) -> i32 {
+    clap_complete::generate(shell, &mut Cli::command(), "host-local", &mut stdout);
+    0
+}
+
+#[derive(Args, Debug)]
+pub struct ValidateArgs {
+    /// Prints the problems as a report with the location and message of
+    /// each instead of one per line.
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+}
+
+/// The report of `validate --format`.
+#[derive(Debug, Serialize)]
+struct ValidationReport<'a> {
+    valid: bool,
+    errors: &'a ValidationErrors,
+}
+
+/// Parses the network configuration on `stdin` and reports every problem
+/// found in it, one per line or as a `ValidationReport`.
+///
+/// Returns the process exit code, non-zero if the configuration is invalid.
+pub fn validate<R: Read, W: Write>(args: &ValidateArgs, stdin: R, mut stdout: W) -> i32 {
+    let errors = match NetConf::load(stdin) {
+        Ok(conf) => conf.ipam.validation_errors(),
+        Err(err) => err.validation_errors(),
+    };
+    let code = if errors.is_empty() { 0 } else { 1 };
+
+    if let Some(format) = args.format {
+        let output = Output {
+            format: format,
+            ..Output::default()
+        };
+        let report = ValidationReport {
+            valid: errors.is_empty(),
+            errors: &errors,
+        };
+        output.print(stdout, &report);
+        return code;
+    }
+
+    if errors.is_empty() {
+        let _ = writeln!(stdout, "configuration is valid");
+    }
+    for err in errors.iter() {
+        let _ = writeln!(stdout, "{}", err);
+    }
+    code
+}
+
+/// Prints the number of allocatable IPs of every range of the network
+/// configuration on `stdin`, and the total of each range set.
+///
+/// Returns the process exit code, non-zero if the configuration is invalid.
+pub fn capacity<R: Read, W: Write>(stdin: R, mut stdout: W) -> i32 {
+    let range_sets = match NetConf::load(stdin).and_then(|conf| conf.ipam.range_sets()) {
+        Ok(range_sets) => range_sets,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    for (index, range_set) in range_sets.iter().enumerate() {
+        let _ = writeln!(stdout, "range set {}:", index);
+
+        let mut total = 0u128;
+        for range in range_set.iter() {
+            let capacity = range.capacity();
+            total = total.saturating_add(capacity);
+
+            let gateway = match range.gateway {
+                Some(gateway) => format!("gateway {}", gateway),
+                None => "no gateway".to_owned(),
+            };
+            let _ = writeln!(
+                stdout,
+                "  {} {}-{} {}: {}",
+                range.subnet, range.start, range.end, gateway, capacity
+            );
+        }
+
+        let _ = writeln!(stdout, "  total: {}", total);
+    }
+    0
+}
+
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+    /// Adds the free blocks and a histogram of the ranges by /24 slice.
+    #[arg(long)]
+    pub detail: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Serialize)]
+struct StatusReport<'a> {
+    network: &'a str,
+    range_sets: Vec<RangeSetStatus>,
+    /// Counters of the store over every command so far.
+    store: StoreStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    free_blocks: Option<Vec<OfRangeSet<FreeBlock>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slices: Option<Vec<OfRangeSet<SliceUsage>>>,
+}
+
+#[derive(Serialize)]
+struct RangeSetStatus {
+    range_set: usize,
+    capacity: u64,
+    used: u64,
+    free_blocks: usize,
+    largest_free_block: u64,
+}
+
+/// An item of the report of the range set at index `range_set`.
+#[derive(Serialize)]
+struct OfRangeSet<T> {
+    range_set: usize,
+    #[serde(flatten)]
+    item: T,
+}
+
+/// Prints how full every range set of the network configured on `stdin`
+/// is, see `Utilization`, and the counters of the store the commands saved
+/// in the data dir, see `StoreStats`. With `--detail` the report lists every free block
+/// and the utilization of every slice of the ranges as well, to decide when
+/// to extend a pool.
+///
+/// Returns the process exit code, non-zero if the store can't be read.
+pub fn status<R: Read, W: Write>(
+    args: &StatusArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let (utilizations, stats) = match range_set_utilizations(&conf, options) {
+        Ok(status) => status,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", err.report());
+            return 1;
+        }
+    };
+
+    let mut report = StatusReport {
+        network: &conf.name,
+        range_sets: Vec::with_capacity(utilizations.len()),
+        store: stats,
+        free_blocks: None,
+        slices: None,
+    };
+    let (mut free_blocks, mut slices) = (Vec::new(), Vec::new());
+    for (index, utilization) in utilizations.into_iter().enumerate() {
+        report.range_sets.push(RangeSetStatus {
+            range_set: index,
+            capacity: utilization.capacity,
+            used: utilization.used,
+            free_blocks: utilization.free_blocks.len(),
+            largest_free_block: utilization
+                .largest_free_block()
+                .map_or(0, |block| block.size),
+        });
+
+        free_blocks.extend(utilization.free_blocks.into_iter().map(|item| OfRangeSet {
+            range_set: index,
+            item: item,
+        }));
+        slices.extend(utilization.slices.into_iter().map(|item| OfRangeSet {
+            range_set: index,
+            item: item,
+        }));
+    }
+    if args.detail {
+        report.free_blocks = Some(free_blocks);
+        report.slices = Some(slices);
+    }
+
+    output.print(&mut stdout, &report);
+    0
+}
+
+fn range_set_utilizations(
+    conf: &NetConf,
+    options: FileStoreOptions,
+) -> Result<(Vec<Utilization>, StoreStats), HostLocalError> {
+    let range_sets = conf.ipam.range_sets()?;
+    let options = FileStoreOptions {
+        read_only: true,
+        ..cni::store_options(conf, options)
+    };
+    let store = FileStore::with_options(&conf.name, &conf.ipam.data_dir, options)?;
+    let store = cni::encrypt(conf, store)?;
+
+    let reserved = store.list()?;
+    let utilizations = range_sets
+        .iter()
+        .map(|range_set| Utilization::of(range_set, &reserved))
+        .collect();
+    Ok((utilizations, store.saved_stats()?))
+}
+
+#[derive(Args, Debug)]
+pub struct FsckArgs {
+    /// Checks another network of the same data dir.
+    #[arg(long, value_name = "NAME")]
+    pub network: Option<String>,
+    /// Repairs what is found.
+    #[arg(long)]
+    pub fix: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Serialize)]
+struct FsckReport<'a> {
+    network: &'a str,
+    problems: Vec<Problem>,
+}
+
+/// Checks the data dir of the network configured on `stdin` for damage
+/// left by crashes, see `FileStore::fsck`, and prints a JSON report.
+///
+/// Without `--fix` the store is opened read-only and not locked, so
+/// temporary files of transactions still in flight show up as orphaned.
+///
+/// Returns the process exit code, non-zero if problems remain.
+pub fn fsck<R: Read, W: Write>(
+    args: &FsckArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+    let network = args.network.clone();
+    let fix = args.fix;
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+    let range_sets = match conf.ipam.range_sets() {
+        Ok(range_sets) => range_sets,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let network = network.unwrap_or_else(|| conf.name.clone());
+    let options = FileStoreOptions {
+        read_only: !fix,
+        ..cni::store_options(&conf, options)
+    };
+    let problems = match FileStore::with_options(&network, &conf.ipam.data_dir, options)
+        .and_then(|store| cni::encrypt(&conf, store))
+        .and_then(|store| store.fsck(&range_sets, fix))
+    {
+        Ok(problems) => problems,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let clean = problems.iter().all(|problem| problem.fixed);
+    let report = FsckReport {
+        network: &network,
+        problems: problems,
+    };
+    output.print(&mut stdout, &report);
+
+    if clean {
+        0
+    } else {
+        1
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ReconcileArgs {
+    /// The configuration before the change.
+    #[arg(long, value_name = "PATH")]
+    pub previous: PathBuf,
+    /// Releases the orphans found.
+    #[arg(long)]
+    pub release: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Serialize)]
+struct ReconcileReport<'a> {
+    network: &'a str,
+    released: bool,
+    orphans: Vec<Orphan>,
+}
+
+/// Compares the network configuration on `stdin` with the one it replaces
+/// and prints the reservations its ranges no longer cover as JSON, see
+/// `Allocator::reconcile`.
+///
+/// Range sets are matched by position, those which were removed altogether
+/// orphan all of their reservations.
+///
+/// Returns the process exit code, non-zero if orphans remain.
+pub fn reconcile<R: Read, W: Write>(
+    args: &ReconcileArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+    let previous = &args.previous;
+    let release = args.release;
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let orphans = match find_orphans(&conf, previous, release, options) {
+        Ok(orphans) => orphans,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", err.report());
+            return 1;
+        }
+    };
+
+    let clean = release || orphans.is_empty();
+    let report = ReconcileReport {
+        network: &conf.name,
+        released: release,
+        orphans: orphans,
+    };
+    output.print(&mut stdout, &report);
+
+    if clean {
+        0
+    } else {
+        1
+    }
+}
+
+fn find_orphans(
+    conf: &NetConf,
+    previous: &Path,
+    release: bool,
+    options: FileStoreOptions,
+) -> Result<Vec<Orphan>, HostLocalError> {
+    let previous = NetConf::load(File::open(previous).map_err(ConfigError::IOError)?)?;
+    let range_sets = conf.ipam.range_sets()?;
+
+    let options = FileStoreOptions {
+        read_only: !release,
+        ..cni::store_options(conf, options)
+    };
+    let store = FileStore::with_options(&conf.name, &conf.ipam.data_dir, options)?;
+    let store = Arc::new(cni::encrypt(conf, store)?);
+    let observers = if release {
+        cni::observers(conf)
+    } else {
+        Vec::new()
+    };
+
+    let mut orphans = Vec::new();
+    for (index, old) in previous.ipam.range_sets()?.into_iter().enumerate() {
+        let mut allocator = Allocator::new(old, store.clone());
+        if previous.ipam.upstream_range_ids {
+            allocator = allocator.with_range_id(index.to_string());
+        }
+        for observer in &observers {
+            allocator.subscribe(Box::new(observer.clone()));
+        }
+
+        let new = range_sets.get(index).cloned().unwrap_or_else(RangeSet::new);
+        orphans.extend(allocator.reconcile(&new, release)?);
+    }
+
+    Ok(orphans)
+}
+
+#[derive(Args, Debug)]
+pub struct GcArgs {
+    /// Judges containers by whether the network namespace recorded on ADD
+    /// still exists, the only way so far.
+    #[arg(long, required = true)]
+    pub by_netns: bool,
+    /// Only reports the dead reservations.
+    #[arg(long)]
+    pub dry_run: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Serialize)]
+struct GcReport<'a> {
+    network: &'a str,
+    released: bool,
+    dead: Vec<Reservation>,
+}
+
+/// Finds the reservations of the network configured on `stdin` whose
+/// container is gone and releases them, printing a JSON report.
+///
+/// Reservations without a recorded namespace are left alone.
+///
+/// Returns the process exit code, non-zero if dead reservations remain.
+pub fn gc<R: Read, W: Write>(
+    args: &GcArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+    let dry_run = args.dry_run;
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let dead = match collect_dead_netns(&conf, !dry_run, options) {
+        Ok(dead) => dead,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", err.report());
+            return 1;
+        }
+    };
+
+    let clean = !dry_run || dead.is_empty();
+    let report = GcReport {
+        network: &conf.name,
+        released: !dry_run,
+        dead: dead,
+    };
+    output.print(&mut stdout, &report);
+
+    if clean {
+        0
+    } else {
+        1
+    }
+}
+
+fn collect_dead_netns(
+    conf: &NetConf,
+    release: bool,
+    options: FileStoreOptions,
+) -> Result<Vec<Reservation>, HostLocalError> {
+    let options = FileStoreOptions {
+        read_only: !release,
+        ..cni::store_options(conf, options)
+    };
+    let store = FileStore::with_options(&conf.name, &conf.ipam.data_dir, options)?;
+    let store = cni::encrypt(conf, store)?;
+    let observers = if release {
+        cni::observers(conf)
+    } else {
+        Vec::new()
+    };
+
+    store.lock()?;
+    let result = find_dead_netns(&store, &observers, release);
+    store.unlock()?;
+
+    result
+}
+
+fn find_dead_netns(
+    store: &FileStore,
+    observers: &[Rc<dyn AllocationObserver>],
+    release: bool,
+) -> Result<Vec<Reservation>, HostLocalError> {
+    let mut ips = store.list()?;
+    ips.sort();
+
+    let mut dead = Vec::new();
+    for ip in ips {
+        let owner = store.owner(ip)?;
+        // `exists` follows symlinks, a bind mount or /proc/<pid>/ns/net
+        // path both vanish with the namespace
+        match &owner.netns {
+            Some(netns) if !Path::new(netns).exists() => {}
+            _ => continue,
+        }
+
+        if release {
+            store.release_checked(ip, &owner.id, &owner.ifname)?;
+            for observer in observers {
+                observer.released(&owner.id, &owner.ifname, ip);
+            }
+        }
+
+        dead.push(Reservation {
+            ip: ip,
+            owner: owner,
+        });
+    }
+
+    Ok(dead)
+}
+
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    /// Lists only reservations whose labels match, e.g. `team=web,tier=db`.
+    #[arg(long, value_name = "KEY=VALUE,...")]
+    pub selector: Option<Selector>,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Prints the reservations of the network configured on `stdin` as a JSON
+/// array, with the network namespace and Kubernetes pod they were made for
+/// where known.
+///
+/// Returns the process exit code, non-zero if the store can't be read.
+pub fn list<R: Read, W: Write>(
+    args: &ListArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let reservations = match list_reservations(&conf, args.selector.as_ref(), options) {
+        Ok(reservations) => reservations,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", err.report());
+            return 1;
+        }
+    };
+
+    output.print(&mut stdout, &reservations);
+    0
+}
+
+fn list_reservations(
+    conf: &NetConf,
+    selector: Option<&Selector>,
+    options: FileStoreOptions,
+) -> Result<Vec<Reservation>, HostLocalError> {
+    let options = FileStoreOptions {
+        read_only: true,
+        ..cni::store_options(conf, options)
+    };
+    let store = FileStore::with_options(&conf.name, &conf.ipam.data_dir, options)?;
+    let store = cni::encrypt(conf, store)?;
+
+    let mut ips = store.list()?;
+    ips.sort();
+
+    let mut reservations = Vec::with_capacity(ips.len());
+    for ip in ips {
+        let owner = store.owner(ip)?;
+        if selector.is_none_or(|selector| selector.matches(&owner.labels)) {
+            reservations.push(Reservation {
+                ip: ip,
+                owner: owner,
+            });
+        }
+    }
+
+    Ok(reservations)
+}
+
+#[derive(Args, Debug)]
+pub struct WhoisArgs {
+    /// The IP to look up.
+    pub ip: IpAddr,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Prints the reservation of an IP of the network configured on `stdin`,
+/// with the container, namespace and pod holding it.
+///
+/// Returns the process exit code, non-zero if the IP isn't reserved.
+pub fn whois<R: Read, W: Write>(
+    args: &WhoisArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    match find_reservation(&conf, args.ip, options) {
+        Ok(Some(reservation)) => {
+            output.print(&mut stdout, &reservation);
+            0
+        }
+        Ok(None) => {
+            let _ = writeln!(stdout, "ip {} is not reserved", args.ip);
+            1
+        }
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", err.report());
+            1
+        }
+    }
+}
+
+fn find_reservation(
+    conf: &NetConf,
+    ip: IpAddr,
+    options: FileStoreOptions,
+) -> Result<Option<Reservation>, HostLocalError> {
+    let options = FileStoreOptions {
+        read_only: true,
+        ..cni::store_options(conf, options)
+    };
+    let store = FileStore::with_options(&conf.name, &conf.ipam.data_dir, options)?;
+    let store = cni::encrypt(conf, store)?;
+
+    Ok(store.get_by_ip(ip)?)
+}
+
+#[derive(Args, Debug)]
+pub struct HealthArgs {
+    /// Bounds the wait for the network lock, by default `lockTimeoutMs` of
+    /// the configuration or five seconds.
+    #[arg(long, value_name = "MS")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Checks that the store of the network configured on `stdin` is usable,
+/// see `FileStore::probe`, for liveness probes of whatever runs the plugin.
+///
+/// A lock held longer than the timeout, by default `lockTimeoutMs` of the
+/// configuration or `DEFAULT_HEALTH_TIMEOUT`, counts as wedged.
+///
+/// Returns the process exit code, non-zero if the store is unusable.
+pub fn health<R: Read, W: Write>(
+    args: &HealthArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let timeout = args.timeout_ms.map(Duration::from_millis);
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let timeout = timeout
+        .or_else(|| conf.ipam.lock_timeout_ms.map(Duration::from_millis))
+        .unwrap_or(DEFAULT_HEALTH_TIMEOUT);
+    let options = FileStoreOptions {
+        lock_timeout: Some(timeout),
+        ..cni::store_options(&conf, options)
+    };
+    let result = FileStore::with_options(&conf.name, &conf.ipam.data_dir, options)
+        .and_then(|store| store.probe());
+    if let Err(err) = result {
+        let _ = writeln!(stdout, "{}", report(&err));
+        return 1;
+    }
+
+    let _ = writeln!(stdout, "store of network {} is healthy", conf.name);
+    0
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// Exports another network of the same data dir.
+    #[arg(long, value_name = "NAME")]
+    pub network: Option<String>,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Prints a complete snapshot of the store of the network configured on
+/// `stdin`, see `Store::export`, for backups or to seed test environments.
+///
+/// Snapshots are printed as JSON or YAML, tables aren't supported and
+/// `--quiet` is ignored. The snapshot records a hash of the configuration,
+/// which `import` compares against.
+///
+/// Returns the process exit code, non-zero if the store can't be read.
+pub fn export<R: Read, W: Write>(
+    args: &ExportArgs,
+    options: FileStoreOptions,
+    mut stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+    if output.format == Format::Table {
+        let _ = writeln!(stdout, "snapshots are exported as json or yaml");
+        return 1;
+    }
+    let network = args.network.clone();
+
+    let mut bytes = Vec::new();
+    if let Err(err) = stdin.read_to_end(&mut bytes) {
+        let _ = writeln!(stdout, "{}", report(&err));
+        return 1;
+    }
+    let conf = match NetConf::load(&bytes[..]) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let network = network.unwrap_or_else(|| conf.name.clone());
+    let options = FileStoreOptions {
+        read_only: true,
+        ..cni::store_options(&conf, options)
+    };
+    let snapshot = FileStore::with_options(&network, &conf.ipam.data_dir, options)
+        .and_then(|store| cni::encrypt(&conf, store))
+        .and_then(|store| store.export());
+    let mut snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+    snapshot.network = Some(network);
+    snapshot.config_hash = Some(config_hash(&bytes));
+
+    Output {
+        quiet: false,
+        ..output
+    }
+    .print(&mut stdout, &snapshot);
+    0
+}
+
+#[derive(Args, Debug)]
+pub struct ImportArgs {
+    /// The snapshot, JSON or with the yaml feature YAML.
+    pub snapshot: PathBuf,
+    /// What happens to IPs reserved for other containers already.
+    #[arg(long, value_enum, default_value_t = OnConflict::Fail)]
+    pub on_conflict: OnConflict,
+    /// Imports into another network of the same data dir.
+    #[arg(long, value_name = "NAME")]
+    pub network: Option<String>,
+    /// Imports snapshots taken with another configuration.
+    #[arg(long)]
+    pub force: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// The values of `--on-conflict`, see `ConflictPolicy`.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OnConflict {
+    Skip,
+    Overwrite,
+    Fail,
+}
+
+impl From<OnConflict> for ConflictPolicy {
+    fn from(on_conflict: OnConflict) -> Self {
+        match on_conflict {
+            OnConflict::Skip => ConflictPolicy::Skip,
+            OnConflict::Overwrite => ConflictPolicy::Overwrite,
+            OnConflict::Fail => ConflictPolicy::Fail,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImportReport<'a> {
+    network: &'a str,
+    #[serde(flatten)]
+    restore: Restore,
+}
+
+/// Restores a snapshot written by `export` into the store of the network
+/// configured on `stdin`, see `Snapshot::restore`, and prints the outcome
+/// of every reservation as JSON.
+///
+/// Reservations outside of the configured ranges are left out. Snapshots
+/// taken with a different configuration are refused unless `--force` is
+/// given.
+///
+/// Returns the process exit code, non-zero if nothing was imported.
+pub fn import<R: Read, W: Write>(
+    args: &ImportArgs,
+    options: FileStoreOptions,
+    mut stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+    let path = &args.snapshot;
+    let network = args.network.clone();
+    let policy = ConflictPolicy::from(args.on_conflict);
+
+    let mut bytes = Vec::new();
+    if let Err(err) = stdin.read_to_end(&mut bytes) {
+        let _ = writeln!(stdout, "{}", report(&err));
+        return 1;
+    }
+    let conf = match NetConf::load(&bytes[..]) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+    let range_sets = match conf.ipam.range_sets() {
+        Ok(range_sets) => range_sets,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+    let snapshot: Snapshot = match read_document(path) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}: {}", path.display(), err);
+            return 1;
+        }
+    };
+
+    let hash = config_hash(&bytes);
+    if !args.force
+        && snapshot
+            .config_hash
+            .as_ref()
+            .is_some_and(|taken| *taken != hash)
+    {
+        let _ = writeln!(
+            stdout,
+            "{} was taken with another configuration, --force imports it anyway",
+            path.display()
+        );
+        return 1;
+    }
+
+    let network = network.unwrap_or_else(|| conf.name.clone());
+    let options = FileStoreOptions {
+        ..cni::store_options(&conf, options)
+    };
+    let restore = FileStore::with_options(&network, &conf.ipam.data_dir, options)
+        .and_then(|store| cni::encrypt(&conf, store))
+        .and_then(|store| {
+            store.lock()?;
+            let restore = snapshot.restore(&store, &range_sets, policy);
+            store.unlock()?;
+            restore
+        });
+    let restore = match restore {
+        Ok(restore) => restore,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let applied = restore.applied;
+    let report = ImportReport {
+        network: &network,
+        restore: restore,
+    };
+    output.print(&mut stdout, &report);
+
+    if applied {
+        0
+    } else {
+        1
+    }
+}
+
+/// Reads the JSON document at `path`, e.g. a snapshot or manifest.
+#[cfg(feature = "yaml")]
+fn read_document<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    // JSON is YAML as well
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    serde_yaml::from_reader(file).map_err(|err| err.to_string())
+}
+
+/// Reads the JSON document at `path`, e.g. a snapshot or manifest.
+#[cfg(not(feature = "yaml"))]
+fn read_document<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    serde_json::from_reader(file).map_err(|err| err.to_string())
+}
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// The manifest, JSON or with the yaml feature YAML.
+    #[arg(long, value_name = "PATH")]
+    pub manifest: PathBuf,
+    /// Makes the planned changes.
+    #[arg(long)]
+    pub apply: bool,
+    /// Releases the IPs of containers the manifest doesn't name as well.
+    #[arg(long)]
+    pub prune: bool,
+    /// Compares another network of the same data dir.
+    #[arg(long, value_name = "NAME")]
+    pub network: Option<String>,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Serialize)]
+struct DiffReport<'a> {
+    network: &'a str,
+    applied: bool,
+    #[serde(flatten)]
+    plan: Plan,
+}
+
+/// Compares the reservations of the network configured on `stdin` with a
+/// manifest of the IPs containers should hold, see `Manifest::diff`, and
+/// prints the planned reservations, releases and conflicts as JSON.
+///
+/// Returns the process exit code, non-zero if the store differs from the
+/// manifest afterwards.
+pub fn diff<R: Read, W: Write>(
+    args: &DiffArgs,
+    options: FileStoreOptions,
+    stdin: R,
+    mut stdout: W,
+) -> i32 {
+    let output = args.output.output();
+    let path = &args.manifest;
+    let network = args.network.clone();
+    let apply = args.apply;
+
+    let conf = match NetConf::load(stdin) {
+        Ok(conf) => conf,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+    let range_sets = match conf.ipam.range_sets() {
+        Ok(range_sets) => range_sets,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+    let manifest: Manifest = match read_document(path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}: {}", path.display(), err);
+            return 1;
+        }
+    };
+
+    let network = network.unwrap_or_else(|| conf.name.clone());
+    let options = FileStoreOptions {
+        read_only: !apply,
+        ..cni::store_options(&conf, options)
+    };
+    let diffed = FileStore::with_options(&network, &conf.ipam.data_dir, options)
+        .and_then(|store| cni::encrypt(&conf, store))
+        .and_then(|store| {
+            if !apply {
+                return Ok((manifest.diff(&store, &range_sets, args.prune)?, false));
+            }
+
+            store.lock()?;
+            let diffed = manifest
+                .diff(&store, &range_sets, args.prune)
+                .and_then(|plan| {
+                    let applied = plan.apply(&store)?;
+                    Ok((plan, applied))
+                });
+            store.unlock()?;
+            diffed
+        });
+    let (plan, applied) = match diffed {
+        Ok(diffed) => diffed,
+        Err(err) => {
+            let _ = writeln!(stdout, "{}", report(&err));
+            return 1;
+        }
+    };
+
+    let in_line = plan.conflicts.is_empty() && (applied || plan.is_empty());
+    let report = DiffReport {
+        network: &network,
+        applied: applied,
+        plan: plan,
+    };
+    output.print(&mut stdout, &report);
+
+    if in_line {
+        0
+    } else {
+        1
+    }
+}
+
+/// Identifies the configuration as given, so snapshots can tell which one
+/// they were taken with.
+fn config_hash(conf: &[u8]) -> String {
+    format!("{:032x}", fnv1a_128(conf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(Some(&"host-local").into_iter().chain(args))
+    }
+
+    /// Runs the subcommand of `args` with `conf` on stdin.
+    fn run(args: &[&str], conf: &str, out: &mut Vec<u8>) -> i32 {
+        parse(args).unwrap().run(conf.as_bytes(), out)
+    }
+
+    #[test]
+    fn rootless_flag() {
+        assert_eq!(parse(&["status"]).unwrap().store_options().rootless, None);
+        for args in &[
+            &["--rootless", "status"][..],
+            &["gc", "--by-netns", "--rootless"],
+        ] {
+            let cli = parse(args).unwrap();
+            assert_eq!(cli.store_options().rootless, Some(true));
+        }
+    }
+
+    #[test]
+    fn validate_config() {
+        let mut out = Vec::new();
+        let code = run(
+            &["validate"],
+            r#"{"name": "n", "ipam": {"ranges": [[{"subnet": "10.1.2.0/24"}]]}}"#,
+            &mut out,
+        );
+        assert_eq!(code, 0);
+        assert_eq!(String::from_utf8(out).unwrap(), "configuration is valid\n");
+
+        let mut out = Vec::new();
+        let code = run(&["validate"], "{\n  \"name\": 1\n}", &mut out);
+        assert_eq!(code, 1);
+        assert!(String::from_utf8(out).unwrap().contains("line 2 column"));
+    }
+
+    #[test]
+    fn validate_report() {
+        let conf = r#"{"name": "n", "ipam": {"ranges": [
+            [{"subnet": "10.1.2.0/24"}, {"subnet": "2001:db8::/64"}],
+            [{"subnet": "10.1.3.0/31"}]
+        ]}}"#;
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["validate"], conf, &mut out), 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ipam.ranges[0][1]: IPv6 subnet 2001:db8::/64 in the IPv4 range set of ipam.ranges[0][0] (10.1.2.0/24)\n"
+        );
+
+        let mut out = Vec::new();
+        let conf = conf.replace("2001:db8::/64", "10.1.4.0/24");
+        assert_eq!(run(&["validate", "--format", "json"], &conf, &mut out), 1);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "valid": false,
+                "errors": [{
+                    "location": "ipam.ranges[1][0]",
+                    "message": "Network 10.1.3.0/31 too small to allocate from",
+                }],
+            })
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["validate", "--format", "table"], "{", &mut out), 1);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("valid: false\n"), "{}", out);
+        assert!(
+            out.contains("failed to parse network configuration"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn capacity_per_range_set() {
+        let mut out = Vec::new();
+        let code = capacity(
+            &br#"{"name": "n", "ipam": {"ranges": [
+                [{"subnet": "10.1.2.0/24"}, {"subnet": "10.1.3.0/24", "rangeStart": "10.1.3.10", "rangeEnd": "10.1.3.19"}],
+                [{"subnet": "2001:db8::/120", "gateway": "2001:db8::ff"}]
+            ]}}"#[..],
+            &mut out,
+        );
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap().lines().collect::<Vec<_>>(),
+            vec![
+                "range set 0:",
+                "  10.1.2.0/24 10.1.2.1-10.1.2.254 gateway 10.1.2.1: 253",
+                "  10.1.3.0/24 10.1.3.10-10.1.3.19 gateway 10.1.3.1: 10",
+                "  total: 263",
+                "range set 1:",
+                "  2001:db8::/120 2001:db8::1-2001:db8::ff gateway 2001:db8::ff: 254",
+                "  total: 254",
+            ]
+        );
+    }
+
+    #[test]
+    fn status_detail() {
+        let data_dir = "/tmp/cni-cli-status";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let store = FileStore::new("n", data_dir).unwrap();
+        for ip in &["10.1.2.2", "10.1.2.3", "10.1.2.6"] {
+            store
+                .reserve("c1", "eth0", ip.parse().unwrap(), "0")
+                .unwrap();
+        }
+        store.save_stats().unwrap();
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24", "rangeStart": "10.1.2.2", "rangeEnd": "10.1.2.9"}}]]}}}}"#,
+            data_dir
+        );
+        let mut out = Vec::new();
+        assert_eq!(run(&["status"], &conf, &mut out), 0);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "network": "n",
+                "range_sets": [{
+                    "range_set": 0,
+                    "capacity": 8,
+                    "used": 3,
+                    "free_blocks": 2,
+                    "largest_free_block": 3
+                }],
+                "store": {
+                    "reserves": 3,
+                    "conflicts": 0,
+                    "releases": 0,
+                    "io_errors": 0,
+                    "lock_wait_ns": 0
+                }
+            })
+        );
+
+        let mut out = Vec::new();
+        let code = run(
+            &["status", "--detail", "--format", "table", "--no-color"],
+            &conf,
+            &mut out,
+        );
+        assert_eq!(code, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "network: n\n\
+             store.reserves: 3\n\
+             store.conflicts: 0\n\
+             store.releases: 0\n\
+             store.io_errors: 0\n\
+             store.lock_wait_ns: 0\n\
+             \n\
+             range_sets:\n\
+             RANGE_SET  CAPACITY  USED  FREE_BLOCKS  LARGEST_FREE_BLOCK\n\
+             0          8         3     2            3\n\
+             \n\
+             free_blocks:\n\
+             RANGE_SET  START     END       SIZE\n\
+             0          10.1.2.4  10.1.2.5  2\n\
+             0          10.1.2.7  10.1.2.9  3\n\
+             \n\
+             slices:\n\
+             RANGE_SET  SLICE        CAPACITY  USED  PERCENT\n\
+             0          10.1.2.0/24  8         3     37\n"
+        );
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn fsck_report() {
+        let data_dir = "/tmp/cni-cli-fsck";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        FileStore::new("n", data_dir).unwrap();
+        let store = FileStore::new("other", data_dir).unwrap();
+        std::fs::write(store.data_dir().join("10.1.2.3"), "").unwrap();
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["fsck"], &conf, &mut out), 0);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["network"], "n");
+        assert_eq!(report["problems"], serde_json::json!([]));
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["fsck", "--network", "other"], &conf, &mut out), 1);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["problems"][0]["kind"], "empty-reservation");
+        assert_eq!(report["problems"][0]["fixed"], false);
+
+        let mut out = Vec::new();
+        let code = run(&["fsck", "--network", "other", "--fix"], &conf, &mut out);
+        assert_eq!(code, 0);
+        assert!(!store.data_dir().join("10.1.2.3").exists());
+
+        assert!(parse(&["fsck", "--bogus"]).is_err());
+
+        let mut out = Vec::new();
+        let code = run(&["fsck", "--network", "missing"], &conf, &mut out);
+        assert_eq!(code, 1);
+        assert!(!Path::new(data_dir).join("missing").exists());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn reconcile_report() {
+        use crate::store::Store;
+
+        let data_dir = "/tmp/cni-cli-reconcile";
+        let _ = std::fs::remove_dir_all(data_dir);
+        std::fs::create_dir_all(data_dir).unwrap();
+
+        let conf = |range: &str| {
+            format!(
+                r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{}]]}}}}"#,
+                data_dir, range
+            )
+        };
+        let previous = Path::new(data_dir).join("previous.json");
+        std::fs::write(&previous, conf(r#"{"subnet": "10.1.2.0/24"}"#)).unwrap();
+
+        let store = FileStore::new("n", data_dir).unwrap();
+        for (id, ip) in &[("c1", "10.1.2.2"), ("c2", "10.1.2.200")] {
+            store.reserve(id, "eth0", ip.parse().unwrap(), "0").unwrap();
+        }
+
+        let shrunk = conf(r#"{"subnet": "10.1.2.0/24", "rangeStart": "10.1.2.100"}"#);
+        let previous = previous.to_str().unwrap();
+
+        let mut out = Vec::new();
+        let code = run(&["reconcile", "--previous", previous], &shrunk, &mut out);
+        assert_eq!(code, 1);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            report["orphans"],
+            serde_json::json!([{"ip": "10.1.2.2", "id": "c1", "ifname": "eth0"}])
+        );
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        let mut out = Vec::new();
+        let code = run(
+            &["reconcile", "--previous", previous, "--release"],
+            &shrunk,
+            &mut out,
+        );
+        assert_eq!(code, 0);
+        assert_eq!(
+            store.list().unwrap(),
+            vec!["10.1.2.200".parse::<std::net::IpAddr>().unwrap()]
+        );
+
+        assert!(parse(&["reconcile"]).is_err());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn gc_by_netns() {
+        use crate::store::Transaction;
+
+        let data_dir = "/tmp/cni-cli-gc";
+        let _ = std::fs::remove_dir_all(data_dir);
+        std::fs::create_dir_all(data_dir).unwrap();
+
+        let live_netns = Path::new(data_dir).join("netns-live");
+        std::fs::write(&live_netns, "").unwrap();
+        let dead_netns = Path::new(data_dir).join("netns-dead");
+
+        let store = FileStore::new("n", data_dir).unwrap();
+        let mut txn = Transaction::new();
+        txn.reserve_in(
+            "c1",
+            "eth0",
+            live_netns.to_str(),
+            "10.1.2.2".parse().unwrap(),
+        )
+        .reserve_in(
+            "c2",
+            "eth0",
+            dead_netns.to_str(),
+            "10.1.2.3".parse().unwrap(),
+        )
+        .reserve("c3", "eth0", "10.1.2.4".parse().unwrap());
+        assert!(store.commit(&txn).unwrap());
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+
+        let mut out = Vec::new();
+        let code = run(&["gc", "--by-netns", "--dry-run"], &conf, &mut out);
+        assert_eq!(code, 1);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            report["dead"],
+            serde_json::json!([{
+                "ip": "10.1.2.3",
+                "id": "c2",
+                "ifname": "eth0",
+                "netns": dead_netns.to_str().unwrap()
+            }])
+        );
+        assert_eq!(store.list().unwrap().len(), 3);
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["gc", "--by-netns"], &conf, &mut out), 0);
+        let mut ips = store.list().unwrap();
+        ips.sort();
+        assert_eq!(
+            ips,
+            vec![
+                "10.1.2.2".parse::<IpAddr>().unwrap(),
+                "10.1.2.4".parse::<IpAddr>().unwrap()
+            ]
+        );
+
+        assert!(parse(&["gc"]).is_err());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn list_pods() {
+        use crate::store::{Owner, Pod, Transaction};
+
+        let data_dir = "/tmp/cni-cli-list";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let store = FileStore::new("n", data_dir).unwrap();
+        let pod = Owner {
+            id: "c1".to_owned(),
+            ifname: "eth0".to_owned(),
+            netns: None,
+            pod: Some(Pod {
+                namespace: "default".to_owned(),
+                name: "web-0".to_owned(),
+                uid: Some("4f6c".to_owned()),
+            }),
+            labels: vec![("team".to_owned(), "web".to_owned())]
+                .into_iter()
+                .collect(),
+        };
+        let mut txn = Transaction::new();
+        txn.reserve_for(pod, "10.1.2.2".parse().unwrap()).reserve(
+            "c2",
+            "eth0",
+            "10.1.2.3".parse().unwrap(),
+        );
+        assert!(store.commit(&txn).unwrap());
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+        let mut out = Vec::new();
+        assert_eq!(run(&["list"], &conf, &mut out), 0);
+        let reservations: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            reservations,
+            serde_json::json!([
+                {
+                    "ip": "10.1.2.2",
+                    "id": "c1",
+                    "ifname": "eth0",
+                    "netns": null,
+                    "pod": {"namespace": "default", "name": "web-0", "uid": "4f6c"},
+                    "labels": {"team": "web"}
+                },
+                {"ip": "10.1.2.3", "id": "c2", "ifname": "eth0", "netns": null}
+            ])
+        );
+
+        let mut out = Vec::new();
+        let code = run(
+            &["list", "--format", "table", "--no-color"],
+            &conf,
+            &mut out,
+        );
+        assert_eq!(code, 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "IP        ID  IFNAME  POD.NAMESPACE  POD.NAME  POD.UID  LABELS.TEAM\n\
+             10.1.2.2  c1  eth0    default        web-0     4f6c     web\n\
+             10.1.2.3  c2  eth0    -              -         -        -\n"
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["list", "--selector", "team=web"], &conf, &mut out), 0);
+        let reservations: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(reservations.as_array().unwrap().len(), 1);
+        assert_eq!(reservations[0]["ip"], "10.1.2.2");
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["list", "--selector", "team=db"], &conf, &mut out), 0);
+        assert_eq!(String::from_utf8(out).unwrap(), "[]\n");
+        assert!(parse(&["list", "--selector", "team"]).is_err());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn whois_ip() {
+        let data_dir = "/tmp/cni-cli-whois";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let store = FileStore::new("n", data_dir).unwrap();
+        store
+            .reserve("c1", "eth0", "10.1.2.2".parse().unwrap(), "0")
+            .unwrap();
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+        let mut out = Vec::new();
+        assert_eq!(run(&["whois", "10.1.2.2"], &conf, &mut out), 0);
+        let reservation: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            reservation,
+            serde_json::json!({"ip": "10.1.2.2", "id": "c1", "ifname": "eth0", "netns": null})
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["whois", "10.1.2.3"], &conf, &mut out), 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ip 10.1.2.3 is not reserved\n"
+        );
+        assert!(parse(&["whois", "10.1.2"]).is_err());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn health_probe() {
+        let data_dir = "/tmp/cni-cli-health";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["health"], &conf, &mut out), 0);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "store of network n is healthy\n"
+        );
+
+        // a plugin stuck with the network lock
+        let store = FileStore::new("n", data_dir).unwrap();
+        store.lock().unwrap();
+        let mut out = Vec::new();
+        assert_eq!(run(&["health", "--timeout-ms", "20"], &conf, &mut out), 1);
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("timed out after 20ms waiting for lock"),
+            "{}",
+            out
+        );
+        store.unlock().unwrap();
+
+        assert!(parse(&["health", "--timeout-ms"]).is_err());
+
+        // the probe leaves nothing behind for fsck
+        let mut out = Vec::new();
+        assert_eq!(run(&["fsck"], &conf, &mut out), 0);
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn export_and_import() {
+        let data_dir = "/tmp/cni-cli-export";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let store = FileStore::new("n", data_dir).unwrap();
+        store
+            .reserve("c1", "eth0", "10.1.2.2".parse().unwrap(), "0")
+            .unwrap();
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["export"], &conf, &mut out), 0);
+        let snapshot: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(snapshot["version"], 1);
+        assert_eq!(snapshot["network"], "n");
+        assert_eq!(snapshot["reservations"][0]["ip"], "10.1.2.2");
+        assert_eq!(snapshot["lastReserved"]["0"], "10.1.2.2");
+
+        let path = Path::new(data_dir).join("snapshot.json");
+        std::fs::write(&path, &out).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut out = Vec::new();
+        let code = run(&["import", path, "--network", "copy"], &conf, &mut out);
+        assert_eq!(code, 0, "{}", String::from_utf8_lossy(&out));
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "network": "copy",
+                "applied": true,
+                "entries": [{"ip": "10.1.2.2", "id": "c1", "ifname": "eth0", "outcome": "imported"}]
+            })
+        );
+        let copy = FileStore::new("copy", data_dir).unwrap();
+        assert_eq!(
+            copy.export().unwrap().reservations,
+            store.export().unwrap().reservations
+        );
+
+        // another container holds the IP
+        FileStore::new("taken", data_dir)
+            .unwrap()
+            .reserve("c2", "eth0", "10.1.2.2".parse().unwrap(), "0")
+            .unwrap();
+        let mut out = Vec::new();
+        let code = run(&["import", path, "--network", "taken"], &conf, &mut out);
+        assert_eq!(code, 1);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["applied"], false);
+        assert_eq!(report["entries"][0]["outcome"], "conflict");
+        let mut out = Vec::new();
+        let code = run(
+            &[
+                "import",
+                path,
+                "--network",
+                "taken",
+                "--on-conflict",
+                "skip",
+            ],
+            &conf,
+            &mut out,
+        );
+        assert_eq!(code, 0);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["entries"][0]["outcome"], "skipped");
+
+        let changed = conf.replace("10.1.2.0/24", "10.1.3.0/24");
+        let mut out = Vec::new();
+        let code = run(&["import", path, "--network", "other"], &changed, &mut out);
+        assert_eq!(code, 1);
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("another configuration"));
+        let mut out = Vec::new();
+        let code = run(
+            &["import", path, "--network", "other", "--force"],
+            &changed,
+            &mut out,
+        );
+        assert_eq!(code, 0);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["entries"][0]["outcome"], "out-of-range");
+
+        assert!(parse(&["import", path, "--on-conflict", "merge"]).is_err());
+        assert!(parse(&["import"]).is_err());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn diff_manifest() {
+        let data_dir = "/tmp/cni-cli-diff";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let store = FileStore::new("n", data_dir).unwrap();
+        store
+            .reserve("c1", "eth0", "10.1.2.9".parse().unwrap(), "0")
+            .unwrap();
+        let manifest = Path::new(data_dir).join("desired.json");
+        std::fs::write(&manifest, r#"{"c1": ["10.1.2.2"], "c2": ["10.1.2.3"]}"#).unwrap();
+        let manifest = manifest.to_str().unwrap();
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["diff", "--manifest", manifest], &conf, &mut out), 1);
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "network": "n",
+                "applied": false,
+                "reserve": [
+                    {"ip": "10.1.2.2", "id": "c1", "ifname": "eth0"},
+                    {"ip": "10.1.2.3", "id": "c2", "ifname": "eth0"}
+                ],
+                "release": [{"ip": "10.1.2.9", "id": "c1", "ifname": "eth0"}],
+                "conflicts": []
+            })
+        );
+        assert_eq!(
+            store.get_by_id("c1", "eth0"),
+            vec!["10.1.2.9".parse::<IpAddr>().unwrap()]
+        );
+
+        let mut out = Vec::new();
+        let code = run(
+            &["diff", "--manifest", manifest, "--apply"],
+            &conf,
+            &mut out,
+        );
+        assert_eq!(code, 0);
+        assert_eq!(
+            store.get_by_id("c1", "eth0"),
+            vec!["10.1.2.2".parse::<IpAddr>().unwrap()]
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(run(&["diff", "--manifest", manifest], &conf, &mut out), 0);
+
+        assert!(parse(&["diff"]).is_err());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn completions_of_shells() {
+        for shell in &["bash", "zsh", "fish"] {
+            let mut out = Vec::new();
+            assert_eq!(run(&["completions", shell], "", &mut out), 0);
+            let script = String::from_utf8(out).unwrap();
+            assert!(script.contains("host-local"), "{}", script);
+            assert!(script.contains("on-conflict"), "{}", script);
+        }
+
+        assert!(parse(&["completions", "tcsh"]).is_err());
+    }
+}
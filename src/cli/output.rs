@@ -0,0 +1,338 @@
+//! Output shared by the subcommands printing reports.
+//!
+//! Subcommands printing a report flatten `OutputArgs` into their arguments.
+//! Reports are printed as JSON by default, as YAML, or as tables for people
+//! at a terminal: every scalar field of the report becomes a `name: value`
+//! line and every list a table with a column per field, nested fields
+//! joined with dots. Headers are bold unless colors are off, which they are
+//! with `--no-color`, when `NO_COLOR` is set or stdout isn't a terminal.
+
+use std::env;
+use std::io::Write;
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    #[cfg_attr(not(feature = "yaml"), value(skip))]
+    Yaml,
+}
+
+/// The arguments selecting the output of a subcommand.
+#[derive(Args, Clone, Debug, PartialEq)]
+pub struct OutputArgs {
+    /// Format of the report, YAML requires the yaml feature.
+    #[arg(long, value_enum, default_value_t = Format::Json)]
+    pub format: Format,
+    /// Leaves tables uncolored even at a terminal.
+    #[arg(long)]
+    pub no_color: bool,
+    /// Prints nothing but errors.
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+/// How a subcommand prints its report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Output {
+    pub format: Format,
+    pub color: bool,
+    /// Prints nothing but errors, for scripts which only need the exit code.
+    pub quiet: bool,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            format: Format::Json,
+            color: false,
+            quiet: false,
+        }
+    }
+}
+
+impl OutputArgs {
+    pub fn output(&self) -> Output {
+        Output {
+            format: self.format,
+            color: !self.no_color && env::var_os("NO_COLOR").is_none() && stdout_is_terminal(),
+            quiet: self.quiet,
+        }
+    }
+}
+
+impl Output {
+    /// Prints `report` in the selected format.
+    pub fn print<W: Write, T: Serialize>(&self, mut stdout: W, report: &T) {
+        if self.quiet {
+            return;
+        }
+
+        match self.format {
+            Format::Json => {
+                let _ = serde_json::to_writer_pretty(&mut stdout, report);
+                let _ = writeln!(stdout);
+            }
+            Format::Yaml => write_yaml(stdout, report),
+            Format::Table => match serde_json::to_value(report) {
+                Ok(value) => self.write_value(&mut stdout, &value),
+                Err(err) => {
+                    let _ = writeln!(stdout, "{}", err);
+                }
+            },
+        }
+    }
+
+    fn write_value<W: Write>(&self, stdout: &mut W, value: &Value) {
+        let fields = match value {
+            Value::Array(items) => return self.write_table(stdout, items),
+            Value::Object(fields) => fields,
+            scalar => {
+                let _ = writeln!(stdout, "{}", cell(scalar));
+                return;
+            }
+        };
+
+        let mut scalars = Vec::new();
+        flatten("", value, &mut scalars);
+        for (name, value) in scalars.iter().filter(|(_, value)| !value.is_array()) {
+            let _ = writeln!(stdout, "{}: {}", name, cell(value));
+        }
+
+        for (name, items) in fields.iter().filter_map(|(name, value)| match value {
+            Value::Array(items) => Some((name, items)),
+            _ => None,
+        }) {
+            let _ = writeln!(stdout);
+            let _ = writeln!(stdout, "{}:", self.bold(name));
+            self.write_table(stdout, items);
+        }
+    }
+
+    fn write_table<W: Write>(&self, stdout: &mut W, items: &[Value]) {
+        if items.is_empty() {
+            let _ = writeln!(stdout, "(none)");
+            return;
+        }
+
+        let rows: Vec<Vec<(String, &Value)>> = items
+            .iter()
+            .map(|item| {
+                let mut fields = Vec::new();
+                flatten("", item, &mut fields);
+                // shown as "-" like fields other rows have
+                fields.retain(|(_, value)| !value.is_null());
+                fields
+            })
+            .collect();
+
+        let mut columns: Vec<String> = Vec::new();
+        for row in &rows {
+            for (name, _) in row {
+                if !columns.contains(name) {
+                    columns.push(name.clone());
+                }
+            }
+        }
+
+        let cells: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .map(|column| match row.iter().find(|(name, _)| name == column) {
+                        Some((_, value)) => cell(value),
+                        None => "-".to_owned(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let widths: Vec<usize> = columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                cells
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(Some(column.chars().count()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let headers: Vec<String> = columns.iter().map(|column| column.to_uppercase()).collect();
+        let _ = writeln!(stdout, "{}", self.bold(&line(&headers, &widths)));
+        for row in &cells {
+            let _ = writeln!(stdout, "{}", line(row, &widths));
+        }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", BOLD, text, RESET)
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+/// Collects the fields of `value` below `prefix`, with the names of nested
+/// objects joined by dots. Lists are kept whole.
+fn flatten<'a>(prefix: &str, value: &'a Value, fields: &mut Vec<(String, &'a Value)>) {
+    match value {
+        Value::Object(object) => flatten_object(prefix, object, fields),
+        value => fields.push((prefix.to_owned(), value)),
+    }
+}
+
+fn flatten_object<'a>(
+    prefix: &str,
+    object: &'a Map<String, Value>,
+    fields: &mut Vec<(String, &'a Value)>,
+) {
+    for (name, value) in object {
+        let name = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        flatten(&name, value, fields);
+    }
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_owned(),
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items.iter().map(cell).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+/// Pads all but the last cell to the width of their column.
+fn line(cells: &[String], widths: &[usize]) -> String {
+    let mut line = String::new();
+    for (i, (cell, width)) in cells.iter().zip(widths).enumerate() {
+        if i + 1 == cells.len() {
+            line.push_str(cell);
+        } else {
+            line.push_str(&format!("{:<width$}  ", cell, width = width));
+        }
+    }
+
+    line
+}
+
+#[cfg(feature = "yaml")]
+fn write_yaml<W: Write, T: Serialize>(stdout: W, report: &T) {
+    let _ = serde_yaml::to_writer(stdout, report);
+}
+
+#[cfg(not(feature = "yaml"))]
+fn write_yaml<W: Write, T: Serialize>(_stdout: W, _report: &T) {}
+
+#[cfg(unix)]
+fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+fn stdout_is_terminal() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(clap::Parser)]
+    struct Command {
+        #[command(flatten)]
+        output: OutputArgs,
+    }
+
+    fn parse(args: &[&str]) -> Result<Output, clap::Error> {
+        use clap::Parser;
+
+        let args = Some("report").into_iter().chain(args.iter().copied());
+        Command::try_parse_from(args).map(|command| command.output.output())
+    }
+
+    #[test]
+    fn output_args() {
+        let output = parse(&["--format", "table", "-q", "--no-color"]).unwrap();
+        assert_eq!(
+            output,
+            Output {
+                format: Format::Table,
+                color: false,
+                quiet: true,
+            }
+        );
+        assert_eq!(parse(&[]).unwrap().format, Format::Json);
+
+        assert!(parse(&["--format", "xml"]).is_err());
+        assert!(parse(&["--format"]).is_err());
+        assert_eq!(parse(&["--format", "yaml"]).is_ok(), cfg!(feature = "yaml"));
+    }
+
+    #[test]
+    fn table() {
+        let output = Output {
+            format: Format::Table,
+            color: false,
+            quiet: false,
+        };
+        let report = serde_json::json!({
+            "network": "n",
+            "released": false,
+            "dead": [
+                {"ip": "10.1.2.3", "id": "c1", "pod": {"name": "web-0"}},
+                {"ip": "10.1.2.10", "id": "c22", "pod": null}
+            ],
+            "problems": []
+        });
+
+        let mut out = Vec::new();
+        output.print(&mut out, &report);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "network: n\n\
+             released: false\n\
+             \n\
+             dead:\n\
+             IP         ID   POD.NAME\n\
+             10.1.2.3   c1   web-0\n\
+             10.1.2.10  c22  -\n\
+             \n\
+             problems:\n\
+             (none)\n"
+        );
+
+        let mut out = Vec::new();
+        Output {
+            color: true,
+            ..output
+        }
+        .print(&mut out, &serde_json::json!([{"ip": "10.1.2.3"}]));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b[1mIP\x1b[0m\n10.1.2.3\n"
+        );
+
+        let mut out = Vec::new();
+        Output {
+            quiet: true,
+            ..output
+        }
+        .print(&mut out, &report);
+        assert!(out.is_empty());
+    }
+}
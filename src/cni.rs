@@ -0,0 +1,1218 @@
+//! The CNI protocol side of the plugin: the parameters handed over in the
+//! environment, and the result and error documents written to stdout.
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::allocator::rangeset::RangeSet;
+use super::allocator::{
+    AllocateError, AllocationObserver, Allocator, CheckFailure, CheckReport, IpConfig, Rollback,
+};
+use super::config::{ConfigError, DuplicateIdCheck, LogLevel, NetConf, OtelConf};
+use super::error::{report, HostLocalError};
+#[cfg(feature = "firewall-sets")]
+use super::firewall::FirewallSetExporter;
+use super::hosts::HostsExporter;
+#[cfg(feature = "otel")]
+use super::otel::{CommandSpan, OtlpExporter};
+use super::store::filestore::{FileStore, FileStoreOptions};
+use super::store::{Pod, Store, StoreError};
+use super::timing::{Phase, Timings};
+
+pub const SUPPORTED_VERSIONS: &[&str] = &["0.3.0", "0.3.1", "0.4.0", "1.0.0"];
+
+pub(crate) const ERR_INVALID_ENV: u32 = 4;
+pub(crate) const ERR_DECODING: u32 = 6;
+pub(crate) const ERR_TRY_AGAIN_LATER: u32 = 11;
+pub(crate) const ERR_INTERNAL: u32 = 999;
+
+/// Parameters passed by the runtime through `CNI_*` environment variables.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CniArgs {
+    pub command: String,
+    pub container_id: String,
+    pub netns: String,
+    pub ifname: String,
+    pub args: String,
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Route {
+    pub dst: IpNetwork,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gw: Option<IpAddr>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Dns {}
+
+/// Interface created by an earlier plugin of the chain, `IpEntry.interface`
+/// indexes into the list of them.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Interface {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IpEntry {
+    /// Only part of results before spec version 1.0.0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<usize>,
+    pub address: IpNetwork,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<IpAddr>,
+}
+
+/// Result document of a plugin, also handed to the next plugin of a chain
+/// as `prevResult`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CniResult {
+    #[serde(default)]
+    pub cni_version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<Interface>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ips: Vec<IpEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+    #[serde(default)]
+    pub dns: Dns,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CniErrorResult<'a> {
+    cni_version: &'a str,
+    code: u32,
+    msg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
+
+    #[error("failed to allocate for range {0}")]
+    AllocateError(usize, #[source] AllocateError),
+
+    #[error("required env variable {0} is missing")]
+    MissingEnv(&'static str),
+
+    #[error("required CNI_ARGS key {0} is missing")]
+    MissingArg(&'static str),
+
+    #[error("container {0} already holds {2} in network {1} with an overlapping subnet")]
+    DuplicateContainerId(String, String, IpAddr),
+
+    #[error("requested ip {0} is in none of the range sets")]
+    UnmatchedIp(IpAddr),
+
+    #[error("requested ips {0} and {1} are in the same range set")]
+    AmbiguousIps(IpAddr, IpAddr),
+
+    #[error("unknown CNI_COMMAND: {0}")]
+    UnknownCommand(String),
+
+    #[error("{0}")]
+    CheckFailed(CheckReport),
+
+    #[error("failed to write result")]
+    OutputError(#[source] serde_json::Error),
+}
+
+impl From<IpConfig> for IpEntry {
+    /// Leaves `version` unset, it depends on the spec version of the result.
+    fn from(ip_config: IpConfig) -> IpEntry {
+        IpEntry {
+            version: None,
+            interface: ip_config.interface(),
+            address: ip_config.address(),
+            gateway: ip_config.gateway(),
+        }
+    }
+}
+
+impl PluginError {
+    /// Maps the error to the well-known CNI error codes.
+    pub fn code(&self) -> u32 {
+        match self {
+            PluginError::ConfigError(ConfigError::JsonError(_)) => ERR_DECODING,
+            PluginError::MissingEnv(_)
+            | PluginError::MissingArg(_)
+            | PluginError::UnknownCommand(_) => ERR_INVALID_ENV,
+            PluginError::StoreError(StoreError::LockTimeout { .. })
+            | PluginError::AllocateError(
+                _,
+                AllocateError::StoreError(StoreError::LockTimeout { .. }),
+            ) => ERR_TRY_AGAIN_LATER,
+            _ => ERR_INTERNAL,
+        }
+    }
+}
+
+impl CniArgs {
+    pub fn from_env() -> CniArgs {
+        let var = |name| env::var(name).unwrap_or_default();
+
+        CniArgs {
+            command: var("CNI_COMMAND"),
+            container_id: var("CNI_CONTAINERID"),
+            netns: var("CNI_NETNS"),
+            ifname: var("CNI_IFNAME"),
+            args: var("CNI_ARGS"),
+            path: var("CNI_PATH"),
+        }
+    }
+
+    /// Value of `key` in `CNI_ARGS`, a `;` separated list of `KEY=VALUE`.
+    pub fn arg(&self, key: &str) -> Option<&str> {
+        self.args.split(';').find_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if k.trim() == key => Some(v.trim()),
+                _ => None,
+            }
+        })
+    }
+
+    /// The Kubernetes pod the container belongs to, if `CNI_ARGS` names
+    /// it. The uid is optional, older runtimes don't pass it.
+    pub fn pod(&self) -> Option<Pod> {
+        Some(Pod {
+            namespace: self.arg("K8S_POD_NAMESPACE")?.to_owned(),
+            name: self.arg("K8S_POD_NAME")?.to_owned(),
+            uid: self.arg("K8S_POD_UID").map(str::to_owned),
+        })
+    }
+
+    fn require(&self) -> Result<(), PluginError> {
+        if self.container_id.is_empty() {
+            return Err(PluginError::MissingEnv("CNI_CONTAINERID"));
+        }
+
+        if self.ifname.is_empty() {
+            return Err(PluginError::MissingEnv("CNI_IFNAME"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the command in `args` against the network configuration read from
+/// `stdin`, writing the result or error document to `stdout`.
+///
+/// Returns the process exit code.
+pub fn run<R: Read, W: Write>(
+    args: &CniArgs,
+    stdin: R,
+    mut stdout: W,
+    options: FileStoreOptions,
+) -> i32 {
+    let mut cni_version = String::new();
+    let mut log_level = None;
+    let mut otel = None;
+    let timings = Rc::new(Timings::new());
+
+    let result = timings
+        .time(Phase::ConfigParse, || NetConf::load(stdin))
+        .map_err(PluginError::ConfigError)
+        .and_then(|conf| {
+            cni_version = conf.cni_version.clone();
+            log_level = Some(conf.ipam.log_level);
+            otel = conf.ipam.otel.clone().map(|otel| (otel, conf.name.clone()));
+            dispatch(args, &conf, options, &timings)
+        })
+        .and_then(|output| match output {
+            Some(result) => timings.time(Phase::ResultSerialize, || {
+                serde_json::to_writer_pretty(&mut stdout, &result).map_err(PluginError::OutputError)
+            }),
+            None => Ok(()),
+        });
+
+    if let Some(log_level) = log_level {
+        let command = format!("{} {}/{}", args.command, args.container_id, args.ifname);
+        log_level.info(timings.summary(&command));
+
+        if let Some((otel, network)) = &otel {
+            let error = result.as_ref().err().map(|err| report(err));
+            export_trace(args, network, otel, &timings, error, log_level);
+        }
+    }
+
+    match result {
+        Ok(_) => 0,
+        Err(err) => {
+            let err = HostLocalError::from(err);
+            let _ = serde_json::to_writer_pretty(
+                &mut stdout,
+                &CniErrorResult {
+                    cni_version: &cni_version,
+                    code: err.code(),
+                    msg: err.report(),
+                    details: err.details(),
+                },
+            );
+            1
+        }
+    }
+}
+
+/// Sends the span of the command to the collector of `otel`.
+#[cfg(feature = "otel")]
+fn export_trace(
+    args: &CniArgs,
+    network: &str,
+    otel: &OtelConf,
+    timings: &Timings,
+    error: Option<String>,
+    log_level: LogLevel,
+) {
+    let span = CommandSpan::new(args, network, timings, error);
+    if let Err(err) = OtlpExporter::new(otel).export(&span) {
+        log_level.warn(format_args!(
+            "exporting trace to {} failed: {}",
+            otel.endpoint, err
+        ));
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+fn export_trace(
+    _args: &CniArgs,
+    _network: &str,
+    otel: &OtelConf,
+    _timings: &Timings,
+    _error: Option<String>,
+    log_level: LogLevel,
+) {
+    log_level.warn(format_args!(
+        "otel endpoint {} ignored, built without the otel feature",
+        otel.endpoint
+    ));
+}
+
+fn dispatch(
+    args: &CniArgs,
+    conf: &NetConf,
+    options: FileStoreOptions,
+    timings: &Rc<Timings>,
+) -> Result<Option<CniResult>, PluginError> {
+    match args.command.as_str() {
+        "ADD" => cmd_add_timed(args, conf, options, timings).map(Some),
+        "DEL" => cmd_del_timed(args, conf, options, timings).map(|_| None),
+        "CHECK" => cmd_check(args, conf, options).map(|_| None),
+        command => Err(PluginError::UnknownCommand(command.to_owned())),
+    }
+}
+
+pub fn cmd_add(
+    args: &CniArgs,
+    conf: &NetConf,
+    options: FileStoreOptions,
+) -> Result<CniResult, PluginError> {
+    cmd_add_timed(args, conf, options, &Rc::new(Timings::new()))
+}
+
+/// Like `cmd_add`, counting where the time goes in `timings`.
+pub fn cmd_add_timed(
+    args: &CniArgs,
+    conf: &NetConf,
+    options: FileStoreOptions,
+    timings: &Rc<Timings>,
+) -> Result<CniResult, PluginError> {
+    args.require()?;
+    let id = conf.ipam.id_mapping.mapper().map(args)?;
+
+    let range_sets = conf.ipam.range_sets().map_err(PluginError::ConfigError)?;
+    let store = open_store(conf, options)?;
+    let _stats = SaveStats::new(&store, conf.ipam.log_level);
+
+    check_other_networks(conf, &range_sets, &store, &id)?;
+
+    let interface = interface_index(args, conf);
+    let observers = observers(conf);
+    let requested_ips = match_requested_ips(&conf.runtime_config.ips, &range_sets)?;
+
+    // IPs reserved for earlier range sets are released if a later one fails
+    let mut rollback = Rollback::new(&*store, &id, &args.ifname).with_observers(observers.clone());
+    let mut ips = Vec::with_capacity(range_sets.len());
+    for (index, range_set) in range_sets.into_iter().enumerate() {
+        let reserved_ips = conf.ipam.reserved_ips_for(&range_set);
+        // a requested IP or a MAC with a static mapping gets its IP, or
+        // fails if that is taken
+        let requested_ip = requested_ips[index].or_else(|| {
+            args.arg("MAC")
+                .and_then(|mac| conf.ipam.static_ip_for(mac, &range_set))
+        });
+        let mut allocator = Allocator::new(range_set.clone(), store.clone())
+            .with_reserved_ips(reserved_ips)
+            .with_strategy(conf.ipam.allocation_strategy)
+            .with_timings(timings.clone());
+        if conf.ipam.upstream_range_ids {
+            allocator = allocator.with_range_id(index.to_string());
+        }
+        if let Some(quota) = conf.ipam.max_allocations_per_id {
+            allocator = allocator.with_quota(quota);
+        }
+        if !args.netns.is_empty() {
+            allocator = allocator.with_netns(&args.netns);
+        }
+        if let Some(pod) = args.pod() {
+            allocator = allocator.with_pod(pod);
+        }
+        if !conf.runtime_config.labels.is_empty() {
+            allocator = allocator.with_labels(conf.runtime_config.labels.clone());
+        }
+        for observer in &observers {
+            allocator.subscribe(Box::new(observer.clone()));
+        }
+
+        // range sets are locked one at a time, so ADDs allocating from
+        // different range sets of the network don't wait for each other
+        let range_id = allocator.range_id().to_owned();
+        let ip_config = with_range_lock(&*store, &range_id, timings, || {
+            // pins are read under the lock on every ADD, so edits of
+            // `reservations.conf` apply without restarting anything
+            let pins = store.pins()?;
+            let requested_ip = requested_ip
+                .or_else(|| pins.ip_for(&args.container_id, args.pod().as_ref(), &range_set));
+            allocator
+                .with_reserved_ips(pins.ips())
+                .get(&id, &args.ifname, requested_ip)
+        })
+        .map(|ip_config| match interface {
+            Some(interface) => ip_config.with_interface(interface),
+            None => ip_config,
+        })
+        .map_err(|err| PluginError::AllocateError(index, err))?;
+
+        rollback.push(ip_config.address().ip());
+        let mut ip = IpEntry::from(ip_config);
+        ip.version = ip_version(&conf.cni_version, &ip.address);
+        ips.push(ip);
+    }
+    rollback.commit();
+
+    Ok(merge_prev_result(conf, ips))
+}
+
+/// Matches the IPs of the `ips` capability to the range sets holding them,
+/// by index of the range set.
+fn match_requested_ips(
+    ips: &[IpNetwork],
+    range_sets: &[RangeSet],
+) -> Result<Vec<Option<IpAddr>>, PluginError> {
+    let mut requested = vec![None; range_sets.len()];
+    for ip in ips.iter().map(IpNetwork::ip) {
+        let index = range_sets
+            .iter()
+            .position(|range_set| range_set.contains(ip))
+            .ok_or(PluginError::UnmatchedIp(ip))?;
+        if let Some(other) = requested[index].replace(ip) {
+            return Err(PluginError::AmbiguousIps(other, ip));
+        }
+    }
+
+    Ok(requested)
+}
+
+/// Runs `allocate` while holding the lock of range set `range_id`. Its time
+/// counts to `Phase::Search`, except for the store writes the allocator
+/// counts to `Phase::Reserve` itself.
+fn with_range_lock<T, F>(
+    store: &dyn Store,
+    range_id: &str,
+    timings: &Timings,
+    allocate: F,
+) -> Result<T, AllocateError>
+where
+    F: FnOnce() -> Result<T, AllocateError>,
+{
+    timings.time(Phase::LockAcquire, || store.lock_range(range_id))?;
+    let reserving = timings.get(Phase::Reserve);
+    let start = Instant::now();
+    let result = allocate();
+    let reserved = timings.get(Phase::Reserve) - reserving;
+    timings.add(Phase::Search, start.elapsed().saturating_sub(reserved));
+    store.unlock_range(range_id)?;
+
+    result
+}
+
+/// Finds the index of the sandbox interface `CNI_IFNAME` in the interfaces
+/// of the previous result.
+fn interface_index(args: &CniArgs, conf: &NetConf) -> Option<usize> {
+    conf.prev_result.as_ref().and_then(|prev_result| {
+        prev_result
+            .interfaces
+            .iter()
+            .position(|interface| interface.name == args.ifname && interface.sandbox.is_some())
+    })
+}
+
+/// Builds the result of ADD on top of the result of the previous plugin of
+/// the chain, if any, keeping its interfaces, IPs and routes.
+///
+/// Every IP keeps the gateway of the range it was allocated from. An IP
+/// already reported by the previous result is only listed once, so are
+/// routes configured more than once.
+fn merge_prev_result(conf: &NetConf, ips: Vec<IpEntry>) -> CniResult {
+    let mut result = conf.prev_result.clone().unwrap_or(CniResult {
+        cni_version: String::new(),
+        interfaces: Vec::new(),
+        ips: Vec::new(),
+        routes: Vec::new(),
+        dns: Dns::default(),
+    });
+
+    result.cni_version = conf.cni_version.clone();
+
+    for ip in ips {
+        match result
+            .ips
+            .iter_mut()
+            .find(|prev| prev.address == ip.address)
+        {
+            Some(prev) => {
+                prev.gateway = ip.gateway;
+                if prev.interface.is_none() {
+                    prev.interface = ip.interface;
+                }
+            }
+            None => result.ips.push(ip),
+        }
+    }
+
+    for route in &conf.ipam.routes {
+        if !result.routes.contains(route) {
+            result.routes.push(route.clone());
+        }
+    }
+
+    result
+}
+
+pub fn cmd_del(
+    args: &CniArgs,
+    conf: &NetConf,
+    options: FileStoreOptions,
+) -> Result<(), PluginError> {
+    cmd_del_timed(args, conf, options, &Rc::new(Timings::new()))
+}
+
+/// Like `cmd_del`, counting where the time goes in `timings`.
+pub fn cmd_del_timed(
+    args: &CniArgs,
+    conf: &NetConf,
+    options: FileStoreOptions,
+    timings: &Rc<Timings>,
+) -> Result<(), PluginError> {
+    args.require()?;
+    let id = conf.ipam.id_mapping.mapper().map(args)?;
+
+    let store = open_store(conf, options)?;
+    let _stats = SaveStats::new(&store, conf.ipam.log_level);
+
+    let observers = observers(conf);
+
+    timings
+        .time(Phase::LockAcquire, || store.lock())
+        .map_err(PluginError::StoreError)?;
+    let result = store
+        .get_by_id(&id, &args.ifname)
+        .into_iter()
+        .try_for_each(|ip| {
+            timings.time(Phase::Reserve, || {
+                store.release_checked(ip, &id, &args.ifname)
+            })?;
+            for observer in &observers {
+                observer.released(&id, &args.ifname, ip);
+            }
+            Ok(())
+        })
+        .map_err(PluginError::StoreError);
+    store.unlock().map_err(PluginError::StoreError)?;
+
+    result
+}
+
+/// Verifies that the IPs held by the container are still covered by the
+/// configured ranges, which stops being the case when an operator shrinks a
+/// range, and that the IPs of the previous result are still reserved for
+/// it. `host-local reconcile` releases reservations out of range.
+///
+/// Fails with a `CheckReport` of every failed IP.
+pub fn cmd_check(
+    args: &CniArgs,
+    conf: &NetConf,
+    options: FileStoreOptions,
+) -> Result<(), PluginError> {
+    args.require()?;
+    let id = conf.ipam.id_mapping.mapper().map(args)?;
+
+    let range_sets = conf.ipam.range_sets().map_err(PluginError::ConfigError)?;
+    let options = FileStoreOptions {
+        read_only: true,
+        ..options
+    };
+    let store = open_store(conf, options)?;
+
+    let mut report = CheckReport::default();
+    for ip in store.get_by_id(&id, &args.ifname) {
+        if !range_sets.iter().any(|range_set| range_set.contains(ip)) {
+            report.fail(ip, CheckFailure::OutOfRange);
+        }
+    }
+
+    let expected: Vec<IpAddr> = conf
+        .prev_result
+        .iter()
+        .flat_map(|prev_result| prev_result.ips.iter())
+        .map(|ip| ip.address.ip())
+        .collect();
+    for (index, range_set) in range_sets.into_iter().enumerate() {
+        let allocator = Allocator::new(range_set, store.clone());
+        let checked = allocator
+            .check(&id, &args.ifname, &expected)
+            .map_err(|err| PluginError::AllocateError(index, err))?;
+        report.merge(checked);
+    }
+
+    if !report.is_ok() {
+        return Err(PluginError::CheckFailed(report));
+    }
+
+    Ok(())
+}
+
+/// Integrations configured for the network which follow its allocations.
+pub(crate) fn observers(conf: &NetConf) -> Vec<Rc<dyn AllocationObserver>> {
+    let mut observers: Vec<Rc<dyn AllocationObserver>> = Vec::new();
+
+    if let Some(hosts_file) = &conf.ipam.hosts_file {
+        observers.push(Rc::new(HostsExporter::new(hosts_file, conf.ipam.log_level)));
+    }
+
+    if let Some(firewall_set) = &conf.ipam.firewall_set {
+        #[cfg(feature = "firewall-sets")]
+        observers.push(Rc::new(FirewallSetExporter::new(
+            firewall_set,
+            &conf.name,
+            conf.ipam.log_level,
+        )));
+
+        #[cfg(not(feature = "firewall-sets"))]
+        conf.ipam.log_level.warn(format_args!(
+            "firewallSet {:?} ignored, built without the firewall-sets feature",
+            firewall_set.backend
+        ));
+    }
+
+    observers
+}
+
+/// `options` with those set by the network configuration `conf`, which
+/// win over the defaults of `options` but can't turn off what it enables.
+pub fn store_options(conf: &NetConf, options: FileStoreOptions) -> FileStoreOptions {
+    FileStoreOptions {
+        upstream_last_reserved: options.upstream_last_reserved || conf.ipam.upstream_range_ids,
+        journal: options.journal || conf.ipam.journal,
+        index: options.index || conf.ipam.index,
+        dir_mode: conf.ipam.dir_mode.unwrap_or(options.dir_mode),
+        file_mode: conf.ipam.file_mode.unwrap_or(options.file_mode),
+        uid: conf.ipam.uid.or(options.uid),
+        gid: conf.ipam.gid.or(options.gid),
+        lock_timeout: conf
+            .ipam
+            .lock_timeout_ms
+            .map(Duration::from_millis)
+            .or(options.lock_timeout),
+        ..options
+    }
+}
+
+fn open_store(conf: &NetConf, options: FileStoreOptions) -> Result<Arc<FileStore>, PluginError> {
+    let options = store_options(conf, options);
+    FileStore::with_options(&conf.name, &conf.ipam.data_dir, options)
+        .and_then(|store| encrypt(conf, store))
+        .map(Arc::new)
+        .map_err(PluginError::StoreError)
+}
+
+/// Adds the counters of a store to those saved in its data dir when
+/// dropped, so they count failed commands too, see `FileStore::save_stats`.
+struct SaveStats<'a> {
+    store: &'a FileStore,
+    log_level: LogLevel,
+}
+
+impl<'a> SaveStats<'a> {
+    fn new(store: &'a FileStore, log_level: LogLevel) -> SaveStats<'a> {
+        SaveStats {
+            store: store,
+            log_level: log_level,
+        }
+    }
+}
+
+impl Drop for SaveStats<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.store.save_stats() {
+            self.log_level
+                .warn(format_args!("failed to save store stats: {}", report(&err)));
+        }
+    }
+}
+
+/// Seals the reservations of `store` with the key of `encryptionKeyFile`,
+/// if configured.
+pub(crate) fn encrypt(conf: &NetConf, store: FileStore) -> Result<FileStore, StoreError> {
+    match &conf.ipam.encryption_key_file {
+        Some(path) => store.encrypt_with_key_file(path),
+        None => Ok(store),
+    }
+}
+
+/// Looks for the container in the other networks of the data dir. Holding
+/// IPs of overlapping subnets in two networks usually means two network
+/// configs were copied from each other without changing the subnet.
+fn check_other_networks(
+    conf: &NetConf,
+    range_sets: &[RangeSet],
+    store: &FileStore,
+    container_id: &str,
+) -> Result<(), PluginError> {
+    if conf.ipam.duplicate_id_check == DuplicateIdCheck::Off {
+        return Ok(());
+    }
+
+    let overlapping = store
+        .find_in_other_networks(container_id)
+        .into_iter()
+        .find(|(_, ip)| {
+            range_sets
+                .iter()
+                .flat_map(|range_set| range_set.iter())
+                .any(|range| range.subnet.contains(*ip))
+        });
+
+    match overlapping {
+        Some((network, ip)) => {
+            let err = PluginError::DuplicateContainerId(container_id.to_owned(), network, ip);
+            if conf.ipam.duplicate_id_check == DuplicateIdCheck::Error {
+                return Err(err);
+            }
+
+            conf.ipam.log_level.warn(err);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+fn ip_version(cni_version: &str, address: &IpNetwork) -> Option<String> {
+    if !cni_version.is_empty() && !cni_version.starts_with("0.") {
+        return None;
+    }
+
+    match address {
+        IpNetwork::V4(_) => Some("4".to_owned()),
+        IpNetwork::V6(_) => Some("6".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::FailedIp;
+    use crate::store::PINS_FILE;
+
+    #[test]
+    fn lock_timeout() {
+        let err = StoreError::LockTimeout {
+            path: "/var/lib/cni/networks/n/lock".into(),
+            holder: Some(42),
+            timeout: Duration::from_secs(1),
+        };
+        assert_eq!(
+            err.to_string(),
+            "timed out after 1s waiting for lock /var/lib/cni/networks/n/lock held by pid 42"
+        );
+
+        let err = PluginError::AllocateError(0, AllocateError::StoreError(err));
+        assert_eq!(err.code(), ERR_TRY_AGAIN_LATER);
+    }
+
+    #[test]
+    fn arg() {
+        let args = CniArgs {
+            args: "IgnoreUnknown=1;MAC=0a:58:0a:01:02:09; K8S_POD_NAME=p=1".to_owned(),
+            ..CniArgs::default()
+        };
+
+        assert_eq!(args.arg("MAC"), Some("0a:58:0a:01:02:09"));
+        assert_eq!(args.arg("K8S_POD_NAME"), Some("p=1"));
+        assert_eq!(args.arg("IP"), None);
+        assert_eq!(args.pod(), None);
+
+        let args = CniArgs {
+            args: "K8S_POD_NAMESPACE=default;K8S_POD_NAME=web-0;K8S_POD_UID=4f6c".to_owned(),
+            ..args
+        };
+        assert_eq!(
+            args.pod(),
+            Some(Pod {
+                namespace: "default".to_owned(),
+                name: "web-0".to_owned(),
+                uid: Some("4f6c".to_owned()),
+            })
+        );
+    }
+
+    #[test]
+    fn interface_index() {
+        let args = CniArgs {
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+
+        let conf = NetConf::parse(br#"{"name": "n", "ipam": {}}"#).unwrap();
+        assert_eq!(super::interface_index(&args, &conf), None);
+
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {}, "prevResult": {"interfaces": [
+                {"name": "eth0"},
+                {"name": "veth1234"},
+                {"name": "eth0", "sandbox": "/var/run/netns/c1"}
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(super::interface_index(&args, &conf), Some(2));
+    }
+
+    #[test]
+    fn check_orphans() {
+        let data_dir = "/tmp/cni-check";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = |range: &str| {
+            let conf = format!(
+                r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{}]]}}}}"#,
+                data_dir, range
+            );
+            NetConf::parse(conf.as_bytes()).unwrap()
+        };
+        let args = CniArgs {
+            container_id: "c1".to_owned(),
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+        let options = FileStoreOptions::default();
+
+        let before = conf(r#"{"subnet": "10.1.2.0/24"}"#);
+        cmd_add(&args, &before, options).unwrap();
+        cmd_check(&args, &before, options).unwrap();
+
+        let after = conf(r#"{"subnet": "10.1.2.0/24", "rangeStart": "10.1.2.100"}"#);
+        match cmd_check(&args, &after, options) {
+            Err(PluginError::CheckFailed(report)) => assert_eq!(
+                report.failed,
+                vec![FailedIp {
+                    ip: "10.1.2.2".parse().unwrap(),
+                    reason: CheckFailure::OutOfRange,
+                }]
+            ),
+            result => panic!("{:?}", result),
+        }
+
+        // the previous result lists an IP the store doesn't hold
+        let conf = format!(
+            r#"{{"name": "n", "cniVersion": "1.0.0", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}},
+                "prevResult": {{"ips": [{{"address": "10.1.2.2/24"}}, {{"address": "10.1.2.3/24"}}]}}}}"#,
+            data_dir
+        );
+        let args = CniArgs {
+            command: "CHECK".to_owned(),
+            ..args
+        };
+        let mut out = Vec::new();
+        assert_eq!(run(&args, conf.as_bytes(), &mut out, options), 1);
+        let result: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(result["code"], ERR_INTERNAL);
+        assert_eq!(result["msg"], "ip 10.1.2.3 is not reserved");
+        let details: serde_json::Value =
+            serde_json::from_str(result["details"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            details,
+            serde_json::json!({"failed": [{"ip": "10.1.2.3", "reason": "not-reserved"}]})
+        );
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn range_ids() {
+        let data_dir = "/tmp/cni-range-ids";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = |network: &str, upstream: bool| {
+            let conf = format!(
+                r#"{{"name": "{}", "ipam": {{"dataDir": "{}", "upstreamRangeIds": {},
+                    "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+                network, data_dir, upstream
+            );
+            NetConf::parse(conf.as_bytes()).unwrap()
+        };
+        let args = CniArgs {
+            container_id: "c1".to_owned(),
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+        let options = FileStoreOptions::default();
+
+        let by_id = conf("by-id", false);
+        cmd_add(&args, &by_id, options).unwrap();
+        let range_set = by_id.ipam.range_sets().unwrap().remove(0);
+        let store = FileStore::new("by-id", data_dir).unwrap();
+        assert!(store.last_reserved_ip(&range_set.id()).is_ok());
+        assert!(store.last_reserved_ip("0").is_err());
+
+        cmd_add(&args, &conf("by-index", true), options).unwrap();
+        let last_reserved = std::path::Path::new(data_dir).join("by-index/last_reserved_ip.0");
+        assert_eq!(std::fs::read_to_string(last_reserved).unwrap(), "10.1.2.2");
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn store_permissions() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let data_dir = "/tmp/cni-store-permissions";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let gid = unsafe { libc::getegid() };
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "dirMode": "0700", "fileMode": 384,
+                "gid": {}, "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir, gid
+        );
+        let conf = NetConf::parse(conf.as_bytes()).unwrap();
+        let options = store_options(&conf, FileStoreOptions::default());
+        assert_eq!((options.dir_mode, options.file_mode), (0o700, 0o600));
+        assert_eq!((options.uid, options.gid), (None, Some(gid)));
+
+        let args = CniArgs {
+            container_id: "c1".to_owned(),
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+        cmd_add(&args, &conf, FileStoreOptions::default()).unwrap();
+
+        let network_dir = std::path::Path::new(data_dir).join("n");
+        let metadata = std::fs::metadata(&network_dir).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        let metadata = std::fs::metadata(network_dir.join("10.1.2.2")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(metadata.gid(), gid);
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn pod_id_mapping() {
+        let data_dir = "/tmp/cni-id-mapping";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "idMapping": "pod",
+                "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+        let conf = NetConf::parse(conf.as_bytes()).unwrap();
+        let args = CniArgs {
+            container_id: "sandbox-1".to_owned(),
+            ifname: "eth0".to_owned(),
+            args: "K8S_POD_NAMESPACE=default;K8S_POD_NAME=web-0".to_owned(),
+            ..CniArgs::default()
+        };
+        let options = FileStoreOptions::default();
+
+        cmd_add(&args, &conf, options).unwrap();
+        let store = FileStore::new("n", data_dir).unwrap();
+        assert_eq!(store.get_by_id("default/web-0", "eth0").len(), 1);
+
+        // the pod's sandbox was recreated with a new container id
+        let recreated = CniArgs {
+            container_id: "sandbox-2".to_owned(),
+            ..args.clone()
+        };
+        cmd_del(&recreated, &conf, options).unwrap();
+        assert!(store.list().unwrap().is_empty());
+
+        let without_pod = CniArgs {
+            args: String::new(),
+            ..args
+        };
+        assert!(matches!(
+            cmd_add(&without_pod, &conf, options),
+            Err(PluginError::MissingArg("K8S_POD_NAMESPACE"))
+        ));
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn runtime_config_labels() {
+        let data_dir = "/tmp/cni-labels";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = format!(
+            r#"{{"name": "n", "runtimeConfig": {{"labels": {{"team": "web"}}}},
+                "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+        let conf = NetConf::parse(conf.as_bytes()).unwrap();
+        let args = CniArgs {
+            container_id: "c1".to_owned(),
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+
+        cmd_add(&args, &conf, FileStoreOptions::default()).unwrap();
+        let store = FileStore::new("n", data_dir).unwrap();
+        let ip = store.list().unwrap()[0];
+        assert_eq!(
+            store
+                .owner(ip)
+                .unwrap()
+                .labels
+                .get("team")
+                .map(String::as_str),
+            Some("web")
+        );
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn pinned_ips() {
+        let data_dir = "/tmp/cni-pins";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+        let conf = NetConf::parse(conf.as_bytes()).unwrap();
+        let store = FileStore::new("n", data_dir).unwrap();
+        let pins = store.data_dir().join(PINS_FILE);
+        std::fs::write(&pins, "c1 10.1.2.9\npod:lab/router 10.1.2.2\n").unwrap();
+        let add = |container_id: &str, args: &str| {
+            let args = CniArgs {
+                container_id: container_id.to_owned(),
+                ifname: "eth0".to_owned(),
+                args: args.to_owned(),
+                ..CniArgs::default()
+            };
+            cmd_add(&args, &conf, FileStoreOptions::default())
+        };
+        let ips = |id: &str| -> Vec<String> {
+            store
+                .get_by_id(id, "eth0")
+                .iter()
+                .map(IpAddr::to_string)
+                .collect()
+        };
+
+        add("c2", "").unwrap();
+        add("c1", "").unwrap();
+        add("c3", "K8S_POD_NAMESPACE=lab;K8S_POD_NAME=router").unwrap();
+        assert_eq!(ips("c2"), vec!["10.1.2.3"]);
+        assert_eq!(ips("c1"), vec!["10.1.2.9"]);
+        assert_eq!(ips("c3"), vec!["10.1.2.2"]);
+
+        // edits apply to the next ADD
+        std::fs::write(&pins, "c4 10.1.2.4\n").unwrap();
+        add("c4", "").unwrap();
+        assert_eq!(ips("c4"), vec!["10.1.2.4"]);
+        std::fs::write(&pins, "c5\n").unwrap();
+        assert!(matches!(
+            add("c5", ""),
+            Err(PluginError::AllocateError(
+                0,
+                AllocateError::StoreError(StoreError::Corrupt { .. })
+            ))
+        ));
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn requested_ips() {
+        let data_dir = "/tmp/cni-requested-ips";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = |ips: &str| {
+            let conf = format!(
+                r#"{{"name": "n", "runtimeConfig": {{"ips": [{}]}}, "ipam": {{"dataDir": "{}",
+                    "ranges": [[{{"subnet": "10.1.2.0/24"}}], [{{"subnet": "2001:db8::/64"}}]]}}}}"#,
+                ips, data_dir
+            );
+            NetConf::parse(conf.as_bytes()).unwrap()
+        };
+        let args = |container_id: &str| CniArgs {
+            container_id: container_id.to_owned(),
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+        let options = FileStoreOptions::default();
+
+        let result = cmd_add(
+            &args("c1"),
+            &conf(r#""2001:db8::5/64", "10.1.2.5""#),
+            options,
+        )
+        .unwrap();
+        let addresses: Vec<String> = result.ips.iter().map(|ip| ip.address.to_string()).collect();
+        assert_eq!(addresses, vec!["10.1.2.5/24", "2001:db8::5/64"]);
+
+        // the IPv4 reservation is rolled back when the IPv6 one fails
+        assert!(matches!(
+            cmd_add(&args("c2"), &conf(r#""10.1.2.6", "2001:db8::5""#), options),
+            Err(PluginError::AllocateError(
+                1,
+                AllocateError::IpNotAvailable(_)
+            ))
+        ));
+        let store = FileStore::new("n", data_dir).unwrap();
+        assert!(store.get_by_id("c2", "eth0").is_empty());
+
+        assert!(matches!(
+            cmd_add(&args("c3"), &conf(r#""10.1.3.6""#), options),
+            Err(PluginError::UnmatchedIp(_))
+        ));
+        assert!(matches!(
+            cmd_add(&args("c3"), &conf(r#""10.1.2.6", "10.1.2.7""#), options),
+            Err(PluginError::AmbiguousIps(_, _))
+        ));
+        assert!(store.get_by_id("c3", "eth0").is_empty());
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn merge_prev_result() {
+        let conf = NetConf::parse(
+            br#"{
+                "cniVersion": "1.0.0",
+                "name": "n",
+                "ipam": {
+                    "ranges": [[{"subnet": "10.1.2.0/24"}]],
+                    "routes": [{"dst": "0.0.0.0/0"}, {"dst": "10.9.0.0/16", "gw": "10.9.0.1"}, {"dst": "0.0.0.0/0"}]
+                },
+                "prevResult": {
+                    "cniVersion": "1.0.0",
+                    "interfaces": [
+                        {"name": "cni0", "mac": "0a:58:0a:01:02:01"},
+                        {"name": "eth0", "sandbox": "/var/run/netns/c1"}
+                    ],
+                    "ips": [
+                        {"interface": 1, "address": "10.9.0.2/24"},
+                        {"interface": 1, "address": "10.1.2.2/24", "gateway": "10.1.2.254"}
+                    ],
+                    "routes": [{"dst": "10.9.0.0/16", "gw": "10.9.0.1"}]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let ips = vec![
+            IpEntry {
+                version: None,
+                interface: None,
+                address: "10.1.2.2/24".parse().unwrap(),
+                gateway: Some("10.1.2.1".parse().unwrap()),
+            },
+            IpEntry {
+                version: None,
+                interface: None,
+                address: "10.1.3.2/24".parse().unwrap(),
+                gateway: Some("10.1.3.1".parse().unwrap()),
+            },
+        ];
+        let result = super::merge_prev_result(&conf, ips.clone());
+
+        assert_eq!(result.cni_version, "1.0.0");
+        assert_eq!(result.interfaces.len(), 2);
+        assert_eq!(
+            result.interfaces[1].sandbox.as_deref(),
+            Some("/var/run/netns/c1")
+        );
+        assert_eq!(result.ips.len(), 3);
+        assert_eq!(result.ips[0].interface, Some(1));
+        assert_eq!(result.ips[1].interface, Some(1));
+        assert_eq!(result.ips[1].gateway, ips[0].gateway);
+        assert_eq!(result.ips[2], ips[1]);
+        assert_eq!(
+            result
+                .routes
+                .iter()
+                .map(|r| r.dst.to_string())
+                .collect::<Vec<_>>(),
+            vec!["10.9.0.0/16", "0.0.0.0/0"]
+        );
+    }
+
+    #[test]
+    fn timed_phases() {
+        let data_dir = "/tmp/cni-timings";
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let conf = format!(
+            r#"{{"name": "n", "ipam": {{"dataDir": "{}", "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+            data_dir
+        );
+        let conf = NetConf::parse(conf.as_bytes()).unwrap();
+        let args = CniArgs {
+            container_id: "c1".to_owned(),
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+
+        let timings = Rc::new(Timings::new());
+        cmd_add_timed(&args, &conf, FileStoreOptions::default(), &timings).unwrap();
+        assert!(timings.get(Phase::LockAcquire) > Duration::from_secs(0));
+        assert!(timings.get(Phase::Reserve) > Duration::from_secs(0));
+        assert_eq!(timings.get(Phase::ConfigParse), Duration::from_secs(0));
+
+        let timings = Rc::new(Timings::new());
+        cmd_del_timed(&args, &conf, FileStoreOptions::default(), &timings).unwrap();
+        assert!(timings.get(Phase::Reserve) > Duration::from_secs(0));
+        assert!(timings.summary("DEL c1/eth0").contains(", reserve "));
+
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+}
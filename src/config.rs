@@ -0,0 +1,1214 @@
+//! Network configuration handed to the plugin by the container runtime on
+//! stdin, see the `host-local` section of the CNI plugins documentation.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt::Display;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use ipnetwork::IpNetwork;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use thiserror::Error;
+
+use super::allocator::partition::{self, PartitionError};
+use super::allocator::range::{Range, RangeError};
+use super::allocator::rangeset::{RangeSet, RangeSetError};
+use super::allocator::AllocationStrategy;
+use super::cni::{CniResult, Route};
+use super::error::report;
+use super::validation::ValidationErrors;
+
+/// Placeholder for the subnet assigned to the node, e.g. kubelet's podCIDR.
+pub const POD_CIDR_TOKEN: &str = "usePodCidr";
+
+/// Fields of a range entry which may hold template tokens.
+const TEMPLATE_FIELDS: &[&str] = &["subnet", "rangeStart", "rangeEnd", "gateway"];
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetConf {
+    #[serde(default)]
+    pub cni_version: String,
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub plugin_type: String,
+    pub ipam: IpamConf,
+    /// Result of the previous plugin when invoked as part of a chain.
+    #[serde(default)]
+    pub prev_result: Option<CniResult>,
+    /// Arguments the runtime passes for capabilities of the plugin.
+    #[serde(default)]
+    pub runtime_config: RuntimeConfig,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct RuntimeConfig {
+    /// Labels attached to the reservations of the container, see
+    /// `store::Labels`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// IPs requested through the `ips` capability, at most one per range
+    /// set, e.g. an IPv4 and an IPv6 address for dual-stack networks.
+    #[serde(default)]
+    pub ips: Vec<IpNetwork>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IpamConf {
+    #[serde(rename = "type", default)]
+    pub ipam_type: String,
+    /// Outer list holds range sets, inner list holds the ranges of one set,
+    /// like the upstream plugin's `"ranges": [[{...}, {...}], [{...}]]`.
+    #[serde(default, deserialize_with = "deserialize_ranges")]
+    pub ranges: Vec<Vec<RangeConf>>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    #[serde(default)]
+    pub data_dir: String,
+    #[serde(default)]
+    pub duplicate_id_check: DuplicateIdCheck,
+    /// Hosts file kept in sync with the allocations of the network.
+    #[serde(default)]
+    pub hosts_file: Option<HostsFileConf>,
+    /// Firewall sets kept in sync with the allocations of the network, only
+    /// honored when built with the `firewall-sets` feature.
+    #[serde(default)]
+    pub firewall_set: Option<FirewallSetConf>,
+    /// Number of IPs at the start of every range kept for explicit requests.
+    #[serde(default)]
+    pub reserved_head: usize,
+    /// Number of IPs at the end of every range kept for explicit requests.
+    #[serde(default)]
+    pub reserved_tail: usize,
+    /// Further IPs kept for explicit requests.
+    #[serde(rename = "reservedIPs", default)]
+    pub reserved_ips: Vec<IpAddr>,
+    /// Most IPs one container may hold in the network, counting all of its
+    /// interfaces.
+    #[serde(default)]
+    pub max_allocations_per_id: Option<usize>,
+    #[serde(default)]
+    pub static_mappings: Vec<StaticMapping>,
+    /// How free IPs are picked when none is requested.
+    #[serde(alias = "strategy", default)]
+    pub allocation_strategy: AllocationStrategy,
+    /// Keys range sets in the store by their position in `ranges` and keeps
+    /// their last reserved IPs in `last_reserved_ip.N` files, like upstream
+    /// host-local, instead of by `RangeSet::id` in one JSON file. Lets a
+    /// node switch from the Go plugin without allocation starting over.
+    /// Reservation files then hold only the container id and interface,
+    /// without network namespace, pod or labels, as the Go plugin expects.
+    #[serde(default)]
+    pub upstream_range_ids: bool,
+    /// What identifies the container in its reservations.
+    #[serde(default)]
+    pub id_mapping: IdMapping,
+    /// Keeps the reservations in a snapshot file and journal instead of a
+    /// file per IP, see `FileStoreOptions::journal`.
+    #[serde(default)]
+    pub journal: bool,
+    /// Keeps an index of the reservation files, see
+    /// `FileStoreOptions::index`.
+    #[serde(default)]
+    pub index: bool,
+    /// How long an ADD or DEL waits for the store's locks before failing
+    /// with a "try again later" error. Waits as long as it takes if unset.
+    #[serde(default)]
+    pub lock_timeout_ms: Option<u64>,
+    /// Permissions of the directories the store creates, an octal string
+    /// like `"0750"` or a number, 0755 if unset.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub dir_mode: Option<u32>,
+    /// Permissions of the files the store creates, like `dir_mode`, 0644
+    /// if unset.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub file_mode: Option<u32>,
+    /// Owner of what the store creates, the user running the plugin if
+    /// unset.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// Group of what the store creates, like `uid`.
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Which messages the plugin writes to stderr.
+    #[serde(default)]
+    pub log_level: LogLevel,
+    /// Key sealing the content of reservation files, see
+    /// `FileStore::encrypt_with_key_file`.
+    #[serde(default)]
+    pub encryption_key_file: Option<PathBuf>,
+    /// Collector receiving a trace of every command, only honored when
+    /// built with the `otel` feature.
+    #[serde(default)]
+    pub otel: Option<OtelConf>,
+}
+
+/// Fixed IP for the interface with MAC address `mac`, passed as `MAC=` in
+/// `CNI_ARGS`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct StaticMapping {
+    pub mac: String,
+    pub ip: IpAddr,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct HostsFileConf {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: HostsFormat,
+}
+
+/// `hosts` writes `/etc/hosts` style lines, `dnsmasq` writes lines for
+/// dnsmasq's `dhcp-hostsfile`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HostsFormat {
+    #[default]
+
+    Hosts,
+    Dnsmasq,
+}
+
+/// What reservations are keyed by, see `idmap`. `containerId` uses
+/// `CNI_CONTAINERID` as is, `shortId` its first 12 characters, `pod` the
+/// `K8S_POD_NAMESPACE` and `K8S_POD_NAME` of `CNI_ARGS`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum IdMapping {
+    #[default]
+
+    ContainerId,
+    ShortId,
+    Pod,
+}
+
+/// Severity of the messages written to stderr, from least to most verbose.
+/// Only warnings are written, `error` silences them.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    #[default]
+    Warning,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name {
+            "error" => Some(LogLevel::Error),
+            "warning" | "warn" => Some(LogLevel::Warning),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    /// Writes `message` as a warning unless the level is `Error`.
+    pub fn warn(self, message: impl Display) {
+        if self >= LogLevel::Warning {
+            eprintln!("warning: {}", message);
+        }
+    }
+
+    /// Writes `message` if the level is `Info` or `Debug`.
+    pub fn info(self, message: impl Display) {
+        if self >= LogLevel::Info {
+            eprintln!("info: {}", message);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct FirewallSetConf {
+    pub backend: FirewallBackend,
+    /// nftables table holding the sets, as `<family> <name>`.
+    #[serde(default = "default_firewall_table")]
+    pub table: String,
+    /// Prefix of the set names, defaults to the network name.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallBackend {
+    Nftables,
+    Ipset,
+}
+
+fn default_firewall_table() -> String {
+    "inet filter".to_owned()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OtelConf {
+    /// `host:port` of the OTLP/HTTP receiver of the collector.
+    #[serde(default = "default_otel_endpoint")]
+    pub endpoint: String,
+    /// `service.name` of the exported spans.
+    #[serde(default = "default_otel_service_name")]
+    pub service_name: String,
+    /// How long exporting may delay the command, one second if unset.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+fn default_otel_endpoint() -> String {
+    "127.0.0.1:4318".to_owned()
+}
+
+fn default_otel_service_name() -> String {
+    "host-local".to_owned()
+}
+
+/// What to do when the container already holds IPs of an overlapping subnet
+/// in another network of the same data dir.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateIdCheck {
+    #[default]
+
+    Off,
+    Warn,
+    Error,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeConf {
+    pub subnet: IpNetwork,
+    pub range_start: Option<IpAddr>,
+    pub range_end: Option<IpAddr>,
+    pub gateway: Option<IpAddr>,
+    /// Opts into /31 and /32 subnets without a gateway, see
+    /// `Range::point_to_point`.
+    #[serde(default)]
+    pub point_to_point: bool,
+    /// Allocates from this node's slice of `subnet` instead of all of it,
+    /// see `NodeSlice`.
+    #[serde(default)]
+    pub node_slice: Option<NodeSlice>,
+    /// Ranges of a higher priority in the range set are exhausted before
+    /// IPs of lower ones are allocated, e.g. to keep a range for bursts.
+    /// Defaults to 0.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// Splits the subnet of a range into `count` equal slices and keeps slice
+/// `index`, e.g. a /24 per node out of a /16 for 256 nodes. Lets every node
+/// of a cluster share the configuration, with only the index differing,
+/// see `NODE_INDEX_VAR`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct NodeSlice {
+    #[serde(default)]
+    pub index: u32,
+    pub count: u32,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to parse network configuration")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("failed to read network configuration")]
+    IOError(#[from] std::io::Error),
+
+    #[error("no IP ranges specified")]
+    NoRanges,
+
+    #[error(transparent)]
+    RangeError(#[from] RangeError),
+
+    #[error(transparent)]
+    RangeSetError(#[from] RangeSetError),
+
+    #[error("failed to resolve template {0}")]
+    UnresolvedTemplate(String),
+
+    #[error(transparent)]
+    PartitionError(#[from] PartitionError),
+
+    #[error("invalid value {value:?} of {name}")]
+    InvalidOverride { name: &'static str, value: String },
+
+    /// Every problem found in the ranges, see `IpamConf::validation_errors`
+    /// and `IpamConf::family_errors`.
+    #[error("invalid network configuration: {0}")]
+    Invalid(ValidationErrors),
+}
+
+impl ConfigError {
+    /// The problems of the configuration, as found or a single one without
+    /// location for errors which aren't about a field, e.g. invalid JSON.
+    pub fn validation_errors(&self) -> ValidationErrors {
+        match self {
+            ConfigError::Invalid(errors) => errors.clone(),
+            err => {
+                let mut errors = ValidationErrors::new();
+                errors.push("", report(err));
+                errors
+            }
+        }
+    }
+}
+
+/// Supplies the values substituted for template tokens in the ranges before
+/// they are parsed: `usePodCidr` and `{{ env "NAME" }}`.
+pub trait Resolver {
+    /// Subnet replacing `usePodCidr`.
+    fn pod_cidr(&self) -> Option<String>;
+
+    /// Value replacing `{{ env "NAME" }}`.
+    fn env(&self, name: &str) -> Option<String>;
+}
+
+/// Resolves templates from the environment of the plugin, `usePodCidr`
+/// from `POD_CIDR`.
+pub struct EnvResolver;
+
+impl Resolver for EnvResolver {
+    fn pod_cidr(&self) -> Option<String> {
+        self.env("POD_CIDR")
+    }
+
+    fn env(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+}
+
+/// Overrides the data dir of every network configuration.
+pub const DATA_DIR_VAR: &str = "HOST_LOCAL_DATA_DIR";
+/// Overrides `ipam.logLevel`, see `LogLevel`.
+pub const LOG_LEVEL_VAR: &str = "HOST_LOCAL_LOG_LEVEL";
+/// Overrides how reservations are stored: `files` for a file per IP,
+/// `index` for files plus an index, `journal` for a snapshot and journal.
+pub const STORE_VAR: &str = "HOST_LOCAL_STORE";
+/// Overrides the index of every `nodeSlice`, see `NodeSlice`.
+pub const NODE_INDEX_VAR: &str = "HOST_LOCAL_NODE_INDEX";
+
+/// Supplies the overrides applied on top of the parsed configuration, see
+/// `NetConf::apply_overrides`.
+pub trait ConfigSource {
+    /// Value of the override `name`, one of the `*_VAR` constants.
+    fn var(&self, name: &str) -> Option<String>;
+}
+
+/// Takes the overrides from the environment of the plugin.
+pub struct EnvSource;
+
+impl ConfigSource for EnvSource {
+    fn var(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+}
+
+impl ConfigSource for HashMap<String, String> {
+    fn var(&self, name: &str) -> Option<String> {
+        self.get(name).cloned()
+    }
+}
+
+impl NetConf {
+    /// Parses the configuration, resolving templates and applying overrides
+    /// from the environment.
+    pub fn parse(bytes: &[u8]) -> Result<NetConf, ConfigError> {
+        let mut conf = Self::parse_with(bytes, &EnvResolver)?;
+        conf.apply_overrides(&EnvSource)?;
+        Ok(conf)
+    }
+
+    /// Parses the configuration after substituting the template tokens of
+    /// the ranges with values from `resolver`.
+    pub fn parse_with(bytes: &[u8], resolver: &dyn Resolver) -> Result<NetConf, ConfigError> {
+        let mut value: Value = serde_json::from_slice(bytes).map_err(ConfigError::JsonError)?;
+
+        let conf: NetConf = if substitute_templates(&mut value, resolver)? {
+            serde_json::from_value(value).map_err(ConfigError::JsonError)?
+        } else {
+            // parse the original bytes so errors keep their line and column
+            serde_json::from_slice(bytes).map_err(ConfigError::JsonError)?
+        };
+
+        conf.ipam
+            .family_errors()
+            .into_result()
+            .map_err(ConfigError::Invalid)?;
+
+        Ok(conf)
+    }
+
+    /// Reads the configuration from `reader`, see `parse`.
+    pub fn load<R: Read>(reader: R) -> Result<NetConf, ConfigError> {
+        let mut conf = Self::load_with(reader, &EnvResolver)?;
+        conf.apply_overrides(&EnvSource)?;
+        Ok(conf)
+    }
+
+    pub fn load_with<R: Read>(
+        mut reader: R,
+        resolver: &dyn Resolver,
+    ) -> Result<NetConf, ConfigError> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(ConfigError::IOError)?;
+
+        Self::parse_with(&bytes, resolver)
+    }
+
+    /// Replaces settings of the configuration with the overrides `source`
+    /// holds. An override takes precedence over the JSON, which takes
+    /// precedence over the defaults. Empty overrides are ignored.
+    pub fn apply_overrides(&mut self, source: &dyn ConfigSource) -> Result<(), ConfigError> {
+        let var = |name| source.var(name).filter(|value| !value.is_empty());
+        let invalid = |name, value| ConfigError::InvalidOverride {
+            name: name,
+            value: value,
+        };
+
+        if let Some(data_dir) = var(DATA_DIR_VAR) {
+            self.ipam.data_dir = data_dir;
+        }
+
+        if let Some(level) = var(LOG_LEVEL_VAR) {
+            self.ipam.log_level =
+                LogLevel::from_name(&level).ok_or_else(|| invalid(LOG_LEVEL_VAR, level))?;
+        }
+
+        if let Some(store) = var(STORE_VAR) {
+            let (journal, index) = match store.as_str() {
+                "files" => (false, false),
+                "index" => (false, true),
+                "journal" => (true, false),
+                _ => return Err(invalid(STORE_VAR, store)),
+            };
+            self.ipam.journal = journal;
+            self.ipam.index = index;
+        }
+
+        if let Some(index) = var(NODE_INDEX_VAR) {
+            let index = index.parse().map_err(|_| invalid(NODE_INDEX_VAR, index))?;
+            for range in self.ipam.ranges.iter_mut().flatten() {
+                if let Some(node_slice) = &mut range.node_slice {
+                    node_slice.index = index;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl IpamConf {
+    /// Checks every configured range instead of stopping at the first
+    /// problem. Each error is located at the offending field, e.g.
+    /// `ipam.ranges[0][1]`.
+    pub fn validation_errors(&self) -> ValidationErrors {
+        let mut errors = ValidationErrors::new();
+        self.collect_range_sets(&mut errors);
+        errors
+    }
+
+    /// Finds the ranges whose subnet is of another IP family than the first
+    /// range of their set, across all sets. A range set only allocates from
+    /// one family, the other family belongs into a range set of its own.
+    pub fn family_errors(&self) -> ValidationErrors {
+        let mut errors = ValidationErrors::new();
+
+        for (i, ranges) in self.ranges.iter().enumerate() {
+            let first = match ranges.first() {
+                Some(first) => first.subnet,
+                None => continue,
+            };
+
+            for (j, range) in ranges.iter().enumerate().skip(1) {
+                if range.subnet.is_ipv4() != first.is_ipv4() {
+                    errors.push(
+                        format!("ipam.ranges[{}][{}]", i, j),
+                        format!(
+                            "{} subnet {} in the {} range set of ipam.ranges[{}][0] ({})",
+                            family(range.subnet),
+                            range.subnet,
+                            family(first),
+                            i,
+                            first
+                        ),
+                    );
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Validates the configured ranges and builds one `RangeSet` per entry of
+    /// `ranges`. Fails with every problem found, see `validation_errors`.
+    pub fn range_sets(&self) -> Result<Vec<RangeSet>, ConfigError> {
+        let mut errors = ValidationErrors::new();
+        let range_sets = self.collect_range_sets(&mut errors);
+        errors.into_result().map_err(ConfigError::Invalid)?;
+
+        Ok(range_sets)
+    }
+
+    /// Builds the range sets, recording the problems of the ranges left out
+    /// in `errors`.
+    fn collect_range_sets(&self, errors: &mut ValidationErrors) -> Vec<RangeSet> {
+        if self.ranges.is_empty() {
+            errors.push("ipam.ranges", ConfigError::NoRanges);
+        }
+
+        let mut range_sets = Vec::with_capacity(self.ranges.len());
+        for (i, ranges) in self.ranges.iter().enumerate() {
+            if ranges.is_empty() {
+                errors.push(format!("ipam.ranges[{}]", i), ConfigError::NoRanges);
+            }
+
+            let ranges = ranges.iter().enumerate().map(|(j, range)| {
+                let location = format!("ipam.ranges[{}][{}]", i, j);
+                (location, range.to_range(), range.priority)
+            });
+            range_sets.push(RangeSet::collect(ranges, errors));
+        }
+
+        range_sets
+    }
+}
+
+impl IpamConf {
+    /// IP of `range_set` mapped to `mac`, MAC addresses are compared
+    /// ignoring case.
+    pub fn static_ip_for(&self, mac: &str, range_set: &RangeSet) -> Option<IpAddr> {
+        self.static_mappings
+            .iter()
+            .find(|mapping| mapping.mac.eq_ignore_ascii_case(mac) && range_set.contains(mapping.ip))
+            .map(|mapping| mapping.ip)
+    }
+
+    /// IPs of `range_set` which are never allocated dynamically, see
+    /// `reserved_head`, `reserved_tail` and `reserved_ips`.
+    pub fn reserved_ips_for(&self, range_set: &RangeSet) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = range_set
+            .iter()
+            .flat_map(|range| range.edge_ips(self.reserved_head, self.reserved_tail))
+            .collect();
+
+        ips.extend(
+            self.reserved_ips
+                .iter()
+                .filter(|ip| range_set.contains(**ip)),
+        );
+
+        ips
+    }
+}
+
+impl RangeConf {
+    /// The subnet allocated from, the node's slice of `subnet` if
+    /// `node_slice` is set.
+    pub fn node_subnet(&self) -> Result<IpNetwork, ConfigError> {
+        match self.node_slice {
+            Some(node_slice) => {
+                partition::node_slice(self.subnet, node_slice.index, node_slice.count)
+                    .map_err(ConfigError::PartitionError)
+            }
+            None => Ok(self.subnet),
+        }
+    }
+
+    pub fn to_range(&self) -> Result<Range, ConfigError> {
+        let subnet = self.node_subnet()?;
+        let range = match (self.point_to_point, self.gateway) {
+            (false, _) => Range::new(subnet, self.range_start, self.range_end, self.gateway),
+            (true, None) => Range::point_to_point(subnet, self.range_start, self.range_end),
+            (true, Some(_)) => Err(RangeError::PointToPointGateway(subnet)),
+        };
+
+        range.map_err(ConfigError::RangeError)
+    }
+}
+
+/// Deserializes `ranges` as a list of range sets, each a list of ranges.
+/// Errors name the offending range set and range, e.g. `ipam.ranges[1][0]`,
+/// as serde would only point at the end of the whole list.
+fn deserialize_ranges<'de, D>(deserializer: D) -> Result<Vec<Vec<RangeConf>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let range_sets = match Value::deserialize(deserializer)? {
+        Value::Array(range_sets) => range_sets,
+        value => {
+            return Err(D::Error::custom(format!(
+                "ipam.ranges: expected a list of range sets, found {}",
+                json_type(&value)
+            )))
+        }
+    };
+
+    let mut ranges = Vec::with_capacity(range_sets.len());
+    for (i, range_set) in range_sets.into_iter().enumerate() {
+        let range_set = match range_set {
+            Value::Array(range_set) => range_set,
+            // e.g. `"ranges": [{"subnet": ...}]`, a range without its set
+            value => {
+                return Err(D::Error::custom(format!(
+                    "ipam.ranges[{}]: expected a list of ranges, found {}",
+                    i,
+                    json_type(&value)
+                )))
+            }
+        };
+
+        let mut set = Vec::with_capacity(range_set.len());
+        for (j, range) in range_set.into_iter().enumerate() {
+            let range = RangeConf::deserialize(range)
+                .map_err(|err| D::Error::custom(format!("ipam.ranges[{}][{}]: {}", i, j, err)))?;
+            set.push(range);
+        }
+        ranges.push(set);
+    }
+
+    Ok(ranges)
+}
+
+/// Deserializes a file mode given as an octal string, e.g. `"0750"`, or
+/// as a number, JSON has no octal literals.
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mode = match Value::deserialize(deserializer)? {
+        Value::Null => return Ok(None),
+        Value::String(mode) => u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .map_err(|_| D::Error::custom(format!("invalid octal file mode {:?}", mode)))?,
+        Value::Number(mode) => mode
+            .as_u64()
+            .and_then(|mode| u32::try_from(mode).ok())
+            .ok_or_else(|| D::Error::custom(format!("invalid file mode {}", mode)))?,
+        value => {
+            return Err(D::Error::custom(format!(
+                "expected a file mode, found {}",
+                json_type(&value)
+            )))
+        }
+    };
+
+    if mode > 0o7777 {
+        return Err(D::Error::custom(format!("invalid file mode {:o}", mode)));
+    }
+
+    Ok(Some(mode))
+}
+
+fn family(subnet: IpNetwork) -> &'static str {
+    if subnet.is_ipv4() {
+        "IPv4"
+    } else {
+        "IPv6"
+    }
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Replaces the template tokens in the ranges of `conf`, returns whether
+/// anything was replaced.
+fn substitute_templates(conf: &mut Value, resolver: &dyn Resolver) -> Result<bool, ConfigError> {
+    let range_sets = match conf
+        .pointer_mut("/ipam/ranges")
+        .and_then(Value::as_array_mut)
+    {
+        Some(range_sets) => range_sets,
+        None => return Ok(false),
+    };
+
+    let mut substituted = false;
+    for ranges in range_sets.iter_mut().filter_map(Value::as_array_mut) {
+        for range in ranges.iter_mut().filter_map(Value::as_object_mut) {
+            for field in TEMPLATE_FIELDS {
+                if let Some(Value::String(value)) = range.get_mut(*field) {
+                    if let Some(resolved) = resolve_template(value, resolver)? {
+                        *value = resolved;
+                        substituted = true;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(substituted)
+}
+
+/// Returns `None` if `value` holds no template token.
+fn resolve_template(value: &str, resolver: &dyn Resolver) -> Result<Option<String>, ConfigError> {
+    if value == POD_CIDR_TOKEN {
+        return resolver
+            .pod_cidr()
+            .map(Some)
+            .ok_or_else(|| ConfigError::UnresolvedTemplate(value.to_owned()));
+    }
+
+    let mut resolved = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end + 2)
+            .ok_or_else(|| ConfigError::UnresolvedTemplate(value.to_owned()))?;
+
+        let template = &rest[start..end];
+        let name = template[2..template.len() - 2]
+            .trim()
+            .strip_prefix("env")
+            .map(|name| name.trim().trim_matches('"'))
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| ConfigError::UnresolvedTemplate(template.to_owned()))?;
+
+        let env = resolver
+            .env(name)
+            .ok_or_else(|| ConfigError::UnresolvedTemplate(template.to_owned()))?;
+
+        resolved.push_str(&rest[..start]);
+        resolved.push_str(&env);
+        rest = &rest[end..];
+    }
+
+    if rest.len() == value.len() {
+        return Ok(None);
+    }
+
+    resolved.push_str(rest);
+    Ok(Some(resolved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "cniVersion": "0.4.0",
+        "name": "mynet",
+        "type": "bridge",
+        "ipam": {
+            "type": "host-local",
+            "ranges": [
+                [
+                    {"subnet": "10.1.2.0/24", "rangeStart": "10.1.2.9", "rangeEnd": "10.1.2.20", "gateway": "10.1.2.30"},
+                    {"subnet": "10.1.4.0/24"}
+                ],
+                [{"subnet": "2001:db8:1::0/64"}]
+            ],
+            "dataDir": "/tmp/cni/networks",
+            "duplicateIdCheck": "warn",
+            "hostsFile": {"path": "/tmp/cni/hosts", "format": "dnsmasq"}
+        }
+    }"#;
+
+    #[test]
+    fn parse() {
+        let conf = NetConf::load(CONFIG.as_bytes()).unwrap();
+
+        assert_eq!(conf.name, "mynet");
+        assert_eq!(conf.ipam.ipam_type, "host-local");
+        assert_eq!(conf.ipam.data_dir, "/tmp/cni/networks");
+        assert_eq!(conf.ipam.duplicate_id_check, DuplicateIdCheck::Warn);
+        assert_eq!(
+            conf.ipam.hosts_file,
+            Some(HostsFileConf {
+                path: PathBuf::from("/tmp/cni/hosts"),
+                format: HostsFormat::Dnsmasq,
+            })
+        );
+        assert_eq!(conf.ipam.ranges.len(), 2);
+        assert_eq!(
+            conf.ipam.ranges[0][0].range_start,
+            Some("10.1.2.9".parse().unwrap())
+        );
+
+        let range_sets = conf.ipam.range_sets().unwrap();
+        assert_eq!(range_sets.len(), 2);
+        assert_eq!(range_sets[0].len(), 2);
+        assert_eq!(range_sets[1].len(), 1);
+    }
+
+    #[test]
+    fn strategy() {
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"strategy": "descending", "ranges": [[{"subnet": "10.1.2.0/24"}]]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.ipam.allocation_strategy,
+            AllocationStrategy::Descending
+        );
+
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"strategy": "balance", "ranges": [[{"subnet": "10.1.2.0/24"}]]}}"#,
+        )
+        .unwrap();
+        assert_eq!(conf.ipam.allocation_strategy, AllocationStrategy::Balance);
+    }
+
+    #[test]
+    fn overrides() {
+        let mut conf = NetConf::load(CONFIG.as_bytes()).unwrap();
+        assert_eq!(conf.ipam.log_level, LogLevel::Warning);
+
+        let mut source = HashMap::new();
+        source.insert(DATA_DIR_VAR.to_owned(), "/run/cni".to_owned());
+        source.insert(LOG_LEVEL_VAR.to_owned(), "error".to_owned());
+        source.insert(STORE_VAR.to_owned(), "journal".to_owned());
+        conf.apply_overrides(&source).unwrap();
+        assert_eq!(conf.ipam.data_dir, "/run/cni");
+        assert_eq!(conf.ipam.log_level, LogLevel::Error);
+        assert!(conf.ipam.journal && !conf.ipam.index);
+
+        // unset and empty overrides keep what is configured
+        let mut source = HashMap::new();
+        source.insert(DATA_DIR_VAR.to_owned(), String::new());
+        conf.apply_overrides(&source).unwrap();
+        assert_eq!(conf.ipam.data_dir, "/run/cni");
+
+        source.insert(STORE_VAR.to_owned(), "sqlite".to_owned());
+        assert!(matches!(
+            conf.apply_overrides(&source),
+            Err(ConfigError::InvalidOverride {
+                name: STORE_VAR,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(matches!(
+            NetConf::parse(br#"{"name": 1}"#),
+            Err(ConfigError::JsonError(_))
+        ));
+        assert!(matches!(
+            NetConf::parse(br#"{"name": "n", "ipam": {"ranges": [[{"subnet": "10.1.2.0/33"}]]}}"#),
+            Err(ConfigError::JsonError(_))
+        ));
+    }
+
+    #[test]
+    fn file_modes() {
+        let parse = |modes: &str| {
+            let conf = format!(r#"{{"name": "n", "ipam": {{{}}}}}"#, modes);
+            NetConf::parse(conf.as_bytes()).map(|conf| (conf.ipam.dir_mode, conf.ipam.file_mode))
+        };
+
+        assert_eq!(parse("").unwrap(), (None, None));
+        assert_eq!(
+            parse(r#""dirMode": "0750", "fileMode": 416"#).unwrap(),
+            (Some(0o750), Some(0o640))
+        );
+        assert_eq!(parse(r#""dirMode": "0o700""#).unwrap().0, Some(0o700));
+        for invalid in &[
+            r#""dirMode": "0789""#,
+            r#""fileMode": -1"#,
+            r#""fileMode": "77777""#,
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_ranges_shape() {
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"ranges": [
+                [{"subnet": "10.1.2.0/24"}, {"subnet": "10.1.3.0/24"}],
+                [{"subnet": "2001:db8::/64"}]
+            ]}}"#,
+        )
+        .unwrap();
+        let subnets: Vec<Vec<String>> = conf
+            .ipam
+            .ranges
+            .iter()
+            .map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|range| range.subnet.to_string())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            subnets,
+            vec![vec!["10.1.2.0/24", "10.1.3.0/24"], vec!["2001:db8::/64"]]
+        );
+
+        let error = |ranges: &str| {
+            let conf = format!(r#"{{"name": "n", "ipam": {{"ranges": {}}}}}"#, ranges);
+            match NetConf::parse(conf.as_bytes()) {
+                Err(ConfigError::JsonError(err)) => err.to_string(),
+                result => panic!("{:?}", result),
+            }
+        };
+        assert!(error(r#"{"subnet": "10.1.2.0/24"}"#)
+            .starts_with("ipam.ranges: expected a list of range sets, found an object"));
+        assert!(
+            error(r#"[[{"subnet": "10.1.2.0/24"}], {"subnet": "10.1.3.0/24"}]"#)
+                .starts_with("ipam.ranges[1]: expected a list of ranges, found an object")
+        );
+        assert!(
+            error(r#"[[{"subnet": "10.1.2.0/24"}, {"subnet": "10.1.3.0/33"}]]"#)
+                .starts_with("ipam.ranges[0][1]: ")
+        );
+        assert!(error(r#"[[], [{"rangeStart": "10.1.2.9"}]]"#)
+            .starts_with("ipam.ranges[1][0]: missing field `subnet`"));
+    }
+
+    struct StaticResolver;
+
+    impl Resolver for StaticResolver {
+        fn pod_cidr(&self) -> Option<String> {
+            Some("10.244.1.0/24".to_owned())
+        }
+
+        fn env(&self, name: &str) -> Option<String> {
+            match name {
+                "NODE_PREFIX" => Some("10.245".to_owned()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn parse_with_templates() {
+        let conf = NetConf::parse_with(
+            br#"{"name": "n", "ipam": {"ranges": [
+                [{"subnet": "usePodCidr"}],
+                [{"subnet": "{{ env \"NODE_PREFIX\" }}.0.0/16", "gateway": "{{env \"NODE_PREFIX\"}}.0.254"}]
+            ]}}"#,
+            &StaticResolver,
+        )
+        .unwrap();
+
+        assert_eq!(
+            conf.ipam.ranges[0][0].subnet,
+            "10.244.1.0/24".parse().unwrap()
+        );
+        assert_eq!(
+            conf.ipam.ranges[1][0].subnet,
+            "10.245.0.0/16".parse().unwrap()
+        );
+        assert_eq!(
+            conf.ipam.ranges[1][0].gateway,
+            Some("10.245.0.254".parse().unwrap())
+        );
+
+        assert!(matches!(
+            NetConf::parse_with(
+                br#"{"name": "n", "ipam": {"ranges": [[{"subnet": "{{ env \"MISSING\" }}/24"}]]}}"#,
+                &StaticResolver,
+            ),
+            Err(ConfigError::UnresolvedTemplate(template)) if template == "{{ env \"MISSING\" }}"
+        ));
+    }
+
+    #[test]
+    fn reserved_ips_for() {
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {
+                "ranges": [[{"subnet": "10.1.2.0/24", "rangeEnd": "10.1.2.100"}]],
+                "reservedHead": 2,
+                "reservedTail": 1,
+                "reservedIPs": ["10.1.2.50", "10.1.3.50"]
+            }}"#,
+        )
+        .unwrap();
+
+        let range_sets = conf.ipam.range_sets().unwrap();
+        let ips: Vec<String> = conf
+            .ipam
+            .reserved_ips_for(&range_sets[0])
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect();
+        assert_eq!(ips, vec!["10.1.2.2", "10.1.2.3", "10.1.2.100", "10.1.2.50"]);
+    }
+
+    #[test]
+    fn static_ip_for() {
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {
+                "ranges": [[{"subnet": "10.1.2.0/24"}], [{"subnet": "2001:db8::/64"}]],
+                "staticMappings": [
+                    {"mac": "0A:58:0A:01:02:09", "ip": "10.1.2.9"},
+                    {"mac": "0a:58:0a:01:02:09", "ip": "2001:db8::9"}
+                ]
+            }}"#,
+        )
+        .unwrap();
+
+        let range_sets = conf.ipam.range_sets().unwrap();
+        assert_eq!(
+            conf.ipam.static_ip_for("0a:58:0a:01:02:09", &range_sets[0]),
+            Some("10.1.2.9".parse().unwrap())
+        );
+        assert_eq!(
+            conf.ipam.static_ip_for("0a:58:0a:01:02:09", &range_sets[1]),
+            Some("2001:db8::9".parse().unwrap())
+        );
+        assert_eq!(
+            conf.ipam.static_ip_for("0a:58:0a:01:02:10", &range_sets[0]),
+            None
+        );
+    }
+
+    #[test]
+    fn validation_errors() {
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"ranges": [
+                [{"subnet": "10.1.2.0/31"}, {"subnet": "10.1.3.0/24", "gateway": "10.1.4.1"}],
+                [{"subnet": "10.1.2.0/24"}, {"subnet": "10.1.2.0/25"}, {"subnet": "10.1.2.0/26", "gateway": "10.1.3.1"}],
+                []
+            ]}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            conf.ipam.validation_errors().lines(),
+            vec![
+                "ipam.ranges[0][0]: Network 10.1.2.0/31 too small to allocate from",
+                "ipam.ranges[0][1]: Gateway 10.1.4.1 is out of network 10.1.3.0/24",
+                "ipam.ranges[1][1]: subnet (10.1.2.1, 10.1.2.254) overlaps with subnet (10.1.2.1, 10.1.2.126)",
+                "ipam.ranges[1][2]: Gateway 10.1.3.1 is out of network 10.1.2.0/26",
+                "ipam.ranges[2]: no IP ranges specified",
+            ]
+        );
+    }
+
+    #[test]
+    fn mixed_families() {
+        let err = NetConf::parse(
+            br#"{"name": "n", "ipam": {"ranges": [
+                [{"subnet": "10.1.2.0/24"}, {"subnet": "2001:db8::/64"}, {"subnet": "10.1.3.0/24"}],
+                [{"subnet": "2001:db8:1::/64"}],
+                [{"subnet": "2001:db8:2::/64"}, {"subnet": "10.1.4.0/24"}]
+            ]}}"#,
+        )
+        .unwrap_err();
+
+        match err {
+            ConfigError::Invalid(errors) => assert_eq!(
+                errors.lines(),
+                vec![
+                    "ipam.ranges[0][1]: IPv6 subnet 2001:db8::/64 in the IPv4 range set of ipam.ranges[0][0] (10.1.2.0/24)",
+                    "ipam.ranges[2][1]: IPv4 subnet 10.1.4.0/24 in the IPv6 range set of ipam.ranges[2][0] (2001:db8:2::/64)",
+                ]
+            ),
+            err => panic!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn range_sets_validation() {
+        let range_set_errors = |conf: NetConf| match conf.ipam.range_sets() {
+            Err(ConfigError::Invalid(errors)) => errors.lines(),
+            result => panic!("{:?}", result),
+        };
+
+        let conf = NetConf::parse(br#"{"name": "n", "ipam": {}}"#).unwrap();
+        assert_eq!(
+            range_set_errors(conf),
+            vec!["ipam.ranges: no IP ranges specified"]
+        );
+
+        let conf =
+            NetConf::parse(br#"{"name": "n", "ipam": {"ranges": [[{"subnet": "10.1.2.1/24"}]]}}"#)
+                .unwrap();
+        assert_eq!(
+            range_set_errors(conf),
+            vec!["ipam.ranges[0][0]: Network address of subnet 10.1.2.1/24 should be 10.1.2.0"]
+        );
+
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"ranges": [
+                [{"subnet": "10.1.2.0/31", "pointToPoint": true}],
+                [{"subnet": "10.1.3.0/31", "pointToPoint": true, "gateway": "10.1.3.1"}]
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(conf.ipam.ranges[0][0].to_range().unwrap().gateway, None);
+        assert_eq!(
+            range_set_errors(conf),
+            vec!["ipam.ranges[1][0]: Point-to-point network 10.1.3.0/31 has no gateway"]
+        );
+
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"ranges": [[{"subnet": "10.1.2.0/24"}, {"subnet": "10.1.2.0/25"}]]}}"#,
+        )
+        .unwrap();
+        let errors = range_set_errors(conf);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("ipam.ranges[0][1]: "), "{:?}", errors);
+    }
+
+    #[test]
+    fn range_priority() {
+        let conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"ranges": [[
+                {"subnet": "10.1.2.0/24", "priority": -1},
+                {"subnet": "10.1.3.0/24"}
+            ]]}}"#,
+        )
+        .unwrap();
+        let range_sets = conf.ipam.range_sets().unwrap();
+
+        let tiers: Vec<String> = range_sets[0]
+            .tiers()
+            .iter()
+            .map(|tier| tier.get(0).unwrap().subnet.to_string())
+            .collect();
+        assert_eq!(tiers, vec!["10.1.3.0/24", "10.1.2.0/24"]);
+    }
+
+    #[test]
+    fn node_slice() {
+        let mut conf = NetConf::parse(
+            br#"{"name": "n", "ipam": {"ranges": [
+                [{"subnet": "10.0.0.0/16", "nodeSlice": {"index": 3, "count": 256}}]
+            ]}}"#,
+        )
+        .unwrap();
+        let range = conf.ipam.ranges[0][0].to_range().unwrap();
+        assert_eq!(range.subnet, "10.0.3.0/24".parse::<IpNetwork>().unwrap());
+        assert_eq!(range.gateway, Some("10.0.3.1".parse().unwrap()));
+
+        let mut source = HashMap::new();
+        source.insert(NODE_INDEX_VAR.to_owned(), "7".to_owned());
+        conf.apply_overrides(&source).unwrap();
+        let range = conf.ipam.ranges[0][0].to_range().unwrap();
+        assert_eq!(range.subnet, "10.0.7.0/24".parse::<IpNetwork>().unwrap());
+
+        source.insert(NODE_INDEX_VAR.to_owned(), "256".to_owned());
+        conf.apply_overrides(&source).unwrap();
+        assert_eq!(
+            conf.ipam.range_sets().unwrap_err().to_string(),
+            format!(
+                "invalid network configuration: ipam.ranges[0][0]: {}",
+                PartitionError::OutOfRangeIndex(256, 256)
+            )
+        );
+    }
+}
@@ -0,0 +1,661 @@
+//! Address arithmetic of ranges, free of allocation and the filesystem.
+//!
+//! Everything here only needs `core`, so agents without `std` can share the
+//! range validation, capacity and iteration math of the plugin by building
+//! the crate without its default `std` feature. `Subnet`, `Range` and
+//! `RangeSet` are built on `core::net`. The `Range` and `RangeSet` of the
+//! allocator wrap them with an `IpNetwork`, parsing and serialization, and
+//! come with `std`.
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// `ip` as a number, IPv4 addresses taking the low 32 bits.
+pub fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// The IP of family `ipv4` numbered `value`, see `to_u128`. IPv4 addresses
+/// keep the low 32 bits.
+pub fn from_u128(value: u128, ipv4: bool) -> IpAddr {
+    if ipv4 {
+        IpAddr::V4(Ipv4Addr::from(value as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(value))
+    }
+}
+
+/// The IP following `ip`, wrapping around at the end of its family.
+pub fn next_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip).wrapping_add(1))),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip).wrapping_add(1))),
+    }
+}
+
+/// The IP preceding `ip`, wrapping around at the start of its family.
+pub fn prev_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip).wrapping_sub(1))),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip).wrapping_sub(1))),
+    }
+}
+
+/// Number of IPs from `start` to `end`, both included, 0 if `end` comes
+/// first. Saturates for a whole IPv6 address space.
+pub fn span(start: IpAddr, end: IpAddr) -> u128 {
+    let (start, end) = (to_u128(start), to_u128(end));
+    if start > end {
+        return 0;
+    }
+
+    (end - start).saturating_add(1)
+}
+
+/// The IP `n` positions after `start`, wrapping around at `end`.
+pub fn nth_ip(start: IpAddr, end: IpAddr, n: u128) -> Option<IpAddr> {
+    let size = span(start, end);
+    if size == 0 {
+        return None;
+    }
+
+    Some(from_u128(to_u128(start) + n % size, start.is_ipv4()))
+}
+
+/// Number of IPs from `start` to `end` which can be allocated, i.e. all but
+/// the `gateway`.
+pub fn capacity(start: IpAddr, end: IpAddr, gateway: Option<IpAddr>) -> u128 {
+    let mut capacity = span(start, end);
+    if capacity > 0 && gateway.is_some_and(|gateway| start <= gateway && gateway <= end) {
+        capacity -= 1;
+    }
+
+    capacity
+}
+
+/// `Iterator::size_hint` for `count` items left. Exact unless `count`
+/// exceeds `usize`, e.g. for IPv6 ranges of more than 64 bits, then the
+/// upper bound is unknown and `ExactSizeIterator::len` panics.
+pub fn size_hint(count: u128) -> (usize, Option<usize>) {
+    match usize::try_from(count) {
+        Ok(count) => (count, Some(count)),
+        Err(_) => (usize::MAX, None),
+    }
+}
+
+/// The IPs from `start` to `end` in order, skipping `gateway`.
+#[derive(Clone, Debug)]
+pub struct Ips {
+    next: Option<u128>,
+    end: u128,
+    gateway: Option<u128>,
+    ipv4: bool,
+}
+
+impl Ips {
+    pub fn new(start: IpAddr, end: IpAddr, gateway: Option<IpAddr>) -> Ips {
+        let (first, last) = (to_u128(start), to_u128(end));
+        Ips {
+            next: if first <= last { Some(first) } else { None },
+            end: last,
+            gateway: gateway.map(to_u128),
+            ipv4: start.is_ipv4(),
+        }
+    }
+}
+
+impl Iterator for Ips {
+    type Item = IpAddr;
+
+    fn next(&mut self) -> Option<IpAddr> {
+        loop {
+            let ip = self.next?;
+            self.next = if ip < self.end { Some(ip + 1) } else { None };
+
+            if Some(ip) != self.gateway {
+                return Some(from_u128(ip, self.ipv4));
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let count = match self.next {
+            Some(next) => {
+                let gateway = self
+                    .gateway
+                    .is_some_and(|gateway| next <= gateway && gateway <= self.end);
+                (self.end - next).saturating_add(1) - gateway as u128
+            }
+            None => 0,
+        };
+
+        size_hint(count)
+    }
+}
+
+impl DoubleEndedIterator for Ips {
+    fn next_back(&mut self) -> Option<IpAddr> {
+        loop {
+            let next = self.next?;
+            let ip = self.end;
+            if ip > next {
+                self.end -= 1;
+            } else {
+                self.next = None;
+            }
+
+            if Some(ip) != self.gateway {
+                return Some(from_u128(ip, self.ipv4));
+            }
+        }
+    }
+}
+
+/// Exact unless more IPs are left than `usize` holds, see `size_hint`.
+impl ExactSizeIterator for Ips {}
+
+/// An IP network, the address of the network and the length of its prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// None if `prefix` is longer than the addresses of the family of `ip`.
+    pub fn new(ip: IpAddr, prefix: u8) -> Option<Subnet> {
+        if prefix > bits(ip) {
+            return None;
+        }
+
+        Some(Subnet {
+            ip: ip,
+            prefix: prefix,
+        })
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.ip.is_ipv4()
+    }
+
+    /// The first IP of the subnet.
+    pub fn network(&self) -> IpAddr {
+        from_u128(to_u128(self.ip) & self.mask(), self.is_ipv4())
+    }
+
+    /// The last IP of the subnet, the broadcast address for IPv4.
+    pub fn last(&self) -> IpAddr {
+        let host_bits = !self.mask() & family_mask(self.ip);
+        from_u128(to_u128(self.ip) | host_bits, self.is_ipv4())
+    }
+
+    /// Where ranges of the subnet end by default: before the broadcast
+    /// address for IPv4, at the last IP for IPv6.
+    pub fn last_host(&self) -> IpAddr {
+        if self.is_ipv4() {
+            prev_ip(self.last())
+        } else {
+            self.last()
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.is_ipv4() && to_u128(ip) & self.mask() == to_u128(self.network())
+    }
+
+    fn mask(&self) -> u128 {
+        let host_bits = u32::from(bits(self.ip) - self.prefix);
+        let host_mask = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+        family_mask(self.ip) & !host_mask
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+/// Number of bits of the addresses of the family of `ip`.
+fn bits(ip: IpAddr) -> u8 {
+    if ip.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+/// The bits `to_u128` uses for the family of `ip`.
+fn family_mask(ip: IpAddr) -> u128 {
+    if ip.is_ipv4() {
+        u32::MAX as u128
+    } else {
+        u128::MAX
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RangeError {
+    TooSmallNetwork(Subnet),
+    WrongNetworkAddr(Subnet, IpAddr),
+    OutOfRangeIp(Subnet, IpAddr),
+    OutOfRangeGateway(Subnet, IpAddr),
+    NotPointToPoint(Subnet),
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RangeError::TooSmallNetwork(subnet) => {
+                write!(f, "Network {} too small to allocate from", subnet)
+            }
+            RangeError::WrongNetworkAddr(subnet, ip) => {
+                write!(f, "Network address of subnet {} should be {}", subnet, ip)
+            }
+            RangeError::OutOfRangeIp(subnet, ip) => {
+                write!(f, "IP {} is out of network {}", ip, subnet)
+            }
+            RangeError::OutOfRangeGateway(subnet, ip) => {
+                write!(f, "Gateway {} is out of network {}", ip, subnet)
+            }
+            RangeError::NotPointToPoint(subnet) => {
+                write!(
+                    f,
+                    "Network {} is too large for a point-to-point range",
+                    subnet
+                )
+            }
+        }
+    }
+}
+
+/// The IPs from `start` to `end` of `subnet`, all but the `gateway` can be
+/// allocated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub subnet: Subnet,
+    pub start: IpAddr,
+    pub end: IpAddr,
+    /// Unset for point-to-point ranges, see `Range::point_to_point`.
+    pub gateway: Option<IpAddr>,
+}
+
+impl Range {
+    /// Validates the bounds and gateway, which default to the first IP
+    /// after the network address and the last host of the subnet.
+    pub fn new(
+        subnet: Subnet,
+        start: Option<IpAddr>,
+        end: Option<IpAddr>,
+        gateway: Option<IpAddr>,
+    ) -> Result<Range, RangeError> {
+        // a subnet needs room for at least the network address and one host
+        if subnet.prefix() + 2 > bits(subnet.ip()) {
+            return Err(RangeError::TooSmallNetwork(subnet));
+        }
+
+        if subnet.ip() != subnet.network() {
+            return Err(RangeError::WrongNetworkAddr(subnet, subnet.network()));
+        }
+
+        let first_host = next_ip(subnet.network());
+        let gateway = match gateway {
+            Some(ip) if !subnet.contains(ip) => {
+                return Err(RangeError::OutOfRangeGateway(subnet, ip))
+            }
+            Some(ip) => ip,
+            None => first_host,
+        };
+
+        for ip in start.iter().chain(end.iter()) {
+            if !subnet.contains(*ip) {
+                return Err(RangeError::OutOfRangeIp(subnet, *ip));
+            }
+        }
+
+        Ok(Range {
+            subnet: subnet,
+            start: start.unwrap_or(first_host),
+            end: end.unwrap_or_else(|| subnet.last_host()),
+            gateway: Some(gateway),
+        })
+    }
+
+    /// A range of an RFC 3021 /31 point-to-point subnet, or a /32 for a
+    /// single host route (/127 and /128 for IPv6). Every IP is allocatable
+    /// and there is no gateway.
+    pub fn point_to_point(
+        subnet: Subnet,
+        start: Option<IpAddr>,
+        end: Option<IpAddr>,
+    ) -> Result<Range, RangeError> {
+        if subnet.prefix() + 1 < bits(subnet.ip()) {
+            return Err(RangeError::NotPointToPoint(subnet));
+        }
+
+        if subnet.ip() != subnet.network() {
+            return Err(RangeError::WrongNetworkAddr(subnet, subnet.network()));
+        }
+
+        for ip in start.iter().chain(end.iter()) {
+            if !subnet.contains(*ip) {
+                return Err(RangeError::OutOfRangeIp(subnet, *ip));
+            }
+        }
+
+        Ok(Range {
+            subnet: subnet,
+            start: start.unwrap_or_else(|| subnet.network()),
+            end: end.unwrap_or_else(|| subnet.last()),
+            gateway: None,
+        })
+    }
+
+    /// The allocatable IPs in order.
+    pub fn ips(&self) -> Ips {
+        Ips::new(self.start, self.end, self.gateway)
+    }
+
+    /// Number of allocatable IPs, what `ips` would yield.
+    pub fn capacity(&self) -> u128 {
+        capacity(self.start, self.end, self.gateway)
+    }
+
+    /// Number of IPs from `start` to `end`, including the gateway.
+    pub fn size(&self) -> u128 {
+        span(self.start, self.end)
+    }
+
+    /// The IP `n` positions after `start`, wrapping around at `end`. The
+    /// gateway isn't skipped.
+    pub fn nth_ip(&self, n: u128) -> Option<IpAddr> {
+        nth_ip(self.start, self.end, n)
+    }
+
+    /// Whether `ip` lies in the subnet, from `start` to `end`.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.subnet.contains(ip) && self.start <= ip && ip <= self.end
+    }
+
+    pub fn is_same_family(&self, other: &Range) -> bool {
+        self.subnet.is_ipv4() == other.subnet.is_ipv4()
+    }
+
+    pub fn overlaps(&self, other: &Range) -> bool {
+        self.is_same_family(other)
+            && (self.contains(other.start)
+                || self.contains(other.end)
+                || other.contains(self.start)
+                || other.contains(self.end))
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {})", self.start, self.end)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RangeSetError {
+    DifferentAddressType,
+    Overlap(Range, Range),
+    ConflictingGateways(Range, Range),
+}
+
+impl fmt::Display for RangeSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RangeSetError::DifferentAddressType => write!(f, "range has different address type"),
+            RangeSetError::Overlap(a, b) => write!(f, "subnet {} overlaps with subnet {}", a, b),
+            RangeSetError::ConflictingGateways(a, b) => write!(
+                f,
+                "ranges {} and {} of the same subnet have different gateways",
+                a, b
+            ),
+        }
+    }
+}
+
+/// Ranges allocated from together, in the given order. Borrows the ranges,
+/// so it needs no allocation.
+#[derive(Clone, Copy, Debug)]
+pub struct RangeSet<'a> {
+    ranges: &'a [Range],
+}
+
+impl<'a> RangeSet<'a> {
+    /// Fails if the ranges mix IP families, overlap, or ranges of the same
+    /// subnet have different gateways.
+    pub fn new(ranges: &'a [Range]) -> Result<RangeSet<'a>, RangeSetError> {
+        for (index, range) in ranges.iter().enumerate() {
+            RangeSet::check(ranges[..index].iter().copied(), range)?;
+        }
+
+        Ok(RangeSet { ranges: ranges })
+    }
+
+    /// Checks that `range` can join a set of `ranges`, see `new`.
+    pub fn check<I>(ranges: I, range: &Range) -> Result<(), RangeSetError>
+    where
+        I: Iterator<Item = Range> + Clone,
+    {
+        if let Some(first) = ranges.clone().next() {
+            if !first.is_same_family(range) {
+                return Err(RangeSetError::DifferentAddressType);
+            }
+        }
+
+        if let Some(other) = ranges.clone().find(|other| other.overlaps(range)) {
+            return Err(RangeSetError::Overlap(other, *range));
+        }
+
+        let mut ranges = ranges;
+        match ranges.find(|other| other.subnet == range.subnet && other.gateway != range.gateway) {
+            Some(other) => Err(RangeSetError::ConflictingGateways(other, *range)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn ranges(&self) -> &'a [Range] {
+        self.ranges
+    }
+
+    /// The range holding `ip`.
+    pub fn find(&self, ip: IpAddr) -> Option<&'a Range> {
+        self.ranges.iter().find(|range| range.contains(ip))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.find(ip).is_some()
+    }
+
+    /// Number of allocatable IPs of every range. Saturates.
+    pub fn capacity(&self) -> u128 {
+        self.ranges
+            .iter()
+            .fold(0u128, |total, range| total.saturating_add(range.capacity()))
+    }
+
+    /// The allocatable IPs of every range, range by range.
+    pub fn ips(&self) -> impl Iterator<Item = IpAddr> + 'a {
+        self.ranges.iter().flat_map(Range::ips)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn math() {
+        assert_eq!(next_ip(ip("10.1.2.255")), ip("10.1.3.0"));
+        assert_eq!(next_ip(ip("255.255.255.255")), ip("0.0.0.0"));
+        assert_eq!(next_ip(ip("2001:db8::ffff")), ip("2001:db8::1:0"));
+        assert_eq!(prev_ip(ip("10.1.3.0")), ip("10.1.2.255"));
+        assert_eq!(
+            prev_ip(ip("::")),
+            ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")
+        );
+
+        assert_eq!(span(ip("10.1.2.1"), ip("10.1.2.254")), 254);
+        assert_eq!(span(ip("10.1.2.2"), ip("10.1.2.1")), 0);
+        assert_eq!(
+            span(ip("::"), ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")),
+            u128::MAX
+        );
+
+        assert_eq!(
+            nth_ip(ip("10.1.2.1"), ip("10.1.2.3"), 4),
+            Some(ip("10.1.2.2"))
+        );
+        assert_eq!(nth_ip(ip("10.1.2.2"), ip("10.1.2.1"), 0), None);
+
+        let gateway = Some(ip("10.1.2.1"));
+        assert_eq!(capacity(ip("10.1.2.1"), ip("10.1.2.254"), gateway), 253);
+        assert_eq!(capacity(ip("10.1.2.2"), ip("10.1.2.254"), gateway), 253);
+    }
+
+    #[test]
+    fn ips() {
+        let ips: Vec<IpAddr> =
+            Ips::new(ip("10.1.2.0"), ip("10.1.2.3"), Some(ip("10.1.2.1"))).collect();
+        assert_eq!(ips, vec![ip("10.1.2.0"), ip("10.1.2.2"), ip("10.1.2.3")]);
+
+        // the last IP of the family doesn't overflow
+        let ips: Vec<IpAddr> =
+            Ips::new(ip("255.255.255.254"), ip("255.255.255.255"), None).collect();
+        assert_eq!(ips, vec![ip("255.255.255.254"), ip("255.255.255.255")]);
+
+        assert_eq!(Ips::new(ip("10.1.2.3"), ip("10.1.2.0"), None).count(), 0);
+
+        let mut ips = Ips::new(ip("10.1.2.0"), ip("10.1.2.3"), Some(ip("10.1.2.1")));
+        assert_eq!(ips.len(), 3);
+        ips.next();
+        assert_eq!(ips.len(), 2);
+        assert_eq!(ips.next_back(), Some(ip("10.1.2.3")));
+        assert_eq!(ips.next_back(), Some(ip("10.1.2.2")));
+        assert_eq!(ips.next_back(), None);
+        assert_eq!(ips.next(), None);
+
+        let ips: Vec<IpAddr> = Ips::new(ip("0.0.0.0"), ip("0.0.0.2"), Some(ip("0.0.0.1")))
+            .rev()
+            .collect();
+        assert_eq!(ips, vec![ip("0.0.0.2"), ip("0.0.0.0")]);
+        assert_eq!(
+            Ips::new(ip("2001:db8::"), ip("2001:db8::ffff:ffff:ffff:ffff"), None).size_hint(),
+            (usize::MAX, None)
+        );
+    }
+
+    fn subnet(subnet: &str) -> Subnet {
+        let (ip_part, prefix) = subnet.split_at(subnet.find('/').unwrap());
+        Subnet::new(ip(ip_part), prefix[1..].parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn subnets() {
+        let net = subnet("10.1.2.5/24");
+        assert_eq!(net.network(), ip("10.1.2.0"));
+        assert_eq!(net.last(), ip("10.1.2.255"));
+        assert_eq!(net.last_host(), ip("10.1.2.254"));
+        assert!(net.contains(ip("10.1.2.77")));
+        assert!(!net.contains(ip("10.1.3.0")));
+        assert!(!net.contains(ip("::a01:200")));
+        assert_eq!(net.to_string(), "10.1.2.5/24");
+
+        let net = subnet("2001:db8::/64");
+        assert_eq!(net.last_host(), ip("2001:db8::ffff:ffff:ffff:ffff"));
+        assert_eq!(subnet("0.0.0.0/0").last(), ip("255.255.255.255"));
+        assert_eq!(subnet("10.1.2.3/32").network(), ip("10.1.2.3"));
+        assert_eq!(Subnet::new(ip("10.1.2.0"), 33), None);
+    }
+
+    #[test]
+    fn ranges() {
+        let range = Range::new(subnet("10.1.2.0/24"), None, None, None).unwrap();
+        assert_eq!(range.start, ip("10.1.2.1"));
+        assert_eq!(range.end, ip("10.1.2.254"));
+        assert_eq!(range.gateway, Some(ip("10.1.2.1")));
+        assert_eq!(range.capacity(), 253);
+        assert_eq!(range.ips().next(), Some(ip("10.1.2.2")));
+        assert!(range.contains(ip("10.1.2.254")));
+        assert!(!range.contains(ip("10.1.2.255")));
+
+        assert_eq!(
+            Range::new(subnet("10.1.2.1/24"), None, None, None),
+            Err(RangeError::WrongNetworkAddr(
+                subnet("10.1.2.1/24"),
+                ip("10.1.2.0")
+            ))
+        );
+        assert_eq!(
+            Range::new(subnet("10.1.2.0/31"), None, None, None),
+            Err(RangeError::TooSmallNetwork(subnet("10.1.2.0/31")))
+        );
+        assert_eq!(
+            Range::new(subnet("10.1.2.0/24"), None, None, Some(ip("10.1.3.1"))),
+            Err(RangeError::OutOfRangeGateway(
+                subnet("10.1.2.0/24"),
+                ip("10.1.3.1")
+            ))
+        );
+
+        let range = Range::point_to_point(subnet("10.1.2.0/31"), None, None).unwrap();
+        assert_eq!(
+            range.ips().collect::<Vec<_>>(),
+            vec![ip("10.1.2.0"), ip("10.1.2.1")]
+        );
+        assert_eq!(
+            Range::point_to_point(subnet("10.1.2.0/30"), None, None),
+            Err(RangeError::NotPointToPoint(subnet("10.1.2.0/30")))
+        );
+    }
+
+    #[test]
+    fn range_sets() {
+        let net = subnet("10.1.2.0/24");
+        let low = Range::new(net, None, Some(ip("10.1.2.9")), None).unwrap();
+        let high = Range::new(net, Some(ip("10.1.2.10")), Some(ip("10.1.2.12")), None).unwrap();
+        let ranges = [low, high];
+        let range_set = RangeSet::new(&ranges).unwrap();
+
+        assert_eq!(range_set.capacity(), 11);
+        assert_eq!(range_set.ips().count(), 11);
+        assert_eq!(range_set.find(ip("10.1.2.11")), Some(&high));
+        assert!(!range_set.contains(ip("10.1.2.13")));
+
+        let overlapping = Range::new(net, Some(ip("10.1.2.5")), None, None).unwrap();
+        assert_eq!(
+            RangeSet::new(&[low, overlapping]).unwrap_err(),
+            RangeSetError::Overlap(low, overlapping)
+        );
+        let ipv6 = Range::new(subnet("2001:db8::/64"), None, None, None).unwrap();
+        assert_eq!(
+            RangeSet::new(&[low, ipv6]).unwrap_err(),
+            RangeSetError::DifferentAddressType
+        );
+        let other_gateway =
+            Range::new(net, Some(ip("10.1.2.20")), None, Some(ip("10.1.2.254"))).unwrap();
+        assert_eq!(
+            RangeSet::new(&[low, other_gateway]).unwrap_err(),
+            RangeSetError::ConflictingGateways(low, other_gateway)
+        );
+    }
+}
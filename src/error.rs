@@ -0,0 +1,114 @@
+//! Top-level error of the plugin, everything that can go wrong while serving
+//! a CNI command, and how it's reported to the runtime.
+
+use std::error::Error as StdError;
+
+use thiserror::Error;
+
+use super::allocator::AllocateError;
+use super::cni::{PluginError, ERR_DECODING, ERR_INTERNAL};
+use super::config::ConfigError;
+use super::store::StoreError;
+
+#[derive(Debug, Error)]
+pub enum HostLocalError {
+    #[error(transparent)]
+    Plugin(#[from] PluginError),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error(transparent)]
+    Allocate(#[from] AllocateError),
+}
+
+impl HostLocalError {
+    /// Maps the error to the well-known CNI error codes.
+    pub fn code(&self) -> u32 {
+        match self {
+            HostLocalError::Plugin(err) => err.code(),
+            HostLocalError::Config(ConfigError::JsonError(_)) => ERR_DECODING,
+            _ => ERR_INTERNAL,
+        }
+    }
+
+    /// The message of the error followed by the messages of its sources,
+    /// the `msg` of the CNI error result.
+    pub fn report(&self) -> String {
+        report(self)
+    }
+
+    /// The `details` of the CNI error result as JSON, the `CheckReport` of
+    /// a failed CHECK or every problem of an invalid configuration.
+    pub fn details(&self) -> Option<String> {
+        match self {
+            HostLocalError::Plugin(PluginError::CheckFailed(report)) => {
+                serde_json::to_string(report).ok()
+            }
+            HostLocalError::Config(err) | HostLocalError::Plugin(PluginError::ConfigError(err)) => {
+                serde_json::to_string(&err.validation_errors()).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Joins the message of `err` and of every error in its `source` chain.
+pub fn report(err: &dyn StdError) -> String {
+    let mut message = err.to_string();
+
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::ValidationErrors;
+    use std::io::Error;
+
+    #[test]
+    fn report_source_chain() {
+        let err = HostLocalError::from(PluginError::AllocateError(
+            1,
+            AllocateError::StoreError(StoreError::IOError(Error::other("disk on fire"))),
+        ));
+
+        assert_eq!(err.code(), ERR_INTERNAL);
+        assert_eq!(
+            err.report(),
+            "failed to allocate for range 1: io error happened: disk on fire"
+        );
+        assert_eq!(err.details(), None);
+    }
+
+    #[test]
+    fn validation_details() {
+        let mut errors = ValidationErrors::new();
+        errors.push(
+            "ipam.ranges[0][1]",
+            "IPv6 subnet 2001:db8::/64 in the IPv4 range set",
+        );
+        let err = HostLocalError::from(ConfigError::Invalid(errors));
+
+        assert_eq!(
+            err.details().unwrap(),
+            r#"[{"location":"ipam.ranges[0][1]","message":"IPv6 subnet 2001:db8::/64 in the IPv4 range set"}]"#
+        );
+
+        let err = HostLocalError::from(PluginError::ConfigError(ConfigError::NoRanges));
+        assert_eq!(
+            err.details().unwrap(),
+            r#"[{"message":"no IP ranges specified"}]"#
+        );
+    }
+}
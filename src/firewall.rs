@@ -0,0 +1,225 @@
+//! Keeps an nftables or ipset set per address family populated with the IPs
+//! allocated in a network, so firewall rules can match every container of
+//! the node. Built with the `firewall-sets` feature.
+
+use std::io::Error as IoError;
+use std::net::IpAddr;
+use std::process::Command;
+
+use super::allocator::{AllocationObserver, IpConfig};
+use super::config::{FirewallBackend, FirewallSetConf, LogLevel};
+
+/// Runs `nft` or `ipset` for every reservation and release. Sets are named
+/// after `FirewallSetConf::name` with a `-v4` or `-v6` suffix and created
+/// on first use.
+#[derive(Clone, Debug)]
+pub struct FirewallSetExporter {
+    backend: FirewallBackend,
+    table: String,
+    name: String,
+    log_level: LogLevel,
+}
+
+impl FirewallSetExporter {
+    /// `network` names the sets unless the config does.
+    pub fn new(conf: &FirewallSetConf, network: &str, log_level: LogLevel) -> FirewallSetExporter {
+        FirewallSetExporter {
+            backend: conf.backend,
+            table: conf.table.clone(),
+            name: conf.name.clone().unwrap_or_else(|| network.to_owned()),
+            log_level: log_level,
+        }
+    }
+
+    pub fn add(&self, ip: IpAddr) -> Result<(), IoError> {
+        for command in self.add_commands(ip) {
+            run(&command)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, ip: IpAddr) -> Result<(), IoError> {
+        run(&self.remove_command(ip))
+    }
+
+    fn set_name(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(_) => format!("{}-v4", self.name),
+            IpAddr::V6(_) => format!("{}-v6", self.name),
+        }
+    }
+
+    /// Creates the set if it doesn't exist yet, then adds `ip` to it.
+    fn add_commands(&self, ip: IpAddr) -> Vec<Vec<String>> {
+        let set = self.set_name(ip);
+
+        match self.backend {
+            FirewallBackend::Nftables => {
+                let addr_type = match ip {
+                    IpAddr::V4(_) => "ipv4_addr",
+                    IpAddr::V6(_) => "ipv6_addr",
+                };
+
+                vec![
+                    args(&["nft", "add", "set"])
+                        .chain(self.table_args())
+                        .chain(vec![set.clone(), format!("{{ type {}; }}", addr_type)])
+                        .collect(),
+                    args(&["nft", "add", "element"])
+                        .chain(self.table_args())
+                        .chain(vec![set, format!("{{ {} }}", ip)])
+                        .collect(),
+                ]
+            }
+            FirewallBackend::Ipset => {
+                let family = match ip {
+                    IpAddr::V4(_) => "inet",
+                    IpAddr::V6(_) => "inet6",
+                };
+
+                vec![
+                    args(&["ipset", "create"])
+                        .chain(vec![set.clone()])
+                        .chain(args(&["hash:ip", "family", family, "-exist"]))
+                        .collect(),
+                    args(&["ipset", "add"])
+                        .chain(vec![set, ip.to_string()])
+                        .chain(args(&["-exist"]))
+                        .collect(),
+                ]
+            }
+        }
+    }
+
+    fn remove_command(&self, ip: IpAddr) -> Vec<String> {
+        let set = self.set_name(ip);
+
+        match self.backend {
+            FirewallBackend::Nftables => args(&["nft", "delete", "element"])
+                .chain(self.table_args())
+                .chain(vec![set, format!("{{ {} }}", ip)])
+                .collect(),
+            FirewallBackend::Ipset => args(&["ipset", "del"])
+                .chain(vec![set, ip.to_string()])
+                .chain(args(&["-exist"]))
+                .collect(),
+        }
+    }
+
+    /// The table is configured as `<family> <name>`, e.g. `inet filter`.
+    fn table_args(&self) -> impl Iterator<Item = String> + '_ {
+        self.table.split_whitespace().map(str::to_owned)
+    }
+}
+
+impl AllocationObserver for FirewallSetExporter {
+    fn allocated(&self, _id: &str, _ifname: &str, ip_config: &IpConfig) {
+        if let Err(err) = self.add(ip_config.address().ip()) {
+            self.log_level.warn(format_args!(
+                "failed to update firewall set {}: {}",
+                self.name, err
+            ));
+        }
+    }
+
+    fn released(&self, _id: &str, _ifname: &str, ip: IpAddr) {
+        if let Err(err) = self.remove(ip) {
+            self.log_level.warn(format_args!(
+                "failed to update firewall set {}: {}",
+                self.name, err
+            ));
+        }
+    }
+}
+
+fn args<'a>(args: &'a [&str]) -> impl Iterator<Item = String> + 'a {
+    args.iter().map(|arg| (*arg).to_owned())
+}
+
+fn run(command: &[String]) -> Result<(), IoError> {
+    let output = Command::new(&command[0]).args(&command[1..]).output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(IoError::other(format!(
+        "{} failed: {}",
+        command.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter(backend: FirewallBackend) -> FirewallSetExporter {
+        FirewallSetExporter::new(
+            &FirewallSetConf {
+                backend: backend,
+                table: "inet filter".to_owned(),
+                name: None,
+            },
+            "mynet",
+            LogLevel::default(),
+        )
+    }
+
+    #[test]
+    fn nftables_commands() {
+        let exporter = exporter(FirewallBackend::Nftables);
+
+        assert_eq!(
+            exporter.add_commands("10.1.0.2".parse().unwrap()),
+            vec![
+                vec![
+                    "nft",
+                    "add",
+                    "set",
+                    "inet",
+                    "filter",
+                    "mynet-v4",
+                    "{ type ipv4_addr; }"
+                ],
+                vec![
+                    "nft",
+                    "add",
+                    "element",
+                    "inet",
+                    "filter",
+                    "mynet-v4",
+                    "{ 10.1.0.2 }"
+                ],
+            ]
+        );
+        assert_eq!(
+            exporter.remove_command("2001:db8::2".parse().unwrap()),
+            vec![
+                "nft",
+                "delete",
+                "element",
+                "inet",
+                "filter",
+                "mynet-v6",
+                "{ 2001:db8::2 }"
+            ]
+        );
+    }
+
+    #[test]
+    fn ipset_commands() {
+        let exporter = exporter(FirewallBackend::Ipset);
+
+        assert_eq!(
+            exporter.add_commands("2001:db8::2".parse().unwrap()),
+            vec![
+                vec!["ipset", "create", "mynet-v6", "hash:ip", "family", "inet6", "-exist"],
+                vec!["ipset", "add", "mynet-v6", "2001:db8::2", "-exist"],
+            ]
+        );
+        assert_eq!(
+            exporter.remove_command("10.1.0.2".parse().unwrap()),
+            vec!["ipset", "del", "mynet-v4", "10.1.0.2", "-exist"]
+        );
+    }
+}
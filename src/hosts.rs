@@ -0,0 +1,147 @@
+//! Exports the allocations of a network into a hosts file, for name
+//! resolution without a cluster DNS.
+
+use std::fs::{read_to_string, rename, write};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process;
+
+use super::allocator::{AllocationObserver, IpConfig};
+use super::config::{HostsFileConf, HostsFormat, LogLevel};
+
+/// Keeps one line per allocated IP in the configured file, rewriting it
+/// through a temporary file and a rename so readers never see it half
+/// written.
+///
+/// Lines of other containers and lines the exporter doesn't understand are
+/// left alone, the file can be shared with hand written entries.
+#[derive(Clone, Debug)]
+pub struct HostsExporter {
+    path: PathBuf,
+    format: HostsFormat,
+    log_level: LogLevel,
+}
+
+impl HostsExporter {
+    pub fn new(conf: &HostsFileConf, log_level: LogLevel) -> HostsExporter {
+        HostsExporter {
+            path: conf.path.clone(),
+            format: conf.format,
+            log_level: log_level,
+        }
+    }
+
+    pub fn add(&self, id: &str, ip: IpAddr) -> Result<(), IoError> {
+        let line = self.line(id, ip);
+        self.update(|lines| {
+            if !lines.contains(&line) {
+                lines.push(line.clone());
+            }
+        })
+    }
+
+    pub fn remove(&self, id: &str, ip: IpAddr) -> Result<(), IoError> {
+        let line = self.line(id, ip);
+        self.update(|lines| lines.retain(|l| *l != line))
+    }
+
+    fn line(&self, id: &str, ip: IpAddr) -> String {
+        match self.format {
+            HostsFormat::Hosts => format!("{}\t{}", ip, id),
+            HostsFormat::Dnsmasq => format!("id:{},{}", id, ip),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Vec<String>)>(&self, f: F) -> Result<(), IoError> {
+        let mut lines: Vec<String> = match read_to_string(&self.path) {
+            Ok(content) => content.lines().map(str::to_owned).collect(),
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        f(&mut lines);
+
+        let mut content = lines.join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(format!(".{}.tmp", process::id()));
+
+        write(&tmp_path, content)?;
+        rename(&tmp_path, &self.path)
+    }
+}
+
+impl AllocationObserver for HostsExporter {
+    fn allocated(&self, id: &str, _ifname: &str, ip_config: &IpConfig) {
+        if let Err(err) = self.add(id, ip_config.address().ip()) {
+            self.log_level.warn(format_args!(
+                "failed to update {}: {}",
+                self.path.display(),
+                err
+            ));
+        }
+    }
+
+    fn released(&self, id: &str, _ifname: &str, ip: IpAddr) {
+        if let Err(err) = self.remove(id, ip) {
+            self.log_level.warn(format_args!(
+                "failed to update {}: {}",
+                self.path.display(),
+                err
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn add_and_remove() {
+        let dir = "/tmp/cni-hosts";
+        let _ = remove_dir_all(dir);
+        create_dir_all(dir).unwrap();
+
+        let path = PathBuf::from(dir).join("hosts");
+        write(&path, "# managed by hand\n10.1.0.100\tinfra\n").unwrap();
+
+        let exporter = HostsExporter::new(
+            &HostsFileConf {
+                path: path.clone(),
+                format: HostsFormat::Hosts,
+            },
+            LogLevel::default(),
+        );
+        exporter.add("c1", "10.1.0.2".parse().unwrap()).unwrap();
+        exporter.add("c1", "2001:db8::2".parse().unwrap()).unwrap();
+        exporter.add("c1", "10.1.0.2".parse().unwrap()).unwrap();
+        exporter.add("c2", "10.1.0.3".parse().unwrap()).unwrap();
+        exporter.remove("c1", "10.1.0.2".parse().unwrap()).unwrap();
+
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            "# managed by hand\n10.1.0.100\tinfra\n2001:db8::2\tc1\n10.1.0.3\tc2\n"
+        );
+
+        let exporter = HostsExporter::new(
+            &HostsFileConf {
+                path: PathBuf::from(dir).join("dhcp-hosts"),
+                format: HostsFormat::Dnsmasq,
+            },
+            LogLevel::default(),
+        );
+        exporter.add("c1", "10.1.0.2".parse().unwrap()).unwrap();
+        assert_eq!(
+            read_to_string(PathBuf::from(dir).join("dhcp-hosts")).unwrap(),
+            "id:c1,10.1.0.2\n"
+        );
+
+        let _ = remove_dir_all(dir);
+    }
+}
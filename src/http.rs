@@ -0,0 +1,164 @@
+//! A minimal HTTP/1.0 client for the JSON APIs the plugin talks to, the
+//! agent of Consul and OpenTelemetry collectors. HTTP/1.0 keeps responses
+//! free of chunked encoding, the server closes the connection once it has
+//! responded.
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Status and body of a response.
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn parse(response: &[u8]) -> Result<Response, IoError> {
+        let invalid = || IoError::new(ErrorKind::InvalidData, "malformed HTTP response");
+
+        let header_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(invalid)?;
+        let status = String::from_utf8_lossy(&response[..header_end])
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(invalid)?;
+
+        Ok(Response {
+            status: status,
+            body: response[header_end + 4..].to_vec(),
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends a request to `address`, `host:port`, with the header lines of
+/// `headers` besides `Host` and `Content-Length`, and reads the response.
+/// Connecting, sending and receiving share one deadline `timeout` from now
+/// and fail with `TimedOut` once it passed.
+pub fn send(
+    address: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<Response, IoError> {
+    let deadline = Instant::now() + timeout;
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| IoError::new(ErrorKind::NotFound, format!("{} not found", address)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, remaining(deadline)?)?;
+
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        address,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    write_all(&mut stream, request.as_bytes(), deadline)?;
+    write_all(&mut stream, body, deadline)?;
+
+    let mut response = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        stream.set_read_timeout(Some(remaining(deadline)?))?;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => response.extend_from_slice(&buf[..read]),
+            Err(err) => check_timeout(err)?,
+        }
+    }
+
+    Response::parse(&response)
+}
+
+/// Writes all of `bytes` before `deadline`.
+fn write_all(stream: &mut TcpStream, mut bytes: &[u8], deadline: Instant) -> Result<(), IoError> {
+    while !bytes.is_empty() {
+        stream.set_write_timeout(Some(remaining(deadline)?))?;
+        match stream.write(bytes) {
+            Ok(0) => return Err(IoError::from(ErrorKind::WriteZero)),
+            Ok(written) => bytes = &bytes[written..],
+            Err(err) => check_timeout(err)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Time left until `deadline`, a `TimedOut` error once it passed.
+fn remaining(deadline: Instant) -> Result<Duration, IoError> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(timed_out());
+    }
+
+    Ok(deadline - now)
+}
+
+/// Passes on `err` of a read or write, unless it was interrupted and can be
+/// retried. Socket timeouts fail with `WouldBlock` on some platforms.
+fn check_timeout(err: IoError) -> Result<(), IoError> {
+    match err.kind() {
+        ErrorKind::Interrupted => Ok(()),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Err(timed_out()),
+        _ => Err(err),
+    }
+}
+
+fn timed_out() -> IoError {
+    IoError::new(ErrorKind::TimedOut, "HTTP request timed out")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn parse() {
+        let response = Response::parse(b"HTTP/1.0 409 Conflict\r\nX: y\r\n\r\nbody").unwrap();
+        assert_eq!(response.status, 409);
+        assert_eq!(response.body, b"body");
+        assert!(!response.is_success());
+        assert!(Response::parse(b"HTTP/1.0 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // answers byte by byte, each read finishing well within the timeout
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for byte in b"HTTP/1.0 200 OK\r\n\r\n" {
+                if stream.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let started = Instant::now();
+        let err = send(&address, "GET", "/", &[], b"", Duration::from_millis(100))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(300));
+        server.join().unwrap();
+    }
+}
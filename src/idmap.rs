@@ -0,0 +1,88 @@
+//! Derives the id reservations are stored under from the CNI parameters.
+//!
+//! Runtimes identify containers differently, a recreated pod sandbox gets a
+//! new container id while its namespace and name stay. Every store
+//! operation of a CNI command goes through the configured `IdMapper`, so
+//! ADD and DEL have to agree on the mapping.
+
+use super::cni::{CniArgs, PluginError};
+use super::config::IdMapping;
+
+/// Length of the ids `docker ps` shows.
+const SHORT_ID_LEN: usize = 12;
+
+pub trait IdMapper {
+    /// The id to reserve IPs for, or an error if `args` lack what the
+    /// mapping needs.
+    fn map(&self, args: &CniArgs) -> Result<String, PluginError>;
+}
+
+/// `CNI_CONTAINERID` as passed by the runtime.
+pub struct ContainerId;
+
+impl IdMapper for ContainerId {
+    fn map(&self, args: &CniArgs) -> Result<String, PluginError> {
+        Ok(args.container_id.clone())
+    }
+}
+
+/// The first characters of `CNI_CONTAINERID`.
+pub struct ShortId(pub usize);
+
+impl IdMapper for ShortId {
+    fn map(&self, args: &CniArgs) -> Result<String, PluginError> {
+        Ok(args.container_id.chars().take(self.0).collect())
+    }
+}
+
+/// `K8S_POD_NAMESPACE/K8S_POD_NAME` from `CNI_ARGS`.
+pub struct PodName;
+
+impl IdMapper for PodName {
+    fn map(&self, args: &CniArgs) -> Result<String, PluginError> {
+        let arg = |key| args.arg(key).ok_or(PluginError::MissingArg(key));
+        Ok(format!(
+            "{}/{}",
+            arg("K8S_POD_NAMESPACE")?,
+            arg("K8S_POD_NAME")?
+        ))
+    }
+}
+
+impl IdMapping {
+    pub fn mapper(&self) -> Box<dyn IdMapper> {
+        match self {
+            IdMapping::ContainerId => Box::new(ContainerId),
+            IdMapping::ShortId => Box::new(ShortId(SHORT_ID_LEN)),
+            IdMapping::Pod => Box::new(PodName),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map() {
+        let args = CniArgs {
+            container_id: "0123456789abcdef".to_owned(),
+            args: "IgnoreUnknown=1;K8S_POD_NAMESPACE=default;K8S_POD_NAME=web-0".to_owned(),
+            ..CniArgs::default()
+        };
+
+        let map = |mapping: IdMapping| mapping.mapper().map(&args).unwrap();
+        assert_eq!(map(IdMapping::ContainerId), "0123456789abcdef");
+        assert_eq!(map(IdMapping::ShortId), "0123456789ab");
+        assert_eq!(map(IdMapping::Pod), "default/web-0");
+
+        let args = CniArgs {
+            args: "K8S_POD_NAMESPACE=default".to_owned(),
+            ..args
+        };
+        assert!(matches!(
+            IdMapping::Pod.mapper().map(&args),
+            Err(PluginError::MissingArg("K8S_POD_NAME"))
+        ));
+    }
+}
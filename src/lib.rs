@@ -0,0 +1,33 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+pub mod allocator;
+#[cfg(feature = "std")]
+pub mod cli;
+#[cfg(feature = "std")]
+pub mod cni;
+#[cfg(feature = "std")]
+pub mod config;
+pub mod core;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "firewall-sets")]
+pub mod firewall;
+#[cfg(feature = "std")]
+pub mod hosts;
+#[cfg(any(feature = "consul", feature = "otel"))]
+mod http;
+#[cfg(feature = "std")]
+pub mod idmap;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "std")]
+pub mod reload;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(all(unix, feature = "std"))]
+pub mod systemd;
+#[cfg(feature = "std")]
+pub mod timing;
+#[cfg(feature = "std")]
+pub mod validation;
@@ -1,10 +1,27 @@
-mod allocator;
-mod store;
+use std::env;
+use std::io;
+use std::process;
 
-use allocator::{range::Range, test};
+use clap::Parser;
+use host_local::cli::Cli;
+use host_local::cni::{self, CniArgs};
+use host_local::store::filestore::FileStoreOptions;
 
 fn main() {
-    test();
-    let range = Range::new("2.2.0.0/16".parse().unwrap(), None, None, None).unwrap();
-    println!("{}", range);
+    // runtimes select CNI commands through the environment, anything else
+    // runs a subcommand
+    if env::var_os("CNI_COMMAND").is_none() {
+        let cli = Cli::parse();
+        process::exit(cli.run(io::stdin(), io::stdout()));
+    }
+
+    let mut options = FileStoreOptions::default();
+    if env::args().skip(1).any(|arg| arg == "--rootless") {
+        options.rootless = Some(true);
+    }
+
+    let args = CniArgs::from_env();
+    let code = cni::run(&args, io::stdin(), io::stdout(), options);
+
+    process::exit(code);
 }
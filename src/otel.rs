@@ -0,0 +1,328 @@
+//! Exports a span per CNI command to an OpenTelemetry collector, so the
+//! latency of IPAM shows up in the traces of sandbox setup.
+//!
+//! The span is sent as OTLP/HTTP JSON to `/v1/traces` of the configured
+//! endpoint, with the time of every `Phase` as an attribute. A runtime
+//! passing W3C trace context in the `TRACEPARENT` environment variable gets
+//! the span as a child of its own, otherwise it starts a new trace.
+//! Exporting never fails the command, errors are only logged.
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Error as IoError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use super::cni::CniArgs;
+use super::config::OtelConf;
+use super::http;
+use super::timing::{Timings, PHASES};
+
+pub const TRACEPARENT_ENV: &str = "TRACEPARENT";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Trace and span id of the runtime's span, parsed from a `traceparent`
+/// like `00-<trace id>-<span id>-<flags>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceParent {
+    pub fn parse(traceparent: &str) -> Option<TraceParent> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        match parts.as_slice() {
+            [version, trace_id, span_id, flags]
+                if *version != "ff"
+                    && is_hex(version, 2)
+                    && is_hex(trace_id, 32)
+                    && is_hex(span_id, 16)
+                    && is_hex(flags, 2)
+                    && !is_zero(trace_id)
+                    && !is_zero(span_id) =>
+            {
+                Some(TraceParent {
+                    trace_id: trace_id.to_string(),
+                    span_id: span_id.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn from_env() -> Option<TraceParent> {
+        env::var(TRACEPARENT_ENV)
+            .ok()
+            .and_then(|traceparent| TraceParent::parse(&traceparent))
+    }
+}
+
+/// The span of one command.
+#[derive(Clone, Debug)]
+pub struct CommandSpan {
+    pub name: String,
+    pub parent: Option<TraceParent>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, Value)>,
+    /// Message of the error the command failed with.
+    pub error: Option<String>,
+}
+
+impl CommandSpan {
+    /// The span of the command of `args` on `network`, ending now.
+    pub fn new(
+        args: &CniArgs,
+        network: &str,
+        timings: &Timings,
+        error: Option<String>,
+    ) -> CommandSpan {
+        let end = SystemTime::now();
+        let mut attributes = vec![
+            ("cni.command".to_owned(), json!(args.command)),
+            ("cni.network".to_owned(), json!(network)),
+            ("cni.container_id".to_owned(), json!(args.container_id)),
+            ("cni.ifname".to_owned(), json!(args.ifname)),
+        ];
+        for phase in PHASES.iter() {
+            let key = format!("ipam.phase.{}_ms", phase.to_string().replace('-', "_"));
+            let millis = timings.get(*phase).as_secs_f64() * 1000.0;
+            attributes.push((key, json!(millis)));
+        }
+
+        CommandSpan {
+            name: format!("host-local {}", args.command),
+            parent: TraceParent::from_env(),
+            start: end.checked_sub(timings.elapsed()).unwrap_or(end),
+            end: end,
+            attributes: attributes,
+            error: error,
+        }
+    }
+
+    /// The OTLP/HTTP JSON request exporting the span for `service_name`.
+    pub fn to_otlp(&self, service_name: &str) -> Value {
+        let (trace_id, parent_span_id) = match &self.parent {
+            Some(parent) => (parent.trace_id.clone(), parent.span_id.clone()),
+            None => (random_id(16), String::new()),
+        };
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect();
+        let status = match &self.error {
+            Some(message) => json!({"code": 2, "message": message}),
+            None => json!({"code": 1}),
+        };
+
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", &json!(service_name))]
+                },
+                "scopeSpans": [{
+                    "scope": {"name": "host-local", "version": env!("CARGO_PKG_VERSION")},
+                    "spans": [{
+                        "traceId": trace_id,
+                        "spanId": random_id(8),
+                        "parentSpanId": parent_span_id,
+                        "name": self.name,
+                        "kind": 1,
+                        "startTimeUnixNano": unix_nanos(self.start),
+                        "endTimeUnixNano": unix_nanos(self.end),
+                        "attributes": attributes,
+                        "status": status,
+                    }]
+                }]
+            }]
+        })
+    }
+}
+
+/// Sends spans to the collector of an `OtelConf`.
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: String,
+    timeout: Duration,
+}
+
+impl OtlpExporter {
+    pub fn new(conf: &OtelConf) -> OtlpExporter {
+        OtlpExporter {
+            endpoint: conf.endpoint.clone(),
+            service_name: conf.service_name.clone(),
+            timeout: conf
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_TIMEOUT),
+        }
+    }
+
+    pub fn export(&self, span: &CommandSpan) -> Result<(), IoError> {
+        let body = span.to_otlp(&self.service_name).to_string();
+        let response = http::send(
+            &self.endpoint,
+            "POST",
+            "/v1/traces",
+            &[("Content-Type", "application/json")],
+            body.as_bytes(),
+            self.timeout,
+        )?;
+        if !response.is_success() {
+            return Err(IoError::other(format!(
+                "otel collector responded with {}",
+                response.status
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::String(value) => json!({ "stringValue": value }),
+        Value::Number(value) if value.is_f64() => json!({ "doubleValue": value }),
+        // OTLP JSON encodes 64 bit integers as strings
+        Value::Number(value) => json!({ "intValue": value.to_string() }),
+        Value::Bool(value) => json!({ "boolValue": value }),
+        value => json!({ "stringValue": value.to_string() }),
+    };
+
+    json!({"key": key, "value": value})
+}
+
+fn is_hex(id: &str, len: usize) -> bool {
+    id.len() == len
+        && id
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(id: &str) -> bool {
+    id.bytes().all(|byte| byte == b'0')
+}
+
+/// `bytes` random bytes as lowercase hex, seeded like the hash maps of the
+/// standard library.
+fn random_id(bytes: usize) -> String {
+    let mut id = String::with_capacity(bytes * 2);
+    while id.len() < bytes * 2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(id.len());
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(bytes * 2);
+
+    id
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::Phase;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn command_span(parent: Option<TraceParent>, error: Option<String>) -> CommandSpan {
+        let args = CniArgs {
+            command: "ADD".to_owned(),
+            container_id: "c1".to_owned(),
+            ifname: "eth0".to_owned(),
+            ..CniArgs::default()
+        };
+        let timings = Timings::new();
+        timings.add(Phase::LockAcquire, Duration::from_millis(12));
+
+        CommandSpan {
+            parent: parent,
+            ..CommandSpan::new(&args, "n", &timings, error)
+        }
+    }
+
+    #[test]
+    fn traceparent() {
+        let parent =
+            TraceParent::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").unwrap();
+        assert_eq!(parent.trace_id, "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(parent.span_id, "b7ad6b7169203331");
+
+        for invalid in &[
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        ] {
+            assert_eq!(TraceParent::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn otlp_json() {
+        let parent = TraceParent::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+        let otlp =
+            command_span(parent, Some("no IP addresses available".to_owned())).to_otlp("ipam");
+
+        assert_eq!(
+            otlp["resourceSpans"][0]["resource"]["attributes"][0],
+            json!({"key": "service.name", "value": {"stringValue": "ipam"}})
+        );
+        let span = &otlp["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "host-local ADD");
+        assert_eq!(span["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(span["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(span["status"]["code"], 2);
+        assert!(span["attributes"].as_array().unwrap().contains(
+            &json!({"key": "ipam.phase.lock_acquire_ms", "value": {"doubleValue": 12.0}})
+        ));
+
+        let otlp = command_span(None, None).to_otlp("ipam");
+        let span = &otlp["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(span["parentSpanId"], "");
+        assert_eq!(span["status"]["code"], 1);
+    }
+
+    #[test]
+    fn export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conf = OtelConf {
+            endpoint: listener.local_addr().unwrap().to_string(),
+            service_name: "host-local".to_owned(),
+            timeout_ms: Some(5000),
+        };
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("}]}]}]}") {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        OtlpExporter::new(&conf)
+            .export(&command_span(None, None))
+            .unwrap();
+        let request = collector.join().unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.0\r\n"));
+        assert!(request.contains("\"host-local ADD\""));
+    }
+}
@@ -0,0 +1,294 @@
+//! Reloading the network configuration of a long running process.
+//!
+//! `ConfigWatcher` notices when the configuration file is written or
+//! replaced, `load` parses and validates the new version, and `apply` moves
+//! the allocators over to the new range sets. A configuration which fails to
+//! load is rejected as a whole, the allocators keep serving the old one.
+
+use std::fs::File;
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use super::allocator::rangeset::RangeSet;
+use super::allocator::{AllocateError, Allocator};
+use super::config::{ConfigError, NetConf};
+use super::store::Store;
+
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error("reading {path:?} failed")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: IoError,
+    },
+
+    #[error("network name changed from {0} to {1}, restart to switch networks")]
+    NetworkRenamed(String, String),
+}
+
+/// Loads the configuration at `path` to replace `current`, together with
+/// its range sets. Every range is validated before anything is returned.
+pub fn load(path: &Path, current: &NetConf) -> Result<(NetConf, Vec<RangeSet>), ReloadError> {
+    let file = File::open(path).map_err(|err| ReloadError::Read {
+        path: path.to_owned(),
+        source: err,
+    })?;
+    let conf = NetConf::load(file)?;
+
+    // the store and its data dir belong to the old name
+    if conf.name != current.name {
+        return Err(ReloadError::NetworkRenamed(
+            current.name.clone(),
+            conf.name.clone(),
+        ));
+    }
+
+    let range_sets = conf.ipam.range_sets()?;
+    Ok((conf, range_sets))
+}
+
+/// Moves `allocators`, one per range set, over to `range_sets`, see
+/// `Allocator::set_range_set`. Range sets beyond the current ones get an
+/// allocator from `new_allocator`, allocators of range sets which were
+/// removed are kept with an empty set so their IPs can still be released.
+pub fn apply<F>(
+    allocators: &mut Vec<Allocator>,
+    range_sets: Vec<RangeSet>,
+    store: &Arc<dyn Store>,
+    mut new_allocator: F,
+) -> Result<(), AllocateError>
+where
+    F: FnMut(usize, RangeSet, Arc<dyn Store>) -> Allocator,
+{
+    let count = allocators.len().max(range_sets.len());
+    let mut range_sets = range_sets.into_iter();
+
+    for index in 0..count {
+        let range_set = range_sets.next().unwrap_or_else(RangeSet::new);
+        match allocators.get_mut(index) {
+            Some(allocator) => allocator.set_range_set(range_set)?,
+            None => allocators.push(new_allocator(index, range_set, store.clone())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Watches a configuration file for changes. Editors usually replace files
+/// instead of writing them in place, so the directory is watched for the
+/// file name.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+    #[cfg(not(target_os = "linux"))]
+    modified: Option<std::time::SystemTime>,
+}
+
+#[cfg(target_os = "linux")]
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Result<ConfigWatcher, IoError> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            let err = IoError::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+
+        Ok(ConfigWatcher {
+            path: path.to_owned(),
+            fd: fd,
+        })
+    }
+
+    /// Waits up to `timeout` for the file to change. Returns true if it
+    /// did, false on timeout or when only other files of the directory
+    /// changed.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool, IoError> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ready < 0 {
+            return Err(IoError::last_os_error());
+        }
+        if ready == 0 {
+            return Ok(false);
+        }
+
+        let name = self.path.file_name().map(|name| name.to_os_string());
+        let mut changed = false;
+        let mut buf = [0u8; 4096];
+        loop {
+            let len =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len < 0 {
+                let err = IoError::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(changed);
+                }
+                return Err(err);
+            }
+
+            changed |= event_names(&buf[..len as usize])
+                .iter()
+                .any(|event_name| Some(event_name) == name.as_ref());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Names of the files the inotify events in `buf` are about.
+#[cfg(target_os = "linux")]
+fn event_names(buf: &[u8]) -> Vec<std::ffi::OsString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut names = Vec::new();
+    let mut offset = 0;
+    while offset + header <= buf.len() {
+        let event: libc::inotify_event =
+            unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const _) };
+        let start = offset + header;
+        let end = (start + event.len as usize).min(buf.len());
+
+        // the name is NUL padded
+        let name = &buf[start..end];
+        let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+        names.push(std::ffi::OsStr::from_bytes(name).to_os_string());
+
+        offset = end;
+    }
+
+    names
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Result<ConfigWatcher, IoError> {
+        Ok(ConfigWatcher {
+            path: path.to_owned(),
+            modified: modified(path),
+        })
+    }
+
+    /// Waits up to `timeout` for the file to change. Without inotify the
+    /// modification time is compared after sleeping for `timeout`.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool, IoError> {
+        std::thread::sleep(timeout);
+
+        let modified = modified(&self.path);
+        let changed = modified != self.modified;
+        self.modified = modified;
+        Ok(changed)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::filestore::FileStore;
+    use std::fs;
+
+    const DIR: &str = "/tmp/cni-reload";
+
+    fn conf(ranges: &str) -> String {
+        format!(
+            r#"{{"name": "reload", "ipam": {{"dataDir": "{}", "ranges": {}}}}}"#,
+            DIR, ranges
+        )
+    }
+
+    #[test]
+    fn reload() {
+        let _ = fs::remove_dir_all(DIR);
+        fs::create_dir_all(DIR).unwrap();
+        let path = Path::new(DIR).join("net.conf");
+        fs::write(&path, conf(r#"[[{"subnet": "10.1.0.0/29"}]]"#)).unwrap();
+
+        let mut watcher = ConfigWatcher::new(&path).unwrap();
+        let current = NetConf::load(File::open(&path).unwrap()).unwrap();
+        let store: Arc<dyn Store> = Arc::new(FileStore::new("reload", DIR).unwrap());
+        let new_allocator = |_, range_set, store| Allocator::new(range_set, store);
+
+        let mut allocators = Vec::new();
+        apply(
+            &mut allocators,
+            current.ipam.range_sets().unwrap(),
+            &store,
+            new_allocator,
+        )
+        .unwrap();
+        let old_ip = allocators[0].get("c1", "eth0", None).unwrap();
+
+        // an invalid configuration is rejected
+        fs::write(&path, conf(r#"[[{"subnet": "10.1.0.0/33"}]]"#)).unwrap();
+        assert!(watcher.wait(Duration::from_secs(5)).unwrap());
+        assert!(load(&path, &current).is_err());
+
+        // the old range is swapped for a new one, a second set is added
+        fs::write(
+            &path,
+            conf(r#"[[{"subnet": "10.2.0.0/29"}], [{"subnet": "2001:db8::/64"}]]"#),
+        )
+        .unwrap();
+        assert!(watcher.wait(Duration::from_secs(5)).unwrap());
+        let (_, range_sets) = load(&path, &current).unwrap();
+        apply(&mut allocators, range_sets, &store, new_allocator).unwrap();
+        assert_eq!(allocators.len(), 2);
+
+        let new_ip = allocators[0].get("c2", "eth0", None).unwrap();
+        assert_eq!(new_ip.address().to_string(), "10.2.0.2/29");
+        assert!(allocators[1].get("c2", "eth0", None).is_ok());
+
+        // the reservation in the removed range survives until released
+        assert_eq!(store.get_by_id("c1", "eth0"), vec![old_ip.address().ip()]);
+        assert_eq!(
+            allocators[0].release("c1", "eth0").unwrap(),
+            vec![old_ip.address().ip()]
+        );
+
+        assert!(!watcher.wait(Duration::from_millis(10)).unwrap());
+
+        let _ = fs::remove_dir_all(DIR);
+    }
+}
@@ -0,0 +1,556 @@
+//! Reservations kept in the KV store of Consul, so the nodes of a cluster
+//! can share one pool.
+//!
+//! Every network lives below `<prefix>/<network>/`: a key per reserved IP in
+//! `ips/` holding its owner as JSON, the last reserved IP of every range set
+//! in `last_reserved/` and the key `lock`, which `lock` acquires with a
+//! session. Reservations are created with check-and-set writes inside Consul
+//! transactions, so an IP is never handed out twice even by nodes not
+//! holding the lock, and a node dying with the lock held only blocks the
+//! others until its session expires.
+//!
+//! The store talks plain HTTP to a Consul agent, usually the one running on
+//! the node.
+
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::filestore::validate_network_name;
+use super::{locked, Operation, Owner, StatsCounter, Store, StoreError, StoreStats, Transaction};
+use crate::http::{self, Response};
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8500";
+pub const DEFAULT_PREFIX: &str = "cni/ipam";
+
+/// Most operations Consul accepts in one transaction.
+pub const MAX_TXN_OPERATIONS: usize = 64;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub struct ConsulOptions {
+    /// `host:port` of the HTTP API of the Consul agent.
+    pub address: String,
+    /// Key below which the networks are kept, without slashes around it.
+    pub prefix: String,
+    /// ACL token sent with every request.
+    pub token: Option<String>,
+    /// How long the lock outlives a node which crashed while holding it, at
+    /// least the 10 seconds Consul allows.
+    pub session_ttl: Duration,
+    /// How long `lock` waits for another node to release the lock before
+    /// failing with `StoreError::LockTimeout`. Waits as long as it takes if
+    /// unset.
+    pub lock_timeout: Option<Duration>,
+}
+
+impl Default for ConsulOptions {
+    fn default() -> Self {
+        ConsulOptions {
+            address: DEFAULT_ADDRESS.to_owned(),
+            prefix: DEFAULT_PREFIX.to_owned(),
+            token: None,
+            session_ttl: Duration::from_secs(15),
+            lock_timeout: None,
+        }
+    }
+}
+
+pub struct ConsulStore {
+    network: String,
+    options: ConsulOptions,
+    /// Session holding the lock of the network, while it is held.
+    session: Mutex<Option<String>>,
+    stats: StatsCounter,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvPair {
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Session {
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+impl ConsulStore {
+    pub fn new(network: &str, options: ConsulOptions) -> Result<ConsulStore, StoreError> {
+        validate_network_name(network)?;
+
+        Ok(ConsulStore {
+            network: network.to_owned(),
+            options: options,
+            session: Mutex::new(None),
+            stats: StatsCounter::new(),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.options.prefix.trim_matches('/'),
+            self.network,
+            name
+        )
+    }
+
+    fn ip_key(&self, ip: IpAddr) -> String {
+        self.key(&format!("ips/{}", ip))
+    }
+
+    fn last_reserved_key(&self, range_id: &str) -> String {
+        self.key(&format!("last_reserved/{}", range_id))
+    }
+
+    /// Value of `key`, None if it doesn't exist.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        let response = self.request("GET", &format!("/v1/kv/{}?raw", escape(key)), None)?;
+        match response.status {
+            404 => Ok(None),
+            _ => response.ok().map(Some),
+        }
+    }
+
+    /// Keys and values below `prefix`.
+    fn list_values(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>, StoreError> {
+        let path = format!("/v1/kv/{}/?recurse=true", escape(prefix));
+        let response = self.request("GET", &path, None)?;
+        if response.status == 404 {
+            return Ok(Vec::new());
+        }
+
+        let pairs: Vec<KvPair> =
+            serde_json::from_slice(&response.ok()?).map_err(|err| corrupt(prefix, err))?;
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let value = base64_decode(pair.value.as_deref().unwrap_or_default())
+                    .ok_or_else(|| corrupt(prefix, "value is not base64"))?;
+                Ok((pair.key, value))
+            })
+            .collect()
+    }
+
+    /// Reservations of the network with their owners.
+    fn reservations(&self) -> Result<Vec<(IpAddr, Owner)>, StoreError> {
+        self.list_values(&self.key("ips"))?
+            .into_iter()
+            .map(|(key, value)| {
+                let ip = key
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or_default()
+                    .parse()
+                    .map_err(|err| corrupt(&key, err))?;
+                let owner = serde_json::from_slice(&value).map_err(|err| corrupt(&key, err))?;
+                Ok((ip, owner))
+            })
+            .collect()
+    }
+
+    /// Runs `operations` as one Consul transaction. Returns false if it was
+    /// rolled back because one of its checks failed.
+    fn txn(&self, operations: Vec<Value>) -> Result<bool, StoreError> {
+        if operations.is_empty() {
+            return Ok(true);
+        }
+        if operations.len() > MAX_TXN_OPERATIONS {
+            return Err(StoreError::IOError(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} operations exceed the {} of a consul transaction",
+                    operations.len(),
+                    MAX_TXN_OPERATIONS
+                ),
+            )));
+        }
+
+        let body = Value::Array(operations).to_string();
+        let response = self.request("PUT", "/v1/txn", Some(body.as_bytes()))?;
+        match response.status {
+            409 => Ok(false),
+            _ => response.ok().map(|_| true),
+        }
+    }
+
+    fn create_session(&self) -> Result<String, StoreError> {
+        let body = json!({
+            "Name": format!("host-local {}", self.network),
+            "TTL": format!("{}s", self.options.session_ttl.as_secs().max(10)),
+            "Behavior": "release",
+            "LockDelay": "0s",
+        })
+        .to_string();
+        let response = self.request("PUT", "/v1/session/create", Some(body.as_bytes()))?;
+
+        let session: Session = serde_json::from_slice(&response.ok()?)
+            .map_err(|err| corrupt("/v1/session/create", err))?;
+        Ok(session.id)
+    }
+
+    fn destroy_session(&self, session: &str) -> Result<(), StoreError> {
+        let path = format!("/v1/session/destroy/{}", escape(session));
+        self.request("PUT", &path, None)?.ok().map(|_| ())
+    }
+
+    fn try_acquire(&self, session: &str) -> Result<bool, StoreError> {
+        let path = format!(
+            "/v1/kv/{}?acquire={}",
+            escape(&self.key("lock")),
+            escape(session)
+        );
+        let response = self.request("PUT", &path, Some(b""))?;
+        Ok(response.ok()?.trim_ascii() == b"true")
+    }
+
+    fn acquire(&self, session: &str) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let mut interval = Duration::from_millis(10);
+
+        loop {
+            if self.try_acquire(session)? {
+                return Ok(());
+            }
+
+            let elapsed = start.elapsed();
+            if let Some(timeout) = self.options.lock_timeout {
+                if elapsed >= timeout {
+                    return Err(StoreError::LockTimeout {
+                        path: PathBuf::from(self.key("lock")),
+                        holder: None,
+                        timeout: timeout,
+                    });
+                }
+                interval = interval.min(timeout - elapsed);
+            }
+
+            thread::sleep(interval);
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+        }
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<Response, IoError> {
+        let mut headers = Vec::new();
+        if let Some(token) = &self.options.token {
+            headers.push(("X-Consul-Token", token.as_str()));
+        }
+
+        http::send(
+            &self.options.address,
+            method,
+            path,
+            &headers,
+            body.unwrap_or_default(),
+            REQUEST_TIMEOUT,
+        )
+    }
+
+    /// `Store::lock` without counting, see `StatsCounter::lock`.
+    fn lock_session(&self) -> Result<(), StoreError> {
+        let session = self.create_session()?;
+        if let Err(err) = self.acquire(&session) {
+            let _ = self.destroy_session(&session);
+            return Err(err);
+        }
+
+        *locked(&self.session) = Some(session);
+        Ok(())
+    }
+
+    /// `Store::commit` without counting.
+    fn commit_txn(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        let mut operations = Vec::new();
+        for operation in txn.operations() {
+            let operation = match operation {
+                // an index of 0 only creates the key if it doesn't exist
+                Operation::Reserve { owner, ip } => json!({"KV": {
+                    "Verb": "cas",
+                    "Key": self.ip_key(*ip),
+                    "Value": base64_encode(&serde_json::to_vec(owner).map_err(IoError::from)?),
+                    "Index": 0,
+                }}),
+                Operation::Release(ip) => json!({"KV": {
+                    "Verb": "delete",
+                    "Key": self.ip_key(*ip),
+                }}),
+                Operation::RecordLastReserved { ip, range_id } => json!({"KV": {
+                    "Verb": "set",
+                    "Key": self.last_reserved_key(range_id),
+                    "Value": base64_encode(ip.to_string().as_bytes()),
+                }}),
+            };
+            operations.push(operation);
+        }
+
+        self.txn(operations)
+    }
+
+    /// `Store::release` without counting.
+    fn release_ip(&self, ip: IpAddr) -> Result<(), StoreError> {
+        let key = self.ip_key(ip);
+        // the get fails the transaction if the IP isn't reserved
+        let released = self.txn(vec![
+            json!({"KV": {"Verb": "get", "Key": key}}),
+            json!({"KV": {"Verb": "delete", "Key": key}}),
+        ])?;
+        if !released {
+            return Err(StoreError::NotFound(ip));
+        }
+
+        Ok(())
+    }
+
+    /// `Store::release_by_id` without counting.
+    fn release_ips_of(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        let operations: Vec<Value> = self
+            .reservations()?
+            .into_iter()
+            .filter(|(_, owner)| owner.id == id && owner.ifname == ifname)
+            .map(|(ip, _)| json!({"KV": {"Verb": "delete", "Key": self.ip_key(ip)}}))
+            .collect();
+
+        self.txn(operations).map(|_| ())
+    }
+}
+
+impl Response {
+    /// The body of a successful response.
+    fn ok(self) -> Result<Vec<u8>, StoreError> {
+        if self.is_success() {
+            return Ok(self.body);
+        }
+
+        Err(StoreError::IOError(IoError::other(format!(
+            "consul responded with {}: {}",
+            self.status,
+            String::from_utf8_lossy(&self.body).trim()
+        ))))
+    }
+}
+
+impl Store for ConsulStore {
+    fn lock(&self) -> Result<(), StoreError> {
+        self.stats.lock(|| self.lock_session())
+    }
+
+    fn unlock(&self) -> Result<(), StoreError> {
+        let session = match locked(&self.session).take() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+
+        let path = format!(
+            "/v1/kv/{}?release={}",
+            escape(&self.key("lock")),
+            escape(&session)
+        );
+        let released = self
+            .request("PUT", &path, Some(b""))
+            .map_err(StoreError::from);
+        // destroying the session releases the lock too, should the release
+        // have failed
+        self.destroy_session(&session)?;
+        released?.ok().map(|_| ())
+    }
+
+    fn close(&self) -> Result<(), StoreError> {
+        self.unlock()
+    }
+
+    fn commit(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        self.stats.commit(txn, self.commit_txn(txn))
+    }
+
+    fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError> {
+        let key = self.last_reserved_key(range_id);
+        let value = self
+            .get(&key)?
+            .ok_or_else(|| StoreError::LastReservedNotFound(range_id.to_owned()))?;
+
+        String::from_utf8_lossy(&value)
+            .trim()
+            .parse()
+            .map_err(|err| corrupt(&key, err))
+    }
+
+    fn last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+        self.list_values(&self.key("last_reserved"))?
+            .into_iter()
+            .map(|(key, value)| {
+                let range_id = key.rsplit('/').next().unwrap_or_default().to_owned();
+                let ip = String::from_utf8_lossy(&value)
+                    .trim()
+                    .parse()
+                    .map_err(|err| corrupt(&key, err))?;
+                Ok((range_id, ip))
+            })
+            .collect()
+    }
+
+    fn release(&self, ip: IpAddr) -> Result<(), StoreError> {
+        self.stats.release(self.release_ip(ip))
+    }
+
+    fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        self.stats.release(self.release_ips_of(id, ifname))
+    }
+
+    fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr> {
+        self.reservations()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, owner)| owner.id == id && owner.ifname == ifname)
+            .map(|(ip, _)| ip)
+            .collect()
+    }
+
+    fn owner(&self, ip: IpAddr) -> Result<Owner, StoreError> {
+        let key = self.ip_key(ip);
+        let value = self.get(&key)?.ok_or(StoreError::NotFound(ip))?;
+
+        serde_json::from_slice(&value).map_err(|err| corrupt(&key, err))
+    }
+
+    fn list(&self) -> Result<Vec<IpAddr>, StoreError> {
+        Ok(self.reservations()?.into_iter().map(|(ip, _)| ip).collect())
+    }
+
+    fn stats(&self) -> Option<StoreStats> {
+        Some(self.stats.get())
+    }
+}
+
+fn corrupt<E: std::fmt::Display>(key: &str, err: E) -> StoreError {
+    StoreError::Corrupt {
+        path: PathBuf::from(key),
+        reason: err.to_string(),
+    }
+}
+
+/// Percent-encodes everything of `key` but unreserved characters and
+/// slashes, so it can be put into a URL path or query.
+fn escape(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    escaped
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Consul transfers values base64 encoded in JSON.
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|b| b == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        for (bytes, encoded) in &[
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"10.1.2.3", "MTAuMS4yLjM="),
+        ] {
+            assert_eq!(base64_encode(bytes), *encoded);
+            assert_eq!(base64_decode(encoded).as_deref(), Some(*bytes));
+        }
+        assert_eq!(base64_decode("Z"), None);
+        assert_eq!(base64_decode("Zm9*"), None);
+    }
+
+    #[test]
+    fn keys() {
+        let options = ConsulOptions {
+            prefix: "/cni/ipam/".to_owned(),
+            ..ConsulOptions::default()
+        };
+        let store = ConsulStore::new("net1", options).unwrap();
+        assert_eq!(
+            store.ip_key("2001:db8::1".parse().unwrap()),
+            "cni/ipam/net1/ips/2001:db8::1"
+        );
+        assert_eq!(
+            store.last_reserved_key("0"),
+            "cni/ipam/net1/last_reserved/0"
+        );
+        assert_eq!(escape("a b/2001:db8::1"), "a%20b/2001%3Adb8%3A%3A1");
+
+        assert!(matches!(
+            ConsulStore::new("..", ConsulOptions::default()),
+            Err(StoreError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn response() {
+        let response = Response::parse(b"HTTP/1.0 409 Conflict\r\nX: y\r\n\r\nbody\n").unwrap();
+        match response.ok() {
+            Err(StoreError::IOError(err)) => {
+                assert_eq!(err.to_string(), "consul responded with 409: body")
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! A store failing chosen operations on purpose, to test how callers cope
+//! with a store breaking halfway, e.g. rollbacks and retries. Built for the
+//! tests of this crate and, with the `test-util` feature, for downstream
+//! users of the library.
+
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Owner, Store, StoreError, StoreStats, Transaction};
+
+/// The operations of a store a `FaultStore` counts and can fail. Lookups by
+/// id can't fail, `Store::get_by_id` has no error to report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Lock,
+    Unlock,
+    LockRange,
+    UnlockRange,
+    Close,
+    Commit,
+    LastReservedIp,
+    Release,
+    ReleaseById,
+    Touch,
+    Owner,
+    List,
+}
+
+/// Wraps another store and fails the operations picked by `fail_nth` or
+/// `fail_on` with an `IOError`, passing every other operation through.
+pub struct FaultStore<S: Store> {
+    inner: S,
+    /// 1-based number of the operation to fail.
+    nth: Option<usize>,
+    ops: Vec<Op>,
+    kind: ErrorKind,
+    count: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl<S: Store> FaultStore<S> {
+    pub fn new(inner: S) -> FaultStore<S> {
+        FaultStore {
+            inner: inner,
+            nth: None,
+            ops: Vec::new(),
+            kind: ErrorKind::Other,
+            count: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fails the `n`th operation, counting from 1.
+    pub fn fail_nth(mut self, n: usize) -> FaultStore<S> {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Fails every operation of kind `op`.
+    pub fn fail_on(mut self, op: Op) -> FaultStore<S> {
+        self.ops.push(op);
+        self
+    }
+
+    /// Fails with errors of `kind`, e.g. `WouldBlock` for failures a
+    /// `RetryPolicy` retries. `Other` by default.
+    pub fn with_error(mut self, kind: ErrorKind) -> FaultStore<S> {
+        self.kind = kind;
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Number of operations tried so far, failed ones included.
+    pub fn operations(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Number of operations failed so far.
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Counts `op` and fails it if it was picked.
+    fn check(&self, op: Op) -> Result<(), StoreError> {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if self.nth == Some(count) || self.ops.contains(&op) {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            let message = format!("injected failure of operation {} ({:?})", count, op);
+            return Err(StoreError::IOError(IoError::new(self.kind, message)));
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Store> Store for FaultStore<S> {
+    fn lock(&self) -> Result<(), StoreError> {
+        self.check(Op::Lock)?;
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> Result<(), StoreError> {
+        self.check(Op::Unlock)?;
+        self.inner.unlock()
+    }
+
+    fn lock_range(&self, range_id: &str) -> Result<(), StoreError> {
+        self.check(Op::LockRange)?;
+        self.inner.lock_range(range_id)
+    }
+
+    fn unlock_range(&self, range_id: &str) -> Result<(), StoreError> {
+        self.check(Op::UnlockRange)?;
+        self.inner.unlock_range(range_id)
+    }
+
+    fn close(&self) -> Result<(), StoreError> {
+        self.check(Op::Close)?;
+        self.inner.close()
+    }
+
+    fn commit(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        self.check(Op::Commit)?;
+        self.inner.commit(txn)
+    }
+
+    fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError> {
+        self.check(Op::LastReservedIp)?;
+        self.inner.last_reserved_ip(range_id)
+    }
+
+    fn last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+        self.check(Op::LastReservedIp)?;
+        self.inner.last_reserved_ips()
+    }
+
+    fn release(&self, ip: IpAddr) -> Result<(), StoreError> {
+        self.check(Op::Release)?;
+        self.inner.release(ip)
+    }
+
+    fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        self.check(Op::ReleaseById)?;
+        self.inner.release_by_id(id, ifname)
+    }
+
+    fn touch(&self, ip: IpAddr, id: &str, ifname: &str) -> Result<(), StoreError> {
+        self.check(Op::Touch)?;
+        self.inner.touch(ip, id, ifname)
+    }
+
+    fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr> {
+        self.inner.get_by_id(id, ifname)
+    }
+
+    fn owner(&self, ip: IpAddr) -> Result<Owner, StoreError> {
+        self.check(Op::Owner)?;
+        self.inner.owner(ip)
+    }
+
+    fn list(&self) -> Result<Vec<IpAddr>, StoreError> {
+        self.check(Op::List)?;
+        self.inner.list()
+    }
+
+    /// The counters of the wrapped store, which never sees injected
+    /// failures.
+    fn stats(&self) -> Option<StoreStats> {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::range::Range;
+    use crate::allocator::rangeset::RangeSet;
+    use crate::allocator::retry::RetryPolicy;
+    use crate::allocator::{AllocateError, Allocator};
+    use crate::error::HostLocalError;
+    use crate::store::filestore::FileStore;
+    use std::fs::remove_dir_all;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn allocator(store: Arc<FaultStore<FileStore>>) -> Allocator {
+        let mut range_set = RangeSet::new();
+        range_set
+            .add(Range::new("10.1.2.0/24".parse().unwrap(), None, None, None).unwrap())
+            .unwrap();
+
+        Allocator::new(range_set, store).with_retry_policy(RetryPolicy {
+            attempts: 2,
+            backoff: Duration::from_millis(1),
+        })
+    }
+
+    #[test]
+    fn nth_and_patterns() {
+        let data_dir = "/tmp/cni-faultstore";
+        let _ = remove_dir_all(data_dir);
+
+        let store = FaultStore::new(FileStore::new("n", data_dir).unwrap())
+            .fail_nth(2)
+            .fail_on(Op::Release);
+        let ip = "10.1.2.2".parse().unwrap();
+        assert!(store.reserve("c1", "eth0", ip, "0").unwrap());
+        assert!(store.reserve("c2", "eth0", ip, "0").is_err());
+        assert!(!store.reserve("c2", "eth0", ip, "0").unwrap());
+        assert!(store.release(ip).is_err());
+        assert_eq!(store.inner().list().unwrap(), vec![ip]);
+        assert_eq!((store.operations(), store.failures()), (4, 2));
+
+        let _ = remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn retries_and_error_mapping() {
+        let data_dir = "/tmp/cni-faultstore-retries";
+        let _ = remove_dir_all(data_dir);
+
+        // a transient failure of the first commit is retried
+        let store = FaultStore::new(FileStore::new("n", data_dir).unwrap())
+            .fail_nth(2)
+            .with_error(ErrorKind::WouldBlock);
+        let store = Arc::new(store);
+        allocator(store.clone()).get("c1", "eth0", None).unwrap();
+        assert_eq!(store.failures(), 1);
+
+        // permanent failures reach the runtime as internal errors
+        let store = FaultStore::new(FileStore::new("n", data_dir).unwrap()).fail_on(Op::Commit);
+        let err = allocator(Arc::new(store))
+            .get("c2", "eth0", None)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AllocateError::StoreError(StoreError::IOError(_))
+        ));
+        let err = HostLocalError::from(err);
+        assert_eq!(err.code(), crate::cni::ERR_INTERNAL);
+        assert!(err.report().contains("injected failure"));
+
+        let _ = remove_dir_all(data_dir);
+    }
+}
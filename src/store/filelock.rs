@@ -0,0 +1,250 @@
+//! Platform specific advisory locking of the store's lock file.
+//!
+//! Unix uses `flock(2)`, Windows uses `LockFileEx`. Locks are exclusive
+//! unless taken with `lock_shared`, block until acquired and are released
+//! automatically when the file handle is closed. `lock_within` polls instead
+//! of blocking, so a hung holder can't keep the caller waiting forever.
+//!
+//! Holders of exclusive locks may record their PID in the lock file for
+//! diagnostics. On Windows the locked file can't be read by others, so the
+//! holder stays unknown there.
+
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest pause between two attempts of `lock_within`.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(unix)]
+pub fn lock(file: &File) -> Result<(), IoError> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+    if ret != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn lock_shared(file: &File) -> Result<(), IoError> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) };
+    if ret != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn unlock(file: &File) -> Result<(), IoError> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+    if ret != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn try_lock(file: &File) -> Result<(), IoError> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn try_lock_shared(file: &File) -> Result<(), IoError> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) };
+    if ret != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn lock(file: &File) -> Result<(), IoError> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, OVERLAPPED};
+
+    let ret = unsafe {
+        let mut overlapped: OVERLAPPED = mem::zeroed();
+        LockFileEx(
+            file.as_raw_handle() as _,
+            LOCKFILE_EXCLUSIVE_LOCK,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    };
+    if ret == 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn lock_shared(file: &File) -> Result<(), IoError> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::OVERLAPPED;
+
+    let ret = unsafe {
+        let mut overlapped: OVERLAPPED = mem::zeroed();
+        LockFileEx(
+            file.as_raw_handle() as _,
+            0,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    };
+    if ret == 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn unlock(file: &File) -> Result<(), IoError> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::UnlockFileEx;
+    use winapi::um::minwinbase::OVERLAPPED;
+
+    let ret = unsafe {
+        let mut overlapped: OVERLAPPED = mem::zeroed();
+        UnlockFileEx(
+            file.as_raw_handle() as _,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    };
+    if ret == 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn try_lock(file: &File) -> Result<(), IoError> {
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY};
+
+    lock_immediately(file, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY)
+}
+
+#[cfg(windows)]
+pub fn try_lock_shared(file: &File) -> Result<(), IoError> {
+    use winapi::um::minwinbase::LOCKFILE_FAIL_IMMEDIATELY;
+
+    lock_immediately(file, LOCKFILE_FAIL_IMMEDIATELY)
+}
+
+/// `LockFileEx` with `flags`, reporting a lock held elsewhere as
+/// `ErrorKind::WouldBlock` like `flock` does.
+#[cfg(windows)]
+fn lock_immediately(file: &File, flags: u32) -> Result<(), IoError> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::OVERLAPPED;
+
+    let ret = unsafe {
+        let mut overlapped: OVERLAPPED = mem::zeroed();
+        LockFileEx(
+            file.as_raw_handle() as _,
+            flags,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    };
+    if ret == 0 {
+        let err = IoError::last_os_error();
+        if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+            return Err(IoError::from(ErrorKind::WouldBlock));
+        }
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Takes the lock with `try_lock` or `try_lock_shared`, retrying with
+/// growing pauses while it is held elsewhere. Fails with
+/// `ErrorKind::TimedOut` once `timeout` passed.
+pub fn lock_within(
+    file: &File,
+    try_lock: fn(&File) -> Result<(), IoError>,
+    timeout: Duration,
+) -> Result<(), IoError> {
+    let start = Instant::now();
+    let mut interval = Duration::from_millis(1);
+
+    loop {
+        match try_lock(file) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            return Err(IoError::from(ErrorKind::TimedOut));
+        }
+
+        thread::sleep(interval.min(timeout - elapsed));
+        interval = (interval * 2).min(MAX_POLL_INTERVAL);
+    }
+}
+
+/// Writes the PID of this process into `file`, whose exclusive lock it
+/// holds.
+pub fn record_holder(file: &File) -> Result<(), IoError> {
+    let mut file = file;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "{}", process::id())
+}
+
+/// Empties `file` before its exclusive lock is released.
+pub fn clear_holder(file: &File) -> Result<(), IoError> {
+    file.set_len(0)
+}
+
+/// The PID recorded in `file` by the holder of its exclusive lock, if any.
+pub fn holder(file: &File) -> Option<u32> {
+    let mut file = file;
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+
+    content.trim().parse().ok()
+}
@@ -1,178 +1,1522 @@
-use super::{Store, StoreError};
-use std::fs::{create_dir_all, read_to_string, remove_file, OpenOptions};
+#[cfg(feature = "encryption")]
+mod crypt;
+mod index;
+mod journal;
+
+#[cfg(feature = "encryption")]
+pub use crypt::RecordKey;
+
+use super::{
+  check_labels, filelock, locked, Operation, Owner, Pins, Pod, StatsCounter, Store, StoreError,
+  StoreStats, Transaction, PINS_FILE,
+};
+use crate::allocator::rangeset::RangeSet;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{
+  hard_link, read_dir, read_to_string, remove_file, rename, DirBuilder, File, OpenOptions,
+};
 use std::io::{Error as IoError, ErrorKind, Write};
 use std::net::IpAddr;
-use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::fs::{chown, fchown, DirBuilderExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
 use walkdir::{DirEntry, WalkDir};
 
-const LAST_IP_FILE_PREFIX: &str = "last_reserved_ip";
+const LAST_IP_FILE: &str = "last_reserved_ip.json";
+const STATS_FILE: &str = "stats.json";
+const UPSTREAM_LAST_IP_FILE_PREFIX: &str = "last_reserved_ip.";
+const LOCK_FILE: &str = "lock";
+const LAST_IP_LOCK_FILE: &str = "lock.last_reserved_ip";
+const INDEX_LOCK_FILE: &str = "lock.index";
+const RANGE_LOCK_FILE_PREFIX: &str = "lock.range-";
+const STATS_LOCK_FILE: &str = "lock.stats";
+#[cfg(unix)]
 const DEFAULT_DATA_DIR: &str = "/var/lib/cni/networks";
+#[cfg(windows)]
+const DEFAULT_DATA_DIR: &str = "C:\\ProgramData\\cni\\networks";
 const LINE_BREAK: &str = "\r\n";
+const TMP_FILE_SUFFIX: &str = ".tmp";
+/// Name the temporary file of `FileStore::probe` is derived from.
+const PROBE_FILE: &str = "probe";
+
+static TMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Permissions and ownership applied to everything `FileStore` creates.
+///
+/// `uid` and `gid` are left untouched when unset, so files end up owned by
+/// the user running the plugin. Modes and ownership are ignored on Windows.
+///
+/// `rootless` controls where an empty data dir resolves to: `Some(true)`
+/// always uses the per-user directory, `Some(false)` always uses the system
+/// one, and `None` uses the system one unless creating it is denied. A
+/// read-only store then uses the per-user one if only that exists.
+///
+/// A `read_only` store inspects an existing data dir, e.g. one in use by
+/// running plugins. It creates nothing, never locks, and fails every write
+/// with `StoreError::ReadOnly`.
+///
+/// With `upstream_last_reserved` the last reserved IP of every range set is
+/// kept in a `last_reserved_ip.<range id>` file holding just the IP, like
+/// the Go plugin does, instead of in one JSON file. Together with range ids
+/// numbered by position a node can switch between the plugins without
+/// allocation starting over. Reservation files then hold only the container
+/// id and interface, the Go plugin finds the IPs of a container by their
+/// whole content, so network namespaces, pods and labels aren't kept.
+///
+/// With `journal` the reservations and last reserved IPs of the network are
+/// kept in a single snapshot file and a journal of the transactions since,
+/// instead of a file per IP, see `FileStore::compact`. It suits networks
+/// large enough for thousands of small files to hurt. Every plugin using
+/// the data dir has to agree on it, a store opened with it folds the files
+/// it finds into the snapshot.
+///
+/// With `index` an index file of the reservations spares `get_by_id` and
+/// `release_by_id` reading every reservation file. It is rebuilt from the
+/// reservations whenever they changed without it. `journal` needs none.
+///
+/// With `lock_timeout` waiting for the network lock or the lock of a range
+/// set gives up with `StoreError::LockTimeout` once it passed, instead of
+/// blocking as long as the holder keeps it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FileStoreOptions {
+  pub dir_mode: u32,
+  pub file_mode: u32,
+  pub uid: Option<u32>,
+  pub gid: Option<u32>,
+  pub rootless: Option<bool>,
+  pub read_only: bool,
+  pub upstream_last_reserved: bool,
+  pub journal: bool,
+  pub index: bool,
+  pub lock_timeout: Option<Duration>,
+}
+
+impl Default for FileStoreOptions {
+  fn default() -> Self {
+    FileStoreOptions {
+      dir_mode: 0o755,
+      file_mode: 0o644,
+      uid: None,
+      gid: None,
+      rootless: None,
+      read_only: false,
+      upstream_last_reserved: false,
+      journal: false,
+      index: false,
+      lock_timeout: None,
+    }
+  }
+}
+
+/// Kinds of damage `FileStore::fsck` looks for in a data dir.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProblemKind {
+  /// A reservation file without content, left by a crash before its data
+  /// reached the disk.
+  EmptyReservation,
+  /// A reservation of an IP which none of the configured ranges contains.
+  OutOfRange,
+  /// A last reserved IP of a range set which no longer exists or no longer
+  /// contains it. Pointing at a released IP is fine, that's what DEL leaves.
+  StaleLastReserved,
+  /// A temporary file of a transaction which never completed.
+  OrphanedTempFile,
+}
+
+/// A single problem found by `FileStore::fsck`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Problem {
+  pub kind: ProblemKind,
+  pub path: PathBuf,
+  pub detail: String,
+  pub fixed: bool,
+}
 
+/// Reservations of a network stored as one file per IP in its data dir.
+///
+/// `lock` takes the network wide lock file exclusively. `lock_range` takes it
+/// shared plus an exclusive lock file per range set, so ADDs on different
+/// range sets don't wait for each other. The JSON of last reserved IPs is
+/// shared by all range sets and guarded by its own lock, held only while it
+/// is rewritten.
 #[derive(Debug)]
-struct FileStore {
+pub struct FileStore {
   data_dir: PathBuf,
+  lock_file: Option<File>,
+  last_reserved_lock: Option<File>,
+  index_lock: Option<File>,
+  range_locks: Mutex<HashMap<String, Arc<File>>>,
+  options: FileStoreOptions,
+  stats: StatsCounter,
+  /// Seals the content of reservation files, see `encrypt_with`.
+  #[cfg(feature = "encryption")]
+  record_key: Option<RecordKey>,
+}
+
+/// Assembles the options of a `FileStore` step by step, see
+/// `FileStore::builder`. The data dir defaults to the platform's.
+#[derive(Clone, Debug)]
+pub struct FileStoreBuilder {
+  network: String,
+  data_dir: String,
+  options: FileStoreOptions,
+  #[cfg(feature = "encryption")]
+  record_key: Option<RecordKey>,
+}
+
+impl FileStoreBuilder {
+  pub fn data_dir(mut self, data_dir: &str) -> FileStoreBuilder {
+    self.data_dir = data_dir.to_owned();
+    self
+  }
+
+  /// Replaces every option set so far.
+  pub fn options(mut self, options: FileStoreOptions) -> FileStoreBuilder {
+    self.options = options;
+    self
+  }
+
+  pub fn dir_mode(mut self, dir_mode: u32) -> FileStoreBuilder {
+    self.options.dir_mode = dir_mode;
+    self
+  }
+
+  pub fn file_mode(mut self, file_mode: u32) -> FileStoreBuilder {
+    self.options.file_mode = file_mode;
+    self
+  }
+
+  pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> FileStoreBuilder {
+    self.options.uid = uid;
+    self.options.gid = gid;
+    self
+  }
+
+  pub fn rootless(mut self, rootless: bool) -> FileStoreBuilder {
+    self.options.rootless = Some(rootless);
+    self
+  }
+
+  pub fn read_only(mut self, read_only: bool) -> FileStoreBuilder {
+    self.options.read_only = read_only;
+    self
+  }
+
+  pub fn upstream_last_reserved(mut self, upstream_last_reserved: bool) -> FileStoreBuilder {
+    self.options.upstream_last_reserved = upstream_last_reserved;
+    self
+  }
+
+  pub fn journal(mut self, journal: bool) -> FileStoreBuilder {
+    self.options.journal = journal;
+    self
+  }
+
+  pub fn index(mut self, index: bool) -> FileStoreBuilder {
+    self.options.index = index;
+    self
+  }
+
+  pub fn lock_timeout(mut self, lock_timeout: Duration) -> FileStoreBuilder {
+    self.options.lock_timeout = Some(lock_timeout);
+    self
+  }
+
+  /// See `FileStore::encrypt_with`.
+  #[cfg(feature = "encryption")]
+  pub fn record_key(mut self, record_key: RecordKey) -> FileStoreBuilder {
+    self.record_key = Some(record_key);
+    self
+  }
+
+  /// Opens the store, see `FileStore::with_options`.
+  pub fn build(self) -> Result<FileStore, StoreError> {
+    let store = FileStore::with_options(&self.network, &self.data_dir, self.options)?;
+    #[cfg(feature = "encryption")]
+    {
+      if let Some(record_key) = self.record_key {
+        return store.encrypt_with(record_key);
+      }
+    }
+
+    Ok(store)
+  }
 }
 
 impl FileStore {
   pub fn new(network: &str, data_dir: &str) -> Result<FileStore, StoreError> {
-    let mut data_dir = data_dir;
-    if data_dir == "" {
-      data_dir = DEFAULT_DATA_DIR;
+    Self::with_options(network, data_dir, FileStoreOptions::default())
+  }
+
+  /// Starts opening the store of `network`, e.g.
+  /// `FileStore::builder("n").data_dir(dir).index(true).build()?`. Every
+  /// option is described at `FileStoreOptions`.
+  pub fn builder(network: &str) -> FileStoreBuilder {
+    FileStoreBuilder {
+      network: network.to_owned(),
+      data_dir: String::new(),
+      options: FileStoreOptions::default(),
+      #[cfg(feature = "encryption")]
+      record_key: None,
+    }
+  }
+
+  /// Seals the content of the reservation files written from now on with
+  /// `record_key`, and opens sealed ones when reading them. Their names
+  /// stay the IPs. The journal isn't sealed, so it can't be combined with
+  /// `FileStoreOptions::journal`.
+  #[cfg(feature = "encryption")]
+  pub fn encrypt_with(mut self, record_key: RecordKey) -> Result<FileStore, StoreError> {
+    if self.options.journal {
+      return Err(StoreError::IOError(IoError::new(
+        ErrorKind::InvalidInput,
+        "the journal of reservations can't be encrypted",
+      )));
+    }
+
+    self.record_key = Some(record_key);
+    Ok(self)
+  }
+
+  /// Like `encrypt_with`, with the key read from `path`, see
+  /// `RecordKey::load`. Fails if the plugin was built without the
+  /// `encryption` feature.
+  pub fn encrypt_with_key_file(self, path: &Path) -> Result<FileStore, StoreError> {
+    #[cfg(feature = "encryption")]
+    {
+      let record_key = RecordKey::load(path).map_err(StoreError::IOError)?;
+      self.encrypt_with(record_key)
+    }
+    #[cfg(not(feature = "encryption"))]
+    {
+      let _ = self;
+      Err(StoreError::IOError(IoError::other(format!(
+        "can't encrypt with {}, built without the encryption feature",
+        path.display()
+      ))))
+    }
+  }
+
+  /// Opens the store of `network` under `data_dir`, creating directories and
+  /// files according to `options`.
+  ///
+  /// An empty `data_dir` selects the platform default, or the per-user data
+  /// directory when running rootless. Unless rootless mode is forced either
+  /// way the platform default is tried first, and the per-user directory
+  /// used if creating it is denied, see `FileStoreOptions::rootless`.
+  pub fn with_options(
+    network: &str,
+    data_dir: &str,
+    options: FileStoreOptions,
+  ) -> Result<FileStore, StoreError> {
+    validate_network_name(network)?;
+
+    let path = if data_dir != "" {
+      Path::new(data_dir).join(network)
+    } else {
+      resolve_data_dir(network, &options, &default_data_dir(), user_data_dir())?
+    };
+
+    let (lock_file, last_reserved_lock) = if options.read_only {
+      read_dir(&path).map_err(StoreError::IOError)?;
+      (None, None)
+    } else {
+      create_dir(&path, &options).map_err(StoreError::IOError)?;
+
+      let lock_file = open_lock_file(&path.join(LOCK_FILE)).map_err(StoreError::IOError)?;
+      let last_reserved_lock =
+        open_lock_file(&path.join(LAST_IP_LOCK_FILE)).map_err(StoreError::IOError)?;
+      (Some(lock_file), Some(last_reserved_lock))
+    };
+    let index_lock = if options.index && !options.read_only {
+      Some(open_lock_file(&path.join(INDEX_LOCK_FILE)).map_err(StoreError::IOError)?)
+    } else {
+      None
+    };
+
+    let store = FileStore {
+      data_dir: path,
+      lock_file: lock_file,
+      last_reserved_lock: last_reserved_lock,
+      index_lock: index_lock,
+      range_locks: Mutex::new(HashMap::new()),
+      options: options,
+      stats: StatsCounter::new(),
+      #[cfg(feature = "encryption")]
+      record_key: None,
+    };
+    if options.journal && !options.read_only {
+      store.recover()?;
+    }
+
+    Ok(store)
+  }
+
+  pub fn data_dir(&self) -> &Path {
+    &self.data_dir
+  }
+
+  /// The IPs pinned in `reservations.conf` of the data dir, none if there
+  /// is no such file. It is read anew on every call, so edits apply to the
+  /// next ADD without restarting anything.
+  pub fn pins(&self) -> Result<Pins, StoreError> {
+    let path = self.data_dir.join(PINS_FILE);
+    match read_to_string(&path) {
+      Ok(text) => text.parse::<Pins>().map_err(|err| StoreError::Corrupt {
+        path: path,
+        reason: err.to_string(),
+      }),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(Pins::default()),
+      Err(err) => Err(StoreError::IOError(err)),
+    }
+  }
+
+  /// Path of the reservation file of `ip`, always named after the
+  /// canonical text form of the parsed address.
+  fn reservation_path(&self, ip: IpAddr) -> PathBuf {
+    self.data_dir.join(ip.to_string())
+  }
+
+  /// Reservation files directly inside the data dir.
+  fn reservations(&self) -> impl Iterator<Item = (DirEntry, IpAddr)> {
+    WalkDir::new(&self.data_dir)
+      .min_depth(1)
+      .max_depth(1)
+      .into_iter()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_type().is_file())
+      .filter_map(|e| get_ip_from_path(&e).map(|ip| (e, ip)))
+  }
+
+  /// Looks for damage a crash or a configuration change can leave behind in
+  /// the data dir and, if `fix` is set, repairs it. `range_sets` are the
+  /// range sets configured for the network, indexed by range id.
+  ///
+  /// Reservations which are empty or outside of every range are removed,
+  /// stale last reserved IPs are forgotten and temporary files deleted. The
+  /// network lock is held throughout, so any temporary file found belongs to
+  /// a transaction which was interrupted.
+  pub fn fsck(&self, range_sets: &[RangeSet], fix: bool) -> Result<Vec<Problem>, StoreError> {
+    if fix {
+      self.writable()?;
     }
 
-    let path = Path::new(data_dir).join(network);
+    self.lock()?;
+    let result = self.check(range_sets, fix);
+    self.unlock()?;
 
-    create_dir_all(&path)
-      .map(|_| FileStore { data_dir: path })
-      .map_err(StoreError::IOError)
+    result
   }
 
-  fn record_last_reserved_ip(&self, ip: IpAddr, range_id: &str) -> Result<(), IoError> {
-    let path = self.get_last_reserved_ip_filepath(range_id);
+  fn check(&self, range_sets: &[RangeSet], fix: bool) -> Result<Vec<Problem>, StoreError> {
+    let mut problems = Vec::new();
+    let mut found = |kind, path: &Path, detail: String| -> Result<(), StoreError> {
+      if fix {
+        remove_file(path).map_err(StoreError::IOError)?;
+      }
+
+      problems.push(Problem {
+        kind: kind,
+        path: path.to_owned(),
+        detail: detail,
+        fixed: fix,
+      });
+      Ok(())
+    };
+
+    for (entry, ip) in self.reservations() {
+      let data = self
+        .read_reservation(entry.path())
+        .map_err(StoreError::IOError)?;
+
+      if data.is_empty() {
+        found(
+          ProblemKind::EmptyReservation,
+          entry.path(),
+          format!("reservation of {} is empty", ip),
+        )?;
+      } else if !range_sets.iter().any(|range_set| range_set.contains(ip)) {
+        let id = data.split(LINE_BREAK).next().unwrap_or_default();
+        found(
+          ProblemKind::OutOfRange,
+          entry.path(),
+          format!("{} reserved by {} is outside of every range", ip, id),
+        )?;
+      }
+    }
+
+    let tmp_files = WalkDir::new(&self.data_dir)
+      .min_depth(1)
+      .max_depth(1)
+      .into_iter()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_type().is_file())
+      .filter(|e| {
+        let name = e.file_name().to_string_lossy();
+        name.starts_with('.') && name.ends_with(TMP_FILE_SUFFIX)
+      });
+    for entry in tmp_files {
+      found(
+        ProblemKind::OrphanedTempFile,
+        entry.path(),
+        "temporary file of an interrupted transaction".to_owned(),
+      )?;
+    }
+
+    if self.options.journal {
+      problems.extend(self.check_journal(range_sets, fix)?);
+      return Ok(problems);
+    }
+
+    let stale: Vec<(String, IpAddr)> = self
+      .load_last_reserved_ips()?
+      .into_iter()
+      .filter(|(range_id, ip)| is_stale(range_sets, range_id, *ip))
+      .collect();
 
-    let mut file = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .create(true)
-      .mode(0o644)
-      .open(&path)?;
+    if fix && !stale.is_empty() {
+      self.update_last_reserved_ips(|ips| {
+        for (range_id, _) in &stale {
+          ips.remove(range_id);
+        }
+      })?;
+    }
 
-    file.write(ip.to_string().as_bytes()).map(|_| ())
+    for (range_id, ip) in stale {
+      problems.push(Problem {
+        kind: ProblemKind::StaleLastReserved,
+        path: self.last_reserved_path(&range_id),
+        detail: format!(
+          "last reserved ip {} of range set {} is outside of it",
+          ip, range_id
+        ),
+        fixed: fix,
+      });
+    }
+
+    Ok(problems)
+  }
+
+  /// Returns the reservations held by `id` in the other networks sharing
+  /// this store's data dir, as pairs of network name and IP.
+  pub fn find_in_other_networks(&self, id: &str) -> Vec<(String, IpAddr)> {
+    let root = match self.data_dir.parent() {
+      Some(root) => root,
+      None => return Vec::new(),
+    };
+
+    WalkDir::new(root)
+      .min_depth(2)
+      .max_depth(2)
+      .into_iter()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_type().is_file())
+      .filter(|e| e.path().parent() != Some(self.data_dir.as_path()))
+      .filter(|e| {
+        self
+          .read_reservation(e.path())
+          .is_ok_and(|data| data.split(LINE_BREAK).next() == Some(id))
+      })
+      .filter_map(|e| {
+        let network = e
+          .path()
+          .parent()
+          .and_then(|dir| dir.file_name())
+          .map(|name| name.to_string_lossy().into_owned())?;
+        get_ip_from_path(&e).map(|ip| (network, ip))
+      })
+      .collect()
+  }
+
+  /// The counters `save_stats` saved in the data dir so far, all zero
+  /// before the first save.
+  pub fn saved_stats(&self) -> Result<StoreStats, StoreError> {
+    let path = self.data_dir.join(STATS_FILE);
+    match read_to_string(&path) {
+      Ok(data) => serde_json::from_str(&data).map_err(|err| corrupt(path, err)),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(StoreStats::default()),
+      Err(err) => Err(StoreError::IOError(err)),
+    }
   }
 
-  fn get_last_reserved_ip_filepath(&self, range_id: &str) -> PathBuf {
+  /// Adds the counters of this store to those saved in the data dir, so
+  /// they add up over the commands using it. Takes a lock of its own
+  /// rather than the network lock, and keeps the index up to date.
+  pub fn save_stats(&self) -> Result<(), StoreError> {
+    self.writable()?;
+    self.keep_index(|| {
+      let file =
+        open_lock_file(&self.data_dir.join(STATS_LOCK_FILE)).map_err(StoreError::IOError)?;
+      self.acquire(&file, STATS_LOCK_FILE, false)?;
+
+      let result = self.saved_stats().and_then(|mut stats| {
+        stats.add(&self.stats.get());
+        let path = self.data_dir.join(STATS_FILE);
+        let content = serde_json::to_vec(&stats).map_err(|err| corrupt(path.clone(), err))?;
+        self.replace_file(&path, &content)
+      });
+      let _ = filelock::clear_holder(&file).and_then(|_| filelock::unlock(&file));
+
+      result
+    })
+  }
+
+  /// Path of the file holding the last reserved IP of `range_id`, shared
+  /// by all range sets unless `upstream_last_reserved` is set.
+  fn last_reserved_path(&self, range_id: &str) -> PathBuf {
+    if self.options.upstream_last_reserved {
+      return self
+        .data_dir
+        .join(format!("{}{}", UPSTREAM_LAST_IP_FILE_PREFIX, range_id));
+    }
+
+    self.data_dir.join(LAST_IP_FILE)
+  }
+
+  fn load_last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+    if self.options.upstream_last_reserved {
+      return self.load_upstream_last_reserved_ips();
+    }
+
+    let path = self.data_dir.join(LAST_IP_FILE);
+    match read_to_string(&path) {
+      Ok(data) => serde_json::from_str(&data).map_err(|err| corrupt(path, err)),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+      Err(err) if err.kind() == ErrorKind::InvalidData => Err(corrupt(path, err)),
+      Err(err) => Err(StoreError::IOError(err)),
+    }
+  }
+
+  /// Reads the `last_reserved_ip.<range id>` files of the Go plugin.
+  fn load_upstream_last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+    let mut ips = BTreeMap::new();
+
+    for entry in read_dir(&self.data_dir).map_err(StoreError::IOError)? {
+      let name = entry.map_err(StoreError::IOError)?.file_name();
+      let range_id = match name.to_str() {
+        Some(LAST_IP_FILE) | None => continue,
+        Some(name) => match name.strip_prefix(UPSTREAM_LAST_IP_FILE_PREFIX) {
+          Some(range_id) => range_id.to_owned(),
+          None => continue,
+        },
+      };
+
+      let path = self.data_dir.join(&name);
+      let ip = match read_to_string(&path) {
+        Ok(data) => data.trim().parse().map_err(|err| corrupt(path, err))?,
+        // released by a concurrent fsck
+        Err(err) if err.kind() == ErrorKind::NotFound => continue,
+        Err(err) => return Err(StoreError::IOError(err)),
+      };
+      ips.insert(range_id, ip);
+    }
+
+    Ok(ips)
+  }
+
+  /// Applies `update` to the last reserved IPs. Range sets locked with
+  /// `lock_range` commit concurrently, so the read-modify-write cycle is
+  /// serialized with a lock of its own.
+  fn update_last_reserved_ips<F>(&self, update: F) -> Result<(), StoreError>
+  where
+    F: FnOnce(&mut BTreeMap<String, IpAddr>),
+  {
+    self.writable()?;
+    apply_lock(&self.last_reserved_lock, filelock::lock)?;
+
+    let result = self.load_last_reserved_ips().and_then(|before| {
+      let mut ips = before.clone();
+      update(&mut ips);
+
+      if self.options.upstream_last_reserved {
+        return self.write_upstream_last_reserved_ips(&before, &ips);
+      }
+
+      let path = self.data_dir.join(LAST_IP_FILE);
+      let content = serde_json::to_vec(&ips).map_err(|err| corrupt(path.clone(), err))?;
+      self.replace_file(&path, &content)
+    });
+
+    apply_lock(&self.last_reserved_lock, filelock::unlock)?;
+    result
+  }
+
+  /// Rewrites the `last_reserved_ip.<range id>` files which differ between
+  /// `before` and `after`.
+  fn write_upstream_last_reserved_ips(
+    &self,
+    before: &BTreeMap<String, IpAddr>,
+    after: &BTreeMap<String, IpAddr>,
+  ) -> Result<(), StoreError> {
+    for (range_id, ip) in after {
+      if before.get(range_id) != Some(ip) {
+        let path = self.last_reserved_path(range_id);
+        self.replace_file(&path, ip.to_string().as_bytes())?;
+      }
+    }
+
+    let removed = before
+      .keys()
+      .filter(|range_id| !after.contains_key(*range_id));
+    for range_id in removed {
+      match remove_file(self.last_reserved_path(range_id)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(StoreError::IOError(err)),
+        _ => {}
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Atomically replaces the content of `path`.
+  fn replace_file(&self, path: &Path, content: &[u8]) -> Result<(), StoreError> {
+    let tmp_path = self
+      .write_tmp_file(path, content)
+      .map_err(StoreError::IOError)?;
+    let _staged = StagedFiles(vec![tmp_path.clone()]);
+
+    rename(&tmp_path, path).map_err(StoreError::IOError)
+  }
+
+  /// Checks that the store can be used: takes the network lock and writes,
+  /// syncs and removes a temporary file in the data dir. A lock held for
+  /// longer than `lock_timeout` fails it like any other operation.
+  pub fn probe(&self) -> Result<(), StoreError> {
+    self.writable()?;
+
+    self.lock()?;
+    let result = self
+      .write_tmp_file(&self.data_dir.join(PROBE_FILE), b"")
+      .and_then(remove_file)
+      .map_err(StoreError::IOError);
+    self.unlock()?;
+
+    result
+  }
+
+  fn writable(&self) -> Result<(), StoreError> {
+    if self.options.read_only {
+      return Err(StoreError::ReadOnly);
+    }
+
+    Ok(())
+  }
+
+  /// Takes the lock file `name` of the data dir, exclusively unless
+  /// `shared`, waiting at most `lock_timeout` if set. Exclusive holders
+  /// record their PID, a timeout reports the holder found.
+  fn acquire(&self, file: &File, name: &str, shared: bool) -> Result<(), StoreError> {
+    let result = match (self.options.lock_timeout, shared) {
+      (None, false) => filelock::lock(file),
+      (None, true) => filelock::lock_shared(file),
+      (Some(timeout), false) => filelock::lock_within(file, filelock::try_lock, timeout),
+      (Some(timeout), true) => filelock::lock_within(file, filelock::try_lock_shared, timeout),
+    };
+
+    match result {
+      Ok(()) if shared => Ok(()),
+      Ok(()) => filelock::record_holder(file).map_err(StoreError::IOError),
+      Err(err) if err.kind() == ErrorKind::TimedOut => Err(StoreError::LockTimeout {
+        path: self.data_dir.join(name),
+        holder: filelock::holder(file),
+        timeout: self.options.lock_timeout.unwrap_or_default(),
+      }),
+      Err(err) => Err(StoreError::IOError(err)),
+    }
+  }
+
+  /// Reads the reservation file `path`, opening it if it is sealed.
+  fn read_reservation(&self, path: &Path) -> Result<String, IoError> {
+    let data = read_to_string(path)?;
+    #[cfg(feature = "encryption")]
+    {
+      if let Some(record_key) = &self.record_key {
+        return record_key.open(&file_name(path), data);
+      }
+    }
+
+    Ok(data)
+  }
+
+  /// `content` of the reservation file `path`, sealed if the store has a
+  /// key.
+  fn seal_reservation(&self, path: &Path, content: String) -> String {
+    #[cfg(feature = "encryption")]
+    {
+      if let Some(record_key) = &self.record_key {
+        return record_key.seal(&file_name(path), &content);
+      }
+    }
+    #[cfg(not(feature = "encryption"))]
+    let _ = path;
+
+    content
+  }
+
+  /// Writes `content` into a fresh temporary file next to `path` and flushes
+  /// it to disk. The caller is responsible for moving it into place.
+  ///
+  /// Temporary files are hidden (dot-prefixed) and never parse as an IP, so
+  /// the directory walks in `get_by_id` and `release_by_id` ignore them.
+  fn write_tmp_file(&self, path: &Path, content: &[u8]) -> Result<PathBuf, IoError> {
+    let tmp_path = self.tmp_path(path);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(self.options.file_mode);
+
+    let mut file = options.open(&tmp_path)?;
+
     self
-      .data_dir
-      .join(format!("{}.{}", LAST_IP_FILE_PREFIX, range_id))
+      .chown_file(&file)
+      .and_then(|_| file.write_all(content))
+      .and_then(|_| file.sync_all())
+      .map(|_| tmp_path.clone())
+      .map_err(|err| {
+        drop(file);
+        let _ = remove_file(&tmp_path);
+        err
+      })
+  }
+
+  /// A fresh name for a temporary file next to `path`.
+  fn tmp_path(&self, path: &Path) -> PathBuf {
+    let name = path
+      .file_name()
+      .and_then(|name| name.to_str())
+      .unwrap_or_default();
+
+    self.data_dir.join(format!(
+      ".{}.{}.{}{}",
+      name,
+      process::id(),
+      TMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst),
+      TMP_FILE_SUFFIX
+    ))
+  }
+
+  #[cfg(unix)]
+  fn chown_file(&self, file: &File) -> Result<(), IoError> {
+    if self.options.uid.is_none() && self.options.gid.is_none() {
+      return Ok(());
+    }
+
+    fchown(file, self.options.uid, self.options.gid)
+  }
+
+  #[cfg(windows)]
+  fn chown_file(&self, _file: &File) -> Result<(), IoError> {
+    Ok(())
   }
+
+  /// Flushes the data directory itself so that renames and new links
+  /// survive a crash.
+  #[cfg(unix)]
+  fn sync_data_dir(&self) -> Result<(), IoError> {
+    File::open(&self.data_dir).and_then(|dir| dir.sync_all())
+  }
+
+  /// Directories can't be opened as plain files on Windows and NTFS
+  /// journals metadata updates such as renames on its own.
+  #[cfg(windows)]
+  fn sync_data_dir(&self) -> Result<(), IoError> {
+    Ok(())
+  }
+}
+
+/// Returns the platform's conventional location for CNI network state.
+fn default_data_dir() -> PathBuf {
+  #[cfg(windows)]
+  {
+    if let Some(program_data) = std::env::var_os("ProgramData") {
+      return Path::new(&program_data).join("cni").join("networks");
+    }
+  }
+
+  PathBuf::from(DEFAULT_DATA_DIR)
+}
+
+/// Returns the per-user data directory following the XDG base directory
+/// specification, i.e. `$XDG_DATA_HOME/cni/networks` falling back to
+/// `$HOME/.local/share/cni/networks`.
+fn user_data_dir() -> Option<PathBuf> {
+  user_data_dir_from(
+    std::env::var_os("XDG_DATA_HOME"),
+    std::env::var_os("HOME"),
+  )
+}
+
+fn user_data_dir_from(xdg_data_home: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+  let data_home = xdg_data_home
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| {
+      home
+        .filter(|dir| !dir.is_empty())
+        .map(|home| Path::new(&home).join(".local").join("share"))
+    })?;
+
+  Some(data_home.join("cni").join("networks"))
+}
+
+fn no_user_data_dir() -> IoError {
+  IoError::new(
+    ErrorKind::NotFound,
+    "can't resolve the per-user data dir, neither XDG_DATA_HOME nor HOME is set",
+  )
+}
+
+/// The data dir of `network` without one configured, below `system_dir` or
+/// `user_dir` according to `FileStoreOptions::rootless`. Unless forced, a
+/// writable store creates its directory below `system_dir` to find out
+/// whether it may.
+fn resolve_data_dir(
+  network: &str,
+  options: &FileStoreOptions,
+  system_dir: &Path,
+  user_dir: Option<PathBuf>,
+) -> Result<PathBuf, StoreError> {
+  let system_path = system_dir.join(network);
+  let user_path = user_dir.map(|user_dir| user_dir.join(network));
+
+  match options.rootless {
+    Some(true) => user_path.ok_or_else(|| StoreError::IOError(no_user_data_dir())),
+    Some(false) => Ok(system_path),
+    None if options.read_only => match user_path {
+      Some(user_path) if !system_path.is_dir() && user_path.is_dir() => Ok(user_path),
+      _ => Ok(system_path),
+    },
+    None => match create_dir(&system_path, options) {
+      Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+        user_path.ok_or(StoreError::IOError(err))
+      }
+      _ => Ok(system_path),
+    },
+  }
+}
+
+#[cfg(unix)]
+fn create_dir(path: &Path, options: &FileStoreOptions) -> Result<(), IoError> {
+  DirBuilder::new()
+    .recursive(true)
+    .mode(options.dir_mode)
+    .create(path)?;
+
+  if options.uid.is_some() || options.gid.is_some() {
+    chown(path, options.uid, options.gid)?;
+  }
+
+  Ok(())
+}
+
+#[cfg(windows)]
+fn create_dir(path: &Path, _options: &FileStoreOptions) -> Result<(), IoError> {
+  DirBuilder::new().recursive(true).create(path)
 }
 
 impl Store for FileStore {
   fn lock(&self) -> Result<(), StoreError> {
-    return Ok(());
+    self.stats.lock(|| match &self.lock_file {
+      Some(file) => self.acquire(file, LOCK_FILE, false),
+      None => Ok(()),
+    })
   }
 
   fn unlock(&self) -> Result<(), StoreError> {
-    return Ok(());
+    apply_lock(&self.lock_file, filelock::clear_holder)?;
+    apply_lock(&self.lock_file, filelock::unlock)
+  }
+
+  fn lock_range(&self, range_id: &str) -> Result<(), StoreError> {
+    self.stats.lock(|| self.lock_range_files(range_id))
+  }
+
+  fn unlock_range(&self, range_id: &str) -> Result<(), StoreError> {
+    let file = locked(&self.range_locks).get(range_id).cloned();
+    if let Some(file) = file {
+      filelock::clear_holder(&file)
+        .and_then(|_| filelock::unlock(&file))
+        .map_err(StoreError::IOError)?;
+    }
+
+    apply_lock(&self.lock_file, filelock::unlock)
   }
 
   fn close(&self) -> Result<(), StoreError> {
     return Ok(());
   }
 
-  fn reserve(
-    &self,
-    id: &str,
-    ifname: &str,
-    ip: IpAddr,
-    range_id: &str,
-  ) -> Result<bool, StoreError> {
-    let fname = self.data_dir.join(ip.to_string());
-
-    let result = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .create(true)
-      .create_new(true)
-      .open(&fname);
-
-    if let Err(err) = result {
-      if err.kind() == ErrorKind::AlreadyExists {
-        return Ok(false);
-      } else {
+  fn commit(&self, txn: &Transaction) -> Result<bool, StoreError> {
+    self.stats.commit(txn, self.commit_txn(txn))
+  }
+
+  fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError> {
+    self
+      .last_reserved_ips()?
+      .get(range_id)
+      .copied()
+      .ok_or_else(|| StoreError::LastReservedNotFound(range_id.to_owned()))
+  }
+
+  fn last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+    if self.options.journal {
+      Ok(self.journal_state()?.last_reserved_ips)
+    } else {
+      self.load_last_reserved_ips()
+    }
+  }
+
+  fn release(&self, ip: IpAddr) -> Result<(), StoreError> {
+    self.stats.release(self.release_ip(ip))
+  }
+
+  fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+    self.stats.release(self.release_ips_of(id, ifname))
+  }
+
+  /// Rewrites the reservation file of `ip`, whose modification time is the
+  /// age of the reservation. The journal doesn't track the age of
+  /// reservations, with it `touch` only checks that `ip` still belongs to
+  /// `id` and `ifname`.
+  fn touch(&self, ip: IpAddr, id: &str, ifname: &str) -> Result<(), StoreError> {
+    self.writable()?;
+    if self.options.journal {
+      return match self.owner(ip)? {
+        owner if owner.id == id && owner.ifname == ifname => Ok(()),
+        _ => Err(StoreError::NotOwner(ip, id.to_owned(), ifname.to_owned())),
+      };
+    }
+    if self.options.index {
+      return self.indexed_touch(ip, id, ifname);
+    }
+
+    self.touch_file(ip, id, ifname)
+  }
+
+  fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr> {
+    if self.options.journal {
+      return self.journal_state().map_or_else(
+        |_| Vec::new(),
+        |state| {
+          state
+            .reservations
+            .into_iter()
+            .filter(|(_, owner)| owner.id == id && owner.ifname == ifname)
+            .map(|(ip, _)| ip)
+            .collect()
+        },
+      );
+    }
+    if self.options.index {
+      if let Some(ips) = self.indexed_get_by_id(id, ifname) {
+        return ips;
+      }
+    }
+
+    let has_key = |entry: &DirEntry| {
+      self
+        .read_reservation(entry.path())
+        .is_ok_and(|data| is_owned_by(&data, id, ifname))
+    };
+
+    self
+      .reservations()
+      .filter(|(entry, _)| has_key(entry))
+      .map(|(_, ip)| ip)
+      .collect()
+  }
+
+  fn owner(&self, ip: IpAddr) -> Result<Owner, StoreError> {
+    if self.options.journal {
+      return self
+        .journal_state()?
+        .reservations
+        .remove(&ip)
+        .ok_or(StoreError::NotFound(ip));
+    }
+
+    self.file_owner(ip)
+  }
+
+  fn list(&self) -> Result<Vec<IpAddr>, StoreError> {
+    if self.options.journal {
+      return Ok(self.journal_state()?.reservations.into_keys().collect());
+    }
+    if self.options.index {
+      if let Some(ips) = self.indexed_list()? {
+        return Ok(ips);
+      }
+    }
+
+    Ok(self.reservations().map(|(_, ip)| ip).collect())
+  }
+
+  fn stats(&self) -> Option<StoreStats> {
+    Some(self.stats.get())
+  }
+}
+
+impl FileStore {
+  /// `Store::lock_range` without counting, see `StatsCounter::lock`.
+  fn lock_range_files(&self, range_id: &str) -> Result<(), StoreError> {
+    validate_network_name(range_id)?;
+    if self.options.read_only {
+      return Ok(());
+    }
+
+    let name = format!("{}{}", RANGE_LOCK_FILE_PREFIX, range_id);
+    let range_lock = match locked(&self.range_locks).entry(range_id.to_owned()) {
+      Entry::Occupied(entry) => entry.get().clone(),
+      Entry::Vacant(entry) => {
+        let file = open_lock_file(&self.data_dir.join(&name)).map_err(StoreError::IOError)?;
+        entry.insert(Arc::new(file)).clone()
+      }
+    };
+
+    if let Some(file) = &self.lock_file {
+      self.acquire(file, LOCK_FILE, true)?;
+    }
+    if let Err(err) = self.acquire(&range_lock, &name, false) {
+      let _ = apply_lock(&self.lock_file, filelock::unlock);
+      return Err(err);
+    }
+
+    Ok(())
+  }
+
+  /// `Store::commit` without counting.
+  fn commit_txn(&self, txn: &Transaction) -> Result<bool, StoreError> {
+    self.writable()?;
+    for operation in txn.operations() {
+      if let Operation::Reserve { owner, .. } = operation {
+        check_labels(&owner.labels)?;
+      }
+    }
+    if self.options.journal {
+      return self.append_journal(|state| {
+        // operations apply in order, an IP released first can be reserved
+        let mut held: HashSet<IpAddr> = state.reservations.keys().copied().collect();
+        for operation in txn.operations() {
+          match operation {
+            Operation::Reserve { ip, .. } => {
+              if !held.insert(*ip) {
+                return Ok(None);
+              }
+            }
+            Operation::Release(ip) => {
+              if !held.remove(ip) {
+                return Err(StoreError::NotFound(*ip));
+              }
+            }
+            Operation::RecordLastReserved { .. } => {}
+          }
+        }
+        Ok(Some(txn.operations().to_vec()))
+      });
+    }
+    if self.options.index {
+      return self.indexed_commit(txn);
+    }
+
+    self.commit_files(txn)
+  }
+
+  /// `Store::release` without counting.
+  fn release_ip(&self, ip: IpAddr) -> Result<(), StoreError> {
+    self.writable()?;
+    if self.options.journal {
+      self.append_journal(|state| {
+        if !state.reservations.contains_key(&ip) {
+          return Err(StoreError::NotFound(ip));
+        }
+        Ok(Some(vec![Operation::Release(ip)]))
+      })?;
+      return Ok(());
+    }
+    if self.options.index {
+      return self.indexed_release(ip);
+    }
+
+    self.release_file(ip)
+  }
+
+  /// `Store::release_by_id` without counting.
+  fn release_ips_of(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+    self.writable()?;
+    if self.options.journal {
+      self.append_journal(|state| {
+        Ok(Some(
+          state
+            .reservations
+            .iter()
+            .filter(|(_, owner)| owner.id == id && owner.ifname == ifname)
+            .map(|(ip, _)| Operation::Release(*ip))
+            .collect(),
+        ))
+      })?;
+      return Ok(());
+    }
+    if self.options.index {
+      return self.indexed_release_by_id(id, ifname);
+    }
+
+    // other range sets may release their IPs concurrently, a reservation
+    // vanishing during the walk isn't ours
+    for (entry, _) in self.reservations() {
+      let matched = match self.read_reservation(entry.path()) {
+        Ok(data) => is_owned_by(&data, id, ifname),
+        Err(err) if err.kind() == ErrorKind::NotFound => false,
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+          return Err(corrupt(entry.path().to_owned(), err))
+        }
+        Err(err) => return Err(StoreError::IOError(err)),
+      };
+
+      if matched {
+        remove_file(entry.path()).map_err(StoreError::IOError)?
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Stages every new file of `txn` as a temporary file first, then moves
+  /// them into place. Released reservations are moved aside first, so a
+  /// transaction can release an IP and reserve it again, then reservations
+  /// are linked and the last reserved IPs replaced. If anything fails on
+  /// the way the links are removed and the released files moved back, so a
+  /// change is never visible without the rest of its transaction.
+  ///
+  /// Every range set of the network shares a single JSON object of last
+  /// reserved IPs keyed by range id, replaced as a whole on each update
+  /// while holding its lock.
+  fn commit_files(&self, txn: &Transaction) -> Result<bool, StoreError> {
+    let mut staged = StagedFiles(Vec::new());
+    let mut reservations = Vec::new();
+    let mut releases = Vec::new();
+    let mut last_reserved_ips = Vec::new();
+
+    for operation in txn.operations() {
+      match operation {
+        Operation::Reserve { owner, ip } => {
+          let path = self.reservation_path(*ip);
+          let content = reservation_content(owner, self.options.upstream_last_reserved);
+          let content = self.seal_reservation(&path, content);
+          let tmp_path = self
+            .write_tmp_file(&path, content.as_bytes())
+            .map_err(StoreError::IOError)?;
+
+          staged.0.push(tmp_path.clone());
+          reservations.push((tmp_path, path));
+        }
+        Operation::Release(ip) => {
+          // fails before anything changed if the IP isn't reserved
+          let path = self.reservation_path(*ip);
+          path
+            .symlink_metadata()
+            .map_err(|err| reservation_error(*ip, err))?;
+          releases.push((*ip, path));
+        }
+        Operation::RecordLastReserved { ip, range_id } => {
+          last_reserved_ips.push((range_id.as_str(), *ip))
+        }
+      }
+    }
+
+    let mut released = Vec::new();
+    for (ip, path) in &releases {
+      let aside = self.tmp_path(path);
+      if let Err(err) = rename(path, &aside) {
+        restore_all(&released);
+        return Err(reservation_error(*ip, err));
+      }
+
+      released.push((aside, path));
+    }
+
+    // hard_link fails if the target exists, which gives us the same
+    // exclusive-create semantics as `create_new` while never exposing a
+    // partially written reservation under its final name.
+    let mut linked = Vec::new();
+    for (tmp_path, path) in &reservations {
+      if let Err(err) = hard_link(tmp_path, path) {
+        remove_all(&linked);
+        restore_all(&released);
+        if err.kind() == ErrorKind::AlreadyExists {
+          return Ok(false);
+        }
         return Err(StoreError::IOError(err));
       }
+
+      linked.push(path);
+    }
+
+    if !last_reserved_ips.is_empty() {
+      let update = |ips: &mut BTreeMap<String, IpAddr>| {
+        for (range_id, ip) in &last_reserved_ips {
+          ips.insert((*range_id).to_owned(), *ip);
+        }
+      };
+
+      if let Err(err) = self.update_last_reserved_ips(update) {
+        remove_all(&linked);
+        restore_all(&released);
+        return Err(err);
+      }
+    }
+
+    let aside: Vec<_> = released.iter().map(|(aside, _)| aside).collect();
+    remove_all(&aside);
+
+    self.sync_data_dir().map_err(StoreError::IOError)?;
+    Ok(true)
+  }
+
+  fn release_file(&self, ip: IpAddr) -> Result<(), StoreError> {
+    remove_file(self.reservation_path(ip)).map_err(|err| reservation_error(ip, err))
+  }
+
+  /// Rewrites the reservation file, which refreshes its modification time.
+  fn touch_file(&self, ip: IpAddr, id: &str, ifname: &str) -> Result<(), StoreError> {
+    let path = self.reservation_path(ip);
+
+    // keeps the network namespace following the owner
+    let content = match self.read_reservation(&path) {
+      Ok(data) if is_owned_by(&data, id, ifname) => data,
+      Ok(_) => return Err(StoreError::NotOwner(ip, id.to_owned(), ifname.to_owned())),
+      Err(err) if err.kind() == ErrorKind::NotFound => return Err(StoreError::NotFound(ip)),
+      Err(err) => return Err(StoreError::IOError(err)),
+    };
+    let content = self.seal_reservation(&path, content);
+
+    let tmp_path = self
+      .write_tmp_file(&path, content.as_bytes())
+      .map_err(StoreError::IOError)?;
+    // removes the temporary file if the rename fails
+    let _staged = StagedFiles(vec![tmp_path.clone()]);
+    rename(&tmp_path, &path).map_err(StoreError::IOError)?;
+
+    self.sync_data_dir().map_err(StoreError::IOError)
+  }
+
+  /// Parses the reservation file of `ip`.
+  fn file_owner(&self, ip: IpAddr) -> Result<Owner, StoreError> {
+    let path = self.reservation_path(ip);
+    let data = self
+      .read_reservation(&path)
+      .map_err(|err| match err.kind() {
+        ErrorKind::NotFound => StoreError::NotFound(ip),
+        ErrorKind::InvalidData => corrupt(path.clone(), err),
+        _ => StoreError::IOError(err),
+      })?;
+
+    // reservations of old plugin versions hold the container id only
+    let mut lines = data.split(LINE_BREAK);
+    match lines.next() {
+      Some(id) if !id.is_empty() => {
+        let mut field = || {
+          lines
+            .next()
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+        };
+        let ifname = field().unwrap_or_default();
+        let netns = field();
+        let pod = match (field(), field(), field()) {
+          (Some(namespace), Some(name), uid) => Some(Pod {
+            namespace: namespace,
+            name: name,
+            uid: uid,
+          }),
+          _ => None,
+        };
+        let labels = lines
+          .filter_map(|line| {
+            let equals = line.find('=')?;
+            Some((line[..equals].to_owned(), line[equals + 1..].to_owned()))
+          })
+          .collect();
+
+        Ok(Owner {
+          id: id.to_owned(),
+          ifname: ifname,
+          netns: netns,
+          pod: pod,
+          labels: labels,
+        })
+      }
+      _ => Err(corrupt(path, "missing container id")),
     }
+  }
+}
+
+/// Whether the last reserved IP `ip` of `range_id` no longer belongs to a
+/// range set of `range_sets`. Pointing at a released IP is fine.
+fn is_stale(range_sets: &[RangeSet], range_id: &str, ip: IpAddr) -> bool {
+  // older versions keyed range sets by their position
+  let range_set = range_sets
+    .iter()
+    .find(|range_set| range_set.id() == range_id)
+    .or_else(|| {
+      range_id
+        .parse::<usize>()
+        .ok()
+        .and_then(|i| range_sets.get(i))
+    });
+  !range_set.is_some_and(|range_set| range_set.contains(ip))
+}
 
-    let mut content = String::from(id);
-    content.push_str(LINE_BREAK);
-    content.push_str(ifname);
+/// Content of a reservation file: the container id and interface, then the
+/// network namespace and the namespace, name and uid of the pod if known,
+/// then a `KEY=VALUE` line per label, one per line. Unknown fields in
+/// between are left empty. Only the id and interface if `upstream`, as the
+/// Go plugin writes them.
+fn reservation_content(owner: &Owner, upstream: bool) -> String {
+  let mut fields: Vec<String> = vec![owner.id.clone(), owner.ifname.clone()];
+  if upstream {
+    return fields.join(LINE_BREAK);
+  }
+  if let Some(netns) = &owner.netns {
+    fields.push(netns.clone());
+  }
+  if let Some(pod) = &owner.pod {
+    fields.resize(2, String::new());
+    fields.push(owner.netns.clone().unwrap_or_default());
+    fields.extend(vec![pod.namespace.clone(), pod.name.clone()]);
+    if let Some(uid) = &pod.uid {
+      fields.push(uid.clone());
+    }
+  }
+  if !owner.labels.is_empty() {
+    fields.resize(6, String::new());
+    for (key, value) in &owner.labels {
+      fields.push(format!("{}={}", key, value));
+    }
+  }
 
-    let mut file = result.unwrap();
+  fields.join(LINE_BREAK)
+}
 
-    file
-      .write(content.as_bytes())
-      .and_then(|_| file.sync_all())
-      .map_err(|err| {
-        drop(file);
-        let _ = remove_file(fname);
-        StoreError::IOError(err)
-      })?;
+/// Whether the reservation file content `data` belongs to `id` and
+/// `ifname`. Compares the first two lines exactly, ids may be suffixes of
+/// one another, e.g. the pod keys `ns/web` and `myns/web`.
+fn is_owned_by(data: &str, id: &str, ifname: &str) -> bool {
+  let mut lines = data.split(LINE_BREAK);
+  lines.next() == Some(id) && lines.next().map(str::trim_end) == Some(ifname)
+}
 
-    self
-      .record_last_reserved_ip(ip, range_id)
-      .map(|_| true)
-      .map_err(StoreError::IOError)
+/// Read-only stores have no lock files, locking them does nothing.
+fn apply_lock(file: &Option<File>, op: fn(&File) -> Result<(), IoError>) -> Result<(), StoreError> {
+  match file {
+    Some(file) => op(file).map_err(StoreError::IOError),
+    None => Ok(()),
   }
+}
 
-  fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError> {
-    let path = self.get_last_reserved_ip_filepath(range_id);
+fn open_lock_file(path: &Path) -> Result<File, IoError> {
+  OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .open(path)
+}
+
+/// Temporary files written while preparing a transaction. They are removed
+/// on drop, whether or not they were linked into place in the meantime.
+struct StagedFiles(Vec<PathBuf>);
 
-    read_to_string(path)
-      .map_err(StoreError::IOError)?
-      .parse::<IpAddr>()
-      .map_err(StoreError::AddrParseError)
+impl Drop for StagedFiles {
+  fn drop(&mut self) {
+    remove_all(&self.0);
   }
+}
 
-  fn release(&self, ip: IpAddr) -> Result<(), StoreError> {
-    let path = self.data_dir.join(ip.to_string());
-    remove_file(path).map_err(StoreError::IOError)
+fn remove_all<P: AsRef<Path>>(paths: &[P]) {
+  for path in paths {
+    let _ = remove_file(path);
   }
+}
 
-  fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
-    let key = format!("{}{}{}", id, LINE_BREAK, ifname);
+/// Moves files set aside back to their paths.
+fn restore_all<P: AsRef<Path>>(released: &[(PathBuf, P)]) {
+  for (aside, path) in released {
+    let _ = rename(aside, path);
+  }
+}
 
-    for entry in WalkDir::new(&self.data_dir)
-      .into_iter()
-      .filter_map(|e| e.ok())
-      .filter(|e| e.file_type().is_file())
-    {
-      let matched = read_to_string(entry.path())
-        .map_err(StoreError::IOError)
-        .map(|data| data.contains(&key))?;
+/// `err` of the reservation file of `ip`, `NotFound` if there is none.
+fn reservation_error(ip: IpAddr, err: IoError) -> StoreError {
+  match err.kind() {
+    ErrorKind::NotFound => StoreError::NotFound(ip),
+    _ => StoreError::IOError(err),
+  }
+}
 
-      if matched {
-        remove_file(entry.path()).map_err(StoreError::IOError)?
-      }
-    }
+/// Reservation files are named after the IP they reserve, anything else in
+/// the data dir doesn't parse and is skipped. So are names which parse but
+/// aren't in canonical form, `FileStore` never writes them.
+fn get_ip_from_path(entry: &DirEntry) -> Option<IpAddr> {
+  entry
+    .path()
+    .file_name()
+    .map(|s| s.to_str())
+    .flatten()
+    .and_then(|s| s.parse::<IpAddr>().ok().filter(|ip| ip.to_string() == s))
+}
 
-    Ok(())
-  }
+/// Name of the file `path`, which sealed reservations are bound to.
+#[cfg(feature = "encryption")]
+fn file_name(path: &Path) -> String {
+  path
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_default()
+}
 
-  fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr> {
-    let key = format!("{}{}{}", id, LINE_BREAK, ifname);
-    let has_key =
-      |entry: &DirEntry| read_to_string(entry.path()).map_or(false, |data| data.contains(&key));
+fn corrupt<E: std::fmt::Display>(path: PathBuf, err: E) -> StoreError {
+  StoreError::Corrupt {
+    path: path,
+    reason: err.to_string(),
+  }
+}
 
-    let get_ip_from_path = |entry: DirEntry| {
-      entry
-        .path()
-        .file_name()
-        .map(|s| s.to_str())
-        .flatten()
-        .map(|s| s.parse::<IpAddr>().ok())
-        .flatten()
-    };
+/// The network name becomes a directory below the data dir, it must not be
+/// able to point anywhere else.
+pub(super) fn validate_network_name(network: &str) -> Result<(), StoreError> {
+  let invalid = network.is_empty()
+    || network == "."
+    || network == ".."
+    || network.contains(|c| c == '/' || c == '\\' || c == '\0');
 
-    WalkDir::new(&self.data_dir)
-      .into_iter()
-      .filter_map(|e| e.ok())
-      .filter(|e| e.file_type().is_file())
-      .filter(has_key)
-      .filter_map(get_ip_from_path)
-      .collect()
+  if invalid {
+    return Err(StoreError::InvalidName(network.to_owned()));
   }
+
+  Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-  use super::{FileStore, Store, StoreError};
+  use super::{
+    resolve_data_dir, user_data_dir_from, FileStore, FileStoreOptions, Store, StoreError,
+    Transaction, TMP_FILE_SUFFIX,
+  };
+  use crate::error::report;
+  use std::error::Error as _;
   use std::fs::remove_dir_all;
   use std::io::{Error, ErrorKind};
   use std::net::IpAddr;
-  use std::path::Path;
+  use std::path::{Path, PathBuf};
 
   fn clean_data_dir() {
     let _ = remove_dir_all("/tmp/cni");
@@ -184,15 +1528,124 @@ mod tests {
     assert!(result.is_ok());
     assert!(Path::new("/tmp/cni/networks/test").exists());
 
-    let result = FileStore::new("test", "");
-    assert!(result
-      .unwrap_err()
-      .to_string()
-      .contains("io error happened: "));
-
     clean_data_dir();
   }
 
+  #[test]
+  fn rootless_data_dir() {
+    let dir = Path::new("/tmp/cni-rootless");
+    let _ = remove_dir_all(dir);
+    let (system_dir, user_dir) = (dir.join("system"), dir.join("user"));
+    let resolve = |rootless: Option<bool>, read_only: bool, user_dir: Option<PathBuf>| {
+      let options = FileStoreOptions {
+        rootless: rootless,
+        read_only: read_only,
+        ..FileStoreOptions::default()
+      };
+      resolve_data_dir("n", &options, &system_dir, user_dir)
+    };
+
+    let path = resolve(Some(true), false, Some(user_dir.clone())).unwrap();
+    assert_eq!(path, user_dir.join("n"));
+    let err = resolve(Some(true), false, None).unwrap_err();
+    assert!(err.source().is_some());
+    assert!(report(&err).starts_with("io error happened: can't resolve the per-user data dir"));
+    let path = resolve(Some(false), false, Some(user_dir.clone())).unwrap();
+    assert_eq!(path, system_dir.join("n"));
+    assert!(!path.exists());
+
+    // read-only stores look for an existing data dir, the system one first
+    std::fs::create_dir_all(user_dir.join("n")).unwrap();
+    let path = resolve(None, true, Some(user_dir.clone())).unwrap();
+    assert_eq!(path, user_dir.join("n"));
+
+    // detection tries the system dir first, creating it
+    let path = resolve(None, false, Some(user_dir.clone())).unwrap();
+    assert_eq!(path, system_dir.join("n"));
+    assert!(path.is_dir());
+    let path = resolve(None, true, Some(user_dir.clone())).unwrap();
+    assert_eq!(path, system_dir.join("n"));
+
+    // and falls back to the user dir where it may not, which root always may
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+
+      let locked = dir.join("locked");
+      std::fs::create_dir_all(&locked).unwrap();
+      std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o555)).unwrap();
+      let options = FileStoreOptions::default();
+      let path = resolve_data_dir("n", &options, &locked, Some(user_dir.clone())).unwrap();
+      if unsafe { libc::geteuid() } == 0 {
+        assert_eq!(path, locked.join("n"));
+      } else {
+        assert_eq!(path, user_dir.join("n"));
+      }
+    }
+
+    // a configured data dir wins
+    let options = FileStoreOptions {
+      rootless: Some(true),
+      ..FileStoreOptions::default()
+    };
+    let store = FileStore::with_options("test-rootless", "/tmp/cni-rootless", options).unwrap();
+    assert_eq!(store.data_dir, dir.join("test-rootless"));
+
+    let _ = remove_dir_all(dir);
+  }
+
+  #[test]
+  fn user_data_dir() {
+    assert_eq!(
+      user_data_dir_from(Some("/xdg".into()), Some("/home/cni".into())),
+      Some(Path::new("/xdg/cni/networks").to_path_buf())
+    );
+    assert_eq!(
+      user_data_dir_from(Some("".into()), Some("/home/cni".into())),
+      Some(Path::new("/home/cni/.local/share/cni/networks").to_path_buf())
+    );
+    assert_eq!(
+      user_data_dir_from(None, Some("/home/cni".into())),
+      Some(Path::new("/home/cni/.local/share/cni/networks").to_path_buf())
+    );
+    assert_eq!(user_data_dir_from(None, None), None);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn with_options() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let options = FileStoreOptions {
+      dir_mode: 0o700,
+      file_mode: 0o600,
+      uid: Some(unsafe { libc::geteuid() }),
+      gid: Some(unsafe { libc::getegid() }),
+      rootless: None,
+      read_only: false,
+      upstream_last_reserved: false,
+      journal: false,
+      index: false,
+      lock_timeout: None,
+    };
+    let cni_data_dir = "/tmp/cni-with-options";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::with_options("test-options", cni_data_dir, options).unwrap();
+
+    let metadata = std::fs::metadata(&store.data_dir).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+    assert_eq!(Some(metadata.uid()), options.uid);
+
+    let ip = "2.2.2.4".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("123456", "enp2s0", ip, "1").unwrap());
+
+    let metadata = std::fs::metadata(store.data_dir.join(ip.to_string())).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    assert_eq!(Some(metadata.gid()), options.gid);
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
   #[test]
   fn reserve_and_last_reserved_ip() {
     let cni_data_dir = "/tmp/cni/networks";
@@ -212,21 +1665,19 @@ mod tests {
     let result = store.last_reserved_ip(range_id);
     assert_eq!(result.unwrap(), ip);
 
-    assert!(
-      store
-        .last_reserved_ip("2")
-        .unwrap_err()
-        .to_string()
-        .contains("io error happened: "),
-      "an io error should happened"
-    );
+    assert!(matches!(
+      store.last_reserved_ip("2"),
+      Err(StoreError::LastReservedNotFound(range_id)) if range_id == "2"
+    ));
 
     let ips = store.get_by_id(id, ifname);
     assert_eq!(ips.len(), 1);
     assert_eq!(ips[0], ip);
+    assert_eq!(store.list().unwrap(), vec![ip]);
 
     assert!(store.release(ip).is_ok());
     assert!(!store.data_dir.join(ip.to_string()).exists());
+    assert!(matches!(store.release(ip), Err(StoreError::NotFound(_))));
 
     clean_data_dir();
   }
@@ -249,4 +1700,705 @@ mod tests {
 
     clean_data_dir();
   }
+
+  #[test]
+  fn release_checked() {
+    let cni_data_dir = "/tmp/cni-release-checked";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test", cni_data_dir).unwrap();
+
+    let ip = "2.2.2.4".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("123456", "enp2s0", ip, "1").unwrap());
+
+    assert!(matches!(
+      store.release_checked(ip, "654321", "enp2s0"),
+      Err(StoreError::NotOwner(_, _, _))
+    ));
+    assert!(matches!(
+      store.release_checked(ip, "123456", "enp3s0"),
+      Err(StoreError::NotOwner(_, _, _))
+    ));
+    assert!(store.data_dir.join(ip.to_string()).exists());
+
+    assert!(store.release_checked(ip, "123456", "enp2s0").is_ok());
+    assert!(!store.data_dir.join(ip.to_string()).exists());
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn suffix_ids() {
+    let cni_data_dir = "/tmp/cni-suffix-ids";
+    let _ = remove_dir_all(cni_data_dir);
+
+    for index in &[false, true] {
+      let options = FileStoreOptions {
+        index: *index,
+        ..FileStoreOptions::default()
+      };
+      let store = FileStore::with_options("test", cni_data_dir, options).unwrap();
+      let ip1 = "2.2.5.1".parse::<IpAddr>().unwrap();
+      let ip2 = "2.2.5.2".parse::<IpAddr>().unwrap();
+      assert!(store.reserve("ns/web", "eth0", ip1, "0").unwrap());
+      assert!(store.reserve("myns/web", "eth0", ip2, "0").unwrap());
+
+      assert_eq!(store.get_by_id("ns/web", "eth0"), vec![ip1]);
+      assert_eq!(store.get_by_id("s/web", "eth0"), Vec::<IpAddr>::new());
+      assert!(matches!(
+        store.release_checked(ip2, "ns/web", "eth0"),
+        Err(StoreError::NotOwner(_, _, _))
+      ));
+      assert!(matches!(
+        store.touch(ip2, "ns/web", "eth0"),
+        Err(StoreError::NotOwner(_, _, _))
+      ));
+
+      store.release_by_id("ns/web", "eth0").unwrap();
+      assert_eq!(store.list().unwrap(), vec![ip2]);
+      store.release_by_id("myns/web", "eth0").unwrap();
+      assert!(store.list().unwrap().is_empty());
+    }
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn hostile_network_names() {
+    for network in &["", ".", "..", "../escape", "a/b", "a\\b", "a\0b"] {
+      assert!(
+        matches!(
+          FileStore::new(network, "/tmp/cni-hostile-names/networks"),
+          Err(StoreError::InvalidName(_))
+        ),
+        "{:?} should be rejected",
+        network
+      );
+    }
+
+    assert!(!Path::new("/tmp/cni-hostile-names/escape").exists());
+    let _ = remove_dir_all("/tmp/cni-hostile-names");
+  }
+
+  #[test]
+  fn list_skips_non_canonical_names() {
+    let cni_data_dir = "/tmp/cni-list-skips-non-canonical-names";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test", cni_data_dir).unwrap();
+
+    let ip = "2001:db8::1".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("123456", "enp2s0", ip, "1").unwrap());
+
+    for name in &["2001:DB8::1", "2001:db8:0::2", "10.1.2.3.tmp", "10.1.2.3 "] {
+      std::fs::write(store.data_dir.join(name), "654321\r\nenp2s0").unwrap();
+    }
+    std::fs::create_dir(store.data_dir.join("nested")).unwrap();
+    std::fs::write(store.data_dir.join("nested").join("10.1.2.4"), "").unwrap();
+
+    assert_eq!(store.list().unwrap(), vec![ip]);
+    assert!(store.get_by_id("654321", "enp2s0").is_empty());
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn touch() {
+    let cni_data_dir = "/tmp/cni-touch";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test", cni_data_dir).unwrap();
+
+    let ip = "2.2.2.5".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("123456", "enp2s0", ip, "1").unwrap());
+
+    let path = store.data_dir.join(ip.to_string());
+    let file = std::fs::File::open(&path).unwrap();
+    let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    file.set_modified(old).unwrap();
+
+    assert!(store.touch(ip, "123456", "enp2s0").is_ok());
+    assert!(std::fs::metadata(&path).unwrap().modified().unwrap() > old);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "123456\r\nenp2s0");
+
+    assert!(matches!(
+      store.touch(ip, "654321", "enp2s0"),
+      Err(StoreError::NotOwner(_, _, _))
+    ));
+    assert!(matches!(
+      store.touch("2.2.2.6".parse().unwrap(), "123456", "enp2s0"),
+      Err(StoreError::NotFound(_))
+    ));
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn stats() {
+    let cni_data_dir = "/tmp/cni-stats";
+    let _ = remove_dir_all(cni_data_dir);
+    let ip = "10.1.2.2".parse().unwrap();
+
+    for _ in 0..2 {
+      let store = FileStore::new("test", cni_data_dir).unwrap();
+      store.lock().unwrap();
+      assert!(store.reserve("c1", "eth0", ip, "0").unwrap());
+      assert!(!store.reserve("c2", "eth0", ip, "0").unwrap());
+      store.release(ip).unwrap();
+      assert!(store.release(ip).is_err());
+      store.unlock().unwrap();
+
+      let stats = store.stats().unwrap();
+      let counts = (stats.reserves, stats.conflicts, stats.releases);
+      assert_eq!(counts, (1, 1, 1));
+      assert_eq!(stats.io_errors, 0);
+      store.save_stats().unwrap();
+    }
+
+    let store = FileStore::new("test", cni_data_dir).unwrap();
+    let saved = store.saved_stats().unwrap();
+    assert_eq!((saved.reserves, saved.conflicts, saved.releases), (2, 2, 2));
+    assert!(saved.lock_wait_ns > 0);
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn owner_netns() {
+    use crate::store::{Labels, Owner};
+
+    let cni_data_dir = "/tmp/cni-owner-netns";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test-netns", cni_data_dir).unwrap();
+
+    let ip = "2.2.2.7".parse::<IpAddr>().unwrap();
+    let mut txn = Transaction::new();
+    txn.reserve_in("c1", "eth0", Some("/var/run/netns/c1"), ip);
+    assert!(store.commit(&txn).unwrap());
+
+    let owner = Owner {
+      id: "c1".to_owned(),
+      ifname: "eth0".to_owned(),
+      netns: Some("/var/run/netns/c1".to_owned()),
+      pod: None,
+      labels: Labels::new(),
+    };
+    assert_eq!(store.owner(ip).unwrap(), owner);
+    assert_eq!(store.get_by_id("c1", "eth0"), vec![ip]);
+    assert_eq!(
+      store.get_by_ip(ip).unwrap().map(|found| found.owner),
+      Some(owner.clone())
+    );
+    assert_eq!(store.get_by_ip("2.2.2.200".parse().unwrap()).unwrap(), None);
+
+    store.touch(ip, "c1", "eth0").unwrap();
+    assert_eq!(store.owner(ip).unwrap(), owner);
+
+    let old = "2.2.2.8".parse::<IpAddr>().unwrap();
+    std::fs::write(store.data_dir.join(old.to_string()), "c2").unwrap();
+    assert_eq!(store.owner(old).unwrap().netns, None);
+
+    // a pod without a known namespace leaves its line empty
+    let pod = "2.2.2.9".parse::<IpAddr>().unwrap();
+    let owner = Owner {
+      id: "c3".to_owned(),
+      ifname: "eth0".to_owned(),
+      netns: None,
+      pod: Some(super::Pod {
+        namespace: "default".to_owned(),
+        name: "web-0".to_owned(),
+        uid: None,
+      }),
+      labels: Labels::new(),
+    };
+    let mut txn = Transaction::new();
+    txn.reserve_for(owner.clone(), pod);
+    assert!(store.commit(&txn).unwrap());
+    assert_eq!(
+      std::fs::read_to_string(store.data_dir.join(pod.to_string())).unwrap(),
+      format!("c3{0}eth0{0}{0}default{0}web-0", super::LINE_BREAK)
+    );
+    assert_eq!(store.owner(pod).unwrap(), owner);
+
+    // labels follow the pod, whose fields are left empty
+    let labeled = "2.2.2.10".parse::<IpAddr>().unwrap();
+    let mut owner = Owner {
+      id: "c4".to_owned(),
+      ifname: "eth0".to_owned(),
+      netns: None,
+      pod: None,
+      labels: Labels::new(),
+    };
+    owner.labels.insert("team".to_owned(), "web".to_owned());
+    owner.labels.insert("query".to_owned(), "a=b".to_owned());
+    assert!(store.reserve_for(owner.clone(), labeled, "0").unwrap());
+    assert_eq!(
+      std::fs::read_to_string(store.data_dir.join(labeled.to_string())).unwrap(),
+      format!(
+        "c4{0}eth0{0}{0}{0}{0}{0}query=a=b{0}team=web",
+        super::LINE_BREAK
+      )
+    );
+    assert_eq!(store.owner(labeled).unwrap(), owner);
+
+    owner.labels.insert("team".to_owned(), "web\r\n".to_owned());
+    assert!(matches!(
+      store.reserve_for(owner, "2.2.2.11".parse().unwrap(), "0"),
+      Err(StoreError::InvalidLabel(_))
+    ));
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn journal() {
+    use std::io::Write;
+
+    let cni_data_dir = "/tmp/cni-journal";
+    let _ = remove_dir_all(cni_data_dir);
+
+    // reservations of a store without journal are folded into the snapshot
+    let ip1 = "2.2.3.1".parse::<IpAddr>().unwrap();
+    let store = FileStore::new("test-journal", cni_data_dir).unwrap();
+    assert!(store.reserve("c1", "eth0", ip1, "0").unwrap());
+
+    let options = FileStoreOptions {
+      journal: true,
+      ..FileStoreOptions::default()
+    };
+    let store = FileStore::with_options("test-journal", cni_data_dir, options).unwrap();
+    assert!(!store.reservation_path(ip1).exists());
+    assert_eq!(store.get_by_id("c1", "eth0"), vec![ip1]);
+    assert_eq!(store.last_reserved_ip("0").unwrap(), ip1);
+
+    let ip2 = "2.2.3.2".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("c2", "eth0", ip2, "0").unwrap());
+    assert!(!store.reserve("c3", "eth0", ip2, "0").unwrap());
+    assert!(!store.reservation_path(ip2).exists());
+    assert_eq!(store.owner(ip2).unwrap().id, "c2");
+    store.release(ip1).unwrap();
+    assert!(matches!(store.release(ip1), Err(StoreError::NotFound(_))));
+
+    // a line torn by a crash is ignored, and cut off by the next append
+    let journal = store.data_dir.join("journal");
+    let mut file = std::fs::OpenOptions::new()
+      .append(true)
+      .open(&journal)
+      .unwrap();
+    file.write_all(br#"[{"Release":"2.2"#).unwrap();
+    assert_eq!(store.list().unwrap(), vec![ip2]);
+
+    let ip3 = "2.2.3.3".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("c3", "eth0", ip3, "0").unwrap());
+    let store = FileStore::with_options("test-journal", cni_data_dir, options).unwrap();
+    assert_eq!(store.list().unwrap(), vec![ip2, ip3]);
+    assert_eq!(store.last_reserved_ip("0").unwrap(), ip3);
+
+    store.compact().unwrap();
+    assert_eq!(std::fs::read(&journal).unwrap(), b"");
+    store.release_by_id("c2", "eth0").unwrap();
+    assert_eq!(store.list().unwrap(), vec![ip3]);
+
+    // an IP released and reserved again in one transaction changes owner
+    let mut txn = Transaction::new();
+    txn.release(ip3).reserve("c4", "eth0", ip3);
+    assert!(store.commit(&txn).unwrap());
+    assert_eq!(store.owner(ip3).unwrap().id, "c4");
+    let mut txn = Transaction::new();
+    txn.release(ip1).reserve("c5", "eth0", ip1);
+    assert!(matches!(store.commit(&txn), Err(StoreError::NotFound(_))));
+    assert!(store.get_by_id("c5", "eth0").is_empty());
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn index() {
+    let cni_data_dir = "/tmp/cni-index";
+    let _ = remove_dir_all(cni_data_dir);
+    let options = FileStoreOptions {
+      index: true,
+      ..FileStoreOptions::default()
+    };
+    let store = FileStore::with_options("test-index", cni_data_dir, options).unwrap();
+
+    let ip1 = "2.2.4.1".parse::<IpAddr>().unwrap();
+    let ip2 = "2.2.4.2".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("c1", "eth0", ip1, "0").unwrap());
+    assert!(!store.reserve("c2", "eth0", ip1, "0").unwrap());
+    assert_eq!(store.get_by_id("c1", "eth0"), vec![ip1]);
+    assert!(store.get_by_id("c1", "eth1").is_empty());
+
+    // a reservation made without the index is found after a rebuild
+    let plain = FileStore::new("test-index", cni_data_dir).unwrap();
+    assert!(plain.reserve("c1", "eth0", ip2, "0").unwrap());
+    assert_eq!(store.get_by_id("c1", "eth0"), vec![ip1, ip2]);
+
+    // so is one of a damaged index
+    std::fs::write(store.data_dir.join("index"), "damaged").unwrap();
+    assert_eq!(store.list().unwrap(), vec![ip1, ip2]);
+
+    store.release(ip1).unwrap();
+    store.release_by_id("c1", "eth0").unwrap();
+    assert!(store.list().unwrap().is_empty());
+    assert!(plain.list().unwrap().is_empty());
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn reserve_leaves_no_tmp_files() {
+    let cni_data_dir = "/tmp/cni-reserve-leaves-no-tmp-files";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test", cni_data_dir).unwrap();
+
+    let ip = "2.2.2.3".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("123456", "enp2s0", ip, "1").unwrap());
+    assert!(!store.reserve("654321", "enp2s0", ip, "1").unwrap());
+
+    let leftovers = std::fs::read_dir(&store.data_dir)
+      .unwrap()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_name().to_string_lossy().ends_with(TMP_FILE_SUFFIX))
+      .count();
+    assert_eq!(leftovers, 0);
+
+    let content = std::fs::read_to_string(store.data_dir.join(ip.to_string())).unwrap();
+    assert_eq!(content, "123456\r\nenp2s0");
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn last_reserved_ip_per_range() {
+    let cni_data_dir = "/tmp/cni-last-reserved-ip-per-range";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test", cni_data_dir).unwrap();
+
+    let long_ip = "2.2.2.250".parse::<IpAddr>().unwrap();
+    let short_ip = "2.2.2.9".parse::<IpAddr>().unwrap();
+    let v6_ip = "2001:db8::5".parse::<IpAddr>().unwrap();
+
+    assert!(store.reserve("1", "eth0", long_ip, "0").unwrap());
+    assert!(store.reserve("2", "eth0", v6_ip, "1").unwrap());
+    assert!(store.reserve("3", "eth0", short_ip, "0").unwrap());
+
+    assert_eq!(store.last_reserved_ip("0").unwrap(), short_ip);
+    assert_eq!(store.last_reserved_ip("1").unwrap(), v6_ip);
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn upstream_last_reserved() {
+    use super::LAST_IP_FILE;
+    use crate::store::{Labels, Owner};
+
+    let cni_data_dir = "/tmp/cni-upstream-last-reserved";
+    let _ = remove_dir_all(cni_data_dir);
+    let options = FileStoreOptions {
+      upstream_last_reserved: true,
+      ..FileStoreOptions::default()
+    };
+    let store = FileStore::with_options("test-upstream", cni_data_dir, options).unwrap();
+
+    // left behind by the Go plugin
+    let go_ip = "10.1.2.5".parse::<IpAddr>().unwrap();
+    std::fs::write(store.data_dir.join("last_reserved_ip.0"), "10.1.2.5").unwrap();
+    assert_eq!(store.last_reserved_ip("0").unwrap(), go_ip);
+
+    let ip = "10.1.2.6".parse::<IpAddr>().unwrap();
+    let owner = Owner {
+      id: "c1".to_owned(),
+      ifname: "eth0".to_owned(),
+      netns: Some("/var/run/netns/c1".to_owned()),
+      pod: None,
+      labels: Labels::new(),
+    };
+    assert!(store.reserve_for(owner, ip, "0").unwrap());
+    let content = std::fs::read_to_string(store.data_dir.join("last_reserved_ip.0")).unwrap();
+    assert_eq!(content, "10.1.2.6");
+    // the Go plugin compares the whole content to find the IPs of c1
+    let content = std::fs::read_to_string(store.reservation_path(ip)).unwrap();
+    assert_eq!(content, "c1\r\neth0");
+    assert!(!store.data_dir.join(LAST_IP_FILE).exists());
+    assert_eq!(store.list().unwrap(), vec![ip]);
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn lock_and_unlock() {
+    let cni_data_dir = "/tmp/cni-lock-and-unlock";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test", cni_data_dir).unwrap();
+
+    assert!(store.lock().is_ok());
+    assert!(store.unlock().is_ok());
+    assert!(store.data_dir.join(super::LOCK_FILE).exists());
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn lock_timeout() {
+    use std::time::Duration;
+
+    let cni_data_dir = "/tmp/cni-lock-timeout";
+    let _ = remove_dir_all(cni_data_dir);
+    let holder = FileStore::new("test-lock-timeout", cni_data_dir).unwrap();
+    let options = FileStoreOptions {
+      lock_timeout: Some(Duration::from_millis(20)),
+      ..FileStoreOptions::default()
+    };
+    let waiter = FileStore::with_options("test-lock-timeout", cni_data_dir, options).unwrap();
+
+    holder.lock().unwrap();
+    match waiter.lock() {
+      Err(StoreError::LockTimeout { holder, .. }) => {
+        assert_eq!(holder, Some(std::process::id()))
+      }
+      result => panic!("expected a lock timeout, got {:?}", result),
+    }
+    assert!(matches!(
+      waiter.lock_range("0"),
+      Err(StoreError::LockTimeout { .. })
+    ));
+
+    holder.unlock().unwrap();
+    let lock_file = holder.data_dir.join(super::LOCK_FILE);
+    assert_eq!(std::fs::read(lock_file).unwrap(), b"");
+    waiter.lock_range("0").unwrap();
+    waiter.unlock_range("0").unwrap();
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn fsck() {
+    use super::{ProblemKind, LAST_IP_FILE};
+    use crate::allocator::range::Range;
+    use crate::allocator::rangeset::RangeSet;
+
+    let cni_data_dir = "/tmp/cni-fsck";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test-fsck", cni_data_dir).unwrap();
+
+    let mut range_set = RangeSet::new();
+    range_set
+      .add(Range::new("10.1.2.0/24".parse().unwrap(), None, None, None).unwrap())
+      .unwrap();
+    let range_sets = vec![range_set];
+
+    let reserved = "10.1.2.3".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("c1", "eth0", reserved, "0").unwrap());
+    let outside = "10.9.9.9".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("c2", "eth0", outside, "1").unwrap());
+    std::fs::write(store.data_dir.join("10.1.2.4"), "").unwrap();
+    std::fs::write(store.data_dir.join(".10.1.2.5.1.0.tmp"), "c3\r\neth0").unwrap();
+
+    let kinds = |problems: Vec<super::Problem>| {
+      let mut kinds: Vec<ProblemKind> = problems.iter().map(|problem| problem.kind).collect();
+      kinds.sort_by_key(|kind| format!("{:?}", kind));
+      kinds
+    };
+    let expected = vec![
+      ProblemKind::EmptyReservation,
+      ProblemKind::OrphanedTempFile,
+      ProblemKind::OutOfRange,
+      ProblemKind::StaleLastReserved,
+    ];
+
+    // checking alone changes nothing
+    assert_eq!(kinds(store.fsck(&range_sets, false).unwrap()), expected);
+    assert_eq!(kinds(store.fsck(&range_sets, false).unwrap()), expected);
+
+    let problems = store.fsck(&range_sets, true).unwrap();
+    assert!(problems.iter().all(|problem| problem.fixed));
+    assert_eq!(kinds(problems), expected);
+
+    assert!(store.fsck(&range_sets, false).unwrap().is_empty());
+    assert_eq!(store.list().unwrap(), vec![reserved]);
+    assert_eq!(store.last_reserved_ip("0").unwrap(), reserved);
+    assert!(store.data_dir.join(LAST_IP_FILE).exists());
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn range_locks() {
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    let cni_data_dir = "/tmp/cni-range-locks";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test-range-locks", cni_data_dir).unwrap();
+    store.lock_range("0").unwrap();
+
+    // another range set of the network can be locked at the same time
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+      let other = FileStore::new("test-range-locks", cni_data_dir).unwrap();
+      other.lock_range("1").unwrap();
+      tx.send(()).unwrap();
+      other.unlock_range("1").unwrap();
+    });
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+    // the whole network waits for every range set
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+      let other = FileStore::new("test-range-locks", cni_data_dir).unwrap();
+      other.lock().unwrap();
+      tx.send(()).unwrap();
+      other.unlock().unwrap();
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+    store.unlock_range("0").unwrap();
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+    assert!(matches!(
+      store.lock_range("../0"),
+      Err(StoreError::InvalidName(_))
+    ));
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn commit_is_all_or_nothing() {
+    let cni_data_dir = "/tmp/cni-commit-is-all-or-nothing";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("test-commit", cni_data_dir).unwrap();
+
+    let taken = "2.2.2.5".parse::<IpAddr>().unwrap();
+    let free = "2.2.2.6".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("123456", "enp2s0", taken, "0").unwrap());
+
+    let mut txn = Transaction::new();
+    txn
+      .reserve("654321", "enp2s0", free)
+      .reserve("654321", "enp2s0", taken)
+      .record_last_reserved(taken, "0")
+      .record_last_reserved(free, "1");
+
+    assert!(!store.commit(&txn).unwrap());
+    assert!(!store.data_dir.join(free.to_string()).exists());
+    assert_eq!(store.last_reserved_ip("0").unwrap(), taken);
+    assert!(matches!(
+      store.last_reserved_ip("1"),
+      Err(StoreError::LastReservedNotFound(_))
+    ));
+
+    let mut txn = Transaction::new();
+    txn
+      .release(taken)
+      .reserve("654321", "enp2s0", free)
+      .record_last_reserved(free, "1");
+
+    assert!(store.commit(&txn).unwrap());
+    assert_eq!(store.list().unwrap(), vec![free]);
+    assert_eq!(store.last_reserved_ip("1").unwrap(), free);
+
+    // releasing a free IP fails before anything changed
+    let mut txn = Transaction::new();
+    txn
+      .release(free)
+      .reserve("654321", "enp2s0", taken)
+      .release(taken);
+    assert!(matches!(
+      store.commit(&txn),
+      Err(StoreError::NotFound(ip)) if ip == taken
+    ));
+    assert_eq!(store.list().unwrap(), vec![free]);
+    assert_eq!(store.owner(free).unwrap().id, "654321");
+
+    // an IP can be released and reserved again for another owner
+    let mut txn = Transaction::new();
+    txn.release(free).reserve("123456", "enp2s0", free);
+    assert!(store.commit(&txn).unwrap());
+    assert_eq!(store.list().unwrap(), vec![free]);
+    assert_eq!(store.owner(free).unwrap().id, "123456");
+
+    // a conflict moves the released reservation back
+    let mut txn = Transaction::new();
+    txn
+      .release(free)
+      .reserve("654321", "enp2s0", taken)
+      .reserve("654321", "enp2s0", taken);
+    assert!(!store.commit(&txn).unwrap());
+    assert_eq!(store.list().unwrap(), vec![free]);
+    assert_eq!(store.owner(free).unwrap().id, "123456");
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[test]
+  fn find_in_other_networks() {
+    let cni_data_dir = "/tmp/cni-find-in-other-networks";
+    let _ = remove_dir_all(cni_data_dir);
+    let store = FileStore::new("net1", cni_data_dir).unwrap();
+    let other = FileStore::new("net2", cni_data_dir).unwrap();
+
+    let ip = "2.2.2.7".parse::<IpAddr>().unwrap();
+    assert!(store.reserve("123456", "eth0", ip, "0").unwrap());
+    assert!(other.reserve("123456", "eth0", ip, "0").unwrap());
+    assert!(other
+      .reserve("1234567", "eth0", "2.2.2.8".parse().unwrap(), "0")
+      .unwrap());
+
+    assert_eq!(
+      store.find_in_other_networks("123456"),
+      vec![("net2".to_owned(), ip)]
+    );
+    assert!(store.find_in_other_networks("654321").is_empty());
+
+    let _ = remove_dir_all(cni_data_dir);
+  }
+
+  #[cfg(feature = "encryption")]
+  #[test]
+  fn encrypted_reservations() {
+    let data_dir = "/tmp/cni-encrypted";
+    let _ = std::fs::remove_dir_all(data_dir);
+
+    let key = super::RecordKey::new(&[7; 32]);
+    let store = FileStore::builder("n")
+      .data_dir(data_dir)
+      .record_key(key.clone())
+      .build()
+      .unwrap();
+
+    let ip = "10.1.2.3".parse::<IpAddr>().unwrap();
+    let plain_ip = "10.1.2.4".parse::<IpAddr>().unwrap();
+    // written before the store had a key
+    FileStore::new("n", data_dir)
+      .unwrap()
+      .reserve("c2", "eth0", plain_ip, "0")
+      .unwrap();
+    assert!(store.reserve("secret", "eth0", ip, "0").unwrap());
+
+    let content = std::fs::read_to_string(store.reservation_path(ip)).unwrap();
+    assert!(!content.contains("secret"));
+    assert_eq!(store.get_by_id("secret", "eth0"), vec![ip]);
+    assert_eq!(store.owner(ip).unwrap().id, "secret");
+    assert_eq!(store.owner(plain_ip).unwrap().id, "c2");
+    store.touch(ip, "secret", "eth0").unwrap();
+    assert_eq!(store.owner(ip).unwrap().id, "secret");
+
+    // a store without the key can't tell who holds the IP
+    let other = FileStore::new("n", data_dir).unwrap();
+    assert!(other.get_by_id("secret", "eth0").is_empty());
+
+    store.release_by_id("secret", "eth0").unwrap();
+    assert_eq!(store.list().unwrap(), vec![plain_ip]);
+
+    assert!(FileStore::builder("n")
+      .data_dir(data_dir)
+      .journal(true)
+      .record_key(key)
+      .build()
+      .is_err());
+
+    let _ = std::fs::remove_dir_all(data_dir);
+  }
 }
@@ -0,0 +1,172 @@
+//! Encryption of reservation files at rest.
+//!
+//! Reservations name the container, its interface and pod, which some
+//! environments treat as sensitive. With a `RecordKey` the content of every
+//! reservation file is sealed with AES-256-GCM, the file keeps the IP as its
+//! name so lookups by IP don't change. The name is authenticated along with
+//! the content, a sealed reservation moved to another IP fails to open.
+//!
+//! Sealed files are text: `SEALED_PREFIX` followed by the hex encoded nonce
+//! and ciphertext. Files without the prefix are read as they are, so a data
+//! dir can start using a key with its existing reservations in place.
+
+use std::fmt;
+use std::fs::read;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+const SEALED_PREFIX: &str = "aes256gcm:";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Key sealing reservation files, see `FileStore::encrypt_with`.
+#[derive(Clone)]
+pub struct RecordKey {
+  cipher: Aes256Gcm,
+}
+
+impl fmt::Debug for RecordKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("RecordKey(..)")
+  }
+}
+
+impl RecordKey {
+  pub fn new(key: &[u8; KEY_LEN]) -> RecordKey {
+    RecordKey {
+      cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+    }
+  }
+
+  /// Reads the key from `path`, holding either the 32 bytes of the key or
+  /// them hex encoded, e.g. from `openssl rand -hex 32`.
+  pub fn load(path: &Path) -> Result<RecordKey, IoError> {
+    let data = read(path)?;
+    let text = String::from_utf8_lossy(&data);
+
+    let key = match decode_hex(text.trim()) {
+      Some(key) if key.len() == KEY_LEN => key,
+      _ if data.len() == KEY_LEN => data,
+      _ => {
+        return Err(IoError::new(
+          ErrorKind::InvalidData,
+          format!("{} holds no 32 byte key", path.display()),
+        ))
+      }
+    };
+
+    let mut bytes = [0; KEY_LEN];
+    bytes.copy_from_slice(&key);
+    Ok(RecordKey::new(&bytes))
+  }
+
+  /// Seals `content` of the file `name`.
+  pub fn seal(&self, name: &str, content: &str) -> String {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload {
+      msg: content.as_bytes(),
+      aad: name.as_bytes(),
+    };
+    // UNWRAP: encrypting only fails for messages larger than GCM allows
+    let ciphertext = self.cipher.encrypt(&nonce, payload).unwrap();
+
+    let mut sealed = String::from(SEALED_PREFIX);
+    sealed.push_str(&encode_hex(&nonce));
+    sealed.push_str(&encode_hex(&ciphertext));
+    sealed
+  }
+
+  /// Opens `data` of the file `name` sealed by `seal`, returns unsealed
+  /// data unchanged.
+  pub fn open(&self, name: &str, data: String) -> Result<String, IoError> {
+    let sealed = match data.strip_prefix(SEALED_PREFIX) {
+      Some(sealed) => sealed,
+      None => return Ok(data),
+    };
+
+    let invalid = |reason: &str| IoError::new(ErrorKind::InvalidData, reason.to_owned());
+    let bytes = decode_hex(sealed.trim()).ok_or_else(|| invalid("sealed record is not hex"))?;
+    if bytes.len() < NONCE_LEN {
+      return Err(invalid("sealed record is truncated"));
+    }
+
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    let payload = Payload {
+      msg: ciphertext,
+      aad: name.as_bytes(),
+    };
+    let content = self
+      .cipher
+      .decrypt(Nonce::from_slice(nonce), payload)
+      .map_err(|_| invalid("sealed record doesn't open with the key"))?;
+
+    String::from_utf8(content).map_err(|_| invalid("sealed record is not UTF-8"))
+  }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+  if text.len() % 2 != 0 || !text.is_ascii() {
+    return None;
+  }
+
+  (0..text.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn seal_and_open() {
+    let key = RecordKey::new(&[7; KEY_LEN]);
+
+    let sealed = key.seal("10.1.2.3", "secret\r\neth0");
+    assert!(sealed.starts_with(SEALED_PREFIX));
+    assert!(!sealed.contains("secret"));
+    assert_eq!(
+      key.open("10.1.2.3", sealed.clone()).unwrap(),
+      "secret\r\neth0"
+    );
+    // nonces differ
+    assert_ne!(key.seal("10.1.2.3", "secret\r\neth0"), sealed);
+
+    // bound to the file name and key
+    assert!(key.open("10.1.2.4", sealed.clone()).is_err());
+    assert!(RecordKey::new(&[8; KEY_LEN])
+      .open("10.1.2.3", sealed)
+      .is_err());
+
+    assert_eq!(
+      key.open("10.1.2.3", "secret\r\neth0".to_owned()).unwrap(),
+      "secret\r\neth0"
+    );
+  }
+
+  #[test]
+  fn load() {
+    let path = Path::new("/tmp/cni-record-key");
+    std::fs::write(path, format!("{}\n", "ab".repeat(KEY_LEN))).unwrap();
+    let hex_key = RecordKey::load(path).unwrap();
+    std::fs::write(path, [0xab; KEY_LEN]).unwrap();
+    let raw_key = RecordKey::load(path).unwrap();
+    assert_eq!(raw_key.open("n", hex_key.seal("n", "c1")).unwrap(), "c1");
+
+    std::fs::write(path, "too short").unwrap();
+    assert_eq!(
+      RecordKey::load(path).unwrap_err().kind(),
+      ErrorKind::InvalidData
+    );
+
+    let _ = std::fs::remove_file(path);
+  }
+}
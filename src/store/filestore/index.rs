@@ -0,0 +1,404 @@
+//! Index of the reservation files of `FileStore`, see
+//! `FileStoreOptions::index`.
+//!
+//! Without it `get_by_id` and `release_by_id` read every reservation file.
+//! The index holds a record per reservation with its IP and a hash of the
+//! id and interface owning it, so a lookup reads a single file and then only
+//! the reservations whose hash matches. Records have a fixed size, the file
+//! can be mapped as is; at 32 bytes per reservation it is simply read whole.
+//!
+//! The header keeps the modification time the data dir had when the index
+//! was last written. Any change to the data dir moves it, so an index with
+//! another time missed changes, e.g. of a plugin without index or of a
+//! crash, and is rebuilt from the directory. Writers hold the index lock
+//! from before they change reservation files until the index is written.
+//! Changes of other files, like the saved stats, re-stamp an index which
+//! was up to date, or every command would rebuild it.
+
+use super::{apply_lock, FileStore, LINE_BREAK};
+use crate::allocator::fnv1a_128;
+use crate::store::{filelock, Operation, Owner, StoreError, Transaction};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{metadata, read, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv6Addr};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+const INDEX_FILE: &str = "index";
+const MAGIC: &[u8; 8] = b"hlindex1";
+/// Magic, then seconds and nanoseconds of the data dir's modification time.
+const HEADER_LEN: usize = 24;
+/// Address family, padding, the IP as 16 bytes and the owner hash.
+const RECORD_LEN: usize = 32;
+
+/// Reserved IPs with the hash of their owner, see `owner_key`.
+pub(super) type Index = BTreeMap<IpAddr, u64>;
+
+/// Hash of the id and interface a reservation belongs to.
+fn owner_key(id: &str, ifname: &str) -> u64 {
+  fnv1a_128(format!("{}{}{}", id, LINE_BREAK, ifname).as_bytes()) as u64
+}
+
+fn encode(stamp: [u8; 16], index: &Index) -> Vec<u8> {
+  let mut data = Vec::with_capacity(HEADER_LEN + index.len() * RECORD_LEN);
+  data.extend_from_slice(MAGIC);
+  data.extend_from_slice(&stamp);
+
+  for (ip, key) in index {
+    let (family, octets) = match ip {
+      IpAddr::V4(ip) => (4, ip.to_ipv6_mapped().octets()),
+      IpAddr::V6(ip) => (6, ip.octets()),
+    };
+    data.extend_from_slice(&[family, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&octets);
+    data.extend_from_slice(&key.to_le_bytes());
+  }
+
+  data
+}
+
+/// The records of `data` written for a data dir modified at `stamp`.
+/// Returns `None` if it is damaged or out of date.
+fn records(data: &[u8], stamp: [u8; 16]) -> Option<&[u8]> {
+  if data.len() < HEADER_LEN
+    || (data.len() - HEADER_LEN) % RECORD_LEN != 0
+    || &data[..8] != MAGIC
+    || data[8..HEADER_LEN] != stamp
+  {
+    return None;
+  }
+
+  Some(&data[HEADER_LEN..])
+}
+
+fn decode_record(record: &[u8]) -> Option<(IpAddr, u64)> {
+  let octets: [u8; 16] = record[8..24].try_into().ok()?;
+  let ip = match record[0] {
+    4 => IpAddr::V4(Ipv6Addr::from(octets).to_ipv4()?),
+    6 => IpAddr::V6(Ipv6Addr::from(octets)),
+    _ => return None,
+  };
+
+  Some((ip, u64::from_le_bytes(record[24..].try_into().ok()?)))
+}
+
+/// Lookups go through the records as read, only updates decode them all.
+fn decode(records: &[u8]) -> Option<Index> {
+  records.chunks(RECORD_LEN).map(decode_record).collect()
+}
+
+impl FileStore {
+  fn index_path(&self) -> PathBuf {
+    self.data_dir.join(INDEX_FILE)
+  }
+
+  /// Modification time of the data dir, as stored in the index header.
+  fn dir_stamp(&self) -> Result<[u8; 16], IoError> {
+    let modified = metadata(&self.data_dir)?
+      .modified()?
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+
+    let mut stamp = [0u8; 16];
+    stamp[..8].copy_from_slice(&modified.as_secs().to_le_bytes());
+    stamp[8..12].copy_from_slice(&modified.subsec_nanos().to_le_bytes());
+    Ok(stamp)
+  }
+
+  /// The records of the index if it is up to date, else `None`.
+  fn read_records(&self) -> Result<Option<Vec<u8>>, StoreError> {
+    let data = match read(self.index_path()) {
+      Ok(data) => data,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(StoreError::IOError(err)),
+    };
+
+    let stamp = self.dir_stamp().map_err(StoreError::IOError)?;
+    Ok(records(&data, stamp).map(<[u8]>::to_vec))
+  }
+
+  /// Reads the index, rebuilding it from the reservation files if it is out
+  /// of date. Must be called with the index lock held.
+  fn load_index(&self) -> Result<Index, StoreError> {
+    if let Some(index) = self.read_records()?.and_then(|records| decode(&records)) {
+      return Ok(index);
+    }
+
+    let mut index = Index::new();
+    for (_, ip) in self.reservations() {
+      // empty reservations of a crash belong to nobody
+      match self.file_owner(ip) {
+        Ok(owner) => {
+          index.insert(ip, owner_key(&owner.id, &owner.ifname));
+        }
+        Err(StoreError::Corrupt { .. }) | Err(StoreError::NotFound(_)) => {}
+        Err(err) => return Err(err),
+      }
+    }
+
+    self.write_index(&index)?;
+    Ok(index)
+  }
+
+  /// Writes `index` for the data dir as it is now. The header is written
+  /// after the records reached the disk, a crash in between leaves the old
+  /// modification time and the index is rebuilt.
+  fn write_index(&self, index: &Index) -> Result<(), StoreError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true);
+    #[cfg(unix)]
+    options.mode(self.options.file_mode);
+
+    let mut file = options
+      .open(self.index_path())
+      .map_err(StoreError::IOError)?;
+    let stamp = self.dir_stamp().map_err(StoreError::IOError)?;
+    let data = encode(stamp, index);
+
+    self
+      .chown_file(&file)
+      .and_then(|_| file.seek(SeekFrom::Start(HEADER_LEN as u64)))
+      .and_then(|_| file.write_all(&data[HEADER_LEN..]))
+      .and_then(|_| file.set_len(data.len() as u64))
+      .and_then(|_| file.sync_data())
+      .and_then(|_| file.seek(SeekFrom::Start(0)))
+      .and_then(|_| file.write_all(&data[..HEADER_LEN]))
+      .map_err(StoreError::IOError)
+  }
+
+  /// Writes the modification time the data dir has now into the header.
+  fn restamp(&self) -> Result<(), StoreError> {
+    let stamp = self.dir_stamp().map_err(StoreError::IOError)?;
+    OpenOptions::new()
+      .write(true)
+      .open(self.index_path())
+      .and_then(|mut file| {
+        file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        file.write_all(&stamp)
+      })
+      .map_err(StoreError::IOError)
+  }
+
+  /// Runs `update`, which changes reservation files, with the index lock
+  /// held and writes the index it leaves behind. If `update` fails the
+  /// index is left alone, the changes it made get it rebuilt.
+  fn update_index<T, F>(&self, update: F) -> Result<T, StoreError>
+  where
+    F: FnOnce(&mut Index) -> Result<T, StoreError>,
+  {
+    apply_lock(&self.index_lock, filelock::lock)?;
+
+    let result = self.load_index().and_then(|mut index| {
+      let value = update(&mut index)?;
+      self.write_index(&index)?;
+      Ok(value)
+    });
+
+    apply_lock(&self.index_lock, filelock::unlock)?;
+    result
+  }
+
+  /// Runs `change`, which changes the data dir but no reservation file,
+  /// with the index lock held and re-stamps the index if it was up to date.
+  pub(super) fn keep_index<T, F>(&self, change: F) -> Result<T, StoreError>
+  where
+    F: FnOnce() -> Result<T, StoreError>,
+  {
+    if self.index_lock.is_none() {
+      return change();
+    }
+
+    apply_lock(&self.index_lock, filelock::lock)?;
+    let result = self.read_records().and_then(|records| {
+      let value = change()?;
+      if records.is_some() {
+        self.restamp()?;
+      }
+      Ok(value)
+    });
+    apply_lock(&self.index_lock, filelock::unlock)?;
+
+    result
+  }
+
+  /// Runs `read` on the records of the index. Returns `None` if a read-only
+  /// store finds it out of date, it can't rebuild it.
+  fn with_records<T, F>(&self, read: F) -> Result<Option<T>, StoreError>
+  where
+    F: FnOnce(&[u8]) -> Result<T, StoreError>,
+  {
+    if self.options.read_only {
+      return match self.read_records()? {
+        Some(records) => read(&records).map(Some),
+        None => Ok(None),
+      };
+    }
+
+    apply_lock(&self.index_lock, filelock::lock)?;
+    let result = self.read_records().and_then(|records| match records {
+      Some(records) => read(&records),
+      None => read(&encode([0u8; 16], &self.load_index()?)[HEADER_LEN..]),
+    });
+    apply_lock(&self.index_lock, filelock::unlock)?;
+
+    result.map(Some)
+  }
+
+  pub(super) fn indexed_commit(&self, txn: &Transaction) -> Result<bool, StoreError> {
+    self.update_index(|index| {
+      if !self.commit_files(txn)? {
+        return Ok(false);
+      }
+
+      for operation in txn.operations() {
+        match operation {
+          Operation::Reserve { owner, ip } => {
+            index.insert(*ip, owner_key(&owner.id, &owner.ifname));
+          }
+          Operation::Release(ip) => {
+            index.remove(ip);
+          }
+          Operation::RecordLastReserved { .. } => {}
+        }
+      }
+      Ok(true)
+    })
+  }
+
+  pub(super) fn indexed_release(&self, ip: IpAddr) -> Result<(), StoreError> {
+    self.update_index(|index| {
+      self.release_file(ip)?;
+      index.remove(&ip);
+      Ok(())
+    })
+  }
+
+  pub(super) fn indexed_release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+    self.update_index(|index| {
+      let records = index.iter().map(|(ip, key)| (*ip, *key));
+      for ip in self.candidates(records, id, ifname)? {
+        self.release_file(ip)?;
+        index.remove(&ip);
+      }
+      Ok(())
+    })
+  }
+
+  pub(super) fn indexed_touch(&self, ip: IpAddr, id: &str, ifname: &str) -> Result<(), StoreError> {
+    self.update_index(|_| self.touch_file(ip, id, ifname))
+  }
+
+  /// `get_by_id` through the index, `None` if it can't be used.
+  pub(super) fn indexed_get_by_id(&self, id: &str, ifname: &str) -> Option<Vec<IpAddr>> {
+    self
+      .with_records(|records| {
+        let records = records.chunks(RECORD_LEN).filter_map(decode_record);
+        self.candidates(records, id, ifname)
+      })
+      .ok()
+      .flatten()
+  }
+
+  /// `list` through the index, `None` if it can't be used.
+  pub(super) fn indexed_list(&self) -> Result<Option<Vec<IpAddr>>, StoreError> {
+    self.with_records(|records| {
+      Ok(
+        records
+          .chunks(RECORD_LEN)
+          .filter_map(decode_record)
+          .map(|(ip, _)| ip)
+          .collect(),
+      )
+    })
+  }
+
+  /// IPs of `records` reserved for `id` and `ifname`. Reservation files of
+  /// matching hashes are read to rule out collisions.
+  fn candidates<I>(&self, records: I, id: &str, ifname: &str) -> Result<Vec<IpAddr>, StoreError>
+  where
+    I: Iterator<Item = (IpAddr, u64)>,
+  {
+    let key = owner_key(id, ifname);
+    let mut ips = Vec::new();
+
+    for (ip, _) in records.filter(|(_, k)| *k == key) {
+      match self.file_owner(ip) {
+        Ok(Owner {
+          id: owner_id,
+          ifname: owner_ifname,
+          ..
+        }) if owner_id == id && owner_ifname == ifname => ips.push(ip),
+        Ok(_) | Err(StoreError::NotFound(_)) => {}
+        Err(err) => return Err(err),
+      }
+    }
+
+    Ok(ips)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cni::{cmd_add, CniArgs};
+  use crate::config::NetConf;
+  use crate::store::filestore::FileStoreOptions;
+  use std::fs::remove_dir_all;
+
+  #[test]
+  fn encode_decode() {
+    let mut index = Index::new();
+    index.insert("10.1.2.3".parse().unwrap(), 1);
+    index.insert("::ffff:10.1.2.3".parse().unwrap(), 2);
+    index.insert("2001:db8::1".parse().unwrap(), u64::MAX);
+
+    let stamp = [7u8; 16];
+    let data = encode(stamp, &index);
+    assert_eq!(data.len(), HEADER_LEN + 3 * RECORD_LEN);
+    assert_eq!(records(&data, stamp).and_then(decode), Some(index));
+
+    // written for another state of the data dir, or cut short
+    assert_eq!(records(&data, [0u8; 16]), None);
+    assert_eq!(records(&data[..data.len() - 1], stamp), None);
+  }
+
+  #[test]
+  fn saving_stats_keeps_index() {
+    let data_dir = "/tmp/cni-index-stats";
+    let _ = remove_dir_all(data_dir);
+    let conf = format!(
+      r#"{{"name": "n", "ipam": {{"dataDir": "{}", "index": true, "ranges": [[{{"subnet": "10.1.2.0/24"}}]]}}}}"#,
+      data_dir
+    );
+    let conf = NetConf::parse(conf.as_bytes()).unwrap();
+    let args = |id: &str| CniArgs {
+      container_id: id.to_owned(),
+      ifname: "eth0".to_owned(),
+      ..CniArgs::default()
+    };
+
+    cmd_add(&args("c1"), &conf, FileStoreOptions::default()).unwrap();
+    let options = FileStoreOptions {
+      index: true,
+      ..FileStoreOptions::default()
+    };
+    let store = FileStore::with_options("n", data_dir, options).unwrap();
+    assert!(store.read_records().unwrap().is_some());
+
+    // cut back to its header, a rebuild would bring back the record of c1
+    OpenOptions::new()
+      .write(true)
+      .open(store.index_path())
+      .and_then(|file| file.set_len(HEADER_LEN as u64))
+      .unwrap();
+    cmd_add(&args("c2"), &conf, FileStoreOptions::default()).unwrap();
+    let records = store.read_records().unwrap().unwrap();
+    assert_eq!(decode(&records).unwrap().len(), 1);
+
+    let _ = remove_dir_all(data_dir);
+  }
+}
@@ -0,0 +1,326 @@
+//! Journal mode of `FileStore`, see `FileStoreOptions::journal`.
+//!
+//! The reservations of a network live in a single snapshot file plus a
+//! journal with one line of JSON per transaction committed since. Reading
+//! replays the journal onto the snapshot. Once the journal holds
+//! `COMPACT_AFTER` transactions the state is written to a new snapshot and
+//! the journal starts over.
+//!
+//! Appends and compaction take the journal lock exclusively, reads take it
+//! shared, so nobody sees a new snapshot together with the old journal. A
+//! transaction is durable once its line is synced. A crash while appending
+//! leaves a torn last line, which replay ignores and the next append cuts
+//! off. A crash between replacing the snapshot and truncating the journal
+//! replays transactions the snapshot already holds, which ends in the same
+//! state since every operation sets or removes a single key.
+
+use super::{apply_lock, corrupt, is_stale, FileStore, Problem, ProblemKind};
+use crate::allocator::rangeset::RangeSet;
+use crate::store::{filelock, Operation, Owner, StoreError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read, read_to_string, remove_file, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const JOURNAL_FILE: &str = "journal";
+/// Transactions appended before the journal is compacted into the snapshot.
+const COMPACT_AFTER: usize = 1024;
+
+/// Everything a network's store holds.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct State {
+  pub(super) reservations: BTreeMap<IpAddr, Owner>,
+  pub(super) last_reserved_ips: BTreeMap<String, IpAddr>,
+}
+
+impl State {
+  fn apply(&mut self, operation: &Operation) {
+    match operation {
+      Operation::Reserve { owner, ip } => {
+        self.reservations.insert(*ip, owner.clone());
+      }
+      Operation::Release(ip) => {
+        self.reservations.remove(ip);
+      }
+      Operation::RecordLastReserved { ip, range_id } => {
+        self.last_reserved_ips.insert(range_id.clone(), *ip);
+      }
+    }
+  }
+}
+
+/// The state read back from disk, with the number of transactions and bytes
+/// of the journal it took.
+struct Replay {
+  state: State,
+  transactions: usize,
+  len: u64,
+}
+
+impl FileStore {
+  fn snapshot_path(&self) -> PathBuf {
+    self.data_dir.join(SNAPSHOT_FILE)
+  }
+
+  fn journal_path(&self) -> PathBuf {
+    self.data_dir.join(JOURNAL_FILE)
+  }
+
+  /// Reads the snapshot and replays the complete lines of the journal onto
+  /// it. Must be called with the journal lock held.
+  fn replay(&self) -> Result<Replay, StoreError> {
+    let path = self.snapshot_path();
+    let state = match read_to_string(&path) {
+      Ok(data) => serde_json::from_str(&data).map_err(|err| corrupt(path, err))?,
+      Err(err) if err.kind() == ErrorKind::NotFound => State::default(),
+      Err(err) => return Err(StoreError::IOError(err)),
+    };
+
+    let path = self.journal_path();
+    let data = match read(&path) {
+      Ok(data) => data,
+      Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+      Err(err) => return Err(StoreError::IOError(err)),
+    };
+
+    let mut replay = Replay {
+      state: state,
+      transactions: 0,
+      len: 0,
+    };
+    // a line missing its line break was torn by a crash, its transaction
+    // never completed
+    for line in data.split_inclusive(|b| *b == b'\n') {
+      if !line.ends_with(b"\n") {
+        break;
+      }
+
+      let operations: Vec<Operation> =
+        serde_json::from_slice(line).map_err(|err| corrupt(path.clone(), err))?;
+      for operation in &operations {
+        replay.state.apply(operation);
+      }
+      replay.transactions += 1;
+      replay.len += line.len() as u64;
+    }
+
+    Ok(replay)
+  }
+
+  /// The current state, read under the shared journal lock.
+  pub(super) fn journal_state(&self) -> Result<State, StoreError> {
+    apply_lock(&self.last_reserved_lock, filelock::lock_shared)?;
+    let result = self.replay().map(|replay| replay.state);
+    apply_lock(&self.last_reserved_lock, filelock::unlock)?;
+
+    result
+  }
+
+  /// Appends the operations `plan` derives from the current state as one
+  /// transaction. Returns false without changes if `plan` returns `None`.
+  pub(super) fn append_journal<F>(&self, plan: F) -> Result<bool, StoreError>
+  where
+    F: FnOnce(&State) -> Result<Option<Vec<Operation>>, StoreError>,
+  {
+    self.writable()?;
+    apply_lock(&self.last_reserved_lock, filelock::lock)?;
+
+    let result = self.replay().and_then(|replay| match plan(&replay.state)? {
+      Some(operations) if !operations.is_empty() => {
+        self.append_locked(replay, &operations).map(|_| true)
+      }
+      Some(_) => Ok(true),
+      None => Ok(false),
+    });
+
+    apply_lock(&self.last_reserved_lock, filelock::unlock)?;
+    result
+  }
+
+  fn append_locked(&self, replay: Replay, operations: &[Operation]) -> Result<(), StoreError> {
+    let mut line = serde_json::to_vec(operations).map_err(|err| StoreError::IOError(err.into()))?;
+    line.push(b'\n');
+
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    options.mode(self.options.file_mode);
+
+    let mut file = options
+      .open(self.journal_path())
+      .map_err(StoreError::IOError)?;
+    self
+      .chown_file(&file)
+      // cuts off a line torn by a crash, appends go to the new end
+      .and_then(|_| file.set_len(replay.len))
+      .and_then(|_| file.write_all(&line))
+      .and_then(|_| file.sync_data())
+      .map_err(StoreError::IOError)?;
+
+    if replay.transactions + 1 >= COMPACT_AFTER {
+      let mut state = replay.state;
+      for operation in operations {
+        state.apply(operation);
+      }
+      self.write_snapshot(&state)?;
+    }
+
+    Ok(())
+  }
+
+  /// Replaces the snapshot with `state` and empties the journal. Must be
+  /// called with the journal lock held exclusively.
+  fn write_snapshot(&self, state: &State) -> Result<(), StoreError> {
+    let content = serde_json::to_vec(state).map_err(|err| StoreError::IOError(err.into()))?;
+    self.replace_file(&self.snapshot_path(), &content)?;
+    self.sync_data_dir().map_err(StoreError::IOError)?;
+
+    match OpenOptions::new().write(true).open(self.journal_path()) {
+      Ok(file) => file
+        .set_len(0)
+        .and_then(|_| file.sync_data())
+        .map_err(StoreError::IOError),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+      Err(err) => Err(StoreError::IOError(err)),
+    }
+  }
+
+  /// Compacts the journal into the snapshot. Reservation files and last
+  /// reserved IP files of a data dir used without `journal` are folded in
+  /// and removed, so a network switches over on its first compaction.
+  /// Entries of the journal win over files for the same IP or range.
+  pub fn compact(&self) -> Result<(), StoreError> {
+    self.writable()?;
+    if !self.options.journal {
+      return Ok(());
+    }
+
+    apply_lock(&self.last_reserved_lock, filelock::lock)?;
+    let result = self.compact_locked();
+    apply_lock(&self.last_reserved_lock, filelock::unlock)?;
+
+    result
+  }
+
+  fn compact_locked(&self) -> Result<(), StoreError> {
+    let mut state = self.replay()?.state;
+
+    let mut migrated = Vec::new();
+    for (entry, ip) in self.reservations() {
+      // empty reservations of a crash are left for fsck
+      match self.file_owner(ip) {
+        Ok(owner) => {
+          state.reservations.entry(ip).or_insert(owner);
+        }
+        Err(StoreError::Corrupt { .. }) | Err(StoreError::NotFound(_)) => continue,
+        Err(err) => return Err(err),
+      }
+      migrated.push(entry.path().to_owned());
+    }
+
+    let last_reserved_ips = self.load_last_reserved_ips()?;
+    for (range_id, ip) in &last_reserved_ips {
+      state
+        .last_reserved_ips
+        .entry(range_id.clone())
+        .or_insert(*ip);
+      migrated.push(self.last_reserved_path(range_id));
+    }
+
+    self.write_snapshot(&state)?;
+
+    for path in migrated {
+      match remove_file(&path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(StoreError::IOError(err)),
+        _ => {}
+      }
+    }
+    self.sync_data_dir().map_err(StoreError::IOError)
+  }
+
+  /// Creates the snapshot of a network opened in journal mode for the first
+  /// time, migrating the files it holds.
+  pub(super) fn recover(&self) -> Result<(), StoreError> {
+    if self.snapshot_path().exists() {
+      return Ok(());
+    }
+
+    self.compact()
+  }
+
+  /// `FileStore::fsck` for journal mode: reservations outside of every
+  /// range and stale last reserved IPs are dropped by writing a new
+  /// snapshot.
+  pub(super) fn check_journal(
+    &self,
+    range_sets: &[RangeSet],
+    fix: bool,
+  ) -> Result<Vec<Problem>, StoreError> {
+    let lock = if fix {
+      filelock::lock
+    } else {
+      filelock::lock_shared
+    };
+    apply_lock(&self.last_reserved_lock, lock)?;
+    let result = self.check_journal_locked(range_sets, fix);
+    apply_lock(&self.last_reserved_lock, filelock::unlock)?;
+
+    result
+  }
+
+  fn check_journal_locked(
+    &self,
+    range_sets: &[RangeSet],
+    fix: bool,
+  ) -> Result<Vec<Problem>, StoreError> {
+    let mut state = self.replay()?.state;
+    let mut problems = Vec::new();
+
+    let out_of_range: Vec<(IpAddr, String)> = state
+      .reservations
+      .iter()
+      .filter(|(ip, _)| !range_sets.iter().any(|range_set| range_set.contains(**ip)))
+      .map(|(ip, owner)| (*ip, owner.id.clone()))
+      .collect();
+    for (ip, id) in out_of_range {
+      state.reservations.remove(&ip);
+      problems.push(Problem {
+        kind: ProblemKind::OutOfRange,
+        path: self.snapshot_path(),
+        detail: format!("{} reserved by {} is outside of every range", ip, id),
+        fixed: fix,
+      });
+    }
+
+    let stale: Vec<(String, IpAddr)> = state
+      .last_reserved_ips
+      .iter()
+      .filter(|(range_id, ip)| is_stale(range_sets, range_id, **ip))
+      .map(|(range_id, ip)| (range_id.clone(), *ip))
+      .collect();
+    for (range_id, ip) in stale {
+      state.last_reserved_ips.remove(&range_id);
+      problems.push(Problem {
+        kind: ProblemKind::StaleLastReserved,
+        path: self.snapshot_path(),
+        detail: format!(
+          "last reserved ip {} of range set {} is outside of it",
+          ip, range_id
+        ),
+        fixed: fix,
+      });
+    }
+
+    if fix && !problems.is_empty() {
+      self.write_snapshot(&state)?;
+    }
+
+    Ok(problems)
+  }
+}
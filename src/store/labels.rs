@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// Key/value metadata attached to a reservation, e.g. the team owning the
+/// container. Keys are neither empty nor hold `=`, and neither keys nor
+/// values hold line breaks, so every store can keep them.
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Error, PartialEq)]
+#[error("invalid label {0:?}, keys must not be empty or hold `=` and labels no line breaks")]
+pub struct InvalidLabel(pub String);
+
+/// Checks that every store can keep `labels`.
+pub fn check_labels(labels: &Labels) -> Result<(), InvalidLabel> {
+    let line_break = |text: &str| text.contains(|c| c == '\r' || c == '\n');
+
+    for (key, value) in labels {
+        if key.is_empty() || key.contains('=') || line_break(key) || line_break(value) {
+            return Err(InvalidLabel(format!("{}={}", key, value)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Selects reservations by their labels, `KEY=VALUE` pairs separated by
+/// commas which all have to match, e.g. `team=web,tier=frontend`. An empty
+/// selector selects everything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selector {
+    labels: Labels,
+}
+
+impl Selector {
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.labels
+            .iter()
+            .all(|(key, value)| labels.get(key) == Some(value))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = InvalidLabel;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut labels = Labels::new();
+        for pair in text
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            match pair.find('=') {
+                Some(equals) => {
+                    let key = pair[..equals].trim().to_owned();
+                    labels.insert(key, pair[equals + 1..].trim().to_owned());
+                }
+                None => return Err(InvalidLabel(pair.to_owned())),
+            }
+        }
+
+        check_labels(&labels)?;
+        Ok(Selector { labels: labels })
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, (key, value)) in self.labels.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn select() {
+        let selector: Selector = " team=web, tier = frontend".parse().unwrap();
+        assert_eq!(selector.to_string(), "team=web,tier=frontend");
+
+        assert!(selector.matches(&labels(&[
+            ("team", "web"),
+            ("tier", "frontend"),
+            ("zone", "a")
+        ])));
+        assert!(!selector.matches(&labels(&[("team", "web")])));
+        assert!(!selector.matches(&labels(&[("team", "db"), ("tier", "frontend")])));
+        assert!("".parse::<Selector>().unwrap().matches(&Labels::new()));
+
+        assert_eq!(
+            "team".parse::<Selector>(),
+            Err(InvalidLabel("team".to_owned()))
+        );
+        assert!("=web".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn check() {
+        assert!(check_labels(&labels(&[("team", "web=1")])).is_ok());
+        assert!(check_labels(&labels(&[("team", "web\r\n")])).is_err());
+        assert!(check_labels(&labels(&[("", "web")])).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Grace added to leases stamped by an earlier run of the clock, which
+/// read wall time that may since have been stepped forward, e.g. by NTP.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// What a `LeaseClock` keeps across restarts: how often it was resumed and
+/// the latest lease time it handed out.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ClockState {
+    pub epoch: u64,
+    /// Seconds since the UNIX epoch.
+    pub wall: u64,
+}
+
+/// A point in lease time. `wall` is in seconds since the UNIX epoch and
+/// never decreases over the runs of a clock, `epoch` counts the runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LeaseStamp {
+    pub epoch: u64,
+    pub wall: u64,
+}
+
+/// Stamps and expires leases without trusting the wall clock alone.
+///
+/// Within a run lease time advances with the monotonic clock, so steps of
+/// the wall clock don't shorten or stretch leases. A run starts from the
+/// wall clock, but never before the latest lease time of earlier runs, so a
+/// clock jumping backwards over a restart delays expiry instead of making
+/// leases appear to be from the future.
+#[derive(Debug)]
+pub struct LeaseClock {
+    epoch: u64,
+    /// Lease time at `started`.
+    base: u64,
+    started: Instant,
+    tolerance: Duration,
+}
+
+impl LeaseClock {
+    /// Starts a new run after the one `state` was saved by.
+    pub fn resume(state: ClockState) -> LeaseClock {
+        LeaseClock::resume_at(state, SystemTime::now(), Instant::now())
+    }
+
+    pub(crate) fn resume_at(state: ClockState, wall: SystemTime, now: Instant) -> LeaseClock {
+        LeaseClock {
+            epoch: state.epoch + 1,
+            base: seconds(wall).max(state.wall),
+            started: now,
+            tolerance: DEFAULT_TOLERANCE,
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: Duration) -> LeaseClock {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn now(&self) -> LeaseStamp {
+        self.at(Instant::now())
+    }
+
+    pub(crate) fn at(&self, now: Instant) -> LeaseStamp {
+        LeaseStamp {
+            epoch: self.epoch,
+            wall: self.base + now.saturating_duration_since(self.started).as_secs(),
+        }
+    }
+
+    /// The state to save for the next run, see `resume`.
+    pub fn state(&self) -> ClockState {
+        self.state_at(Instant::now())
+    }
+
+    pub(crate) fn state_at(&self, now: Instant) -> ClockState {
+        ClockState {
+            epoch: self.epoch,
+            wall: self.at(now).wall,
+        }
+    }
+
+    /// Whether a lease stamped `since` for `ttl` has run out at `now`.
+    ///
+    /// Leases of the current run compare monotonic time exactly, leases of
+    /// earlier runs get `tolerance` on top. Leases stamped after `now`,
+    /// e.g. by a clock whose saved state was lost, expire once lease time
+    /// has caught up with them.
+    pub fn expired(&self, since: LeaseStamp, ttl: Duration, now: LeaseStamp) -> bool {
+        let mut limit = ttl;
+        if since.epoch != now.epoch {
+            limit += self.tolerance;
+        }
+
+        Duration::from_secs(now.wall.saturating_sub(since.wall)) >= limit
+    }
+}
+
+fn seconds(wall: SystemTime) -> u64 {
+    wall.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(300);
+
+    fn wall(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn within_a_run() {
+        let start = Instant::now();
+        let clock = LeaseClock::resume_at(ClockState::default(), wall(1000), start);
+        let since = clock.at(start);
+        assert_eq!(
+            since,
+            LeaseStamp {
+                epoch: 1,
+                wall: 1000
+            }
+        );
+
+        // wall time is only read when resuming, steps of it don't matter
+        let later = clock.at(start + Duration::from_secs(299));
+        assert!(!clock.expired(since, TTL, later));
+        let later = clock.at(start + Duration::from_secs(300));
+        assert!(clock.expired(since, TTL, later));
+    }
+
+    #[test]
+    fn clock_jumps_backward_over_restart() {
+        let start = Instant::now();
+        let clock = LeaseClock::resume_at(ClockState::default(), wall(10_000), start);
+        let since = clock.at(start);
+        let state = clock.state_at(start + Duration::from_secs(100));
+
+        // restarted with the wall clock an hour behind
+        let restart = start + Duration::from_secs(200);
+        let clock = LeaseClock::resume_at(state, wall(10_000 - 3600), restart);
+        let now = clock.at(restart);
+        assert_eq!(
+            now,
+            LeaseStamp {
+                epoch: 2,
+                wall: 10_100
+            }
+        );
+        assert!(now > since);
+        assert!(!clock.expired(since, TTL, now));
+
+        // the time lost to the restart only delays expiry
+        let later = |seconds| clock.at(restart + Duration::from_secs(seconds));
+        assert!(!clock.expired(since, TTL, later(259)));
+        assert!(clock.expired(since, TTL, later(260)));
+    }
+
+    #[test]
+    fn tolerance() {
+        let start = Instant::now();
+        let clock = LeaseClock::resume_at(ClockState::default(), wall(10_000), start);
+        let since = clock.at(start);
+
+        // restarted after the wall clock was stepped forward a little
+        let clock = LeaseClock::resume_at(clock.state_at(start), wall(10_330), start)
+            .with_tolerance(Duration::from_secs(60));
+        assert!(!clock.expired(since, TTL, clock.at(start)));
+        assert!(clock.expired(since, TTL, clock.at(start + Duration::from_secs(30))));
+
+        // stamped by a run whose state was lost, ahead of the current one
+        let ahead = LeaseStamp {
+            epoch: 7,
+            wall: 10_500,
+        };
+        assert!(!clock.expired(ahead, TTL, clock.at(start)));
+        assert!(clock.expired(ahead, TTL, clock.at(start + Duration::from_secs(530))));
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Labels, Owner, Store, StoreError, Transaction};
+use crate::allocator::rangeset::RangeSet;
+
+/// Interface of manifest entries which don't name one.
+pub const DEFAULT_IFNAME: &str = "eth0";
+
+/// Declares which IPs containers should hold, e.g. static assignments kept
+/// in git. Keys are container ids, optionally followed by `/` and the
+/// interface, which is `eth0` otherwise:
+///
+/// ```text
+/// {"c1": ["10.1.2.2"], "c2/net1": ["10.1.2.3", "2001:db8::3"]}
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Manifest {
+    pub assignments: BTreeMap<String, Vec<IpAddr>>,
+}
+
+/// An IP of a container, reserved or to be.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Assignment {
+    pub ip: IpAddr,
+    pub id: String,
+    pub ifname: String,
+}
+
+/// An assignment of the manifest which can't be made.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Conflict {
+    /// The IP is reserved for a container the manifest doesn't assign it
+    /// to, which doesn't release it.
+    Taken {
+        #[serde(flatten)]
+        assignment: Assignment,
+        holder: String,
+    },
+    /// The IP lies outside of every range set.
+    OutOfRange {
+        #[serde(flatten)]
+        assignment: Assignment,
+    },
+}
+
+/// The changes bringing a store in line with a manifest.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Plan {
+    pub reserve: Vec<Assignment>,
+    pub release: Vec<Assignment>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl Manifest {
+    /// Compares the reservations of `store` with the manifest. Containers
+    /// the manifest names lose the IPs it doesn't assign them, with `prune`
+    /// every other container loses its IPs as well.
+    pub fn diff<S: Store + ?Sized>(
+        &self,
+        store: &S,
+        range_sets: &[RangeSet],
+        prune: bool,
+    ) -> Result<Plan, StoreError> {
+        let mut desired = BTreeMap::new();
+        for (key, ips) in &self.assignments {
+            let (id, ifname) = match key.find('/') {
+                Some(slash) => (&key[..slash], &key[slash + 1..]),
+                None => (key.as_str(), DEFAULT_IFNAME),
+            };
+            for ip in ips {
+                desired.insert(*ip, (id, ifname));
+            }
+        }
+        let named: BTreeSet<(&str, &str)> = desired.values().copied().collect();
+
+        let mut current = BTreeMap::new();
+        for ip in store.list()? {
+            current.insert(ip, store.owner(ip)?);
+        }
+
+        let mut plan = Plan::default();
+        for (ip, owner) in &current {
+            let holder = (owner.id.as_str(), owner.ifname.as_str());
+            let wanted = desired.get(ip).is_some_and(|wanted| *wanted == holder);
+            if !wanted && (prune || named.contains(&holder)) {
+                plan.release.push(assignment(*ip, holder));
+            }
+        }
+
+        for (ip, wanted) in &desired {
+            if !range_sets.iter().any(|range_set| range_set.contains(*ip)) {
+                plan.conflicts.push(Conflict::OutOfRange {
+                    assignment: assignment(*ip, *wanted),
+                });
+                continue;
+            }
+
+            match current.get(ip) {
+                None => plan.reserve.push(assignment(*ip, *wanted)),
+                Some(owner) if (owner.id.as_str(), owner.ifname.as_str()) == *wanted => {}
+                // released to another container of the manifest first
+                Some(_) if plan.release.iter().any(|released| released.ip == *ip) => {
+                    plan.reserve.push(assignment(*ip, *wanted))
+                }
+                Some(owner) => plan.conflicts.push(Conflict::Taken {
+                    assignment: assignment(*ip, *wanted),
+                    holder: owner.id.clone(),
+                }),
+            }
+        }
+
+        Ok(plan)
+    }
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.reserve.is_empty() && self.release.is_empty()
+    }
+
+    /// Releases and reserves what the plan says, conflicts are left alone.
+    /// The reservations are committed as one transaction after the
+    /// releases, returns false if one of their IPs was taken meanwhile. The
+    /// store must be locked.
+    pub fn apply<S: Store + ?Sized>(&self, store: &S) -> Result<bool, StoreError> {
+        for released in &self.release {
+            store.release_checked(released.ip, &released.id, &released.ifname)?;
+        }
+
+        let mut txn = Transaction::new();
+        for reserved in &self.reserve {
+            let owner = Owner {
+                id: reserved.id.clone(),
+                ifname: reserved.ifname.clone(),
+                netns: None,
+                pod: None,
+                labels: Labels::new(),
+            };
+            txn.reserve_for(owner, reserved.ip);
+        }
+
+        store.commit(&txn)
+    }
+}
+
+fn assignment(ip: IpAddr, (id, ifname): (&str, &str)) -> Assignment {
+    Assignment {
+        ip: ip,
+        id: id.to_owned(),
+        ifname: ifname.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::filestore::FileStore;
+    use std::fs::remove_dir_all;
+
+    const DATA_DIR: &str = "/tmp/cni-manifest";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn ips(plan: &[Assignment]) -> Vec<IpAddr> {
+        plan.iter().map(|assignment| assignment.ip).collect()
+    }
+
+    #[test]
+    fn diff_and_apply() {
+        let _ = remove_dir_all(DATA_DIR);
+
+        let range_sets = ["10.1.2.0/24".parse::<RangeSet>().unwrap()];
+
+        let store = FileStore::new("n", DATA_DIR).unwrap();
+        let mut txn = Transaction::new();
+        txn.reserve("c1", "eth0", ip("10.1.2.2"))
+            .reserve("c1", "eth0", ip("10.1.2.3"))
+            .reserve("c2", "net1", ip("10.1.2.4"))
+            .reserve("dynamic", "eth0", ip("10.1.2.5"));
+        assert!(store.commit(&txn).unwrap());
+
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "c1": ["10.1.2.2", "10.1.2.4"],
+                "c2/net1": ["10.1.2.3"],
+                "c3": ["10.1.2.5", "10.1.3.5"]
+            }"#,
+        )
+        .unwrap();
+
+        let plan = manifest.diff(&store, &range_sets, false).unwrap();
+        assert_eq!(ips(&plan.release), vec![ip("10.1.2.3"), ip("10.1.2.4")]);
+        assert_eq!(ips(&plan.reserve), vec![ip("10.1.2.3"), ip("10.1.2.4")]);
+        assert_eq!(plan.reserve[0].ifname, "net1");
+        assert_eq!(
+            plan.conflicts,
+            vec![
+                Conflict::Taken {
+                    assignment: assignment(ip("10.1.2.5"), ("c3", "eth0")),
+                    holder: "dynamic".to_owned(),
+                },
+                Conflict::OutOfRange {
+                    assignment: assignment(ip("10.1.3.5"), ("c3", "eth0")),
+                },
+            ]
+        );
+
+        store.lock().unwrap();
+        assert!(plan.apply(&store).unwrap());
+        store.unlock().unwrap();
+        assert_eq!(store.owner(ip("10.1.2.3")).unwrap().ifname, "net1");
+        assert_eq!(store.owner(ip("10.1.2.4")).unwrap().id, "c1");
+        assert!(manifest
+            .diff(&store, &range_sets, false)
+            .unwrap()
+            .is_empty());
+
+        // pruning frees the IP of the container the manifest doesn't name
+        let plan = manifest.diff(&store, &range_sets, true).unwrap();
+        assert_eq!(ips(&plan.release), vec![ip("10.1.2.5")]);
+        assert_eq!(ips(&plan.reserve), vec![ip("10.1.2.5")]);
+        assert_eq!(plan.conflicts.len(), 1);
+
+        let _ = remove_dir_all(DATA_DIR);
+    }
+}
@@ -1,31 +1,280 @@
+#[cfg(feature = "consul")]
+pub mod consul;
+#[cfg(any(test, feature = "test-util"))]
+pub mod faultstore;
+mod filelock;
 pub mod filestore;
+mod labels;
+mod leaseclock;
+mod manifest;
+#[cfg(feature = "pgstore")]
+pub mod pgstore;
+mod pins;
+#[cfg(feature = "redis")]
+pub mod redis;
+mod snapshot;
+mod stats;
+mod tee;
+mod transaction;
 
-use std::io::Error as IoError;
-use std::net::{AddrParseError, IpAddr};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
 use thiserror::Error;
 
+pub use labels::{check_labels, InvalidLabel, Labels, Selector};
+pub use leaseclock::{ClockState, LeaseClock, LeaseStamp, DEFAULT_TOLERANCE};
+pub use manifest::{Assignment, Conflict, Manifest, Plan, DEFAULT_IFNAME};
+pub use pins::{InvalidPin, Pins, PINS_FILE};
+pub use snapshot::{
+    ConflictPolicy, Outcome, Reservation, Restore, Restored, Snapshot, SNAPSHOT_VERSION,
+};
+pub use stats::{StatsCounter, StoreStats};
+pub use tee::TeeStore;
+pub use transaction::{Operation, Transaction};
+
 #[derive(Debug, Error)]
 pub enum StoreError {
-    #[error("io error happened: {0}")]
-    IOError(IoError),
+    #[error("io error happened")]
+    IOError(#[from] IoError),
+
+    #[error("ip {0} is not reserved")]
+    NotFound(IpAddr),
+
+    #[error("no ip has been reserved for range {0} yet")]
+    LastReservedNotFound(String),
+
+    #[error("store file {path} is corrupt: {reason}")]
+    Corrupt { path: PathBuf, reason: String },
+
+    #[error("ip {0} is not reserved for {1} {2}")]
+    NotOwner(IpAddr, String, String),
+
+    #[error("invalid network name {0:?}")]
+    InvalidName(String),
+
+    #[error("store is read-only")]
+    ReadOnly,
+
+    #[error(transparent)]
+    InvalidLabel(#[from] InvalidLabel),
+
+    #[error(
+        "timed out after {timeout:?} waiting for lock {path}{}",
+        .holder.map(|pid| format!(" held by pid {}", pid)).unwrap_or_default()
+    )]
+    LockTimeout {
+        path: PathBuf,
+        /// PID of the process holding the lock exclusively, if known.
+        holder: Option<u32>,
+        timeout: Duration,
+    },
+
+    #[error("snapshot version {0} is newer than this plugin supports")]
+    UnsupportedSnapshot(u32),
+}
+
+/// Who a reservation belongs to.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Owner {
+    pub id: String,
+    pub ifname: String,
+    /// Network namespace path given on ADD, unknown for reservations of
+    /// older versions.
+    pub netns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod: Option<Pod>,
+    /// Metadata attached on ADD, see `Labels`.
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+}
+
+/// Kubernetes pod an IP was reserved for, as passed in `CNI_ARGS`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Pod {
+    pub namespace: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+}
 
-    #[error("wrong ip format: {0}")]
-    AddrParseError(AddrParseError),
+impl StoreError {
+    /// Whether the operation may succeed when tried again, e.g. on a
+    /// contended or NFS backed data dir.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StoreError::IOError(err) => is_transient(err),
+            _ => false,
+        }
+    }
 }
 
-pub trait Store {
+fn is_transient(err: &IoError) -> bool {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut => return true,
+        _ => {}
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(code) = err.raw_os_error() {
+            return code == libc::EAGAIN || code == libc::ESTALE || code == libc::EINTR;
+        }
+    }
+
+    false
+}
+
+/// Locks in-process state of a store. A panic while holding the guard
+/// leaves nothing half updated, so poisoning is ignored.
+pub(crate) fn locked<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Where reservations are kept, e.g. files of a data dir or a database.
+///
+/// Stores are shared as `Arc<dyn Store>` by the allocators of a network and
+/// the plugin, so every method takes `&self`, including those changing the
+/// reservations, and stores must be `Send` and `Sync`. Their state lives
+/// outside the process, in-process state like connections or held locks
+/// sits behind a `Mutex` or in atomics. Processes sharing a store coordinate
+/// through `lock` and `lock_range` alone, never through the in-process
+/// state, and implementations must not hold a guard of it across calls to
+/// other methods of the store.
+pub trait Store: Send + Sync {
     fn lock(&self) -> Result<(), StoreError>;
     fn unlock(&self) -> Result<(), StoreError>;
+    /// Locks the IPs of a single range set, so allocations from different
+    /// range sets of the network can run concurrently. Excludes `lock`, but
+    /// must not be nested with it. Stores without finer grained locking lock
+    /// everything.
+    fn lock_range(&self, _range_id: &str) -> Result<(), StoreError> {
+        self.lock()
+    }
+    fn unlock_range(&self, _range_id: &str) -> Result<(), StoreError> {
+        self.unlock()
+    }
     fn close(&self) -> Result<(), StoreError>;
+    /// Applies every operation of `txn` or none of them. Returns false
+    /// without changes if one of the IPs to reserve is already reserved.
+    fn commit(&self, txn: &Transaction) -> Result<bool, StoreError>;
     fn reserve(
         &self,
         id: &str,
         ifname: &str,
         ip: IpAddr,
         range_id: &str,
-    ) -> Result<bool, StoreError>;
+    ) -> Result<bool, StoreError> {
+        let owner = Owner {
+            id: id.to_owned(),
+            ifname: ifname.to_owned(),
+            netns: None,
+            pod: None,
+            labels: Labels::new(),
+        };
+        self.reserve_for(owner, ip, range_id)
+    }
+    /// Like `reserve`, but records everything known about `owner`, e.g. the
+    /// labels attached to the reservation.
+    fn reserve_for(&self, owner: Owner, ip: IpAddr, range_id: &str) -> Result<bool, StoreError> {
+        let mut txn = Transaction::new();
+        txn.reserve_for(owner, ip)
+            .record_last_reserved(ip, range_id);
+        self.commit(&txn)
+    }
+    /// Like `reserve_for`, but also succeeds if `ip` is already reserved
+    /// for the id and ifname of `owner`, e.g. when the runtime retries an ADD.
+    fn reserve_or_confirm(
+        &self,
+        owner: Owner,
+        ip: IpAddr,
+        range_id: &str,
+    ) -> Result<bool, StoreError> {
+        let (id, ifname) = (owner.id.clone(), owner.ifname.clone());
+        if self.reserve_for(owner, ip, range_id)? {
+            return Ok(true);
+        }
+
+        Ok(self.get_by_id(&id, &ifname).contains(&ip))
+    }
+    /// Reserves every IP of `ips` for `owner` or none of them. Returns false
+    /// without changes if any of them is already reserved.
+    fn reserve_many(
+        &self,
+        owner: Owner,
+        ips: &[IpAddr],
+        range_id: &str,
+    ) -> Result<bool, StoreError> {
+        let mut txn = Transaction::new();
+        for ip in ips {
+            txn.reserve_for(owner.clone(), *ip);
+        }
+        if let Some(ip) = ips.last() {
+            txn.record_last_reserved(*ip, range_id);
+        }
+
+        self.commit(&txn)
+    }
     fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError>;
+    /// Returns the last reserved IP of every range set id. Stores which
+    /// can't enumerate them return none.
+    fn last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+        Ok(BTreeMap::new())
+    }
     fn release(&self, ip: IpAddr) -> Result<(), StoreError>;
+    /// Releases `ip` only if it is reserved for `id` and `ifname`.
+    fn release_checked(&self, ip: IpAddr, id: &str, ifname: &str) -> Result<(), StoreError> {
+        if !self.get_by_id(id, ifname).contains(&ip) {
+            return Err(StoreError::NotOwner(ip, id.to_owned(), ifname.to_owned()));
+        }
+
+        self.release(ip)
+    }
     fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError>;
+    /// Marks the reservation of `ip` by `id` and `ifname` as still in use.
+    /// Stores which don't track the age of reservations only check that it
+    /// still belongs to them.
+    fn touch(&self, ip: IpAddr, id: &str, ifname: &str) -> Result<(), StoreError> {
+        if !self.get_by_id(id, ifname).contains(&ip) {
+            return Err(StoreError::NotOwner(ip, id.to_owned(), ifname.to_owned()));
+        }
+
+        Ok(())
+    }
     fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr>;
+    /// Returns the container `ip` is reserved for.
+    fn owner(&self, ip: IpAddr) -> Result<Owner, StoreError>;
+    /// Returns the reservation of `ip`, or none if it is free. Looks up `ip`
+    /// alone through `owner`, without walking the other reservations.
+    fn get_by_ip(&self, ip: IpAddr) -> Result<Option<Reservation>, StoreError> {
+        match self.owner(ip) {
+            Ok(owner) => Ok(Some(Reservation {
+                ip: ip,
+                owner: owner,
+            })),
+            Err(StoreError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+    /// Returns every reserved IP of the network, in no particular order.
+    fn list(&self) -> Result<Vec<IpAddr>, StoreError>;
+    /// Returns a complete, versioned dump of the store, see `Snapshot`.
+    fn export(&self) -> Result<Snapshot, StoreError> {
+        Snapshot::of(self)
+    }
+    /// Recreates the reservations and last reserved IPs of `snapshot` as
+    /// one transaction, e.g. in an empty store restoring a backup. Returns
+    /// false without changes if one of its IPs is already reserved.
+    fn import(&self, snapshot: &Snapshot) -> Result<bool, StoreError> {
+        self.commit(&snapshot.transaction()?)
+    }
+    /// Counters of the operations of this store since it was opened, see
+    /// `StoreStats`. None for stores which don't count them.
+    fn stats(&self) -> Option<StoreStats> {
+        None
+    }
 }
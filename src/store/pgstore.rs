@@ -0,0 +1,313 @@
+//! Reservations kept in PostgreSQL, for clusters where many writers, e.g.
+//! a central IPAM service with several replicas, share the pools.
+//!
+//! Every IP of a pool is a row of `host_local_ips`, free while it has no
+//! container. Writes are single conditional statements or database
+//! transactions, so `lock` doesn't have to serialize writers: `claim`
+//! picks a free row with `SELECT ... FOR UPDATE SKIP LOCKED`, concurrent
+//! claims skip each other's rows instead of waiting for them, and `commit`
+//! only takes rows which are still free. Rows are added by `populate`, or
+//! on the fly when an IP outside the populated pool is reserved.
+
+use std::collections::BTreeMap;
+use std::io::Error as IoError;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use postgres::{Client, NoTls};
+
+use super::filestore::validate_network_name;
+use super::{locked, Operation, Owner, StatsCounter, Store, StoreError, StoreStats, Transaction};
+use crate::allocator::range::Range;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS host_local_ips (
+    network TEXT NOT NULL,
+    ip INET NOT NULL,
+    container_id TEXT,
+    ifname TEXT,
+    owner TEXT,
+    PRIMARY KEY (network, ip)
+);
+CREATE INDEX IF NOT EXISTS host_local_ips_container
+    ON host_local_ips (network, container_id, ifname);
+CREATE TABLE IF NOT EXISTS host_local_last_reserved (
+    network TEXT NOT NULL,
+    range_id TEXT NOT NULL,
+    ip INET NOT NULL,
+    PRIMARY KEY (network, range_id)
+);
+";
+
+/// Takes the row of an IP unless it belongs to a container already,
+/// creating it if the IP isn't part of the pool yet.
+const RESERVE: &str = "
+INSERT INTO host_local_ips (network, ip, container_id, ifname, owner)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (network, ip) DO UPDATE
+    SET container_id = EXCLUDED.container_id, ifname = EXCLUDED.ifname, owner = EXCLUDED.owner
+    WHERE host_local_ips.container_id IS NULL
+";
+
+const RELEASE: &str = "
+UPDATE host_local_ips SET container_id = NULL, ifname = NULL, owner = NULL
+WHERE network = $1 AND ip = $2 AND container_id IS NOT NULL
+";
+
+const RECORD_LAST_RESERVED: &str = "
+INSERT INTO host_local_last_reserved (network, range_id, ip) VALUES ($1, $2, $3)
+ON CONFLICT (network, range_id) DO UPDATE SET ip = EXCLUDED.ip
+";
+
+/// Takes the lowest free IP from `$2` to `$3` other than `$4`, skipping the
+/// rows other writers are claiming.
+const CLAIM: &str = "
+UPDATE host_local_ips SET container_id = $5, ifname = $6, owner = $7
+WHERE network = $1 AND ip = (
+    SELECT ip FROM host_local_ips
+    WHERE network = $1 AND container_id IS NULL AND ip BETWEEN $2 AND $3
+        AND ($4::inet IS NULL OR ip <> $4)
+    ORDER BY ip
+    LIMIT 1
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING ip
+";
+
+/// Adds the rows from `$2` on for `$3` further IPs, but `$4`.
+const POPULATE: &str = "
+INSERT INTO host_local_ips (network, ip)
+SELECT $1, $2::inet + n FROM generate_series(0::bigint, $3::bigint) AS n
+WHERE $4::inet IS NULL OR $2::inet + n <> $4
+ON CONFLICT (network, ip) DO NOTHING
+";
+
+pub struct PgStore {
+    network: String,
+    client: Mutex<Client>,
+    stats: StatsCounter,
+}
+
+impl PgStore {
+    /// Connects without TLS, e.g. to `host=/run/postgresql user=cni`, and
+    /// creates the tables if they don't exist.
+    pub fn connect(network: &str, params: &str) -> Result<PgStore, StoreError> {
+        let client = Client::connect(params, NoTls).map_err(pg_error)?;
+        PgStore::with_client(network, client)
+    }
+
+    /// Uses a client connected by the caller, e.g. over TLS, and creates the
+    /// tables if they don't exist.
+    pub fn with_client(network: &str, mut client: Client) -> Result<PgStore, StoreError> {
+        validate_network_name(network)?;
+        client.batch_execute(SCHEMA).map_err(pg_error)?;
+
+        Ok(PgStore {
+            network: network.to_owned(),
+            client: Mutex::new(client),
+            stats: StatsCounter::new(),
+        })
+    }
+
+    /// Adds every allocatable IP of `range` to the pool, returns how many
+    /// weren't part of it yet. Takes a row per IP, so ranges should be of a
+    /// sensible size.
+    pub fn populate(&self, range: &Range) -> Result<u64, StoreError> {
+        if range.size() == 0 {
+            return Ok(0);
+        }
+
+        let count = (range.size() - 1).min(i64::MAX as u128) as i64;
+        locked(&self.client)
+            .execute(
+                POPULATE,
+                &[&self.network, &range.start, &count, &range.gateway],
+            )
+            .map_err(pg_error)
+    }
+
+    /// Reserves the lowest free IP of `range` for `owner`, None if there is
+    /// none left. Only finds IPs which were populated.
+    pub fn claim(&self, owner: &Owner, range: &Range) -> Result<Option<IpAddr>, StoreError> {
+        let content = serde_json::to_string(owner).map_err(IoError::from)?;
+        let row = locked(&self.client)
+            .query_opt(
+                CLAIM,
+                &[
+                    &self.network,
+                    &range.start,
+                    &range.end,
+                    &range.gateway,
+                    &owner.id,
+                    &owner.ifname,
+                    &content,
+                ],
+            )
+            .map_err(pg_error);
+
+        let ip = self.stats.result(row)?.map(|row| row.get(0));
+        if ip.is_some() {
+            self.stats.reserved(1);
+        }
+        Ok(ip)
+    }
+
+    /// `Store::commit` without counting.
+    fn commit_txn(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        let mut client = locked(&self.client);
+        let mut db_txn = client.transaction().map_err(pg_error)?;
+
+        for operation in txn.operations() {
+            match operation {
+                Operation::Reserve { owner, ip } => {
+                    let content = serde_json::to_string(owner).map_err(IoError::from)?;
+                    let reserved = db_txn
+                        .execute(
+                            RESERVE,
+                            &[&self.network, ip, &owner.id, &owner.ifname, &content],
+                        )
+                        .map_err(pg_error)?;
+                    if reserved == 0 {
+                        // dropping the transaction rolls it back
+                        return Ok(false);
+                    }
+                }
+                Operation::Release(ip) => {
+                    db_txn
+                        .execute(RELEASE, &[&self.network, ip])
+                        .map_err(pg_error)?;
+                }
+                Operation::RecordLastReserved { ip, range_id } => {
+                    db_txn
+                        .execute(RECORD_LAST_RESERVED, &[&self.network, range_id, ip])
+                        .map_err(pg_error)?;
+                }
+            }
+        }
+
+        db_txn.commit().map_err(pg_error)?;
+        Ok(true)
+    }
+
+    /// `Store::release` without counting.
+    fn release_ip(&self, ip: IpAddr) -> Result<(), StoreError> {
+        let released = locked(&self.client)
+            .execute(RELEASE, &[&self.network, &ip])
+            .map_err(pg_error)?;
+        if released == 0 {
+            return Err(StoreError::NotFound(ip));
+        }
+
+        Ok(())
+    }
+
+    /// `Store::release_by_id` without counting.
+    fn release_ips_of(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        locked(&self.client)
+            .execute(
+                "UPDATE host_local_ips SET container_id = NULL, ifname = NULL, owner = NULL
+                 WHERE network = $1 AND container_id = $2 AND ifname = $3",
+                &[&self.network, &id, &ifname],
+            )
+            .map_err(pg_error)?;
+
+        Ok(())
+    }
+}
+
+impl Store for PgStore {
+    /// Writers don't need to exclude each other, see the module docs.
+    fn lock(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn unlock(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn close(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn commit(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        self.stats.commit(txn, self.commit_txn(txn))
+    }
+
+    fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError> {
+        let row = locked(&self.client)
+            .query_opt(
+                "SELECT ip FROM host_local_last_reserved WHERE network = $1 AND range_id = $2",
+                &[&self.network, &range_id],
+            )
+            .map_err(pg_error)?;
+
+        row.map(|row| row.get(0))
+            .ok_or_else(|| StoreError::LastReservedNotFound(range_id.to_owned()))
+    }
+
+    fn last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+        let rows = locked(&self.client)
+            .query(
+                "SELECT range_id, ip FROM host_local_last_reserved WHERE network = $1",
+                &[&self.network],
+            )
+            .map_err(pg_error)?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn release(&self, ip: IpAddr) -> Result<(), StoreError> {
+        self.stats.release(self.release_ip(ip))
+    }
+
+    fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        self.stats.release(self.release_ips_of(id, ifname))
+    }
+
+    fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr> {
+        let rows = locked(&self.client).query(
+            "SELECT ip FROM host_local_ips
+             WHERE network = $1 AND container_id = $2 AND ifname = $3 ORDER BY ip",
+            &[&self.network, &id, &ifname],
+        );
+
+        rows.map(|rows| rows.iter().map(|row| row.get(0)).collect())
+            .unwrap_or_default()
+    }
+
+    fn owner(&self, ip: IpAddr) -> Result<Owner, StoreError> {
+        let row = locked(&self.client)
+            .query_opt(
+                "SELECT owner FROM host_local_ips
+                 WHERE network = $1 AND ip = $2 AND container_id IS NOT NULL",
+                &[&self.network, &ip],
+            )
+            .map_err(pg_error)?
+            .ok_or(StoreError::NotFound(ip))?;
+
+        let content: String = row.get(0);
+        serde_json::from_str(&content).map_err(|err| StoreError::Corrupt {
+            path: format!("host_local_ips/{}/{}", self.network, ip).into(),
+            reason: err.to_string(),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<IpAddr>, StoreError> {
+        let rows = locked(&self.client)
+            .query(
+                "SELECT ip FROM host_local_ips WHERE network = $1 AND container_id IS NOT NULL",
+                &[&self.network],
+            )
+            .map_err(pg_error)?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn stats(&self) -> Option<StoreStats> {
+        Some(self.stats.get())
+    }
+}
+
+fn pg_error(err: postgres::Error) -> StoreError {
+    StoreError::IOError(IoError::other(err))
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use super::Pod;
+use crate::allocator::rangeset::RangeSet;
+
+/// Name of the file in the data dir of a network which pins IPs to
+/// containers, see `Pins`.
+pub const PINS_FILE: &str = "reservations.conf";
+
+/// Static assignments declared ahead of the ADDs, like `dhcp-host` of
+/// dnsmasq. Every line pins one or more IPs to a container id, or to a pod
+/// as `pod:NAMESPACE/NAME`, and `#` starts a comment:
+///
+/// ```text
+/// # lab routers
+/// 0f3a9c          10.1.2.10
+/// pod:lab/router  10.1.2.11 2001:db8::11
+/// ```
+///
+/// Pinned IPs are only handed out to the containers they are pinned to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pins {
+    containers: BTreeMap<String, Vec<IpAddr>>,
+    pods: BTreeMap<(String, String), Vec<IpAddr>>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("line {line}: {reason}")]
+pub struct InvalidPin {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl Pins {
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty() && self.pods.is_empty()
+    }
+
+    /// Every pinned IP.
+    pub fn ips(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.containers
+            .values()
+            .chain(self.pods.values())
+            .flatten()
+            .copied()
+    }
+
+    /// IP of `range_set` pinned to container `id`, or else to `pod`.
+    pub fn ip_for(&self, id: &str, pod: Option<&Pod>, range_set: &RangeSet) -> Option<IpAddr> {
+        let by_pod = pod.and_then(|pod| self.pods.get(&(pod.namespace.clone(), pod.name.clone())));
+
+        self.containers
+            .get(id)
+            .into_iter()
+            .chain(by_pod)
+            .flatten()
+            .copied()
+            .find(|ip| range_set.contains(*ip))
+    }
+}
+
+impl FromStr for Pins {
+    type Err = InvalidPin;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut pins = Pins::default();
+        let mut pinned = BTreeMap::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |reason: String| InvalidPin {
+                line: index + 1,
+                reason: reason,
+            };
+            let line = match line.find('#') {
+                Some(hash) => &line[..hash],
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            let key = match fields.next() {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let mut ips = Vec::new();
+            for field in fields {
+                let ip: IpAddr = field
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid ip {:?}", field)))?;
+                if let Some(holder) = pinned.insert(ip, key) {
+                    return Err(invalid(format!("{} is pinned to {} already", ip, holder)));
+                }
+                ips.push(ip);
+            }
+            if ips.is_empty() {
+                return Err(invalid(format!("no ip pinned to {}", key)));
+            }
+
+            let previous = if let Some(pod) = key.strip_prefix("pod:") {
+                match pod.find('/') {
+                    Some(slash) if slash > 0 && slash + 1 < pod.len() => {
+                        let pod = (pod[..slash].to_owned(), pod[slash + 1..].to_owned());
+                        pins.pods.insert(pod, ips)
+                    }
+                    _ => {
+                        return Err(invalid(format!(
+                            "invalid pod {:?}, expected pod:NAMESPACE/NAME",
+                            key
+                        )))
+                    }
+                }
+            } else {
+                pins.containers.insert(key.to_owned(), ips)
+            };
+            if previous.is_some() {
+                return Err(invalid(format!("{} is pinned twice", key)));
+            }
+        }
+
+        Ok(pins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let pins: Pins = "
+            # lab routers
+            c1              10.1.2.10   # the first one
+            pod:lab/router  10.1.2.11 2001:db8::11
+        "
+        .parse()
+        .unwrap();
+        let range_set: RangeSet = "10.1.2.0/24".parse().unwrap();
+        let router = Pod {
+            namespace: "lab".to_owned(),
+            name: "router".to_owned(),
+            uid: None,
+        };
+
+        assert_eq!(pins.ips().count(), 3);
+        assert_eq!(
+            pins.ip_for("c1", Some(&router), &range_set),
+            Some("10.1.2.10".parse().unwrap())
+        );
+        assert_eq!(
+            pins.ip_for("c2", Some(&router), &range_set),
+            Some("10.1.2.11".parse().unwrap())
+        );
+        assert_eq!(
+            pins.ip_for("c2", Some(&router), &"2001:db8::/64".parse().unwrap()),
+            Some("2001:db8::11".parse().unwrap())
+        );
+        assert_eq!(pins.ip_for("c2", None, &range_set), None);
+        assert!("".parse::<Pins>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        let line = |text: &str| text.parse::<Pins>().unwrap_err().line;
+
+        assert_eq!(line("c1 10.1.2.10\nc2"), 2);
+        assert_eq!(line("c1 10.1.2.300"), 1);
+        assert_eq!(line("c1 10.1.2.10\nc2 10.1.2.10"), 2);
+        assert_eq!(line("c1 10.1.2.10\nc1 10.1.2.11"), 2);
+        assert_eq!(line("pod:lab 10.1.2.10"), 1);
+        assert_eq!(line("pod:/router 10.1.2.10"), 1);
+    }
+}
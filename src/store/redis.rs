@@ -0,0 +1,549 @@
+//! Reservations kept in Redis, for nodes already running it which want
+//! allocation faster than the filesystem and replication for free.
+//!
+//! A network keeps two hashes, `<prefix>:{<network>}:ips` mapping reserved
+//! IPs to their owners as JSON and `<prefix>:{<network>}:last_reserved`
+//! mapping range sets to their last reserved IP, and the key
+//! `<prefix>:{<network>}:lock` while it is locked. The braces put all of
+//! them into the same slot of a Redis Cluster. Transactions run as a Lua
+//! script, which Redis executes atomically: reservations are only written
+//! if none of their IPs is taken, together with the last reserved IPs.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::filestore::validate_network_name;
+use super::{locked, Operation, Owner, StatsCounter, Store, StoreError, StoreStats, Transaction};
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:6379";
+pub const DEFAULT_PREFIX: &str = "host-local";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Applies the operations passed as triples of ARGV, all of them or none
+/// if one of the IPs to reserve is taken. Returns 1 if they were applied.
+const COMMIT_SCRIPT: &str = r#"
+local seen = {}
+for i = 1, #ARGV, 3 do
+    if ARGV[i] == 'reserve' then
+        if seen[ARGV[i + 1]] or redis.call('HEXISTS', KEYS[1], ARGV[i + 1]) == 1 then
+            return 0
+        end
+        seen[ARGV[i + 1]] = true
+    end
+end
+for i = 1, #ARGV, 3 do
+    if ARGV[i] == 'reserve' then
+        redis.call('HSET', KEYS[1], ARGV[i + 1], ARGV[i + 2])
+    elseif ARGV[i] == 'release' then
+        redis.call('HDEL', KEYS[1], ARGV[i + 1])
+    else
+        redis.call('HSET', KEYS[2], ARGV[i + 1], ARGV[i + 2])
+    end
+end
+return 1
+"#;
+
+/// Deletes the lock only if this store still holds it, it may have expired
+/// and been taken by another node meanwhile.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+#[derive(Clone, Debug)]
+pub struct RedisOptions {
+    /// `host:port` of the Redis server.
+    pub address: String,
+    /// Prepended to the keys of every network.
+    pub prefix: String,
+    /// Sent with `AUTH` after connecting.
+    pub password: Option<String>,
+    /// Database selected after connecting.
+    pub database: u32,
+    /// How long the lock outlives a node which crashed while holding it.
+    pub lock_ttl: Duration,
+    /// How long `lock` waits for another node to release the lock before
+    /// failing with `StoreError::LockTimeout`. Waits as long as it takes if
+    /// unset.
+    pub lock_timeout: Option<Duration>,
+}
+
+impl Default for RedisOptions {
+    fn default() -> Self {
+        RedisOptions {
+            address: DEFAULT_ADDRESS.to_owned(),
+            prefix: DEFAULT_PREFIX.to_owned(),
+            password: None,
+            database: 0,
+            lock_ttl: Duration::from_secs(15),
+            lock_timeout: None,
+        }
+    }
+}
+
+pub struct RedisStore {
+    network: String,
+    options: RedisOptions,
+    /// Opened by the first command and kept for the following ones.
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+    /// Value of the lock key while this store holds it.
+    lock_token: Mutex<Option<String>>,
+    stats: StatsCounter,
+}
+
+/// A reply in the Redis serialization protocol.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl RedisStore {
+    pub fn new(network: &str, options: RedisOptions) -> Result<RedisStore, StoreError> {
+        validate_network_name(network)?;
+
+        Ok(RedisStore {
+            network: network.to_owned(),
+            options: options,
+            connection: Mutex::new(None),
+            lock_token: Mutex::new(None),
+            stats: StatsCounter::new(),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{{{}}}:{}", self.options.prefix, self.network, name)
+    }
+
+    /// Runs a command, any error reply becomes an error.
+    fn command(&self, args: &[&[u8]]) -> Result<Reply, StoreError> {
+        let mut connection = locked(&self.connection);
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+
+        // UNWRAP: connected above
+        let reply = send(connection.as_mut().unwrap(), args);
+        if reply.is_err() {
+            // the connection may be left in the middle of a reply
+            *connection = None;
+        }
+
+        match reply? {
+            Reply::Error(message) => Err(StoreError::IOError(redis_error(message))),
+            reply => Ok(reply),
+        }
+    }
+
+    fn connect(&self) -> Result<BufReader<TcpStream>, IoError> {
+        let addr = self
+            .options
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| IoError::new(ErrorKind::NotFound, "redis address not found"))?;
+        let stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut connection = BufReader::new(stream);
+
+        let database = self.options.database.to_string();
+        let mut setup: Vec<Vec<&[u8]>> = Vec::new();
+        if let Some(password) = &self.options.password {
+            setup.push(vec![b"AUTH", password.as_bytes()]);
+        }
+        if self.options.database != 0 {
+            setup.push(vec![b"SELECT", database.as_bytes()]);
+        }
+        for args in setup {
+            if let Reply::Error(message) = send(&mut connection, &args)? {
+                return Err(redis_error(message));
+            }
+        }
+
+        Ok(connection)
+    }
+
+    fn eval(&self, script: &str, keys: &[String], args: &[Vec<u8>]) -> Result<Reply, StoreError> {
+        let key_count = keys.len().to_string();
+        let mut command: Vec<&[u8]> = vec![b"EVAL", script.as_bytes(), key_count.as_bytes()];
+        command.extend(keys.iter().map(|key| key.as_bytes()));
+        command.extend(args.iter().map(Vec::as_slice));
+
+        self.command(&command)
+    }
+
+    /// Reservations of the network with their owners.
+    fn reservations(&self) -> Result<Vec<(IpAddr, Owner)>, StoreError> {
+        let key = self.key("ips");
+        let fields = match self.command(&[b"HGETALL", key.as_bytes()])? {
+            Reply::Array(Some(fields)) => fields,
+            reply => return Err(unexpected(reply)),
+        };
+
+        let mut reservations = Vec::with_capacity(fields.len() / 2);
+        let mut fields = fields.into_iter();
+        while let (Some(Reply::Bulk(Some(ip))), Some(Reply::Bulk(Some(owner)))) =
+            (fields.next(), fields.next())
+        {
+            let ip = String::from_utf8_lossy(&ip)
+                .parse()
+                .map_err(|err| corrupt(&key, err))?;
+            let owner = serde_json::from_slice(&owner).map_err(|err| corrupt(&key, err))?;
+            reservations.push((ip, owner));
+        }
+
+        Ok(reservations)
+    }
+
+    fn try_acquire(&self, token: &str) -> Result<bool, StoreError> {
+        let key = self.key("lock");
+        let ttl = self.options.lock_ttl.as_millis().max(1).to_string();
+        let reply = self.command(&[
+            b"SET",
+            key.as_bytes(),
+            token.as_bytes(),
+            b"NX",
+            b"PX",
+            ttl.as_bytes(),
+        ])?;
+
+        match reply {
+            Reply::Status(_) => Ok(true),
+            Reply::Bulk(None) => Ok(false),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// `Store::lock` without counting, see `StatsCounter::lock`.
+    fn lock_key(&self) -> Result<(), StoreError> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let token = format!("{}-{}", process::id(), nanos);
+
+        let start = Instant::now();
+        let mut interval = Duration::from_millis(1);
+        while !self.try_acquire(&token)? {
+            let elapsed = start.elapsed();
+            if let Some(timeout) = self.options.lock_timeout {
+                if elapsed >= timeout {
+                    return Err(StoreError::LockTimeout {
+                        path: PathBuf::from(self.key("lock")),
+                        holder: None,
+                        timeout: timeout,
+                    });
+                }
+                interval = interval.min(timeout - elapsed);
+            }
+
+            thread::sleep(interval);
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+        }
+
+        *locked(&self.lock_token) = Some(token);
+        Ok(())
+    }
+
+    /// `Store::commit` without counting.
+    fn commit_txn(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        if txn.is_empty() {
+            return Ok(true);
+        }
+
+        let mut args = Vec::new();
+        for operation in txn.operations() {
+            let (verb, field, value) = match operation {
+                Operation::Reserve { owner, ip } => (
+                    "reserve",
+                    ip.to_string(),
+                    serde_json::to_vec(owner).map_err(IoError::from)?,
+                ),
+                Operation::Release(ip) => ("release", ip.to_string(), Vec::new()),
+                Operation::RecordLastReserved { ip, range_id } => (
+                    "last_reserved",
+                    range_id.clone(),
+                    ip.to_string().into_bytes(),
+                ),
+            };
+            args.extend(vec![verb.as_bytes().to_vec(), field.into_bytes(), value]);
+        }
+
+        let keys = [self.key("ips"), self.key("last_reserved")];
+        match self.eval(COMMIT_SCRIPT, &keys, &args)? {
+            Reply::Integer(applied) => Ok(applied == 1),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// `Store::release` without counting.
+    fn release_ip(&self, ip: IpAddr) -> Result<(), StoreError> {
+        let key = self.key("ips");
+        let ip_str = ip.to_string();
+        match self.command(&[b"HDEL", key.as_bytes(), ip_str.as_bytes()])? {
+            Reply::Integer(0) => Err(StoreError::NotFound(ip)),
+            Reply::Integer(_) => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// `Store::release_by_id` without counting.
+    fn release_ips_of(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        let ips: Vec<String> = self
+            .reservations()?
+            .into_iter()
+            .filter(|(_, owner)| owner.id == id && owner.ifname == ifname)
+            .map(|(ip, _)| ip.to_string())
+            .collect();
+        if ips.is_empty() {
+            return Ok(());
+        }
+
+        let key = self.key("ips");
+        let mut command: Vec<&[u8]> = vec![b"HDEL", key.as_bytes()];
+        command.extend(ips.iter().map(|ip| ip.as_bytes()));
+        self.command(&command).map(|_| ())
+    }
+}
+
+impl Store for RedisStore {
+    fn lock(&self) -> Result<(), StoreError> {
+        self.stats.lock(|| self.lock_key())
+    }
+
+    fn unlock(&self) -> Result<(), StoreError> {
+        let token = match locked(&self.lock_token).take() {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        self.eval(UNLOCK_SCRIPT, &[self.key("lock")], &[token.into_bytes()])
+            .map(|_| ())
+    }
+
+    fn close(&self) -> Result<(), StoreError> {
+        let result = self.unlock();
+        *locked(&self.connection) = None;
+        result
+    }
+
+    fn commit(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        self.stats.commit(txn, self.commit_txn(txn))
+    }
+
+    fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError> {
+        let key = self.key("last_reserved");
+        match self.command(&[b"HGET", key.as_bytes(), range_id.as_bytes()])? {
+            Reply::Bulk(Some(ip)) => String::from_utf8_lossy(&ip)
+                .parse()
+                .map_err(|err| corrupt(&key, err)),
+            Reply::Bulk(None) => Err(StoreError::LastReservedNotFound(range_id.to_owned())),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    fn last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+        let key = self.key("last_reserved");
+        let fields = match self.command(&[b"HGETALL", key.as_bytes()])? {
+            Reply::Array(Some(fields)) => fields,
+            reply => return Err(unexpected(reply)),
+        };
+
+        let mut ips = BTreeMap::new();
+        let mut fields = fields.into_iter();
+        while let (Some(Reply::Bulk(Some(range_id))), Some(Reply::Bulk(Some(ip)))) =
+            (fields.next(), fields.next())
+        {
+            let ip = String::from_utf8_lossy(&ip)
+                .parse()
+                .map_err(|err| corrupt(&key, err))?;
+            ips.insert(String::from_utf8_lossy(&range_id).into_owned(), ip);
+        }
+
+        Ok(ips)
+    }
+
+    fn release(&self, ip: IpAddr) -> Result<(), StoreError> {
+        self.stats.release(self.release_ip(ip))
+    }
+
+    fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        self.stats.release(self.release_ips_of(id, ifname))
+    }
+
+    fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr> {
+        self.reservations()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, owner)| owner.id == id && owner.ifname == ifname)
+            .map(|(ip, _)| ip)
+            .collect()
+    }
+
+    fn owner(&self, ip: IpAddr) -> Result<Owner, StoreError> {
+        let key = self.key("ips");
+        let ip_str = ip.to_string();
+        match self.command(&[b"HGET", key.as_bytes(), ip_str.as_bytes()])? {
+            Reply::Bulk(Some(owner)) => {
+                serde_json::from_slice(&owner).map_err(|err| corrupt(&key, err))
+            }
+            Reply::Bulk(None) => Err(StoreError::NotFound(ip)),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<IpAddr>, StoreError> {
+        Ok(self.reservations()?.into_iter().map(|(ip, _)| ip).collect())
+    }
+
+    fn stats(&self) -> Option<StoreStats> {
+        Some(self.stats.get())
+    }
+}
+
+/// Writes `args` as a command and reads its reply.
+fn send<S: Read + Write>(connection: &mut BufReader<S>, args: &[&[u8]]) -> Result<Reply, IoError> {
+    connection.get_mut().write_all(&encode(args))?;
+    read_reply(connection)
+}
+
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend(*arg);
+        command.extend(b"\r\n");
+    }
+
+    command
+}
+
+fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, IoError> {
+    let invalid = |what: &str| IoError::new(ErrorKind::InvalidData, format!("malformed {}", what));
+
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(IoError::from(ErrorKind::UnexpectedEof));
+    }
+    let line = line.trim_end_matches("\r\n");
+    if line.is_empty() {
+        return Err(invalid("reply"));
+    }
+
+    let (kind, rest) = line.split_at(1);
+    let length = || rest.parse::<i64>().map_err(|_| invalid("length"));
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_owned())),
+        "-" => Ok(Reply::Error(rest.to_owned())),
+        ":" => Ok(Reply::Integer(length()?)),
+        "$" => {
+            let length = length()?;
+            if length < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+
+            let mut bulk = vec![0; length as usize + 2];
+            reader.read_exact(&mut bulk)?;
+            bulk.truncate(length as usize);
+            Ok(Reply::Bulk(Some(bulk)))
+        }
+        "*" => {
+            let length = length()?;
+            if length < 0 {
+                return Ok(Reply::Array(None));
+            }
+
+            let items = (0..length)
+                .map(|_| read_reply(reader))
+                .collect::<Result<_, _>>()?;
+            Ok(Reply::Array(Some(items)))
+        }
+        _ => Err(invalid("reply")),
+    }
+}
+
+fn redis_error(message: String) -> IoError {
+    IoError::other(format!("redis: {}", message))
+}
+
+fn unexpected(reply: Reply) -> StoreError {
+    StoreError::IOError(IoError::new(
+        ErrorKind::InvalidData,
+        format!("unexpected reply from redis: {:?}", reply),
+    ))
+}
+
+fn corrupt<E: std::fmt::Display>(key: &str, err: E) -> StoreError {
+    StoreError::Corrupt {
+        path: PathBuf::from(key),
+        reason: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol() {
+        assert_eq!(
+            encode(&[b"HGET", b"ips", b"10.1.2.3"]),
+            b"*3\r\n$4\r\nHGET\r\n$3\r\nips\r\n$8\r\n10.1.2.3\r\n".to_vec()
+        );
+
+        let mut replies: &[u8] =
+            b"+OK\r\n-ERR wrong\r\n:1\r\n$-1\r\n$5\r\na\r\nbc\r\n*2\r\n$1\r\nx\r\n:2\r\n*-1\r\n";
+        let mut replies = BufReader::new(&mut replies);
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Status("OK".to_owned())
+        );
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Error("ERR wrong".to_owned())
+        );
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Integer(1));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(None));
+        // bulk strings may hold line breaks
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Bulk(Some(b"a\r\nbc".to_vec()))
+        );
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Array(Some(vec![
+                Reply::Bulk(Some(b"x".to_vec())),
+                Reply::Integer(2)
+            ]))
+        );
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Array(None));
+        assert_eq!(
+            read_reply(&mut replies).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let store = RedisStore::new("net1", RedisOptions::default()).unwrap();
+        assert_eq!(store.key("ips"), "host-local:{net1}:ips");
+        assert!(matches!(
+            RedisStore::new("a/b", RedisOptions::default()),
+            Err(StoreError::InvalidName(_))
+        ));
+    }
+}
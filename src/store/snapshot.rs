@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Owner, Store, StoreError, Transaction};
+use crate::allocator::rangeset::RangeSet;
+
+/// Version of the snapshot format written by `Store::export`. Bumped
+/// whenever a snapshot couldn't be read by an older `Store::import`.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The complete state of a network's store, for backups and for seeding
+/// test environments, see `Store::export` and `Store::import`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub version: u32,
+    /// Network the snapshot was taken of, set by the caller as stores don't
+    /// know their network name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+    /// Hash of the configuration the snapshot was taken with, set by the
+    /// caller, so a restore can tell whether the ranges still match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+    /// Reservations ordered by IP.
+    pub reservations: Vec<Reservation>,
+    /// Last reserved IP of every range set id.
+    #[serde(default)]
+    pub last_reserved: BTreeMap<String, IpAddr>,
+}
+
+/// A reserved IP and who holds it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Reservation {
+    pub ip: IpAddr,
+    #[serde(flatten)]
+    pub owner: Owner,
+}
+
+/// What `Snapshot::restore` does with a reservation whose IP is already
+/// reserved for another container.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Keeps the reservation of the store.
+    Skip,
+    /// Replaces it with the reservation of the snapshot.
+    Overwrite,
+    /// Restores nothing at all.
+    Fail,
+}
+
+/// What became of a reservation of the snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Imported,
+    /// The store holds the same reservation already.
+    Unchanged,
+    Skipped,
+    Overwritten,
+    /// The IP lies outside of every range set, it is left out.
+    OutOfRange,
+    /// The IP is reserved for another container and the policy is `Fail`.
+    Conflict,
+}
+
+/// The outcome of restoring a single reservation.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Restored {
+    pub ip: IpAddr,
+    pub id: String,
+    pub ifname: String,
+    pub outcome: Outcome,
+}
+
+/// The result of `Snapshot::restore`. Unless `applied`, the store wasn't
+/// changed and the outcomes are what would have happened.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Restore {
+    pub applied: bool,
+    pub entries: Vec<Restored>,
+}
+
+impl Snapshot {
+    /// Reads everything `store` holds. The store should be locked, or at
+    /// least not written to, so the snapshot is consistent.
+    pub fn of<S: Store + ?Sized>(store: &S) -> Result<Snapshot, StoreError> {
+        let mut ips = store.list()?;
+        ips.sort();
+
+        let mut reservations = Vec::with_capacity(ips.len());
+        for ip in ips {
+            reservations.push(Reservation {
+                ip: ip,
+                owner: store.owner(ip)?,
+            });
+        }
+
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            network: None,
+            config_hash: None,
+            reservations: reservations,
+            last_reserved: store.last_reserved_ips()?,
+        })
+    }
+
+    /// The transaction recreating the snapshot in a store.
+    pub fn transaction(&self) -> Result<Transaction, StoreError> {
+        if self.version > SNAPSHOT_VERSION {
+            return Err(StoreError::UnsupportedSnapshot(self.version));
+        }
+
+        let mut txn = Transaction::new();
+        for reservation in &self.reservations {
+            txn.reserve_for(reservation.owner.clone(), reservation.ip);
+        }
+        for (range_id, ip) in &self.last_reserved {
+            txn.record_last_reserved(*ip, range_id);
+        }
+
+        Ok(txn)
+    }
+
+    /// Repopulates `store` from the snapshot, leaving out reservations and
+    /// last reserved IPs outside of `range_sets`, with reservations of
+    /// other containers handled by `policy`. The store must be locked.
+    ///
+    /// New reservations and last reserved IPs are committed as one
+    /// transaction, together with the releases of the reservations replaced
+    /// by `Overwrite`, so a failed commit leaves them with their owners.
+    pub fn restore<S: Store + ?Sized>(
+        &self,
+        store: &S,
+        range_sets: &[RangeSet],
+        policy: ConflictPolicy,
+    ) -> Result<Restore, StoreError> {
+        if self.version > SNAPSHOT_VERSION {
+            return Err(StoreError::UnsupportedSnapshot(self.version));
+        }
+        let in_range = |ip: IpAddr| range_sets.iter().any(|range_set| range_set.contains(ip));
+
+        let mut entries = Vec::with_capacity(self.reservations.len());
+        let mut txn = Transaction::new();
+        for reservation in &self.reservations {
+            let ip = reservation.ip;
+            let owner = &reservation.owner;
+
+            let outcome = if !in_range(ip) {
+                Outcome::OutOfRange
+            } else {
+                match store.owner(ip) {
+                    Err(StoreError::NotFound(_)) => {
+                        txn.reserve_for(owner.clone(), ip);
+                        Outcome::Imported
+                    }
+                    Err(err) => return Err(err),
+                    Ok(current) if current.id == owner.id && current.ifname == owner.ifname => {
+                        Outcome::Unchanged
+                    }
+                    Ok(_) => match policy {
+                        ConflictPolicy::Skip => Outcome::Skipped,
+                        ConflictPolicy::Overwrite => {
+                            txn.release(ip).reserve_for(owner.clone(), ip);
+                            Outcome::Overwritten
+                        }
+                        ConflictPolicy::Fail => Outcome::Conflict,
+                    },
+                }
+            };
+
+            entries.push(Restored {
+                ip: ip,
+                id: owner.id.clone(),
+                ifname: owner.ifname.clone(),
+                outcome: outcome,
+            });
+        }
+
+        if entries
+            .iter()
+            .any(|entry| entry.outcome == Outcome::Conflict)
+        {
+            return Ok(Restore {
+                applied: false,
+                entries: entries,
+            });
+        }
+
+        for (range_id, ip) in &self.last_reserved {
+            if in_range(*ip) {
+                txn.record_last_reserved(*ip, range_id);
+            }
+        }
+        Ok(Restore {
+            applied: store.commit(&txn)?,
+            entries: entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::faultstore::{FaultStore, Op};
+    use crate::store::filestore::FileStore;
+    use crate::store::{Labels, Pod};
+    use std::fs::remove_dir_all;
+
+    const DATA_DIR: &str = "/tmp/cni-snapshot";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn export_and_import() {
+        let _ = remove_dir_all(DATA_DIR);
+
+        let store = FileStore::new("source", DATA_DIR).unwrap();
+        let pod = Owner {
+            id: "c2".to_owned(),
+            ifname: "eth0".to_owned(),
+            netns: Some("/var/run/netns/c2".to_owned()),
+            pod: Some(Pod {
+                namespace: "default".to_owned(),
+                name: "web-0".to_owned(),
+                uid: None,
+            }),
+            labels: Labels::new(),
+        };
+        let mut txn = Transaction::new();
+        txn.reserve("c1", "eth0", ip("10.1.2.3"))
+            .reserve_for(pod.clone(), ip("10.1.2.2"))
+            .record_last_reserved(ip("10.1.2.3"), "0")
+            .reserve("c1", "eth1", ip("2001:db8::2"))
+            .record_last_reserved(ip("2001:db8::2"), "1");
+        assert!(store.commit(&txn).unwrap());
+
+        let snapshot = store.export().unwrap();
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        assert_eq!(
+            snapshot
+                .reservations
+                .iter()
+                .map(|reservation| reservation.ip)
+                .collect::<Vec<_>>(),
+            vec![ip("10.1.2.2"), ip("10.1.2.3"), ip("2001:db8::2")]
+        );
+        assert_eq!(snapshot.reservations[0].owner, pod);
+        assert_eq!(snapshot.last_reserved["1"], ip("2001:db8::2"));
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+
+        let restored = FileStore::new("restored", DATA_DIR).unwrap();
+        assert!(restored.import(&snapshot).unwrap());
+        assert_eq!(restored.export().unwrap(), snapshot);
+        assert_eq!(restored.last_reserved_ip("0").unwrap(), ip("10.1.2.3"));
+        // all or nothing once the IPs are taken
+        assert!(!restored.import(&snapshot).unwrap());
+
+        let newer = Snapshot {
+            version: SNAPSHOT_VERSION + 1,
+            ..snapshot
+        };
+        assert!(matches!(
+            restored.import(&newer),
+            Err(StoreError::UnsupportedSnapshot(_))
+        ));
+
+        let _ = remove_dir_all(DATA_DIR);
+    }
+
+    #[test]
+    fn restore_with_policies() {
+        let dir = format!("{}-restore", DATA_DIR);
+        let _ = remove_dir_all(&dir);
+
+        let range_sets = ["10.1.2.0/24".parse::<RangeSet>().unwrap()];
+
+        let source = FileStore::new("source", &dir).unwrap();
+        let mut txn = Transaction::new();
+        txn.reserve("c1", "eth0", ip("10.1.2.2"))
+            .reserve("c2", "eth0", ip("10.1.2.3"))
+            .reserve("c3", "eth0", ip("10.1.3.3"))
+            .record_last_reserved(ip("10.1.2.3"), "0")
+            .record_last_reserved(ip("10.1.3.3"), "1");
+        assert!(source.commit(&txn).unwrap());
+        let snapshot = source.export().unwrap();
+
+        let store = FileStore::new("target", &dir).unwrap();
+        store.reserve("c1", "eth0", ip("10.1.2.2"), "0").unwrap();
+        store.reserve("other", "eth0", ip("10.1.2.3"), "0").unwrap();
+        let outcomes = |restore: &Restore| {
+            restore
+                .entries
+                .iter()
+                .map(|entry| entry.outcome)
+                .collect::<Vec<_>>()
+        };
+
+        let restore = snapshot
+            .restore(&store, &range_sets, ConflictPolicy::Fail)
+            .unwrap();
+        assert!(!restore.applied);
+        assert_eq!(
+            outcomes(&restore),
+            vec![Outcome::Unchanged, Outcome::Conflict, Outcome::OutOfRange]
+        );
+        assert_eq!(store.owner(ip("10.1.2.3")).unwrap().id, "other");
+
+        let restore = snapshot
+            .restore(&store, &range_sets, ConflictPolicy::Skip)
+            .unwrap();
+        assert!(restore.applied);
+        assert_eq!(outcomes(&restore)[1], Outcome::Skipped);
+        assert_eq!(store.owner(ip("10.1.2.3")).unwrap().id, "other");
+
+        let restore = snapshot
+            .restore(&store, &range_sets, ConflictPolicy::Overwrite)
+            .unwrap();
+        assert!(restore.applied);
+        assert_eq!(outcomes(&restore)[1], Outcome::Overwritten);
+        assert_eq!(store.owner(ip("10.1.2.3")).unwrap().id, "c2");
+        assert!(matches!(
+            store.owner(ip("10.1.3.3")),
+            Err(StoreError::NotFound(_))
+        ));
+        assert_eq!(
+            store
+                .last_reserved_ips()
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![("0".to_owned(), ip("10.1.2.3"))]
+        );
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn overwrite_is_one_transaction() {
+        let dir = format!("{}-overwrite", DATA_DIR);
+        let _ = remove_dir_all(&dir);
+
+        let range_sets = ["10.1.2.0/24".parse::<RangeSet>().unwrap()];
+        let source = FileStore::new("source", &dir).unwrap();
+        source.reserve("c1", "eth0", ip("10.1.2.2"), "0").unwrap();
+        let snapshot = source.export().unwrap();
+
+        let store = FaultStore::new(FileStore::new("target", &dir).unwrap()).fail_on(Op::Commit);
+        store
+            .inner()
+            .reserve("other", "eth0", ip("10.1.2.2"), "0")
+            .unwrap();
+        assert!(snapshot
+            .restore(&store, &range_sets, ConflictPolicy::Overwrite)
+            .is_err());
+        assert_eq!(store.inner().owner(ip("10.1.2.2")).unwrap().id, "other");
+
+        let restore = snapshot
+            .restore(store.inner(), &range_sets, ConflictPolicy::Overwrite)
+            .unwrap();
+        assert!(restore.applied);
+        assert_eq!(store.inner().owner(ip("10.1.2.2")).unwrap().id, "c1");
+
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use super::{locked, Operation, StoreError, Transaction};
+
+/// Counters of the operations of a store, to tune a backend by, e.g. how
+/// long commands wait for locks or how often reservations collide.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    /// IPs reserved by committed transactions or claimed by the store.
+    pub reserves: u64,
+    /// Transactions not committed because an IP was already reserved.
+    pub conflicts: u64,
+    /// Successful `release` and `release_by_id` calls.
+    pub releases: u64,
+    /// Operations failed with `StoreError::IOError`.
+    pub io_errors: u64,
+    /// Nanoseconds spent in `lock` and `lock_range`.
+    pub lock_wait_ns: u64,
+}
+
+impl StoreStats {
+    pub fn add(&mut self, other: &StoreStats) {
+        self.reserves += other.reserves;
+        self.conflicts += other.conflicts;
+        self.releases += other.releases;
+        self.io_errors += other.io_errors;
+        self.lock_wait_ns += other.lock_wait_ns;
+    }
+}
+
+/// Where a backend keeps its `StoreStats`, updated from the results of its
+/// operations.
+#[derive(Debug, Default)]
+pub struct StatsCounter(Mutex<StoreStats>);
+
+impl StatsCounter {
+    pub fn new() -> StatsCounter {
+        StatsCounter::default()
+    }
+
+    pub fn get(&self) -> StoreStats {
+        *locked(&self.0)
+    }
+
+    fn update<F: FnOnce(&mut StoreStats)>(&self, update: F) {
+        update(&mut locked(&self.0));
+    }
+
+    /// Runs `lock`, counting the time it takes.
+    pub fn lock<F>(&self, lock: F) -> Result<(), StoreError>
+    where
+        F: FnOnce() -> Result<(), StoreError>,
+    {
+        let start = Instant::now();
+        let result = self.result(lock());
+        let waited = start.elapsed().as_nanos() as u64;
+        self.update(|stats| stats.lock_wait_ns += waited);
+
+        result
+    }
+
+    /// Counts the outcome of committing `txn`.
+    pub fn commit(
+        &self,
+        txn: &Transaction,
+        result: Result<bool, StoreError>,
+    ) -> Result<bool, StoreError> {
+        match result {
+            Ok(true) => {
+                let reserves = txn
+                    .operations()
+                    .iter()
+                    .filter(|operation| matches!(operation, Operation::Reserve { .. }))
+                    .count() as u64;
+                self.update(|stats| stats.reserves += reserves);
+            }
+            Ok(false) => self.update(|stats| stats.conflicts += 1),
+            Err(_) => {}
+        }
+
+        self.result(result)
+    }
+
+    /// Counts `ips` reserved outside of transactions, e.g. claimed by a
+    /// database picking them itself.
+    pub fn reserved(&self, ips: u64) {
+        self.update(|stats| stats.reserves += ips);
+    }
+
+    /// Counts the outcome of a release.
+    pub fn release(&self, result: Result<(), StoreError>) -> Result<(), StoreError> {
+        if result.is_ok() {
+            self.update(|stats| stats.releases += 1);
+        }
+
+        self.result(result)
+    }
+
+    /// Counts `result` if it is an I/O error.
+    pub fn result<T>(&self, result: Result<T, StoreError>) -> Result<T, StoreError> {
+        if let Err(StoreError::IOError(_)) = &result {
+            self.update(|stats| stats.io_errors += 1);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Error as IoError;
+
+    #[test]
+    fn counts() {
+        let counter = StatsCounter::new();
+        let mut txn = Transaction::new();
+        let ip = "10.1.2.2".parse().unwrap();
+        txn.reserve("c1", "eth0", ip)
+            .reserve("c1", "eth1", "10.1.2.3".parse().unwrap())
+            .record_last_reserved(ip, "0");
+
+        assert!(counter.commit(&txn, Ok(true)).unwrap());
+        assert!(!counter.commit(&txn, Ok(false)).unwrap());
+        counter.release(Ok(())).unwrap();
+        let io_error = || StoreError::IOError(IoError::other("disk full"));
+        assert!(counter.release(Err(io_error())).is_err());
+        assert!(counter.result::<()>(Err(StoreError::NotFound(ip))).is_err());
+        assert!(counter.lock(|| Err(io_error())).is_err());
+
+        let stats = counter.get();
+        assert_eq!(
+            (
+                stats.reserves,
+                stats.conflicts,
+                stats.releases,
+                stats.io_errors
+            ),
+            (2, 1, 1, 2)
+        );
+
+        let mut total = stats;
+        total.add(&stats);
+        assert_eq!(total.reserves, 4);
+        assert_eq!(total.lock_wait_ns, stats.lock_wait_ns * 2);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::{locked, Owner, Store, StoreError, StoreStats, Transaction};
+use crate::config::LogLevel;
+use crate::error::report;
+
+/// A change of the primary store replayed on the mirror.
+enum Mirrored {
+    Commit(Transaction),
+    Release(IpAddr),
+    ReleaseById(String, String),
+    Touch(IpAddr, String, String),
+}
+
+/// Writes through to a primary store and mirrors every successful change to
+/// a secondary one in the background, e.g. files as the source of truth and
+/// a shared database for central visibility. Everything is read from and
+/// locked on the primary. The mirror can't fail an operation: its failures
+/// are logged and counted, see `mirror_failures`.
+///
+/// The secondary store is moved to the thread mirroring the changes, which
+/// every `Store` being `Send` allows. Changes still queued are mirrored by
+/// `close` or when the store is dropped, both wait for the mirror to catch
+/// up.
+pub struct TeeStore<P: Store> {
+    primary: P,
+    sender: Mutex<Option<Sender<Mirrored>>>,
+    mirror: Mutex<Option<JoinHandle<()>>>,
+    failures: Arc<AtomicUsize>,
+}
+
+impl<P: Store> TeeStore<P> {
+    pub fn new<S: Store + 'static>(primary: P, secondary: S) -> TeeStore<P> {
+        TeeStore::with_log_level(primary, secondary, LogLevel::default())
+    }
+
+    /// Like `new`, with mirror failures logged at `log_level`.
+    pub fn with_log_level<S: Store + 'static>(
+        primary: P,
+        secondary: S,
+        log_level: LogLevel,
+    ) -> TeeStore<P> {
+        let (sender, receiver) = mpsc::channel();
+        let failures = Arc::new(AtomicUsize::new(0));
+
+        let mirror_failures = failures.clone();
+        let mirror = thread::spawn(move || {
+            for change in receiver {
+                if let Err(err) = mirror(&secondary, change) {
+                    mirror_failures.fetch_add(1, Ordering::Relaxed);
+                    log_level.warn(format_args!(
+                        "failed to mirror store change: {}",
+                        report(&err)
+                    ));
+                }
+            }
+        });
+
+        TeeStore {
+            primary: primary,
+            sender: Mutex::new(Some(sender)),
+            mirror: Mutex::new(Some(mirror)),
+            failures: failures,
+        }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Number of changes which couldn't be mirrored so far.
+    pub fn mirror_failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, change: Mirrored) {
+        if let Some(sender) = &*locked(&self.sender) {
+            // only fails if the mirror thread panicked, which the join in
+            // `finish` reports
+            let _ = sender.send(change);
+        }
+    }
+
+    /// Waits until every queued change is mirrored and stops the mirror.
+    fn finish(&self) {
+        locked(&self.sender).take();
+        let mirror = locked(&self.mirror).take();
+        if let Some(mirror) = mirror {
+            if mirror.join().is_err() {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Applies `change` to `secondary` under its lock.
+fn mirror<S: Store>(secondary: &S, change: Mirrored) -> Result<(), StoreError> {
+    secondary.lock()?;
+    let result = match change {
+        Mirrored::Commit(txn) => match secondary.commit(&txn) {
+            // the mirror went out of sync, e.g. it missed a release
+            Ok(false) => Err(StoreError::IOError(IoError::new(
+                ErrorKind::AlreadyExists,
+                "an IP of the transaction is already reserved on the mirror",
+            ))),
+            result => result.map(|_| ()),
+        },
+        Mirrored::Release(ip) => secondary.release(ip),
+        Mirrored::ReleaseById(id, ifname) => secondary.release_by_id(&id, &ifname),
+        Mirrored::Touch(ip, id, ifname) => secondary.touch(ip, &id, &ifname),
+    };
+    let unlocked = secondary.unlock();
+
+    result.and(unlocked)
+}
+
+impl<P: Store> Drop for TeeStore<P> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl<P: Store> Store for TeeStore<P> {
+    fn lock(&self) -> Result<(), StoreError> {
+        self.primary.lock()
+    }
+
+    fn unlock(&self) -> Result<(), StoreError> {
+        self.primary.unlock()
+    }
+
+    fn lock_range(&self, range_id: &str) -> Result<(), StoreError> {
+        self.primary.lock_range(range_id)
+    }
+
+    fn unlock_range(&self, range_id: &str) -> Result<(), StoreError> {
+        self.primary.unlock_range(range_id)
+    }
+
+    fn close(&self) -> Result<(), StoreError> {
+        let result = self.primary.close();
+        self.finish();
+        result
+    }
+
+    fn commit(&self, txn: &Transaction) -> Result<bool, StoreError> {
+        let committed = self.primary.commit(txn)?;
+        if committed {
+            self.send(Mirrored::Commit(txn.clone()));
+        }
+
+        Ok(committed)
+    }
+
+    fn last_reserved_ip(&self, range_id: &str) -> Result<IpAddr, StoreError> {
+        self.primary.last_reserved_ip(range_id)
+    }
+
+    fn last_reserved_ips(&self) -> Result<BTreeMap<String, IpAddr>, StoreError> {
+        self.primary.last_reserved_ips()
+    }
+
+    fn release(&self, ip: IpAddr) -> Result<(), StoreError> {
+        self.primary.release(ip)?;
+        self.send(Mirrored::Release(ip));
+        Ok(())
+    }
+
+    fn release_by_id(&self, id: &str, ifname: &str) -> Result<(), StoreError> {
+        self.primary.release_by_id(id, ifname)?;
+        self.send(Mirrored::ReleaseById(id.to_owned(), ifname.to_owned()));
+        Ok(())
+    }
+
+    fn touch(&self, ip: IpAddr, id: &str, ifname: &str) -> Result<(), StoreError> {
+        self.primary.touch(ip, id, ifname)?;
+        self.send(Mirrored::Touch(ip, id.to_owned(), ifname.to_owned()));
+        Ok(())
+    }
+
+    fn get_by_id(&self, id: &str, ifname: &str) -> Vec<IpAddr> {
+        self.primary.get_by_id(id, ifname)
+    }
+
+    fn owner(&self, ip: IpAddr) -> Result<Owner, StoreError> {
+        self.primary.owner(ip)
+    }
+
+    fn list(&self) -> Result<Vec<IpAddr>, StoreError> {
+        self.primary.list()
+    }
+
+    /// The counters of the primary, the mirror isn't waited for.
+    fn stats(&self) -> Option<StoreStats> {
+        self.primary.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::filestore::FileStore;
+    use std::fs::remove_dir_all;
+
+    const DATA_DIR: &str = "/tmp/cni-tee";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn store(dir: &str, read_only: bool) -> FileStore {
+        FileStore::builder("n")
+            .data_dir(&format!("{}/{}", DATA_DIR, dir))
+            .read_only(read_only)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn mirrors() {
+        let dir = format!("{}/mirrors", DATA_DIR);
+        let _ = remove_dir_all(&dir);
+
+        let tee = TeeStore::with_log_level(
+            store("mirrors/primary", false),
+            store("mirrors/secondary", false),
+            LogLevel::Error,
+        );
+        tee.lock().unwrap();
+        assert!(tee.reserve("c1", "eth0", ip("10.1.2.2"), "0").unwrap());
+        assert!(tee.reserve("c2", "eth0", ip("10.1.2.3"), "0").unwrap());
+        assert!(!tee.reserve("c3", "eth0", ip("10.1.2.3"), "0").unwrap());
+        tee.release(ip("10.1.2.2")).unwrap();
+        tee.unlock().unwrap();
+        tee.close().unwrap();
+
+        let secondary = store("mirrors/secondary", false);
+        assert_eq!(secondary.list().unwrap(), vec![ip("10.1.2.3")]);
+        assert_eq!(secondary.owner(ip("10.1.2.3")).unwrap().id, "c2");
+        assert_eq!(tee.mirror_failures(), 0);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mirror_failures_are_not_fatal() {
+        let dir = format!("{}/failing", DATA_DIR);
+        let _ = remove_dir_all(&dir);
+        // a read-only store needs an existing data dir
+        store("failing/secondary", false);
+
+        let tee = TeeStore::with_log_level(
+            store("failing/primary", false),
+            store("failing/secondary", true),
+            LogLevel::Error,
+        );
+        assert!(tee.reserve("c1", "eth0", ip("10.1.2.2"), "0").unwrap());
+        tee.close().unwrap();
+
+        assert_eq!(tee.primary().list().unwrap(), vec![ip("10.1.2.2")]);
+        assert_eq!(tee.mirror_failures(), 1);
+
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,81 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Labels, Owner};
+
+/// A single change applied by `Store::commit`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Operation {
+    Reserve { owner: Owner, ip: IpAddr },
+    Release(IpAddr),
+    RecordLastReserved { ip: IpAddr, range_id: String },
+}
+
+/// A batch of store changes which `Store::commit` applies all-or-nothing.
+/// Operations are recorded through chained calls, e.g.
+/// `txn.reserve(id, ifname, ip).record_last_reserved(ip, range_id)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transaction {
+    operations: Vec<Operation>,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    pub fn reserve(&mut self, id: &str, ifname: &str, ip: IpAddr) -> &mut Transaction {
+        self.reserve_in(id, ifname, None, ip)
+    }
+
+    /// Like `reserve`, but also records the network namespace of the
+    /// container, e.g. so a garbage collector can tell whether it still
+    /// exists.
+    pub fn reserve_in(
+        &mut self,
+        id: &str,
+        ifname: &str,
+        netns: Option<&str>,
+        ip: IpAddr,
+    ) -> &mut Transaction {
+        let owner = Owner {
+            id: id.to_owned(),
+            ifname: ifname.to_owned(),
+            netns: netns.map(str::to_owned),
+            pod: None,
+            labels: Labels::new(),
+        };
+        self.reserve_for(owner, ip)
+    }
+
+    /// Reserves `ip` for `owner` with everything known about it.
+    pub fn reserve_for(&mut self, owner: Owner, ip: IpAddr) -> &mut Transaction {
+        self.operations.push(Operation::Reserve {
+            owner: owner,
+            ip: ip,
+        });
+        self
+    }
+
+    pub fn release(&mut self, ip: IpAddr) -> &mut Transaction {
+        self.operations.push(Operation::Release(ip));
+        self
+    }
+
+    pub fn record_last_reserved(&mut self, ip: IpAddr, range_id: &str) -> &mut Transaction {
+        self.operations.push(Operation::RecordLastReserved {
+            ip: ip,
+            range_id: range_id.to_owned(),
+        });
+        self
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
@@ -0,0 +1,113 @@
+//! Running a long running process as a systemd service.
+//!
+//! `listen_fds` takes over the sockets of socket activation, so systemd can
+//! accept connections before the process is up and none are refused while
+//! it starts. `notify` reports readiness and state changes of a
+//! `Type=notify` service. Both are no-ops outside of systemd.
+
+use std::env;
+use std::io::Error as IoError;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+/// First file descriptor passed by socket activation, following stdin,
+/// stdout and stderr.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// File descriptors of the sockets systemd passed to this process, empty if
+/// it wasn't socket activated. The variables describing them are removed,
+/// so child processes don't take them for their own, and the descriptors
+/// are marked close-on-exec.
+pub fn listen_fds() -> Result<Range<RawFd>, IoError> {
+    let fds = listen_fds_from(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    );
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    for fd in fds.clone() {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(IoError::last_os_error());
+        }
+    }
+
+    Ok(fds)
+}
+
+/// The file descriptors `LISTEN_PID` and `LISTEN_FDS` describe, if they are
+/// meant for the process `pid`.
+fn listen_fds_from(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Range<RawFd> {
+    let count = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) if listen_pid.parse() == Ok(pid) => {
+            listen_fds.parse::<RawFd>().unwrap_or(0).max(0)
+        }
+        _ => 0,
+    };
+
+    LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)
+}
+
+/// Sends `state` to the service manager, e.g. `READY=1` once the process
+/// serves requests or `STOPPING=1` when it shuts down. Returns false if
+/// there is no service manager listening, i.e. `NOTIFY_SOCKET` is unset.
+pub fn notify(state: &str) -> Result<bool, IoError> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_to(&socket.to_string_lossy(), state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+/// Sends `state` to the datagram socket `socket`, a path or, on Linux, an
+/// abstract socket name prefixed with `@`.
+fn notify_to(socket: &str, state: &str) -> Result<(), IoError> {
+    let sender = UnixDatagram::unbound()?;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if let Some(name) = socket.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+            return sender.send_to_addr(state.as_bytes(), &addr).map(|_| ());
+        }
+    }
+
+    sender.send_to(state.as_bytes(), socket).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fds() {
+        assert_eq!(listen_fds_from(Some("42"), Some("2"), 42), 3..5);
+        // meant for another process, e.g. the parent
+        assert_eq!(listen_fds_from(Some("41"), Some("2"), 42), 3..3);
+        assert_eq!(listen_fds_from(None, Some("2"), 42), 3..3);
+        assert_eq!(listen_fds_from(Some("42"), Some("x"), 42), 3..3);
+        assert_eq!(listen_fds_from(Some("42"), Some("-1"), 42), 3..3);
+    }
+
+    #[test]
+    fn notify_socket() {
+        let path = "/tmp/cni-systemd-notify";
+        let _ = std::fs::remove_file(path);
+
+        let receiver = UnixDatagram::bind(path).unwrap();
+        notify_to(path, "READY=1\nSTATUS=serving").unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=serving");
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,135 @@
+//! Where the time of a CNI command goes, to tell lock contention apart from
+//! a slow store on slow nodes. With `logLevel` `info` every command ends
+//! with a summary line like
+//!
+//! ```text
+//! info: ADD c1/eth0 took 12.40ms: config-parse 0.12ms, lock-acquire 10.01ms, search 1.30ms, reserve 0.85ms, result-serialize 0.12ms
+//! ```
+
+use std::cell::Cell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The phases of a CNI command, in the order they run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    ConfigParse,
+    /// Waiting for the network or range set locks.
+    LockAcquire,
+    /// Picking IPs, including the reads of the store it takes.
+    Search,
+    /// Writing the store: reservations on ADD, releases on DEL.
+    Reserve,
+    ResultSerialize,
+}
+
+pub const PHASES: [Phase; 5] = [
+    Phase::ConfigParse,
+    Phase::LockAcquire,
+    Phase::Search,
+    Phase::Reserve,
+    Phase::ResultSerialize,
+];
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Phase::ConfigParse => "config-parse",
+            Phase::LockAcquire => "lock-acquire",
+            Phase::Search => "search",
+            Phase::Reserve => "reserve",
+            Phase::ResultSerialize => "result-serialize",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Time spent in each phase of one command. Phases running repeatedly,
+/// e.g. once per range set, add up.
+#[derive(Debug)]
+pub struct Timings {
+    started: Instant,
+    phases: Cell<[Duration; 5]>,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings {
+            started: Instant::now(),
+            phases: Cell::new([Duration::from_secs(0); 5]),
+        }
+    }
+
+    /// Runs `f`, counting its time to `phase`.
+    pub fn time<T, F: FnOnce() -> T>(&self, phase: Phase, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+
+        result
+    }
+
+    pub fn add(&self, phase: Phase, elapsed: Duration) {
+        let mut phases = self.phases.get();
+        phases[phase as usize] += elapsed;
+        self.phases.set(phases);
+    }
+
+    pub fn get(&self, phase: Phase) -> Duration {
+        self.phases.get()[phase as usize]
+    }
+
+    /// Time since the timings were created.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The summary line of `command`, e.g. `ADD c1/eth0`, with the time
+    /// since the timings were created.
+    pub fn summary(&self, command: &str) -> String {
+        let phases: Vec<String> = PHASES
+            .iter()
+            .map(|phase| format!("{} {}", phase, millis(self.get(*phase))))
+            .collect();
+
+        format!(
+            "{} took {}: {}",
+            command,
+            millis(self.elapsed()),
+            phases.join(", ")
+        )
+    }
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Timings::new()
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let timings = Timings::new();
+        timings.add(Phase::LockAcquire, Duration::from_micros(10_010));
+        timings.add(Phase::LockAcquire, Duration::from_micros(2_000));
+        assert_eq!(timings.time(Phase::Search, || 7), 7);
+
+        assert_eq!(
+            timings.get(Phase::LockAcquire),
+            Duration::from_micros(12_010)
+        );
+        let summary = timings.summary("ADD c1/eth0");
+        assert!(summary.starts_with("ADD c1/eth0 took "));
+        assert!(summary.ends_with(
+            ": config-parse 0.00ms, lock-acquire 12.01ms, search 0.00ms, reserve 0.00ms, result-serialize 0.00ms"
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+//! Every problem found while validating a configuration, instead of only
+//! the first. Each error names where it was found with a JSON path like
+//! `ipam.ranges[0][1]`, so `validate` can list them all and a failed CNI
+//! command can return them in the `details` of its error result, as
+//!
+//! ```json
+//! [{"location": "ipam.ranges[0][1]", "message": "Gateway 10.1.4.1 is out of network 10.1.3.0/24"}]
+//! ```
+
+use std::error::Error as StdError;
+use std::fmt;
+
+use serde::Serialize;
+
+/// A problem and the location of the offending field, empty for problems
+/// of the configuration as a whole, e.g. invalid JSON.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ValidationError {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.location.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.location, self.message)
+        }
+    }
+}
+
+/// The problems of a configuration in the order they were found. Displayed
+/// joined by `; `, serialized as a list of `ValidationError`s.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct ValidationErrors(Vec<ValidationError>);
+
+impl ValidationErrors {
+    pub fn new() -> ValidationErrors {
+        ValidationErrors::default()
+    }
+
+    pub fn push<L: Into<String>, M: fmt::Display>(&mut self, location: L, message: M) {
+        self.0.push(ValidationError {
+            location: location.into(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Records the error of `result` at `location`, returns the value of an
+    /// `Ok` to go on validating it.
+    pub fn check<T, E: fmt::Display>(&mut self, location: &str, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.push(location, err);
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ValidationError> {
+        self.0.iter()
+    }
+
+    /// Every error as a `location: message` line.
+    pub fn lines(&self) -> Vec<String> {
+        self.0.iter().map(ToString::to_string).collect()
+    }
+
+    /// Ok if no problem was found.
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, err) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", err)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StdError for ValidationErrors {}
+
+impl IntoIterator for ValidationErrors {
+    type Item = ValidationError;
+    type IntoIter = std::vec::IntoIter<ValidationError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn collects() {
+        let mut errors = ValidationErrors::new();
+        assert_eq!(errors.clone().into_result(), Ok(()));
+
+        assert_eq!(errors.check::<_, String>("ipam.ranges[0]", Ok(7)), Some(7));
+        assert_eq!(
+            errors.check::<u8, _>("ipam.ranges[0][1]", Err("too small")),
+            None
+        );
+        errors.push("", "failed to parse network configuration");
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors.to_string(),
+            "ipam.ranges[0][1]: too small; failed to parse network configuration"
+        );
+        assert_eq!(
+            serde_json::to_value(&errors).unwrap(),
+            json!([
+                {"location": "ipam.ranges[0][1]", "message": "too small"},
+                {"message": "failed to parse network configuration"},
+            ])
+        );
+        assert!(errors.into_result().is_err());
+    }
+}
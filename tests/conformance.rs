@@ -0,0 +1,149 @@
+//! Conformance tests against the reference Go `host-local` plugin.
+//!
+//! Every directory under `tests/golden` holds a network configuration, a
+//! sequence of ADD/DEL invocations with the results the Go plugin produces
+//! for them, and the reservation files it leaves in its data dir afterwards. The
+//! cases are replayed against our binary exactly the way a runtime would call
+//! it, and results are compared as JSON values so key order doesn't matter.
+//! Every case runs with `upstreamRangeIds`, which keeps the data dir in the
+//! layout of the Go plugin, so reservation files are compared as a whole.
+//!
+//! Known, intentional deviations:
+//!
+//! - Last reserved IPs are not compared, the golden data doesn't record them.
+//! - A repeated ADD for the same container and interface returns the existing
+//!   allocation, where the Go plugin fails with a duplicate allocation error.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Step {
+    command: String,
+    container_id: String,
+    ifname: String,
+    /// Expected stdout, absent when the plugin prints nothing.
+    result: Option<Value>,
+    /// Expected CNI error code, only the code is compared since error
+    /// messages aren't part of the spec.
+    error_code: Option<u64>,
+}
+
+fn golden_dir(case: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(case)
+}
+
+fn read_json(path: &Path) -> Value {
+    let data = fs::read_to_string(path).unwrap();
+    serde_json::from_str(&data).unwrap()
+}
+
+fn exec(config: &Value, step: &Step) -> (bool, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_host-local"))
+        .env("CNI_COMMAND", &step.command)
+        .env("CNI_CONTAINERID", &step.container_id)
+        .env("CNI_NETNS", "/var/run/netns/test")
+        .env("CNI_IFNAME", &step.ifname)
+        .env("CNI_PATH", "/opt/cni/bin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(config.to_string().as_bytes())
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+/// Reads every reservation file of the network, skipping the lock file and
+/// last reserved bookkeeping.
+fn reservations(network_dir: &Path) -> BTreeMap<String, String> {
+    fs::read_dir(network_dir)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| {
+            !name.starts_with("lock")
+                && !name.starts_with("last_reserved_ip")
+                && name != "stats.json"
+        })
+        .map(|name| {
+            let content = fs::read_to_string(network_dir.join(&name)).unwrap();
+            (name, content)
+        })
+        .collect()
+}
+
+fn run_case(case: &str) {
+    let dir = golden_dir(case);
+    let data_dir = std::env::temp_dir().join(format!("host-local-conformance-{}", case));
+    let _ = fs::remove_dir_all(&data_dir);
+
+    let mut config = read_json(&dir.join("config.json"));
+    config["ipam"]["dataDir"] = Value::from(data_dir.to_string_lossy().into_owned());
+    config["ipam"]["upstreamRangeIds"] = Value::from(true);
+    let network = config["name"].as_str().unwrap().to_owned();
+
+    let steps: Vec<Step> = serde_json::from_value(read_json(&dir.join("steps.json"))).unwrap();
+    for (index, step) in steps.iter().enumerate() {
+        let (success, stdout) = exec(&config, step);
+
+        match step.error_code {
+            Some(code) => {
+                assert!(!success, "{} step {} should fail", case, index);
+                let error: Value = serde_json::from_str(&stdout).unwrap();
+                assert_eq!(error["code"], code, "{} step {}: {}", case, index, stdout);
+            }
+            None => {
+                assert!(success, "{} step {} failed: {}", case, index, stdout);
+                match &step.result {
+                    Some(expected) => {
+                        let result: Value = serde_json::from_str(&stdout).unwrap();
+                        assert_eq!(&result, expected, "{} step {}", case, index);
+                    }
+                    None => assert_eq!(stdout, "", "{} step {}", case, index),
+                }
+            }
+        }
+    }
+
+    let expected: BTreeMap<String, String> =
+        serde_json::from_value(read_json(&dir.join("store.json"))).unwrap();
+    assert_eq!(reservations(&data_dir.join(network)), expected, "{}", case);
+
+    let _ = fs::remove_dir_all(&data_dir);
+}
+
+#[test]
+fn single_range() {
+    run_case("single-range");
+}
+
+#[test]
+fn dual_stack() {
+    run_case("dual-stack");
+}
+
+#[test]
+fn exhausted_range() {
+    run_case("exhausted-range");
+}
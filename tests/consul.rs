@@ -0,0 +1,96 @@
+//! `ConsulStore` against a real Consul agent, e.g. a throwaway one in
+//! docker:
+//!
+//! ```text
+//! docker run --rm -d -p 8500:8500 hashicorp/consul agent -dev -client 0.0.0.0
+//! CONSUL_HTTP_ADDR=127.0.0.1:8500 cargo test --features consul --test consul -- --ignored
+//! ```
+//!
+//! Every test works on its own network below a prefix unique to the run, so
+//! they neither collide with each other nor with earlier runs.
+
+use std::env;
+use std::net::IpAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use host_local::store::consul::{ConsulOptions, ConsulStore};
+use host_local::store::{Store, StoreError, Transaction};
+
+fn store(network: &str) -> ConsulStore {
+    let address = env::var("CONSUL_HTTP_ADDR")
+        .expect("CONSUL_HTTP_ADDR must point to the Consul agent to test against");
+    let options = ConsulOptions {
+        address: address.trim_start_matches("http://").to_owned(),
+        prefix: format!("host-local-test/{}", process::id()),
+        token: env::var("CONSUL_HTTP_TOKEN").ok(),
+        session_ttl: Duration::from_secs(10),
+        lock_timeout: Some(Duration::from_millis(500)),
+    };
+
+    ConsulStore::new(network, options).unwrap()
+}
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+#[test]
+#[ignore]
+fn reserve_and_release() {
+    let store = store("reserve");
+
+    store.lock().unwrap();
+    assert!(store.reserve("c1", "eth0", ip("10.1.2.3"), "0").unwrap());
+    assert!(!store.reserve("c2", "eth0", ip("10.1.2.3"), "0").unwrap());
+    assert!(store.reserve("c1", "eth1", ip("2001:db8::3"), "1").unwrap());
+    store.unlock().unwrap();
+
+    assert_eq!(store.last_reserved_ip("0").unwrap(), ip("10.1.2.3"));
+    assert_eq!(store.get_by_id("c1", "eth0"), vec![ip("10.1.2.3")]);
+    assert_eq!(store.owner(ip("2001:db8::3")).unwrap().ifname, "eth1");
+    assert_eq!(store.list().unwrap().len(), 2);
+
+    store.release(ip("10.1.2.3")).unwrap();
+    assert!(matches!(
+        store.release(ip("10.1.2.3")),
+        Err(StoreError::NotFound(_))
+    ));
+    store.release_by_id("c1", "eth1").unwrap();
+    assert!(store.list().unwrap().is_empty());
+}
+
+#[test]
+#[ignore]
+fn commit_is_all_or_nothing() {
+    let store = store("commit");
+    assert!(store.reserve("c1", "eth0", ip("10.1.2.2"), "0").unwrap());
+
+    let mut txn = Transaction::new();
+    txn.reserve("c2", "eth0", ip("10.1.2.3"))
+        .reserve("c2", "eth0", ip("10.1.2.2"))
+        .record_last_reserved(ip("10.1.2.3"), "0");
+    assert!(!store.commit(&txn).unwrap());
+
+    assert_eq!(store.list().unwrap(), vec![ip("10.1.2.2")]);
+    assert_eq!(store.last_reserved_ip("0").unwrap(), ip("10.1.2.2"));
+}
+
+#[test]
+#[ignore]
+fn lock_excludes_other_nodes() {
+    let first = store("lock");
+    let second = store("lock");
+
+    first.lock().unwrap();
+    assert!(matches!(second.lock(), Err(StoreError::LockTimeout { .. })));
+
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        first.unlock().unwrap();
+    });
+    second.lock().unwrap();
+    second.unlock().unwrap();
+    handle.join().unwrap();
+}
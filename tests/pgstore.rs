@@ -0,0 +1,113 @@
+//! `PgStore` against a real PostgreSQL server, e.g. a throwaway one in
+//! docker:
+//!
+//! ```text
+//! docker run --rm -d -p 5432:5432 -e POSTGRES_PASSWORD=cni postgres
+//! PG_PARAMS="host=127.0.0.1 user=postgres password=cni" \
+//!     cargo test --features pgstore --test pgstore -- --ignored
+//! ```
+//!
+//! Every test works on its own network, named after the test and the run,
+//! so they neither collide with each other nor with earlier runs.
+
+use std::collections::HashSet;
+use std::env;
+use std::net::IpAddr;
+use std::process;
+use std::thread;
+
+use host_local::allocator::range::Range;
+use host_local::store::pgstore::PgStore;
+use host_local::store::{Owner, Store, StoreError, Transaction};
+
+fn store(network: &str) -> PgStore {
+    let params = env::var("PG_PARAMS")
+        .expect("PG_PARAMS must hold the connection parameters of the server to test against");
+
+    PgStore::connect(&format!("{}-{}", network, process::id()), &params).unwrap()
+}
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+fn owner(id: &str) -> Owner {
+    Owner {
+        id: id.to_owned(),
+        ifname: "eth0".to_owned(),
+        netns: None,
+        pod: None,
+        labels: Default::default(),
+    }
+}
+
+#[test]
+#[ignore]
+fn reserve_and_release() {
+    let store = store("reserve");
+
+    assert!(store.reserve("c1", "eth0", ip("10.1.2.3"), "0").unwrap());
+    assert!(!store.reserve("c2", "eth0", ip("10.1.2.3"), "0").unwrap());
+    assert!(store.reserve("c1", "eth1", ip("2001:db8::3"), "1").unwrap());
+
+    assert_eq!(store.last_reserved_ip("0").unwrap(), ip("10.1.2.3"));
+    assert_eq!(store.get_by_id("c1", "eth0"), vec![ip("10.1.2.3")]);
+    assert_eq!(store.owner(ip("2001:db8::3")).unwrap().ifname, "eth1");
+    assert_eq!(store.list().unwrap().len(), 2);
+
+    store.release(ip("10.1.2.3")).unwrap();
+    assert!(matches!(
+        store.release(ip("10.1.2.3")),
+        Err(StoreError::NotFound(_))
+    ));
+    store.release_by_id("c1", "eth1").unwrap();
+    assert!(store.list().unwrap().is_empty());
+}
+
+#[test]
+#[ignore]
+fn commit_is_all_or_nothing() {
+    let store = store("commit");
+    assert!(store.reserve("c1", "eth0", ip("10.1.2.2"), "0").unwrap());
+
+    let mut txn = Transaction::new();
+    txn.reserve("c2", "eth0", ip("10.1.2.3"))
+        .reserve("c2", "eth0", ip("10.1.2.2"))
+        .record_last_reserved(ip("10.1.2.3"), "0");
+    assert!(!store.commit(&txn).unwrap());
+
+    assert_eq!(store.list().unwrap(), vec![ip("10.1.2.2")]);
+    assert_eq!(store.last_reserved_ip("0").unwrap(), ip("10.1.2.2"));
+}
+
+#[test]
+#[ignore]
+fn concurrent_claims_never_collide() {
+    let range = Range::new("10.1.2.0/28".parse().unwrap(), None, None, None).unwrap();
+    assert_eq!(store("claim").populate(&range).unwrap(), 13);
+
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            thread::spawn(move || {
+                let store = store("claim");
+                let mut ips = Vec::new();
+                while let Some(ip) = store
+                    .claim(&owner(&format!("c{}", worker)), &range)
+                    .unwrap()
+                {
+                    ips.push(ip);
+                }
+                ips
+            })
+        })
+        .collect();
+
+    let mut claimed = HashSet::new();
+    for worker in workers {
+        for ip in worker.join().unwrap() {
+            assert!(claimed.insert(ip), "{} was claimed twice", ip);
+        }
+    }
+    assert_eq!(claimed.len(), 13);
+    assert!(!claimed.contains(&range.gateway.unwrap()));
+}
@@ -0,0 +1,162 @@
+//! Concurrent ADD/DEL against one network, the way parallel pod creation
+//! hits the plugin on a busy node.
+//!
+//! Every worker thread runs the plugin binary as a separate process, like a
+//! runtime would, so processes only coordinate through the data dir. The
+//! test tracks which container holds which IP: an IP is forgotten before the
+//! DEL releasing it is run and recorded after the ADD returning it finished,
+//! so at any point an IP handed out while still recorded for another
+//! container was allocated twice.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ipnetwork::IpNetwork;
+use serde_json::{json, Value};
+
+const NETWORK: &str = "stress";
+const WORKERS: usize = 24;
+const ADDS_PER_WORKER: usize = 10;
+/// Containers a worker keeps alive before deleting its oldest one.
+const LIVE_PER_WORKER: usize = 2;
+
+fn config(data_dir: &Path) -> Value {
+    json!({
+        "cniVersion": "1.0.0",
+        "name": NETWORK,
+        "type": "bridge",
+        "ipam": {
+            "type": "host-local",
+            "dataDir": data_dir.to_string_lossy(),
+            "ranges": [
+                [{"subnet": "10.1.0.0/26"}],
+                [{"subnet": "10.2.0.0/26"}]
+            ]
+        }
+    })
+}
+
+fn exec(config: &Value, command: &str, container_id: &str) -> Value {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_host-local"))
+        .env("CNI_COMMAND", command)
+        .env("CNI_CONTAINERID", container_id)
+        .env("CNI_NETNS", "/var/run/netns/test")
+        .env("CNI_IFNAME", "eth0")
+        .env("CNI_PATH", "/opt/cni/bin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(config.to_string().as_bytes())
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.status.success(),
+        "{} {} failed: {}",
+        command,
+        container_id,
+        stdout
+    );
+
+    match stdout.as_str() {
+        "" => Value::Null,
+        _ => serde_json::from_str(&stdout).unwrap(),
+    }
+}
+
+fn result_ips(result: &Value) -> Vec<IpAddr> {
+    result["ips"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|ip| {
+            let address: IpNetwork = ip["address"].as_str().unwrap().parse().unwrap();
+            address.ip()
+        })
+        .collect()
+}
+
+/// Reads every reservation file of the network as IP to container id.
+fn reservations(network_dir: &Path) -> BTreeMap<IpAddr, String> {
+    fs::read_dir(network_dir)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let ip = entry.file_name().to_str()?.parse::<IpAddr>().ok()?;
+            let content = fs::read_to_string(entry.path()).unwrap();
+            let id = content.split("\r\n").next().unwrap().to_owned();
+            Some((ip, id))
+        })
+        .collect()
+}
+
+fn worker(config: &Value, worker: usize, holders: &Mutex<BTreeMap<IpAddr, String>>) {
+    let mut live: Vec<(String, Vec<IpAddr>)> = Vec::new();
+
+    for n in 0..ADDS_PER_WORKER {
+        if live.len() == LIVE_PER_WORKER {
+            let (container_id, ips) = live.remove(0);
+            {
+                let mut holders = holders.lock().unwrap();
+                for ip in &ips {
+                    assert_eq!(holders.remove(ip).as_ref(), Some(&container_id));
+                }
+            }
+            exec(config, "DEL", &container_id);
+        }
+
+        let container_id = format!("w{}-c{}", worker, n);
+        let ips = result_ips(&exec(config, "ADD", &container_id));
+        assert_eq!(ips.len(), 2, "{} got {:?}", container_id, ips);
+
+        {
+            let mut holders = holders.lock().unwrap();
+            for ip in &ips {
+                if let Some(holder) = holders.insert(*ip, container_id.clone()) {
+                    panic!("{} allocated to {} and {}", ip, holder, container_id);
+                }
+            }
+        }
+        live.push((container_id, ips));
+    }
+}
+
+#[test]
+fn concurrent_add_del() {
+    let data_dir = std::env::temp_dir().join("host-local-stress");
+    let _ = fs::remove_dir_all(&data_dir);
+
+    let config = Arc::new(config(&data_dir));
+    let holders = Arc::new(Mutex::new(BTreeMap::new()));
+
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|n| {
+            let config = config.clone();
+            let holders = holders.clone();
+            thread::spawn(move || worker(&config, n, &holders))
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let holders = holders.lock().unwrap();
+    assert_eq!(holders.len(), WORKERS * LIVE_PER_WORKER * 2);
+    assert_eq!(reservations(&data_dir.join(NETWORK)), *holders);
+
+    let _ = fs::remove_dir_all(&data_dir);
+}